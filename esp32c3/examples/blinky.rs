@@ -2,6 +2,8 @@
 //!
 //! This assumes that a LED is connected to the pin assigned to `led`. (GPIO7 for the ESP32c3-RUST DK)
 //!
+//! The blink pattern is driven by `shared::led_pattern::LedSequencer`, try another `BuiltinPattern`.
+//!
 //! Run on target:
 //!
 //! cargo embed --example blinky
@@ -13,6 +15,7 @@
 use esp32c3_hal::{clock::ClockControl, gpio::IO, peripherals::Peripherals, prelude::*, Delay};
 
 use rtt_target::{rprintln, rtt_init_print};
+use shared::led_pattern::{BuiltinPattern, LedSequencer};
 
 use panic_rtt_target as _;
 
@@ -31,16 +34,25 @@ fn main() -> ! {
 
     led.set_high().unwrap();
 
-    // Initialize the Delay peripheral, and use it to toggle the LED state in a loop.
+    // Initialize the Delay peripheral, and use it to wait for the next LED change in a loop.
+    // We keep track of time (in ms) by adding up the delays.
     let mut delay = Delay::new(&clocks);
-
-    let mut i = 0;
+    let mut now = 0;
+    let mut seq = LedSequencer::new(BuiltinPattern::Blink.into(), now);
 
     loop {
-        i = (i + 1) % 10;
-        rprintln!("blink {}", i);
-
-        led.toggle().unwrap();
-        delay.delay_ms(500u32);
+        let (on, next) = seq.tick(now);
+        rprintln!("blink {} at {} ms", on, now);
+
+        if on {
+            led.set_high().unwrap();
+        } else {
+            led.set_low().unwrap();
+        }
+
+        // a finished pattern keeps its level, check back later
+        let next = next.unwrap_or(now + 500);
+        delay.delay_ms((next - now) as u32);
+        now = next;
     }
 }
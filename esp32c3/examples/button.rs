@@ -1,10 +1,11 @@
-//! panic
+//! button
 //!
 //! Run on target:
 //!
-//! cargo embed --example panic
+//! cargo embed --example button
 //!
-//! Showcases basic panic handling
+//! Showcases debounced button gestures (press, click, double click, long press)
+//! on GPIO9, recognized by `shared::button::ButtonGestures`
 
 #![no_main]
 #![no_std]
@@ -13,22 +14,29 @@
 // bring in panic handler
 use panic_rtt_target as _;
 
-#[rtic::app(device = esp32c3)]
+#[rtic::app(device = esp32c3, dispatchers = [FROM_CPU_INTR0])]
 mod app {
+    use rtic_monotonics::{
+        esp32c3_systimer::{ExtU64, Systimer},
+        Monotonic,
+    };
     use rtt_target::{rprintln, rtt_init_print};
+    use shared::button::ButtonGestures;
 
     // to bring in interrupt vector initialization
     use esp32c3_hal::{
         self as _,
         clock::ClockControl,
-        gpio::{Gpio9, Input, PullUp},
+        gpio::{Event, Gpio9, Input, PullUp},
         peripherals::Peripherals,
         prelude::*,
         IO,
     };
 
     #[shared]
-    struct Shared {}
+    struct Shared {
+        gestures: ButtonGestures,
+    }
 
     #[local]
     struct Local {
@@ -36,7 +44,7 @@ mod app {
     }
 
     #[init]
-    fn init(_: init::Context) -> (Shared, Local) {
+    fn init(cx: init::Context) -> (Shared, Local) {
         rtt_init_print!();
         rprintln!(env!("CARGO_CRATE_NAME"));
 
@@ -44,17 +52,47 @@ mod app {
         let system = peripherals.SYSTEM.split();
         let _ = ClockControl::max(system.clock_control).freeze();
 
+        let systimer_token = rtic_monotonics::create_systimer_token!();
+        Systimer::start(cx.core.SYSTIMER, systimer_token);
+
         let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
         let mut button = io.pins.gpio9.into_pull_up_input();
-        button.listen(esp32c3_hal::gpio::Event::FallingEdge);
+        // both edges, the recognizer needs releases too
+        button.listen(Event::AnyEdge);
+
+        poll::spawn().unwrap();
 
-        #[allow(unreachable_code)]
-        (Shared {}, Local { button })
+        (
+            Shared {
+                gestures: ButtonGestures::default(),
+            },
+            Local { button },
+        )
     }
 
-    #[task(binds = GPIO, local = [button])]
-    fn button(cx: button::Context) {
-        rprintln!("button press");
+    fn now_ms() -> u64 {
+        Systimer::now().duration_since_epoch().to_millis()
+    }
+
+    #[task(binds = GPIO, local = [button], shared = [gestures])]
+    fn button(mut cx: button::Context) {
+        // active low
+        let pressed = cx.local.button.is_low().unwrap();
+        cx.shared.gestures.lock(|g| g.edge(pressed, now_ms()));
         cx.local.button.clear_interrupt();
     }
+
+    // polling period well below the debounce time
+    #[task(shared = [gestures])]
+    async fn poll(mut cx: poll::Context) {
+        loop {
+            let now = now_ms();
+            cx.shared.gestures.lock(|g| {
+                while let Some(gesture) = g.poll(now) {
+                    rprintln!("{:?}", gesture);
+                }
+            });
+            Systimer::delay(10.millis()).await;
+        }
+    }
 }
@@ -4,14 +4,37 @@
 //!
 //! cargo embed --example panic
 //!
-//! Showcases basic panic handling
+//! Showcases panic handling. The panic handler keeps a report in RAM that
+//! survives the reset (a `shared::crash::CrashRing`) and resets, the report
+//! is printed after the restart. `uart_echo_split` answers
+//! `Command::GetCrashReport` instead. Panics on every other boot.
 
 #![no_main]
 #![no_std]
 #![feature(type_alias_impl_trait)]
 
-// bring in panic handler
-use panic_rtt_target as _;
+use core::panic::PanicInfo;
+use esp32c3_hal::macros::ram;
+use rtt_target::rprintln;
+use shared::crash::{CrashReport, CrashRing};
+
+/// In RTC fast memory, not initialized at boot
+#[ram(rtc_fast, uninitialized)]
+static mut CRASHES: CrashRing<4> = CrashRing::new();
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rprintln!("{}", info);
+    let (file, line) = info.location().map_or(("?", 0), |l| (l.file(), l.line()));
+    // SAFETY: nothing else runs after a panic
+    let crashes = unsafe { &mut *core::ptr::addr_of_mut!(CRASHES) };
+    // no clock in this example, so no uptime
+    let report = CrashReport::new(&info.message(), file, line, 0, crashes.resets());
+    crashes.record(&report);
+    esp32c3_hal::reset::software_reset();
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
 
 #[rtic::app(device = esp32c3)]
 mod app {
@@ -29,11 +52,20 @@ mod app {
     #[init]
     fn init(_: init::Context) -> (Shared, Local) {
         rtt_init_print!();
-        rprintln!("no panic so far");
+        // SAFETY: the panic handler is the only other user
+        let crashes = unsafe { &mut *core::ptr::addr_of_mut!(super::CRASHES) };
+        rprintln!("boot {}", crashes.boot());
 
-        panic!("explicit panic");
+        let mut reported = false;
+        while let Some(report) = crashes.take() {
+            rprintln!("{}", report);
+            reported = true;
+        }
+        if !reported {
+            rprintln!("no panic so far");
+            panic!("explicit panic");
+        }
 
-        #[allow(unreachable_code)]
         (Shared {}, Local {})
     }
 }
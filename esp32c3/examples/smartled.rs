@@ -4,23 +4,32 @@
 //! A RGB LED is connected to that pin on the ESP32-C3-DevKitM-1 and
 //! ESP32-C3-DevKitC-02 boards.
 //!
-//! The demo will leverage the [`smart_leds`](https://crates.io/crates/smart-leds)
-//! crate functionality to circle through the HSV hue color space (with
-//! saturation and value both at 255). Additionally, we apply a gamma correction
-//! and limit the brightness to 10 (out of 255).
+//! The demo renders the `shared::animation` rainbow effect, circling through the
+//! HSV hue color space (with saturation and value both at 255). The animation
+//! applies a gamma correction and limits the brightness to 10 (out of 255), we
+//! just push the frames to the LED. Try the other effects on the host first:
+//!
+//! cd host; cargo run -- preview rainbow --brightness 10
+//!
+//! The frames are turned into RMT pulse codes by `shared::ws2812`, encoding the
+//! next frame while the current one is sent.
 #![no_std]
 #![no_main]
 
-use esp32c3_hal::{clock::ClockControl, peripherals, prelude::*, rmt::Rmt, Delay, IO};
+use esp32c3_hal::{
+    clock::ClockControl,
+    peripherals,
+    prelude::*,
+    rmt::{Rmt, TxChannel, TxChannelConfig, TxChannelCreator},
+    Delay, IO,
+};
 //use esp_backtrace as _;
-use esp_hal_smartled::{smartLedAdapter, SmartLedsAdapter};
 use panic_rtt_target as _;
 use rtt_target::{rprintln, rtt_init_print};
-use smart_leds::{
-    brightness, gamma,
-    hsv::{hsv2rgb, Hsv},
-    SmartLedsWrite,
-};
+use shared::animation::{Animation, Effect, RGB8};
+use shared::ws2812::{buffer_len, ColorOrder, DoubleBuffer, Encoder, Timing};
+
+const LEDS: usize = 1;
 
 #[entry]
 fn main() -> ! {
@@ -41,35 +50,47 @@ fn main() -> ! {
     )
     .unwrap();
 
-    // We use one of the RMT channels to instantiate a `SmartLedsAdapter` which can
-    // be used directly with all `smart_led` implementations
-    let mut led = <smartLedAdapter!(0, 1)>::new(rmt.channel0, io.pins.gpio2);
+    // 12.5 ns ticks, the LED data line idles low
+    let mut channel = rmt
+        .channel0
+        .configure(
+            io.pins.gpio2.into_push_pull_output(),
+            TxChannelConfig {
+                clk_divider: 1,
+                idle_output_level: false,
+                idle_output: true,
+                ..TxChannelConfig::default()
+            },
+        )
+        .unwrap();
+    let encoder = Encoder::new(Timing::SK6812, ColorOrder::Grb, 80_000_000, 1);
+    let mut buffers = DoubleBuffer::<{ buffer_len::<RGB8>(LEDS) }>::new();
 
-    // Initialize the Delay peripheral, and use it to toggle the LED state in a
-    // loop.
+    // Initialize the Delay peripheral, and use it to pace the frames. We keep track
+    // of time (in ms) by adding up the delays.
     let mut delay = Delay::new(&clocks);
+    let mut now = 0;
 
-    let mut color = Hsv {
-        hue: 0,
-        sat: 255,
-        val: 255,
-    };
-    let mut data;
+    // a full hue cycle in 256 frames of 20 ms
+    let mut animation = Animation::<LEDS>::new(Effect::Rainbow {
+        period_ms: 256 * 20,
+    })
+    .with_brightness(10);
 
+    buffers.encode(&encoder, animation.frame(now)).unwrap();
+    buffers.swap();
     loop {
-        // Iterate over the rainbow!
-        for hue in 0..=255 {
-            color.hue = hue;
-            rprintln!("hue:{}", hue);
-            // Convert from the HSV color space (where we can easily transition from one
-            // color to the other) to the RGB color space that we can then send to the LED
-            data = [hsv2rgb(color)];
-            // When sending to the LED, we do a gamma correction first (see smart_leds
-            // documentation for details) and then limit the brightness to 10 out of 255 so
-            // that the output it's not too bright.
-            led.write(brightness(gamma(data.iter().cloned()), 10))
-                .unwrap();
-            delay.delay_ms(20u8);
-        }
+        let (front, back) = buffers.split();
+        let transaction = channel.transmit(front);
+
+        // the next frame, while the current one is sent
+        now += 20;
+        let data = animation.frame(now);
+        rprintln!("t:{} {:?}", now, data[0]);
+        back.encode(&encoder, data).unwrap();
+
+        channel = transaction.wait().unwrap();
+        buffers.swap();
+        delay.delay_ms(20u8);
     }
 }
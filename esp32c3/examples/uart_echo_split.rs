@@ -12,7 +12,30 @@
 //!
 //! moserial -p moserial_acm1.cfg
 //!
-//! Echoes incoming data
+//! Commands come as binary control frames (`shared::mux`, as sent by the
+//! host) or as AT lines, `shared::sniffer` tells them apart and answers in
+//! the same format. The AT command shell, see `shared::at`, e.g.:
+//!
+//! AT+SET=0x12,12
+//! OK
+//! AT+GET=0x12
+//! +DATA: 0x12,0,12,0
+//! OK
+//! AT+INFO?
+//! +INFO: version  0.1.0 (1a2b3c4d5e6f)
+//! ...
+//! OK
+//!
+//! Actions scheduled with `Command::Schedule` (after `Command::SetTime`, e.g.,
+//! `cargo run -- schedule` on the host) run once due, their responses are
+//! traced over RTT.
+//!
+//! A panic is kept across the reset and answered to a binary
+//! `Command::GetCrashReport` frame (`cargo run -- crash` on the host).
+//!
+//! Log records (`shared::log`) go out as `Channel::Log` frames while the link
+//! is in binary mode, i.e., the last command came as a frame, so they never
+//! mix with the AT shell's text. `cargo run -- logs` on the host shows them.
 //!
 //! This assumes we have usb<->serial adepter appearing as /dev/ACM1
 //! - Target TX = GPIO0, connect to RX on adapter
@@ -22,17 +45,49 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
-use panic_rtt_target as _;
+use core::panic::PanicInfo;
+use esp32c3_hal::macros::ram;
+use rtt_target::rprintln;
+use shared::crash::{CrashReport, CrashRing};
+
+/// In RTC fast memory, not initialized at boot
+#[ram(rtc_fast, uninitialized)]
+static mut CRASHES: CrashRing<4> = CrashRing::new();
+
+/// Runs `f` on the panic reports, no reference outlives the call
+pub(crate) fn with_crashes<R>(f: impl FnOnce(&mut CrashRing<4>) -> R) -> R {
+    // SAFETY: only called from init, priority 1 tasks, which do not preempt
+    // each other, and the panic handler, with closures that do not panic
+    f(unsafe { &mut *core::ptr::addr_of_mut!(CRASHES) })
+}
 
-// bring in panic handler
-use panic_rtt_target as _;
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rprintln!("{}", info);
+    let (file, line) = info.location().map_or(("?", 0), |l| (l.file(), l.line()));
+    with_crashes(|crashes| {
+        let report = CrashReport::new(
+            &info.message(),
+            file,
+            line,
+            app::uptime_ms(),
+            crashes.resets(),
+        );
+        crashes.record(&report);
+    });
+    esp32c3_hal::reset::software_reset();
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
 
 #[rtic::app(device = esp32c3, dispatchers = [FROM_CPU_INTR0, FROM_CPU_INTR1])]
 mod app {
     use esp32c3_hal::{
         clock::ClockControl,
+        efuse::Efuse,
         peripherals::{Peripherals, TIMG0, UART0},
         prelude::*,
+        reset::{get_reset_reason, SocResetReason},
         timer::{Timer, Timer0, TimerGroup},
         uart::{
             config::{Config, DataBits, Parity, StopBits},
@@ -41,26 +96,178 @@ mod app {
         Uart, IO,
     };
 
+    use core::mem::size_of;
+    use rtic_monotonics::esp32c3_systimer::Systimer;
     use rtic_sync::{channel::*, make_channel};
     use rtt_target::{rprint, rprintln, rtt_init_print};
+    use shared::at::Handler;
+    use shared::info::{Info, ResetReason};
+    use shared::log::{Level, Logger, BOOT, LATENCY, MAX_FRAME};
+    use shared::mux::max_frame_len;
+    use shared::schedule::{Schedule, ScheduleError};
+    use shared::sniffer::{Mode, Responder};
+    use shared::wall_clock::WallClock;
+    use shared::{date_time::UtcDateTime, Command, Message, Response};
 
     const CAPACITY: usize = 100;
 
+    /// A command frame, or an AT line
+    const IN_SIZE: usize = max_frame_len(size_of::<Command>());
+    /// A response frame, or the AT shell's answer to a line
+    const OUT_SIZE: usize = {
+        let frame = max_frame_len(size_of::<Response>());
+        if frame > 256 {
+            frame
+        } else {
+            256
+        }
+    };
+
+    /// Log frames queued for the UART
+    const LOG_FRAMES: usize = 4;
+
+    /// An encoded record and its length
+    type LogFrame = ([u8; MAX_FRAME], usize);
+    type LogSender = Sender<'static, LogFrame, LOG_FRAMES>;
+
+    /// Queues the frames for `log_writer`, a record is dropped (and counted)
+    /// when the queue is full
+    fn logger(mut sender: LogSender) -> Logger<impl FnMut(&[u8]) -> bool> {
+        Logger::new(Level::Info, move |frame: &[u8]| {
+            let mut data = [0; MAX_FRAME];
+            data[..frame.len()].copy_from_slice(frame);
+            sender.try_send((data, frame.len())).is_ok()
+        })
+    }
+
+    /// Remembers the last value set, and runs scheduled actions
+    struct Values {
+        value: u32,
+        /// On the uptime in ms, set with `Command::SetTime`
+        clock: WallClock,
+        schedule: Schedule<4>,
+    }
+
+    /// Also used by the panic handler
+    pub(crate) fn uptime_ms() -> u64 {
+        Systimer::now().duration_since_epoch().to_millis()
+    }
+
+    impl Values {
+        fn run_schedule(&mut self) {
+            let Some(now) = self.clock.now_nanos(uptime_ms()) else {
+                return;
+            };
+            while let Some((slot, action)) = self.schedule.poll(now) {
+                let response = self.command(action.into());
+                rprintln!("scheduled {}: {:?}", slot, response);
+            }
+        }
+    }
+
+    impl Handler for Values {
+        fn command(&mut self, cmd: Command) -> Response {
+            match cmd {
+                Command::Set(_, Message::B(v), _) => {
+                    self.value = v;
+                    Response::SetOk
+                }
+                Command::Get(id, par, dev) => Response::Data(id, par, self.value, dev),
+                Command::GetInfo => Response::Info(Info::new(
+                    env!("CARGO_PKG_VERSION"),
+                    Efuse::get_mac_address(),
+                    uptime_ms(),
+                    reset_reason(),
+                )),
+                Command::SetTime(t) => match self.clock.sync(uptime_ms(), t) {
+                    Ok(()) => Response::SetOk,
+                    Err(_) => Response::ScheduleError(ScheduleError::InvalidTime),
+                },
+                Command::Schedule(spec, action) => {
+                    let now = self.clock.now_nanos(uptime_ms());
+                    match now
+                        .ok_or(ScheduleError::NoClock)
+                        .and_then(|now| self.schedule.insert(spec, action, now))
+                    {
+                        Ok(slot) => Response::Scheduled(slot),
+                        Err(e) => Response::ScheduleError(e),
+                    }
+                }
+                Command::Unschedule(slot) => match self.schedule.remove(slot) {
+                    Ok(_) => Response::SetOk,
+                    Err(e) => Response::ScheduleError(e),
+                },
+                Command::GetCrashReport => Response::CrashReport(super::with_crashes(|c| c.take())),
+                _ => Response::ParseError,
+            }
+        }
+
+        fn time(&mut self) -> Option<UtcDateTime> {
+            self.clock.now(uptime_ms())
+        }
+
+        fn version(&self) -> &str {
+            env!("CARGO_PKG_VERSION")
+        }
+    }
+
+    fn reset_reason() -> ResetReason {
+        match get_reset_reason() {
+            Some(SocResetReason::ChipPowerOn) => ResetReason::PowerOn,
+            Some(SocResetReason::CoreSw | SocResetReason::CpuSw) => ResetReason::Software,
+            Some(SocResetReason::CoreDeepSleep) => ResetReason::DeepSleep,
+            Some(SocResetReason::SysBrownOut) => ResetReason::Brownout,
+            Some(
+                SocResetReason::CoreMwdt0
+                | SocResetReason::CoreMwdt1
+                | SocResetReason::CoreRtcWdt
+                | SocResetReason::CpuMwdt0
+                | SocResetReason::CpuMwdt1
+                | SocResetReason::CpuRtcWdt
+                | SocResetReason::SysRtcWdt
+                | SocResetReason::SysSuperWdt,
+            ) => ResetReason::Watchdog,
+            _ => ResetReason::Unknown,
+        }
+    }
+
     #[shared]
-    struct Shared {}
+    struct Shared {
+        values: Values,
+        /// The answers to commands and the log frames
+        tx: UartTx<'static, UART0>,
+        /// Format of the last command, log frames are only sent in binary
+        mode: Mode,
+    }
 
     #[local]
     struct Local {
         timer0: Timer<Timer0<TIMG0>>,
-        tx: UartTx<'static, UART0>,
         rx: UartRx<'static, UART0>,
         sender: Sender<'static, u8, CAPACITY>,
     }
     #[init]
-    fn init(_: init::Context) -> (Shared, Local) {
+    fn init(cx: init::Context) -> (Shared, Local) {
         rtt_init_print!();
         rprintln!("uart_echo_split");
+        super::with_crashes(|c| rprintln!("boot {}, {} panic reports", c.boot(), c.len()));
+        // the uptime in `Command::GetInfo`, and the wall clock
+        let systimer_token = rtic_monotonics::create_systimer_token!();
+        Systimer::start(cx.core.SYSTIMER, systimer_token);
         let (sender, receiver) = make_channel!(u8, CAPACITY);
+        let (log_sender, log_receiver) = make_channel!(LogFrame, LOG_FRAMES);
+        let version = |v: &str| v.parse().unwrap_or(0);
+        logger(log_sender.clone()).format(
+            Level::Info,
+            "main",
+            uptime_ms(),
+            BOOT,
+            &[
+                version(env!("CARGO_PKG_VERSION_MAJOR")),
+                version(env!("CARGO_PKG_VERSION_MINOR")),
+                version(env!("CARGO_PKG_VERSION_PATCH")),
+            ],
+        );
 
         let peripherals = Peripherals::take();
         let mut system = peripherals.SYSTEM.split();
@@ -94,8 +301,7 @@ mod app {
             &mut system.peripheral_clock_control,
         );
 
-        // This is stupid!
-        // TODO, use at commands with break character
+        // one interrupt per byte, the sniffer does the line editing
         uart0.set_rx_fifo_full_threshold(1).unwrap();
         uart0.listen_rx_fifo_full();
 
@@ -103,16 +309,23 @@ mod app {
 
         let (tx, rx) = uart0.split();
 
-        lowprio::spawn(receiver).unwrap();
+        lowprio::spawn(receiver, log_sender).unwrap();
+        log_writer::spawn(log_receiver).unwrap();
+        scheduler::spawn().unwrap();
+
+        let values = Values {
+            value: 0,
+            clock: WallClock::new(1_000, 64),
+            schedule: Schedule::new(),
+        };
 
         (
-            Shared {},
-            Local {
-                timer0,
+            Shared {
+                values,
                 tx,
-                rx,
-                sender,
+                mode: Mode::Binary,
             },
+            Local { timer0, rx, sender },
         )
     }
 
@@ -146,14 +359,68 @@ mod app {
         rx.reset_rx_fifo_full_interrupt()
     }
 
-    #[task(priority = 1, local = [ tx ])]
-    async fn lowprio(cx: lowprio::Context, mut receiver: Receiver<'static, u8, CAPACITY>) {
+    #[task(priority = 1, shared = [values, tx, mode])]
+    async fn lowprio(
+        mut cx: lowprio::Context,
+        mut receiver: Receiver<'static, u8, CAPACITY>,
+        log_sender: LogSender,
+    ) {
         rprintln!("LowPrio started");
-        let tx = cx.local.tx;
+        let mut responder = Responder::<IN_SIZE>::new();
+        let mut out_buf = [0u8; OUT_SIZE];
+        let mut logger = logger(log_sender);
 
         while let Ok(c) = receiver.recv().await {
             rprintln!("Receiver got: {}", c);
-            tx.write(c).unwrap();
+            let start = Systimer::now();
+            let answered = (
+                &mut cx.shared.values,
+                &mut cx.shared.tx,
+                &mut cx.shared.mode,
+            )
+                .lock(|values, tx, mode| {
+                    let answer = responder.push(c, values, &mut out_buf);
+                    *mode = responder.mode();
+                    let Some(answer) = answer else {
+                        return false;
+                    };
+                    for b in answer {
+                        nb::block!(tx.write(*b)).unwrap();
+                    }
+                    true
+                });
+            if answered {
+                let us = (Systimer::now() - start).to_micros();
+                logger.format(Level::Info, "cmd", uptime_ms(), LATENCY, &[us as u32]);
+            }
+        }
+    }
+
+    /// Writes the log frames, a whole frame at a time, and drops them in
+    /// text mode
+    #[task(priority = 1, shared = [tx, mode])]
+    async fn log_writer(
+        mut cx: log_writer::Context,
+        mut receiver: Receiver<'static, LogFrame, LOG_FRAMES>,
+    ) {
+        while let Ok((data, len)) = receiver.recv().await {
+            (&mut cx.shared.tx, &mut cx.shared.mode).lock(|tx, mode| {
+                if *mode != Mode::Binary {
+                    return;
+                }
+                for b in &data[..len] {
+                    nb::block!(tx.write(*b)).unwrap();
+                }
+            });
+        }
+    }
+
+    /// Checks the schedule once a second
+    #[task(priority = 1, shared = [values])]
+    async fn scheduler(mut cx: scheduler::Context) {
+        loop {
+            Systimer::delay(1u64.secs()).await;
+            cx.shared.values.lock(|values| values.run_schedule());
         }
     }
 }
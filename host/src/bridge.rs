@@ -0,0 +1,260 @@
+//! UDP pixel bridge
+//!
+//! Accepts DDP (Distributed Display Protocol) or E1.31 (sACN) packets from PC
+//! lighting software and forwards them to the target as `Command::Pixels`.
+
+use crate::{unexpected, Target};
+use shared::{animation::RGB8, pixels::PixelFrame, Command, Response};
+use std::io::Result;
+use std::net::UdpSocket;
+
+pub const DDP_PORT: u16 = 4048;
+pub const E131_PORT: u16 = 5568;
+
+/// Pixels per E1.31 universe (512 channels, 3 per pixel)
+pub const E131_PIXELS_PER_UNIVERSE: usize = 170;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Ddp,
+    /// Pixel 0 is the first channel of `start_universe`
+    E131 {
+        start_universe: u16,
+    },
+}
+
+/// Pixels at an offset, to be shown if `commit` is set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PixelUpdate {
+    pub offset: u16,
+    pub pixels: Vec<RGB8>,
+    pub commit: bool,
+}
+
+impl PixelUpdate {
+    pub fn frames(&self) -> impl Iterator<Item = PixelFrame> + '_ {
+        PixelFrame::split(self.offset, &self.pixels, self.commit)
+    }
+}
+
+fn rgb(data: &[u8]) -> Vec<RGB8> {
+    data.chunks_exact(3)
+        .map(|c| RGB8::new(c[0], c[1], c[2]))
+        .collect()
+}
+
+fn be16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+fn be32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+const DDP_VERSION_MASK: u8 = 0xc0;
+const DDP_VERSION_1: u8 = 0x40;
+const DDP_TIMECODE: u8 = 0x10;
+const DDP_QUERY: u8 = 0x02;
+const DDP_PUSH: u8 = 0x01;
+
+/// Parse a DDP data packet, None if not one
+pub fn parse_ddp(packet: &[u8]) -> Option<PixelUpdate> {
+    let flags = *packet.first()?;
+    if flags & DDP_VERSION_MASK != DDP_VERSION_1 || flags & DDP_QUERY != 0 {
+        return None;
+    }
+    let header = if flags & DDP_TIMECODE != 0 { 14 } else { 10 };
+    if packet.len() < header {
+        return None;
+    }
+    let offset = be32(&packet[4..8]) as usize; // in bytes
+    let len = be16(&packet[8..10]) as usize;
+    let data = packet.get(header..header + len)?;
+    // not starting at a pixel, the colors would be shifted
+    if !offset.is_multiple_of(3) {
+        return None;
+    }
+    Some(PixelUpdate {
+        offset: u16::try_from(offset / 3).ok()?,
+        pixels: rgb(data),
+        commit: flags & DDP_PUSH != 0,
+    })
+}
+
+const ACN_ID: &[u8; 12] = b"ASC-E1.17\0\0\0";
+const E131_ROOT_DATA: u32 = 0x0000_0004;
+const E131_ROOT_EXTENDED: u32 = 0x0000_0008;
+const E131_FRAMING_DATA: u32 = 0x0000_0002;
+const E131_FRAMING_SYNC: u32 = 0x0000_0001;
+const E131_DATA_START: usize = 126;
+
+/// Parse an E1.31 data or sync packet, None if not one
+///
+/// Data packets with a synchronization address are only shown on a sync packet
+/// for that address, `pending_sync` keeps the address between packets.
+pub fn parse_e131(
+    packet: &[u8],
+    start_universe: u16,
+    pending_sync: &mut Option<u16>,
+) -> Option<PixelUpdate> {
+    if packet.len() < 49 || &packet[4..16] != ACN_ID {
+        return None;
+    }
+    match (be32(&packet[18..22]), be32(&packet[40..44])) {
+        (E131_ROOT_DATA, E131_FRAMING_DATA) if packet.len() >= E131_DATA_START => {
+            let sync_address = be16(&packet[109..111]);
+            let universe = be16(&packet[113..115]);
+            let start_code = packet[125];
+            // property value count includes the start code
+            let channels = (be16(&packet[123..125]) as usize).checked_sub(1)?;
+            if start_code != 0 || universe < start_universe {
+                return None;
+            }
+            let offset = (universe - start_universe) as usize * E131_PIXELS_PER_UNIVERSE;
+            let data = packet.get(E131_DATA_START..E131_DATA_START + channels)?;
+            if sync_address != 0 {
+                *pending_sync = Some(sync_address);
+            }
+            Some(PixelUpdate {
+                offset: u16::try_from(offset).ok()?,
+                pixels: rgb(data),
+                commit: sync_address == 0,
+            })
+        }
+        (E131_ROOT_EXTENDED, E131_FRAMING_SYNC) => {
+            let sync_address = be16(&packet[45..47]);
+            if *pending_sync != Some(sync_address) {
+                return None;
+            }
+            *pending_sync = None;
+            Some(PixelUpdate {
+                offset: 0,
+                pixels: vec![],
+                commit: true,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Forward packets received on `socket` to the target, until an error occurs
+/// (e.g., the read timeout of `socket`)
+pub fn run<T: Target>(protocol: Protocol, socket: &UdpSocket, target: &mut T) -> Result<()> {
+    let mut packet = [0u8; 1500];
+    let mut pending_sync = None;
+    loop {
+        let (n, from) = socket.recv_from(&mut packet)?;
+        let update = match protocol {
+            Protocol::Ddp => parse_ddp(&packet[..n]),
+            Protocol::E131 { start_universe } => {
+                parse_e131(&packet[..n], start_universe, &mut pending_sync)
+            }
+        };
+        let Some(update) = update else {
+            println!("ignoring packet from {}", from);
+            continue;
+        };
+        for frame in update.frames() {
+            match target.request(&Command::Pixels(frame))? {
+                Response::SetOk => {}
+                r => return Err(unexpected(r)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn ddp_packet(offset_px: u32, pixels: &[RGB8], push: bool) -> Vec<u8> {
+    let mut p = vec![DDP_VERSION_1 | if push { DDP_PUSH } else { 0 }, 1, 0x0b, 1];
+    p.extend((offset_px * 3).to_be_bytes());
+    p.extend(((pixels.len() * 3) as u16).to_be_bytes());
+    pixels.iter().for_each(|c| p.extend([c.r, c.g, c.b]));
+    p
+}
+
+#[cfg(test)]
+fn e131_packet(universe: u16, sync_address: u16, data: &[u8]) -> Vec<u8> {
+    let mut p = vec![0u8; E131_DATA_START];
+    p[0..2].copy_from_slice(&0x0010u16.to_be_bytes());
+    p[4..16].copy_from_slice(ACN_ID);
+    p[18..22].copy_from_slice(&E131_ROOT_DATA.to_be_bytes());
+    p[40..44].copy_from_slice(&E131_FRAMING_DATA.to_be_bytes());
+    p[109..111].copy_from_slice(&sync_address.to_be_bytes());
+    p[113..115].copy_from_slice(&universe.to_be_bytes());
+    p[117] = 0x02;
+    p[123..125].copy_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+    p.extend(data);
+    p
+}
+
+#[test]
+fn ddp() {
+    let pixels = [RGB8::new(1, 2, 3), RGB8::new(4, 5, 6)];
+    let update = parse_ddp(&ddp_packet(7, &pixels, true)).unwrap();
+    assert_eq!(
+        update,
+        PixelUpdate {
+            offset: 7,
+            pixels: pixels.to_vec(),
+            commit: true
+        }
+    );
+
+    // with a timecode
+    let mut packet = ddp_packet(0, &pixels, false);
+    packet[0] |= DDP_TIMECODE;
+    packet.splice(10..10, [0, 0, 0, 1]);
+    let update = parse_ddp(&packet).unwrap();
+    assert_eq!((update.pixels.len(), update.commit), (2, false));
+
+    // truncated
+    assert_eq!(parse_ddp(&packet[..15]), None);
+
+    // an offset within a pixel
+    let mut packet = ddp_packet(1, &pixels, true);
+    packet[7] -= 1;
+    assert_eq!(parse_ddp(&packet), None);
+}
+
+#[cfg(test)]
+fn e131_sync(sync_address: u16) -> Vec<u8> {
+    let mut p = e131_packet(1, 0, &[]);
+    p.truncate(49);
+    p[18..22].copy_from_slice(&E131_ROOT_EXTENDED.to_be_bytes());
+    p[40..44].copy_from_slice(&E131_FRAMING_SYNC.to_be_bytes());
+    p[45..47].copy_from_slice(&sync_address.to_be_bytes());
+    p
+}
+
+#[test]
+fn e131() {
+    let mut pending = None;
+    let update = parse_e131(&e131_packet(3, 0, &[10, 20, 30, 40]), 2, &mut pending).unwrap();
+    assert_eq!(
+        update,
+        PixelUpdate {
+            offset: 170,
+            pixels: vec![RGB8::new(10, 20, 30)],
+            commit: true
+        }
+    );
+
+    // synchronized universes wait for the sync packet of their address
+    let update = parse_e131(&e131_packet(1, 7000, &[1, 2, 3]), 1, &mut pending).unwrap();
+    assert!(!update.commit);
+    assert_eq!(parse_e131(&e131_sync(7001), 1, &mut pending), None);
+    assert!(
+        parse_e131(&e131_sync(7000), 1, &mut pending)
+            .unwrap()
+            .commit
+    );
+    // nothing left to show
+    assert_eq!(parse_e131(&e131_sync(7000), 1, &mut pending), None);
+
+    // universe below the start and garbage
+    assert_eq!(
+        parse_e131(&e131_packet(1, 0, &[1, 2, 3]), 2, &mut pending),
+        None
+    );
+    assert_eq!(parse_e131(b"hello", 1, &mut pending), None);
+}
@@ -0,0 +1,230 @@
+//! I2C and SPI through the target
+//!
+//! `RemoteI2c` and `RemoteSpi` implement the embedded-hal 1.0 traits, so
+//! platform agnostic sensor drivers can be run on the host against the real bus.
+
+use crate::Target;
+use embedded_hal::{i2c, spi};
+use shared::bus::{Address, BusError, Bytes, I2c, Nack, Spi, MAX_BYTES};
+use shared::gpio::Pin;
+use shared::{Command, Response};
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Bus(BusError),
+    Io(io::Error),
+    Unexpected(Response),
+    /// An I2C write, an I2C read or an SPI transfer longer than `MAX_BYTES`,
+    /// or I2C operations that need more than one write-read
+    Unsupported,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl i2c::Error for Error {
+    fn kind(&self) -> i2c::ErrorKind {
+        use i2c::{ErrorKind, NoAcknowledgeSource};
+        match self {
+            Error::Bus(BusError::Nack(Nack::Address)) => {
+                ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)
+            }
+            Error::Bus(BusError::Nack(Nack::Data)) => {
+                ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data)
+            }
+            Error::Bus(BusError::ArbitrationLoss) => ErrorKind::ArbitrationLoss,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl spi::Error for Error {
+    fn kind(&self) -> spi::ErrorKind {
+        spi::ErrorKind::Other
+    }
+}
+
+fn bytes(data: &[u8]) -> Result<Bytes, Error> {
+    Bytes::new(data).ok_or(Error::Unsupported)
+}
+
+fn copy(to: &mut [u8], from: &[u8]) {
+    to.iter_mut().zip(from).for_each(|(t, f)| *t = *f);
+}
+
+fn bus_request<T: Target>(target: &mut T, cmd: Command) -> Result<Response, Error> {
+    match target.request(&cmd)? {
+        Response::BusError(e) => Err(Error::Bus(e)),
+        r => Ok(r),
+    }
+}
+
+/// The I2C bus of the target
+pub struct RemoteI2c<T> {
+    target: T,
+}
+
+impl<T: Target> RemoteI2c<T> {
+    pub fn new(target: T) -> Self {
+        Self { target }
+    }
+
+    /// Run a single transaction, returns the bytes read
+    pub fn execute(&mut self, i2c: I2c) -> Result<Vec<u8>, Error> {
+        match bus_request(&mut self.target, Command::I2c(i2c))? {
+            Response::SetOk => Ok(vec![]),
+            Response::BusData(data) => Ok(data.as_slice().to_vec()),
+            r => Err(Error::Unexpected(r)),
+        }
+    }
+
+    /// Addresses that acknowledge a read, like `i2cdetect`
+    pub fn scan(&mut self) -> Result<Vec<Address>, Error> {
+        let mut found = vec![];
+        for address in 0x08..=0x77 {
+            match self.execute(I2c::Read(address, 1)) {
+                Ok(_) => found.push(address),
+                Err(Error::Bus(BusError::Nack(_))) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(found)
+    }
+}
+
+impl<T> i2c::ErrorType for RemoteI2c<T> {
+    type Error = Error;
+}
+
+impl<T: Target> i2c::I2c for RemoteI2c<T> {
+    /// Adjacent operations of the same kind are merged, the result has to be a
+    /// single write, read or write-read of up to `MAX_BYTES` each, anything
+    /// else can not be issued as one transaction and is `Unsupported`
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [i2c::Operation<'_>],
+    ) -> Result<(), Error> {
+        use i2c::Operation::{Read, Write};
+
+        let split = operations
+            .iter()
+            .position(|op| matches!(op, Read(_)))
+            .unwrap_or(operations.len());
+        let (writes, reads) = operations.split_at_mut(split);
+        if reads.iter().any(|op| matches!(op, Write(_))) {
+            return Err(Error::Unsupported);
+        }
+
+        let mut data = vec![];
+        for op in writes.iter() {
+            if let Write(w) = op {
+                data.extend_from_slice(w);
+            }
+        }
+        let mut bufs: Vec<&mut [u8]> = reads
+            .iter_mut()
+            .filter_map(|op| match op {
+                Read(buf) => Some(&mut **buf),
+                Write(_) => None,
+            })
+            .collect();
+        let read_len: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if read_len > MAX_BYTES {
+            return Err(Error::Unsupported);
+        }
+
+        let i2c = match (writes.is_empty(), bufs.is_empty()) {
+            (_, true) => I2c::Write(address, bytes(&data)?),
+            (true, false) => I2c::Read(address, read_len as u8),
+            (false, false) => I2c::WriteRead(address, bytes(&data)?, read_len as u8),
+        };
+        let mut received = &self.execute(i2c)?[..];
+        for buf in bufs.iter_mut() {
+            let (chunk, rest) = received.split_at(buf.len().min(received.len()));
+            copy(buf, chunk);
+            received = rest;
+        }
+        Ok(())
+    }
+}
+
+/// A device on the SPI bus of the target
+pub struct RemoteSpi<T> {
+    target: T,
+    cs: Option<Pin>,
+}
+
+impl<T: Target> RemoteSpi<T> {
+    /// `cs` is driven low during transactions
+    pub fn new(target: T, cs: Option<Pin>) -> Self {
+        Self { target, cs }
+    }
+
+    /// Full duplex transfer of up to `MAX_BYTES` bytes
+    pub fn transfer_chunk(&mut self, data: &[u8], keep_cs: bool) -> Result<Vec<u8>, Error> {
+        let spi = Spi {
+            cs: self.cs,
+            data: bytes(data)?,
+            keep_cs,
+        };
+        match bus_request(&mut self.target, Command::Spi(spi))? {
+            Response::BusData(data) => Ok(data.as_slice().to_vec()),
+            r => Err(Error::Unexpected(r)),
+        }
+    }
+}
+
+impl<T> spi::ErrorType for RemoteSpi<T> {
+    type Error = Error;
+}
+
+impl<T: Target> spi::SpiDevice for RemoteSpi<T> {
+    fn transaction(&mut self, operations: &mut [spi::Operation<'_, u8>]) -> Result<(), Error> {
+        use spi::Operation::*;
+
+        // chip select is released after the last byte
+        let mut left: usize = operations
+            .iter()
+            .map(|op| match op {
+                Read(buf) | TransferInPlace(buf) => buf.len(),
+                Write(data) => data.len(),
+                // the longer of the two is clocked
+                Transfer(read, write) => read.len().max(write.len()),
+                DelayNs(_) => 0,
+            })
+            .sum();
+        let mut transfer = |data: &[u8], read: &mut [u8]| -> Result<(), Error> {
+            for (i, chunk) in data.chunks(MAX_BYTES).enumerate() {
+                left -= chunk.len();
+                let received = self.transfer_chunk(chunk, left > 0)?;
+                if let Some(read) = read.get_mut(i * MAX_BYTES..) {
+                    copy(read, &received);
+                }
+            }
+            Ok(())
+        };
+
+        for op in operations.iter_mut() {
+            match op {
+                Read(buf) => transfer(&vec![0; buf.len()], buf)?,
+                Write(data) => transfer(data, &mut [])?,
+                Transfer(read, write) => {
+                    let mut data = write.to_vec();
+                    data.resize(read.len().max(write.len()), 0);
+                    transfer(&data, read)?
+                }
+                TransferInPlace(buf) => {
+                    let data = buf.to_vec();
+                    transfer(&data, buf)?
+                }
+                DelayNs(ns) => std::thread::sleep(std::time::Duration::from_nanos(*ns as u64)),
+            }
+        }
+        Ok(())
+    }
+}
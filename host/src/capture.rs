@@ -0,0 +1,275 @@
+//! ADC capture on the target
+//!
+//! Reassembles `Response::Samples` blocks into a recording and writes it as
+//! WAV or CSV.
+
+use crate::{receive, request, unexpected, InBuf, OutBuf};
+use serial2::SerialPort;
+use shared::adc::{SampleBlock, SAMPLES_PER_BLOCK};
+use shared::{Command, Response};
+use std::io::{Error, ErrorKind, Result, Write};
+
+/// Samples lost in transfer, filled by repeating the previous sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    /// Index of the first missing sample
+    pub at: usize,
+    pub missing: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recording {
+    pub rate_hz: u32,
+    /// 12 bit raw values
+    pub samples: Vec<u16>,
+    pub gaps: Vec<Gap>,
+}
+
+impl Recording {
+    fn fill(&mut self, missing: usize) {
+        if missing == 0 {
+            return;
+        }
+        let at = self.samples.len();
+        let last = self.samples.last().copied().unwrap_or(0);
+        self.samples.resize(at + missing, last);
+        self.gaps.push(Gap { at, missing });
+    }
+
+    /// 16 bit mono PCM, mid scale is 0
+    pub fn write_wav(&self, mut w: impl Write) -> Result<()> {
+        let data_len = self.samples.len() as u32 * 2;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(36 + data_len).to_le_bytes())?;
+        w.write_all(b"WAVEfmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        w.write_all(&1u16.to_le_bytes())?; // PCM
+        w.write_all(&1u16.to_le_bytes())?; // mono
+        w.write_all(&self.rate_hz.to_le_bytes())?;
+        w.write_all(&(self.rate_hz * 2).to_le_bytes())?; // bytes per second
+        w.write_all(&2u16.to_le_bytes())?; // block align
+        w.write_all(&16u16.to_le_bytes())?; // bits per sample
+        w.write_all(b"data")?;
+        w.write_all(&data_len.to_le_bytes())?;
+        for s in &self.samples {
+            let pcm = ((*s as i32 - 2048) << 4).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+            w.write_all(&pcm.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn write_csv(&self, mut w: impl Write) -> Result<()> {
+        writeln!(w, "time_s,raw")?;
+        for (i, s) in self.samples.iter().enumerate() {
+            writeln!(w, "{},{}", i as f64 / self.rate_hz as f64, s)?;
+        }
+        Ok(())
+    }
+}
+
+/// More lost blocks than this in a row are checked against the timestamp
+pub const MAX_LOST_BLOCKS: u16 = 64;
+
+/// Collects blocks in order, lost blocks are detected by their sequence number
+#[derive(Debug, Clone)]
+pub struct Reassembler {
+    next_seq: u16,
+    recording: Recording,
+}
+
+impl Reassembler {
+    pub fn new(rate_hz: u32) -> Self {
+        Self {
+            next_seq: 0,
+            recording: Recording {
+                rate_hz,
+                ..Recording::default()
+            },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.recording.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Index of the first sample of `block` from its timestamp
+    fn position(&self, block: &SampleBlock) -> usize {
+        let rate_hz = self.recording.rate_hz as u64;
+        (block.timestamp_us * rate_hz).div_ceil(1_000_000) as usize
+    }
+
+    /// Returns false for a dropped block, a duplicate, a late one or one with
+    /// a corrupt sequence number
+    pub fn push(&mut self, block: &SampleBlock) -> bool {
+        // behind `next_seq` wraps to a large difference
+        let lost = block.seq.wrapping_sub(self.next_seq);
+        let missing = if lost <= MAX_LOST_BLOCKS {
+            // all but the last block are full
+            lost as usize * SAMPLES_PER_BLOCK
+        } else {
+            // resync on a long loss if the timestamp agrees that far ahead
+            let at = self.position(block);
+            if at <= self.len() + MAX_LOST_BLOCKS as usize * SAMPLES_PER_BLOCK {
+                return false;
+            }
+            at - self.len()
+        };
+        self.recording.fill(missing);
+        self.recording.samples.extend(block.samples());
+        self.next_seq = block.seq.wrapping_add(1);
+        true
+    }
+
+    /// Blocks lost at the end are filled up to `samples`
+    pub fn finish(mut self, samples: usize) -> Recording {
+        let missing = samples.saturating_sub(self.len());
+        self.recording.fill(missing);
+        self.recording.samples.truncate(samples);
+        self.recording
+    }
+}
+
+/// Capture `samples` samples, stops at the first read timeout after the capture started
+pub fn capture(
+    channel: u8,
+    rate_hz: u32,
+    samples: u32,
+    port: &mut SerialPort,
+    out_buf: &mut OutBuf,
+    in_buf: &mut InBuf,
+) -> Result<Recording> {
+    let cmd = Command::StartCapture {
+        channel,
+        rate_hz,
+        samples,
+    };
+    match request(&cmd, port, out_buf, in_buf)? {
+        Response::SetOk => {}
+        Response::CaptureError(e) => return Err(Error::other(format!("capture error {:?}", e))),
+        r => return Err(unexpected(r)),
+    }
+
+    let mut reassembler = Reassembler::new(rate_hz);
+    while reassembler.len() < samples as usize {
+        match receive(port, in_buf) {
+            Ok(Response::Samples(block)) => {
+                reassembler.push(&block);
+            }
+            Ok(r) => return Err(unexpected(r)),
+            Err(e) if e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(reassembler.finish(samples as usize))
+}
+
+#[cfg(test)]
+fn blocks(samples: u32) -> Vec<SampleBlock> {
+    let mut capture = shared::adc::Capture::new(0, 1000, samples).unwrap();
+    (0..samples as u16)
+        .filter_map(|s| capture.push(s))
+        .collect()
+}
+
+#[test]
+fn reassemble_with_gaps() {
+    let blocks = blocks(50);
+    let mut reassembler = Reassembler::new(1000);
+    // the second and last blocks are lost
+    reassembler.push(&blocks[0]);
+    reassembler.push(&blocks[2]);
+    let recording = reassembler.finish(50);
+
+    assert_eq!(recording.samples.len(), 50);
+    assert_eq!(
+        recording.gaps,
+        [
+            Gap {
+                at: 16,
+                missing: 16
+            },
+            Gap { at: 48, missing: 2 }
+        ]
+    );
+    // the previous sample is held
+    assert_eq!(recording.samples[16..32], [15; 16]);
+    assert_eq!(recording.samples[32..48], (32..48).collect::<Vec<_>>()[..]);
+}
+
+#[test]
+fn reassemble_repeated_blocks() {
+    let blocks = blocks(48);
+    let mut reassembler = Reassembler::new(1000);
+    assert!(reassembler.push(&blocks[0]));
+    assert!(reassembler.push(&blocks[1]));
+    // repeated and late blocks are dropped
+    assert!(!reassembler.push(&blocks[1]));
+    assert!(!reassembler.push(&blocks[0]));
+    // a corrupt sequence number
+    let mut far = blocks[2];
+    far.seq = 2 + MAX_LOST_BLOCKS + 1;
+    assert!(!reassembler.push(&far));
+    assert!(reassembler.push(&blocks[2]));
+    let recording = reassembler.finish(48);
+
+    assert_eq!(recording.samples, (0..48).collect::<Vec<_>>());
+    assert!(recording.gaps.is_empty());
+}
+
+#[test]
+fn reassemble_after_a_long_loss() {
+    let blocks = blocks(200 * SAMPLES_PER_BLOCK as u32);
+    let mut reassembler = Reassembler::new(1000);
+    assert!(reassembler.push(&blocks[0]));
+    // blocks 1 to 100 are lost
+    assert!(reassembler.push(&blocks[101]));
+    assert!(reassembler.push(&blocks[102]));
+    assert!(!reassembler.push(&blocks[50]));
+    let recording = reassembler.finish(103 * SAMPLES_PER_BLOCK);
+
+    assert_eq!(
+        recording.gaps,
+        [Gap {
+            at: SAMPLES_PER_BLOCK,
+            missing: 100 * SAMPLES_PER_BLOCK
+        }]
+    );
+    assert_eq!(
+        recording.samples[101 * SAMPLES_PER_BLOCK..],
+        (1616..1648).collect::<Vec<_>>()[..]
+    );
+}
+
+#[test]
+fn wav_and_csv() {
+    let recording = Recording {
+        rate_hz: 8000,
+        samples: vec![0, 2048, 4095],
+        gaps: vec![],
+    };
+    let mut wav = vec![];
+    recording.write_wav(&mut wav).unwrap();
+    assert_eq!(wav.len(), 44 + 6);
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(&wav[24..28], &8000u32.to_le_bytes());
+    assert_eq!(
+        wav[44..],
+        [
+            (-32768i16).to_le_bytes(),
+            0i16.to_le_bytes(),
+            32752i16.to_le_bytes()
+        ]
+        .concat()
+    );
+
+    let mut csv = vec![];
+    recording.write_csv(&mut csv).unwrap();
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "time_s,raw\n0,0\n0.000125,2048\n0.00025,4095\n"
+    );
+}
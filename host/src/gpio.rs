@@ -0,0 +1,58 @@
+//! Remote GPIO on the target
+
+use crate::{unexpected, Serial, Target};
+use shared::gpio::{GpioError, Pin, PinMode, Trigger};
+use shared::{Command, Response};
+use std::io::{Error, ErrorKind, Result};
+
+fn gpio_error(e: GpioError) -> Error {
+    Error::other(format!("gpio error {:?}", e))
+}
+
+fn expect_ok(r: Response) -> Result<()> {
+    match r {
+        Response::SetOk => Ok(()),
+        Response::GpioError(e) => Err(gpio_error(e)),
+        r => Err(unexpected(r)),
+    }
+}
+
+pub fn configure<T: Target>(target: &mut T, pin: Pin, mode: PinMode) -> Result<()> {
+    expect_ok(target.request(&Command::ConfigurePin(pin, mode))?)
+}
+
+/// Set an output, unconfigured pins are made push-pull outputs first
+pub fn write<T: Target>(target: &mut T, pin: Pin, high: bool) -> Result<()> {
+    match target.request(&Command::WritePin(pin, high))? {
+        Response::GpioError(GpioError::NotConfigured) => {
+            configure(target, pin, PinMode::Output)?;
+            expect_ok(target.request(&Command::WritePin(pin, high))?)
+        }
+        r => expect_ok(r),
+    }
+}
+
+pub fn read<T: Target>(target: &mut T, pin: Pin) -> Result<bool> {
+    match target.request(&Command::ReadPin(pin))? {
+        Response::Level(p, high) if p == pin => Ok(high),
+        Response::GpioError(e) => Err(gpio_error(e)),
+        r => Err(unexpected(r)),
+    }
+}
+
+pub fn subscribe<T: Target>(target: &mut T, pin: Pin, trigger: Option<Trigger>) -> Result<()> {
+    expect_ok(target.request(&Command::SubscribePin(pin, trigger))?)
+}
+
+/// Wait for the next edge on a subscribed pin, returns the pin and its new level
+pub fn next_edge(serial: &mut Serial) -> Result<(Pin, bool)> {
+    loop {
+        match serial.receive() {
+            Ok(Response::PinEdge(pin, high)) => return Ok((pin, high)),
+            Ok(r) => return Err(unexpected(r)),
+            // the port has a read timeout, keep waiting
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
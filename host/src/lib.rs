@@ -1,5 +1,27 @@
+pub mod bridge;
+pub mod bus;
+pub mod capture;
+pub mod gpio;
+pub mod logs;
+pub mod modbus;
+pub mod pack;
+pub mod pattern;
+pub mod preview;
+#[cfg(target_os = "linux")]
+pub mod pty;
+pub mod pwm;
+pub mod schedule;
+pub mod sim;
+pub mod update;
+
 use serial2::SerialPort;
-use std::io::Result;
+use shared::crash::CrashReport;
+use shared::framing::{Cobs, Framer};
+use shared::info::Info;
+use shared::mux::{self, Channel, Demux, OVERHEAD};
+use shared::{Command, Response};
+use std::io::{Error, ErrorKind, Read, Result};
+use std::mem::size_of;
 use std::time::Duration;
 
 // On Windows, use something like "COM1".
@@ -12,7 +34,7 @@ static COM_PATH: &str = "/dev/ttyACM1";
 static COM_PATH: &str = "COM3";
 
 // A one second timeout
-const TIME_OUT: Duration = Duration::from_millis(1000);
+pub(crate) const TIME_OUT: Duration = Duration::from_millis(1000);
 
 pub fn open() -> Result<SerialPort> {
     let mut port = SerialPort::open(COM_PATH, 115200)?;
@@ -24,3 +46,117 @@ pub fn open() -> Result<SerialPort> {
 
     Ok(port)
 }
+
+/// Worst case framed size of `payload` bytes for any framer, that of SLIP
+const fn max_frame_len(payload: usize) -> usize {
+    2 * (payload + OVERHEAD) + 2
+}
+
+// Commands and responses are sent on the control channel of the `shared::mux` framing
+pub const IN_SIZE: usize = max_frame_len(size_of::<Response>());
+pub const OUT_SIZE: usize = max_frame_len(size_of::<Command>());
+
+/// Responses are collected by a `Demux` for the framer `F`
+pub type InBuf<F = Cobs> = Demux<IN_SIZE, F>;
+pub type OutBuf = [u8; OUT_SIZE];
+
+pub(crate) fn unexpected(r: Response) -> Error {
+    Error::other(format!("unexpected response {:?}", r))
+}
+
+/// Send a command and wait for the response, framed like `in_buf`
+///
+/// Unsolicited `Response::PinEdge` events received meanwhile are dropped,
+/// use `receive` to wait for them.
+pub fn request<F: Framer>(
+    cmd: &Command,
+    port: &mut SerialPort,
+    out_buf: &mut OutBuf,
+    in_buf: &mut InBuf<F>,
+) -> Result<Response> {
+    let to_write = mux::encode_control_with::<F, _, OUT_SIZE>(cmd, out_buf).map_err(invalid)?;
+    port.write_all(to_write)?;
+
+    loop {
+        match receive(port, in_buf)? {
+            Response::PinEdge(..) => {}
+            response => return Ok(response),
+        }
+    }
+}
+
+pub(crate) fn invalid(e: impl std::fmt::Debug) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+/// Wait for the next response, frames on other channels and frames that
+/// fail to decode are dropped (use `pty::Mux` to get at the other channels)
+pub fn receive<F: Framer>(port: &mut SerialPort, in_buf: &mut InBuf<F>) -> Result<Response> {
+    let mut byte = [0u8];
+    loop {
+        port.read_exact(&mut byte)?;
+        if let Some(Ok((Channel::Control, payload))) = in_buf.push(byte[0]) {
+            if let Ok((response, _)) = ssmarshal::deserialize(payload) {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+/// Something that answers commands, i.e., the target over serial or the simulator
+pub trait Target {
+    fn request(&mut self, cmd: &Command) -> Result<Response>;
+}
+
+impl<T: Target + ?Sized> Target for &mut T {
+    fn request(&mut self, cmd: &Command) -> Result<Response> {
+        (**self).request(cmd)
+    }
+}
+
+/// The target on the serial port, with its buffers
+pub struct Serial {
+    pub port: SerialPort,
+    out_buf: OutBuf,
+    in_buf: InBuf,
+}
+
+impl Serial {
+    pub fn open() -> Result<Self> {
+        Ok(Self {
+            port: open()?,
+            out_buf: [0; OUT_SIZE],
+            in_buf: InBuf::new(),
+        })
+    }
+
+    /// Wait for a response nobody asked for, e.g. an event
+    pub fn receive(&mut self) -> Result<Response> {
+        receive(&mut self.port, &mut self.in_buf)
+    }
+}
+
+impl Target for Serial {
+    fn request(&mut self, cmd: &Command) -> Result<Response> {
+        request(cmd, &mut self.port, &mut self.out_buf, &mut self.in_buf)
+    }
+}
+
+/// Fetch and clear the panic reports of the target, oldest first
+pub fn crash_reports<T: Target>(target: &mut T) -> Result<Vec<CrashReport>> {
+    let mut reports = vec![];
+    loop {
+        match target.request(&Command::GetCrashReport)? {
+            Response::CrashReport(Some(report)) => reports.push(report),
+            Response::CrashReport(None) => return Ok(reports),
+            r => return Err(unexpected(r)),
+        }
+    }
+}
+
+pub fn info<T: Target>(target: &mut T) -> Result<Info> {
+    match target.request(&Command::GetInfo)? {
+        Response::Info(info) => Ok(info),
+        r => Err(unexpected(r)),
+    }
+}
@@ -0,0 +1,138 @@
+//! Log records from the target
+//!
+//! Reads `Channel::Log` frames from the link, prints the records passing a
+//! `Filter`, colored by level, and optionally captures them to a file.
+
+use shared::log::{Level, Record, MAX_FRAME};
+use shared::mux::{Channel, Demux};
+use std::io::{ErrorKind, Read, Result, Write};
+
+#[derive(Debug, Clone)]
+pub struct Filter {
+    /// The least severe level shown
+    pub level: Level,
+    /// Only targets starting with this
+    pub target: Option<String>,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record) -> bool {
+        record.level <= self.level
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|t| record.target().starts_with(t.as_str()))
+    }
+}
+
+/// Seconds since boot, level, target and message
+pub fn line(record: &Record) -> String {
+    let mut message = String::new();
+    let _ = record.write_message(&mut message);
+    format!(
+        "{:>6}.{:03} {:<5} {}: {}",
+        record.timestamp / 1000,
+        record.timestamp % 1000,
+        record.level.name(),
+        record.target(),
+        message
+    )
+}
+
+/// `line` with ANSI colors
+pub fn colored(record: &Record) -> String {
+    let color = match record.level {
+        Level::Error => "31",
+        Level::Warn => "33",
+        Level::Info => "32",
+        Level::Debug => "36",
+        Level::Trace => "90",
+    };
+    format!("\x1b[{}m{}\x1b[0m", color, line(record))
+}
+
+/// Print records from `link` to `out` until it ends, and `line`s to `capture`
+pub fn follow(
+    mut link: impl Read,
+    filter: &Filter,
+    color: bool,
+    mut out: impl Write,
+    mut capture: Option<impl Write>,
+) -> Result<()> {
+    let mut demux = Demux::<MAX_FRAME>::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let n = match link.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        for b in &buf[..n] {
+            // other channels and corrupted frames are skipped
+            let Some(Ok((Channel::Log, payload))) = demux.push(*b) else {
+                continue;
+            };
+            let Ok((record, _)) = ssmarshal::deserialize::<Record>(payload) else {
+                continue;
+            };
+            if !filter.matches(&record) {
+                continue;
+            }
+            if color {
+                writeln!(out, "{}", colored(&record))?;
+            } else {
+                writeln!(out, "{}", line(&record))?;
+            }
+            if let Some(capture) = &mut capture {
+                writeln!(capture, "{}", line(&record))?;
+            }
+        }
+    }
+}
+
+#[test]
+fn follow_filtered() {
+    use shared::log::{Logger, LATENCY};
+
+    let link = std::cell::RefCell::new(vec![]);
+    let mut logger = Logger::new(Level::Trace, |frame: &[u8]| {
+        link.borrow_mut().extend_from_slice(frame);
+        true
+    });
+    logger.text(Level::Error, "uart", 1_234, "overrun");
+    logger.format(Level::Debug, "cmd", 1_500, LATENCY, &[80]);
+    logger.text(Level::Warn, "adc", 61_002, "clipped");
+    // noise and a console frame in between
+    link.borrow_mut().extend_from_slice(&[1, 2, 3, 0]);
+    let mut out_buf = [0u8; 32];
+    let console = shared::mux::encode(Channel::Console, b"hi", &mut out_buf).unwrap();
+    link.borrow_mut().extend_from_slice(console);
+    logger.text(Level::Warn, "uart", 61_003, "parity");
+    let link = link.take();
+
+    let mut out = vec![];
+    let mut capture = vec![];
+    let filter = Filter {
+        level: Level::Warn,
+        target: Some("ua".into()),
+    };
+    follow(&link[..], &filter, true, &mut out, Some(&mut capture)).unwrap();
+    assert_eq!(
+        String::from_utf8(capture).unwrap(),
+        "     1.234 ERROR uart: overrun\n    61.003 WARN  uart: parity\n"
+    );
+    assert!(String::from_utf8(out)
+        .unwrap()
+        .starts_with("\x1b[31m     1.234 ERROR uart: overrun\x1b[0m\n"));
+
+    let mut out = vec![];
+    let filter = Filter {
+        level: Level::Trace,
+        target: None,
+    };
+    follow(&link[..], &filter, false, &mut out, None::<Vec<u8>>).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(out.lines().count(), 4);
+    assert!(out.contains("DEBUG cmd: command answered in 80 us"));
+}
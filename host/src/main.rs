@@ -8,29 +8,645 @@
 //!
 //! cargo run
 //!
+//! Preview a smart LED effect in the terminal (no target needed)
+//!
+//! cargo run -- preview rainbow
+//!
+//! Forward DDP or E1.31 (sACN) pixel data from PC software to the LED strip
+//!
+//! cargo run -- bridge ddp
+//!
+//! Show a status pattern on the LED, predefined or custom
+//!
+//! cargo run -- pattern select blink-code --code 3
+//! cargo run -- pattern upload on:100,off:100,on:100,off:700 --repeat 5
+//!
+//! Use the target as an I/O expander
+//!
+//! cargo run -- gpio set 7 high
+//! cargo run -- gpio watch 9 --edge falling
+//!
+//! Dim the LED with PWM, or drive a servo
+//!
+//! cargo run -- pwm config 0 --pin 7 --freq 5000 --bits 13
+//! cargo run -- pwm duty 0 25%
+//! cargo run -- pwm config 1 --pin 4 --freq 50 --bits 14
+//! cargo run -- pwm duty 1 1500us
+//!
+//! List the devices on the I2C bus of the target
+//!
+//! cargo run -- i2c-scan
+//!
+//! Share the link, the console and passthrough channels get a pseudo-terminal
+//! each (Linux only)
+//!
+//! cargo run -- mux
+//! minicom -D /dev/pts/<n>
+//!
+//! Capture an ADC channel to a WAV or CSV file
+//!
+//! cargo run -- capture 0 --rate 8000 --samples 8000 --out capture.wav
+//!
+//! Update the firmware with a packed image (see `pack` below), run again to
+//! resume an interrupted update
+//!
+//! cargo run -- update firmware.img
+//!
+//! Show the log records of the target, warnings and errors only, also
+//! captured to a file
+//!
+//! cargo run -- logs --level warn --out target.log
+//!
+//! Show firmware version, build, chip id and uptime of the target
+//!
+//! cargo run -- info
+//!
+//! Read parameter 0x12 every 5 minutes and set parameter 0x10 to 1 at 07:00
+//! (UTC) every weekday, without the host staying connected
+//!
+//! cargo run -- schedule add every:300 18
+//! cargo run -- schedule add weekdays:07:00 16 1
+//! cargo run -- schedule remove 0
+//!
+//! Fetch the panic reports kept by the target since it restarted
+//!
+//! cargo run -- crash
+//!
+//! Sign an app image with a local key, the target only accepts images signed
+//! with its key and not older than the running version
+//!
+//! espflash save-image --chip esp32c3 firmware.elf firmware.bin
+//! head -c 32 /dev/urandom > key.bin
+//! cargo run -- pack firmware.bin --version 2 --key key.bin --out firmware.img
+//!
 
 // Rust dependencies
-use std::{io::Read, mem::size_of};
+use std::{
+    fs::File,
+    io::{BufWriter, IsTerminal, Write},
+    net::UdpSocket,
+    path::PathBuf,
+    time::Duration,
+};
 
 // Libraries
-use corncobs::{max_encoded_len, ZERO};
-use serial2::SerialPort;
+use clap::{Parser, Subcommand, ValueEnum};
 
 // Application dependencies
-use host::open;
-use shared::{deserialize_crc_cobs, serialize_crc_cobs, Command, Message, Response}; // local library
+use host::bridge::{self, Protocol, DDP_PORT, E131_PORT};
+use host::bus::RemoteI2c;
+use host::{
+    capture, gpio, logs, open, pack, pattern, preview, pwm, request, schedule, update, InBuf,
+    Serial, OUT_SIZE,
+};
+use shared::animation::{Effect, RGB8};
+use shared::gpio::{Pin, PinMode, Trigger};
+use shared::info::PROTOCOL_VERSION;
+use shared::led_pattern::{BuiltinPattern, LedPattern};
+use shared::log;
+use shared::pwm::{Channel, Duty, PwmConfig};
+use shared::schedule::{Slot, TimeSpec};
+use shared::{Action, Command, Id, Message}; // local library
+
+#[derive(Parser)]
+#[command(about = "host side application")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Cmd>,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Set and get a parameter on the target (default)
+    Demo,
+    /// Preview a smart LED effect in the terminal
+    Preview {
+        #[arg(value_enum)]
+        effect: EffectKind,
+        /// Color as hex rrggbb
+        #[arg(long, default_value = "ff4000", value_parser = parse_color)]
+        color: RGB8,
+        /// Second color (for fade) as hex rrggbb
+        #[arg(long, default_value = "0040ff", value_parser = parse_color)]
+        to: RGB8,
+        /// Period in ms
+        #[arg(long, default_value_t = 2000)]
+        period: u32,
+        #[arg(long, default_value_t = 255)]
+        brightness: u8,
+        #[arg(long, default_value_t = 10)]
+        seconds: u64,
+    },
+    /// Forward pixel data received over UDP to the target
+    Bridge {
+        #[arg(value_enum)]
+        protocol: BridgeProtocol,
+        /// Address to listen on, the port defaults to the protocol's
+        #[arg(long, default_value = "0.0.0.0")]
+        bind: String,
+        #[arg(long)]
+        port: Option<u16>,
+        /// E1.31 universe of the first pixel
+        #[arg(long, default_value_t = 1)]
+        universe: u16,
+    },
+    /// Show a pattern on the status LED of the target
+    Pattern {
+        #[command(subcommand)]
+        action: PatternAction,
+    },
+    /// Configure, write, read or watch a pin on the target
+    Gpio {
+        #[command(subcommand)]
+        action: GpioAction,
+    },
+    /// Configure a PWM channel or set its duty
+    Pwm {
+        #[command(subcommand)]
+        action: PwmAction,
+    },
+    /// List the addresses answering on the I2C bus
+    I2cScan,
+    /// Relay the console and passthrough channels to pseudo-terminals
+    #[cfg(target_os = "linux")]
+    Mux,
+    /// Capture samples from an ADC channel
+    Capture {
+        /// ADC1 channel (0 to 4)
+        channel: u8,
+        /// Sample rate in Hz
+        #[arg(long, default_value_t = 8000)]
+        rate: u32,
+        #[arg(long, default_value_t = 8000)]
+        samples: u32,
+        /// Output file, CSV if it ends in .csv, WAV otherwise
+        #[arg(long, default_value = "capture.wav")]
+        out: PathBuf,
+    },
+    /// Send a packed firmware image to the target and commit it
+    Update {
+        /// Built with `pack`
+        image: PathBuf,
+    },
+    /// Run actions on the target at a time, periodically or daily
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleAction,
+    },
+    /// Show what the target runs and for how long
+    Info,
+    /// Fetch and clear the panic reports of the target
+    Crash,
+    /// Print the log records of the target
+    Logs {
+        /// The least severe level shown
+        #[arg(long, value_enum, default_value = "info")]
+        level: LogLevel,
+        /// Only targets starting with this
+        #[arg(long)]
+        target: Option<String>,
+        /// Also write the records to this file
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Build a signed image from an app image (`espflash save-image`)
+    Pack {
+        input: PathBuf,
+        #[arg(long, default_value_t = 0)]
+        version: u32,
+        /// File holding the 32 byte Ed25519 secret seed
+        #[arg(long)]
+        key: PathBuf,
+        #[arg(long, default_value = "firmware.img")]
+        out: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum PatternAction {
+    /// A predefined pattern
+    Select {
+        #[arg(value_enum)]
+        pattern: PatternKind,
+        /// Number of blinks (1 to 7) for blink-code
+        #[arg(long, default_value_t = 1)]
+        code: u8,
+    },
+    /// Steps like on:100,off:100,on:100,off:700 (in ms)
+    Upload {
+        steps: String,
+        /// Runs through the steps, forever if not given
+        #[arg(long)]
+        repeat: Option<u16>,
+        /// Leave the LED on once done
+        #[arg(long)]
+        rest_on: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PatternKind {
+    Off,
+    On,
+    Blink,
+    FastBlink,
+    Heartbeat,
+    BlinkCode,
+}
+
+#[derive(Subcommand)]
+enum ScheduleAction {
+    /// Set a parameter (with a value) or read it, syncs the target clock first
+    Add {
+        /// every:<seconds>, daily:<hh:mm>, weekdays:<hh:mm> or a time like
+        /// 2023-10-16T07:00:00Z
+        #[arg(value_parser = schedule::parse_spec)]
+        when: TimeSpec,
+        id: Id,
+        value: Option<u32>,
+    },
+    /// Remove a scheduled action
+    Remove { slot: Slot },
+}
+
+#[derive(Subcommand)]
+enum PwmAction {
+    /// Attach a channel to a pin
+    Config {
+        channel: Channel,
+        #[arg(long)]
+        pin: Pin,
+        /// Frequency in Hz
+        #[arg(long, default_value_t = 5000)]
+        freq: u32,
+        /// Resolution in bits (1 to 14)
+        #[arg(long, default_value_t = 13)]
+        bits: u8,
+    },
+    /// Set the duty as a percentage (25%) or a pulse width (1500us)
+    Duty {
+        channel: Channel,
+        #[arg(value_parser = pwm::parse_duty)]
+        duty: Duty,
+    },
+}
+
+#[derive(Subcommand)]
+enum GpioAction {
+    /// Set an output (configured as push-pull output if needed)
+    Set {
+        pin: Pin,
+        #[arg(value_enum)]
+        level: Level,
+    },
+    /// Read the level of a pin
+    Get { pin: Pin },
+    /// Configure a pin
+    Mode {
+        pin: Pin,
+        #[arg(value_enum)]
+        mode: Mode,
+    },
+    /// Print edges on an input until interrupted
+    Watch {
+        pin: Pin,
+        #[arg(long, value_enum, default_value = "any")]
+        edge: Edge,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Level {
+    High,
+    Low,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Mode {
+    Input,
+    PullUp,
+    Output,
+    OpenDrain,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Edge {
+    Rising,
+    Falling,
+    Any,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BridgeProtocol {
+    Ddp,
+    E131,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum EffectKind {
+    Solid,
+    Rainbow,
+    Breathing,
+    Chase,
+    Fire,
+    Fade,
+}
 
-const IN_SIZE: usize = max_encoded_len(size_of::<Response>() + size_of::<u32>());
-const OUT_SIZE: usize = max_encoded_len(size_of::<Command>() + size_of::<u32>());
+#[derive(Clone, Copy, ValueEnum)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
 
-type InBuf = [u8; IN_SIZE];
-type OutBuf = [u8; OUT_SIZE];
+impl From<LogLevel> for log::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Trace => log::Level::Trace,
+        }
+    }
+}
+
+fn parse_color(s: &str) -> Result<RGB8, String> {
+    let v = u32::from_str_radix(s.trim_start_matches('#'), 16).map_err(|e| e.to_string())?;
+    if s.trim_start_matches('#').len() != 6 {
+        return Err("expected rrggbb".into());
+    }
+    Ok(RGB8::new((v >> 16) as u8, (v >> 8) as u8, v as u8))
+}
 
 fn main() -> Result<(), std::io::Error> {
+    match Cli::parse().command.unwrap_or(Cmd::Demo) {
+        Cmd::Demo => demo(),
+        Cmd::Preview {
+            effect,
+            color,
+            to,
+            period,
+            brightness,
+            seconds,
+        } => {
+            let effect = match effect {
+                EffectKind::Solid => Effect::Solid(color),
+                EffectKind::Rainbow => Effect::Rainbow { period_ms: period },
+                EffectKind::Breathing => Effect::Breathing {
+                    color,
+                    period_ms: period,
+                },
+                EffectKind::Chase => Effect::Chase {
+                    color,
+                    width: 3,
+                    step_ms: period / preview::PIXELS as u32,
+                },
+                EffectKind::Fire => Effect::Fire,
+                EffectKind::Fade => Effect::Fade {
+                    from: color,
+                    to,
+                    period_ms: period,
+                },
+            };
+            preview::run(effect, brightness, Duration::from_secs(seconds))
+        }
+        Cmd::Bridge {
+            protocol,
+            bind,
+            port,
+            universe,
+        } => {
+            let (protocol, default_port) = match protocol {
+                BridgeProtocol::Ddp => (Protocol::Ddp, DDP_PORT),
+                BridgeProtocol::E131 => (
+                    Protocol::E131 {
+                        start_universe: universe,
+                    },
+                    E131_PORT,
+                ),
+            };
+            let socket = UdpSocket::bind((bind.as_str(), port.unwrap_or(default_port)))?;
+            println!("listening on {}", socket.local_addr()?);
+
+            bridge::run(protocol, &socket, &mut Serial::open()?)
+        }
+        Cmd::Pattern { action } => {
+            let mut target = Serial::open()?;
+            match action {
+                PatternAction::Select { pattern, code } => {
+                    let pattern = match pattern {
+                        PatternKind::Off => BuiltinPattern::Off,
+                        PatternKind::On => BuiltinPattern::On,
+                        PatternKind::Blink => BuiltinPattern::Blink,
+                        PatternKind::FastBlink => BuiltinPattern::FastBlink,
+                        PatternKind::Heartbeat => BuiltinPattern::Heartbeat,
+                        PatternKind::BlinkCode => BuiltinPattern::BlinkCode(code),
+                    };
+                    pattern::select(&mut target, pattern)
+                }
+                PatternAction::Upload {
+                    steps,
+                    repeat,
+                    rest_on,
+                } => {
+                    let steps = pattern::parse_steps(&steps).map_err(std::io::Error::other)?;
+                    let pattern = LedPattern::new(&steps, repeat)
+                        .map_err(|e| std::io::Error::other(format!("{:?}", e)))?;
+                    pattern::upload(&mut target, pattern.with_rest(rest_on))
+                }
+            }
+        }
+        Cmd::Gpio { action } => gpio(action),
+        Cmd::I2cScan => {
+            let mut i2c = RemoteI2c::new(Serial::open()?);
+            let found = i2c
+                .scan()
+                .map_err(|e| std::io::Error::other(format!("{:?}", e)))?;
+            for address in found {
+                println!("0x{:02x}", address);
+            }
+            Ok(())
+        }
+        #[cfg(target_os = "linux")]
+        Cmd::Mux => {
+            let mux = host::pty::Mux::start(open()?)?;
+            for (channel, path) in &mux.ptys {
+                println!("{:?} on {}", channel, path.display());
+            }
+            // until the link fails
+            for event in mux.events.iter() {
+                println!("{:?}", event?);
+            }
+            Ok(())
+        }
+        Cmd::Capture {
+            channel,
+            rate,
+            samples,
+            out,
+        } => {
+            let mut port = open()?;
+            let mut out_buf = [0u8; OUT_SIZE];
+            let mut in_buf = InBuf::new();
+            let recording =
+                capture::capture(channel, rate, samples, &mut port, &mut out_buf, &mut in_buf)?;
+            for gap in &recording.gaps {
+                println!("{} samples lost at {}", gap.missing, gap.at);
+            }
+            let file = BufWriter::new(File::create(&out)?);
+            if out.extension().is_some_and(|e| e == "csv") {
+                recording.write_csv(file)
+            } else {
+                recording.write_wav(file)
+            }
+        }
+        Cmd::Update { image } => {
+            let image = std::fs::read(image)?;
+            let mut target = Serial::open()?;
+            let version = update::update(&mut target, &image, |sent, total| {
+                print!(
+                    "\r{:3}% {}/{} bytes",
+                    sent as u64 * 100 / total.max(1) as u64,
+                    sent,
+                    total
+                );
+                let _ = std::io::stdout().flush();
+            })?;
+            println!("\ncommitted version {}", version);
+            Ok(())
+        }
+        Cmd::Schedule { action } => {
+            let mut target = Serial::open()?;
+            match action {
+                ScheduleAction::Add { when, id, value } => {
+                    let action = match value {
+                        Some(v) => Action::Set(id, Message::B(v), 0),
+                        None => Action::Get(id, 0, 0),
+                    };
+                    let slot = schedule::add(&mut target, when, action)?;
+                    println!("scheduled in slot {}", slot);
+                    Ok(())
+                }
+                ScheduleAction::Remove { slot } => schedule::remove(&mut target, slot),
+            }
+        }
+        Cmd::Info => {
+            let info = host::info(&mut Serial::open()?)?;
+            println!("{}", info);
+            if info.protocol != PROTOCOL_VERSION {
+                println!(
+                    "warning: protocol {} differs from the host's {}",
+                    info.protocol, PROTOCOL_VERSION
+                );
+            }
+            Ok(())
+        }
+        Cmd::Crash => {
+            let reports = host::crash_reports(&mut Serial::open()?)?;
+            if reports.is_empty() {
+                println!("no panic reports");
+            }
+            for report in reports {
+                println!("{}", report);
+            }
+            Ok(())
+        }
+        Cmd::Logs { level, target, out } => {
+            let filter = logs::Filter {
+                level: level.into(),
+                target,
+            };
+            let capture = match out {
+                Some(path) => Some(BufWriter::new(File::create(path)?)),
+                None => None,
+            };
+            let color = std::io::stdout().is_terminal();
+            // until the link fails
+            logs::follow(open()?, &filter, color, std::io::stdout(), capture)
+        }
+        Cmd::Pack {
+            input,
+            version,
+            key,
+            out,
+        } => {
+            let payload = pack::payload(&std::fs::read(input)?)?;
+            let key = pack::signing_key(&std::fs::read(key)?)?;
+            std::fs::write(&out, pack::pack(&payload, version, &key)?)?;
+            print!("{} bytes, version {}, public key ", payload.len(), version);
+            for b in key.verifying_key().to_bytes() {
+                print!("{:02x}", b);
+            }
+            println!();
+            Ok(())
+        }
+        Cmd::Pwm { action } => {
+            let mut serial = Serial::open()?;
+            match action {
+                PwmAction::Config {
+                    channel,
+                    pin,
+                    freq,
+                    bits,
+                } => {
+                    let config = PwmConfig {
+                        pin,
+                        freq_hz: freq,
+                        resolution_bits: bits,
+                    };
+                    pwm::configure(&mut serial, channel, config)
+                }
+                PwmAction::Duty { channel, duty } => pwm::set_duty(&mut serial, channel, duty),
+            }
+        }
+    }
+}
+
+fn gpio(action: GpioAction) -> Result<(), std::io::Error> {
+    let mut serial = Serial::open()?;
+    let level = |high| if high { "high" } else { "low" };
+
+    match action {
+        GpioAction::Set { pin, level } => {
+            gpio::write(&mut serial, pin, matches!(level, Level::High))
+        }
+        GpioAction::Get { pin } => {
+            let high = gpio::read(&mut serial, pin)?;
+            println!("{}", level(high));
+            Ok(())
+        }
+        GpioAction::Mode { pin, mode } => {
+            let mode = match mode {
+                Mode::Input => PinMode::Input,
+                Mode::PullUp => PinMode::InputPullUp,
+                Mode::Output => PinMode::Output,
+                Mode::OpenDrain => PinMode::OpenDrain,
+            };
+            gpio::configure(&mut serial, pin, mode)
+        }
+        GpioAction::Watch { pin, edge } => {
+            let trigger = match edge {
+                Edge::Rising => Trigger::Rising,
+                Edge::Falling => Trigger::Falling,
+                Edge::Any => Trigger::AnyEdge,
+            };
+            gpio::subscribe(&mut serial, pin, Some(trigger))?;
+            loop {
+                let (pin, high) = gpio::next_edge(&mut serial)?;
+                println!("GPIO{} {}", pin, level(high));
+            }
+        }
+    }
+}
+
+fn demo() -> Result<(), std::io::Error> {
     let mut port = open()?;
 
     let mut out_buf = [0u8; OUT_SIZE];
-    let mut in_buf = [0u8; IN_SIZE];
+    let mut in_buf: InBuf = InBuf::new();
 
     let cmd = Command::Set(0x12, Message::B(12), 0b001);
     println!("request {:?}", cmd);
@@ -43,29 +659,3 @@ fn main() -> Result<(), std::io::Error> {
     println!("response {:?}", response);
     Ok(())
 }
-
-fn request(
-    cmd: &Command,
-    port: &mut SerialPort,
-    out_buf: &mut OutBuf,
-    in_buf: &mut InBuf,
-) -> Result<Response, std::io::Error> {
-    println!("out_buf {}", out_buf.len());
-    let to_write = serialize_crc_cobs(cmd, out_buf);
-    port.write_all(to_write)?;
-
-    let mut index: usize = 0;
-    loop {
-        let slice = &mut in_buf[index..index + 1];
-        if index < IN_SIZE {
-            index += 1;
-        }
-        port.read_exact(slice)?;
-        if slice[0] == ZERO {
-            println!("-- cobs package received --");
-            break;
-        }
-    }
-    println!("cobs index {}", index);
-    Ok(deserialize_crc_cobs(in_buf).unwrap())
-}
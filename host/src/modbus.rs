@@ -0,0 +1,232 @@
+//! Modbus RTU master
+//!
+//! `Loopback` runs a `shared::modbus::Server` in memory against a `Target`
+//! (e.g., the simulator), with simulated character timing, so the register
+//! mapping can be tested like plant tooling would use it.
+
+use crate::Target;
+use shared::modbus::{
+    append_crc, check_crc, silence_us, Exception, Server, MAX_FRAME, READ_HOLDING, READ_INPUT,
+    WRITE_MULTIPLE, WRITE_SINGLE,
+};
+use shared::Response;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Exception(Exception),
+    /// No response, e.g., a corrupted request
+    Timeout,
+    /// Bad crc, or not the response to the request
+    Invalid,
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Carries RTU frames to a server
+pub trait Link {
+    /// Send a request, returns the response if any
+    fn transact(&mut self, request: &[u8]) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// A server and its target in memory
+pub struct Loopback<T> {
+    pub server: Server,
+    pub target: T,
+    baud: u32,
+    now_us: u64,
+}
+
+impl<T: Target> Loopback<T> {
+    pub fn new(unit: u8, baud: u32, target: T) -> Self {
+        Self {
+            server: Server::new(unit, baud),
+            target,
+            baud,
+            now_us: 0,
+        }
+    }
+
+    /// Byte at a time, `gap_us` between bytes, then silence
+    pub fn send(&mut self, frame: &[u8], gap_us: u64) -> io::Result<Option<Vec<u8>>> {
+        let char_us = 11_000_000 / self.baud as u64;
+        for b in frame {
+            self.server.push(*b, self.now_us);
+            self.now_us += char_us + gap_us;
+        }
+        self.now_us += silence_us(self.baud).0;
+
+        let mut error = None;
+        let target = &mut self.target;
+        let mut handler = |cmd| match target.request(&cmd) {
+            Ok(response) => response,
+            Err(e) => {
+                error = Some(e);
+                Response::ParseError
+            }
+        };
+        let mut out_buf = [0; MAX_FRAME];
+        let response = self
+            .server
+            .poll(self.now_us, &mut handler, &mut out_buf)
+            .map(|r| r.to_vec());
+        match error {
+            Some(e) => Err(e),
+            None => Ok(response),
+        }
+    }
+}
+
+impl<T: Target> Link for Loopback<T> {
+    fn transact(&mut self, request: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.send(request, 0)
+    }
+}
+
+pub struct Master<L> {
+    pub link: L,
+    unit: u8,
+}
+
+impl<L: Link> Master<L> {
+    pub fn new(link: L, unit: u8) -> Self {
+        Self { link, unit }
+    }
+
+    /// Send a PDU, returns the response PDU after the function code
+    fn request(&mut self, pdu: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut frame = vec![self.unit];
+        frame.extend(pdu);
+        frame.extend([0, 0]);
+        let n = frame.len() - 2;
+        append_crc(&mut frame, n);
+
+        let response = self.link.transact(&frame)?.ok_or(Error::Timeout)?;
+        if response.len() < 5 || !check_crc(&response) || response[0] != self.unit {
+            return Err(Error::Invalid);
+        }
+        let function = response[1];
+        if function == pdu[0] | 0x80 {
+            return Err(Exception::from_u8(response[2]).map_or(Error::Invalid, Error::Exception));
+        }
+        if function != pdu[0] {
+            return Err(Error::Invalid);
+        }
+        Ok(response[2..response.len() - 2].to_vec())
+    }
+
+    fn read(&mut self, function: u8, address: u16, count: u16) -> Result<Vec<u16>, Error> {
+        let mut pdu = vec![function];
+        pdu.extend(address.to_be_bytes());
+        pdu.extend(count.to_be_bytes());
+        let data = self.request(&pdu)?;
+        if data.len() != 1 + 2 * count as usize || data[0] as usize != 2 * count as usize {
+            return Err(Error::Invalid);
+        }
+        Ok(data[1..]
+            .chunks(2)
+            .map(|w| u16::from_be_bytes([w[0], w[1]]))
+            .collect())
+    }
+
+    pub fn read_holding(&mut self, address: u16, count: u16) -> Result<Vec<u16>, Error> {
+        self.read(READ_HOLDING, address, count)
+    }
+
+    pub fn read_input(&mut self, address: u16, count: u16) -> Result<Vec<u16>, Error> {
+        self.read(READ_INPUT, address, count)
+    }
+
+    pub fn write_single(&mut self, address: u16, value: u16) -> Result<(), Error> {
+        let mut pdu = vec![WRITE_SINGLE];
+        pdu.extend(address.to_be_bytes());
+        pdu.extend(value.to_be_bytes());
+        match self.request(&pdu)? {
+            echo if echo == pdu[1..] => Ok(()),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    pub fn write_multiple(&mut self, address: u16, values: &[u16]) -> Result<(), Error> {
+        let mut pdu = vec![WRITE_MULTIPLE];
+        pdu.extend(address.to_be_bytes());
+        pdu.extend((values.len() as u16).to_be_bytes());
+        pdu.push(2 * values.len() as u8);
+        pdu.extend(values.iter().flat_map(|v| v.to_be_bytes()));
+        match self.request(&pdu)? {
+            echo if echo == pdu[1..5] => Ok(()),
+            _ => Err(Error::Invalid),
+        }
+    }
+
+    /// The value of an `Id`, i.e., holding registers 2 id and 2 id + 1
+    pub fn read_value(&mut self, id: u16) -> Result<u32, Error> {
+        let words = self.read_holding(2 * id, 2)?;
+        Ok((words[0] as u32) << 16 | words[1] as u32)
+    }
+
+    pub fn write_value(&mut self, id: u16, value: u32) -> Result<(), Error> {
+        self.write_multiple(2 * id, &[(value >> 16) as u16, value as u16])
+    }
+}
+
+#[cfg(test)]
+fn master() -> Master<Loopback<crate::sim::Simulator>> {
+    Master::new(Loopback::new(1, 9_600, crate::sim::Simulator::new()), 1)
+}
+
+#[test]
+fn simulator_registers() {
+    use shared::{Command, Message};
+
+    let mut master = master();
+    master.write_value(0x12, 0x0001_0002).unwrap();
+    assert_eq!(master.read_value(0x12).unwrap(), 0x0001_0002);
+    assert_eq!(master.read_holding(0x24, 2).unwrap(), [1, 2]);
+
+    master.write_single(0x25, 12).unwrap();
+    assert_eq!(
+        master.link.target.handle(Command::Get(0x12, 0, 0)),
+        Response::Data(0x12, 0, 0x0001_000c, 0)
+    );
+
+    // parameters of an id as input registers, the simulator ignores the parameter
+    master
+        .link
+        .target
+        .handle(Command::Set(0x03, Message::B(7), 0));
+    assert_eq!(master.read_input(0x0302, 2).unwrap(), [0, 7]);
+}
+
+#[test]
+fn errors() {
+    let mut master = master();
+    assert!(matches!(
+        master.read_value(0x40),
+        Err(Error::Exception(Exception::IllegalDataAddress))
+    ));
+    assert!(matches!(
+        master.read_holding(0, shared::modbus::MAX_READ + 1),
+        Err(Error::Exception(Exception::IllegalDataValue))
+    ));
+
+    // another unit does not answer
+    let mut other = Master::new(master.link, 2);
+    assert!(matches!(other.read_holding(0, 1), Err(Error::Timeout)));
+
+    // a pause within the frame invalidates it, and a corrupted one is dropped
+    let link = &mut other.link;
+    link.target
+        .handle(shared::Command::Set(0, shared::Message::B(1), 0));
+    let mut frame = vec![1, READ_HOLDING, 0, 0, 0, 2, 0, 0];
+    append_crc(&mut frame, 6);
+    assert!(link.send(&frame, 0).unwrap().is_some());
+    assert_eq!(link.send(&frame, 2_000).unwrap(), None);
+    frame[3] ^= 1;
+    assert_eq!(link.send(&frame, 0).unwrap(), None);
+}
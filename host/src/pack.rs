@@ -0,0 +1,266 @@
+//! Pack and sign firmware images
+//!
+//! Builds a `shared::image` header for an ESP-IDF app image, as written by
+//! `espflash save-image`. An ELF file is converted to such an image first.
+//! The key file holds the 32 byte Ed25519 secret seed, e.g.,
+//! `head -c 32 /dev/urandom > key.bin`.
+
+use ed25519_dalek::{Signer, SigningKey};
+use object::elf::{SHF_ALLOC, SHT_PROGBITS};
+use object::read::elf::{ElfFile32, SectionHeader};
+use object::Endianness;
+use sha2::{Digest, Sha256};
+use shared::image::ImageHeader;
+use std::io::{Error, Result};
+
+const MAGIC: u8 = 0xe9;
+/// Segment header, load address and length
+const SEGMENT_HEADER: usize = 8;
+/// Flash is mapped in 64 KiB pages, a segment's offset in the image must
+/// match its address within the page
+const PAGE: usize = 0x1_0000;
+/// Instruction and data bus address ranges mapped from flash (ESP32-C3)
+const FLASH_MAPPED: [std::ops::Range<u32>; 2] =
+    [0x3c00_0000..0x3c80_0000, 0x4200_0000..0x4280_0000];
+const CHIP_ID_ESP32C3: u16 = 5;
+
+fn flash_mapped(addr: u32) -> bool {
+    FLASH_MAPPED.iter().any(|r| r.contains(&addr))
+}
+
+/// Load address and data
+type Segment = (u32, Vec<u8>);
+
+/// The entry point and the allocated sections with contents, adjacent ones
+/// merged
+fn segments(elf: &[u8]) -> Result<(u32, Vec<Segment>)> {
+    let file = ElfFile32::<Endianness>::parse(elf).map_err(Error::other)?;
+    let endian = file.endian();
+    let mut sections = vec![];
+    for section in file.elf_section_table().iter() {
+        if section.sh_type(endian) != SHT_PROGBITS || section.sh_flags(endian) & SHF_ALLOC == 0 {
+            continue;
+        }
+        let data = section
+            .data(endian, elf)
+            .map_err(|_| Error::other("bad section"))?;
+        if !data.is_empty() {
+            sections.push((section.sh_addr(endian), data));
+        }
+    }
+    sections.sort_by_key(|(addr, _)| *addr);
+
+    let mut segments: Vec<Segment> = vec![];
+    for (addr, data) in sections {
+        match segments.last_mut() {
+            Some((start, d)) if *start as usize + d.len() == addr as usize => {
+                d.extend_from_slice(data)
+            }
+            _ => segments.push((addr, data.to_vec())),
+        }
+    }
+    Ok((file.elf_header().e_entry.get(endian), segments))
+}
+
+fn push_segment(image: &mut Vec<u8>, addr: u32, data: &[u8]) {
+    // lengths are word aligned
+    let len = data.len().next_multiple_of(4);
+    image.extend_from_slice(&addr.to_le_bytes());
+    image.extend_from_slice(&(len as u32).to_le_bytes());
+    image.extend_from_slice(data);
+    image.resize(image.len() + len - data.len(), 0);
+}
+
+/// Convert an ELF file to an ESP-IDF app image
+///
+/// Segments mapped from flash come first, each aligned to its page with a
+/// padding segment as needed, followed by those loaded into RAM. The image
+/// ends with the XOR checksum of the segment data and a SHA-256 digest.
+pub fn from_elf(elf: &[u8]) -> Result<Vec<u8>> {
+    let (entry, segments) = segments(elf)?;
+    let (flash, ram): (Vec<_>, Vec<_>) = segments.iter().partition(|(a, _)| flash_mapped(*a));
+
+    // the segment count is set below, the flash is DIO, 4 MB at 40 MHz
+    let mut image = vec![MAGIC, 0, 0x02, 0x20];
+    image.extend_from_slice(&entry.to_le_bytes());
+    image.push(0xee); // no WP pin
+    image.extend_from_slice(&[0; 3]); // SPI pin drive strengths
+    image.extend_from_slice(&CHIP_ID_ESP32C3.to_le_bytes());
+    image.push(0); // deprecated minimum revision
+    image.extend_from_slice(&0u16.to_le_bytes()); // minimum revision
+    image.extend_from_slice(&u16::MAX.to_le_bytes()); // no maximum revision
+    image.extend_from_slice(&[0; 4]);
+    image.push(1); // a digest is appended
+
+    let mut segment_count = 0;
+    let mut checksum = 0xefu8;
+    for (addr, data) in flash.into_iter().chain(ram) {
+        let offset = *addr as usize % PAGE;
+        if flash_mapped(*addr) && (image.len() + SEGMENT_HEADER) % PAGE != offset {
+            // moves the data to its offset in the page, after both headers
+            let at = image.len() + 2 * SEGMENT_HEADER;
+            push_segment(&mut image, 0, &vec![0; (offset + PAGE - at % PAGE) % PAGE]);
+            segment_count += 1;
+        }
+        push_segment(&mut image, *addr, data);
+        segment_count += 1;
+        checksum = data.iter().fold(checksum, |c, b| c ^ b);
+    }
+    image[1] = u8::try_from(segment_count).map_err(|_| Error::other("too many segments"))?;
+
+    // the checksum is the last byte of a 16 byte block
+    image.resize((image.len() + 1).next_multiple_of(16) - 1, 0);
+    image.push(checksum);
+    let digest = Sha256::digest(&image);
+    image.extend_from_slice(&digest);
+    Ok(image)
+}
+
+/// The app image to sign, an ELF file is converted first
+pub fn payload(file: &[u8]) -> Result<Vec<u8>> {
+    if file.starts_with(b"\x7fELF") {
+        from_elf(file)
+    } else {
+        Ok(file.to_vec())
+    }
+}
+
+pub fn signing_key(key_file: &[u8]) -> Result<SigningKey> {
+    let seed: &[u8; 32] = key_file
+        .try_into()
+        .map_err(|_| Error::other("the key file must hold 32 bytes"))?;
+    Ok(SigningKey::from_bytes(seed))
+}
+
+/// Signed header followed by the payload
+pub fn pack(payload: &[u8], version: u32, key: &SigningKey) -> Result<Vec<u8>> {
+    u32::try_from(payload.len()).map_err(Error::other)?;
+    let mut header = ImageHeader::new(version, payload);
+    header.signature = key.sign(&header.signed_bytes()).to_bytes();
+    let mut image = header.to_bytes().to_vec();
+    image.extend_from_slice(payload);
+    Ok(image)
+}
+
+/// A RISC-V ELF file with the sections `(type, address, data)`
+#[cfg(test)]
+fn elf(entry: u32, sections: &[(u32, u32, &[u8])]) -> Vec<u8> {
+    const EHSIZE: usize = 52;
+    let mut file = vec![0x7f, b'E', b'L', b'F', 1, 1, 1];
+    file.resize(16, 0);
+    let data_len: usize = sections.iter().map(|(_, _, d)| d.len()).sum();
+    let shoff = EHSIZE + data_len + 1;
+    for v in [2u16, 0xf3] {
+        file.extend_from_slice(&v.to_le_bytes()); // executable, RISC-V
+    }
+    for v in [1, entry, 0, shoff as u32, 0] {
+        file.extend_from_slice(&v.to_le_bytes());
+    }
+    // the last section is the empty section name table
+    let shnum = sections.len() as u16 + 2;
+    for v in [EHSIZE as u16, 32, 0, 40, shnum, shnum - 1] {
+        file.extend_from_slice(&v.to_le_bytes());
+    }
+    for (_, _, data) in sections {
+        file.extend_from_slice(data);
+    }
+    file.push(0);
+
+    file.extend_from_slice(&[0; 40]);
+    let mut offset = EHSIZE;
+    for (sh_type, addr, data) in sections {
+        let flags = SHF_ALLOC;
+        for v in [
+            0,
+            *sh_type,
+            flags,
+            *addr,
+            offset as u32,
+            data.len() as u32,
+            0,
+            0,
+            4,
+            0,
+        ] {
+            file.extend_from_slice(&v.to_le_bytes());
+        }
+        offset += data.len();
+    }
+    for v in [0, 3, 0, 0, offset as u32, 1, 0, 0, 1, 0] {
+        file.extend_from_slice(&v.to_le_bytes());
+    }
+    file
+}
+
+#[test]
+fn payloads() {
+    use object::elf::SHT_NOBITS;
+
+    assert_eq!(payload(b"raw").unwrap(), b"raw");
+    assert!(payload(b"\x7fELF").is_err());
+
+    let entry = 0x4200_0020u32;
+    let file = elf(
+        entry,
+        &[
+            (SHT_PROGBITS, 0x3c00_0020, &[1, 2, 3, 4, 5, 6, 7, 8]),
+            (SHT_PROGBITS, entry, &[9, 10, 11, 12, 13, 14]),
+            // adjacent, merged with the previous
+            (SHT_PROGBITS, entry + 6, &[15]),
+            (SHT_PROGBITS, 0x3fc8_0000, &[16, 17, 18, 19]),
+            (SHT_NOBITS, 0x3fc8_0004, &[]),
+        ],
+    );
+    let image = payload(&file).unwrap();
+    assert_eq!(image[0], MAGIC);
+    assert_eq!(image[4..8], entry.to_le_bytes());
+    assert_eq!(image[12..14], CHIP_ID_ESP32C3.to_le_bytes());
+
+    // (address, offset, data) of the segments without padding
+    let mut at = 24;
+    let mut segments = vec![];
+    for _ in 0..image[1] {
+        let word = |i: usize| u32::from_le_bytes(image[i..i + 4].try_into().unwrap());
+        let (addr, len) = (word(at), word(at + 4) as usize);
+        at += SEGMENT_HEADER;
+        if addr != 0 {
+            segments.push((addr, at, image[at..at + len].to_vec()));
+        }
+        at += len;
+    }
+    assert_eq!(
+        segments
+            .iter()
+            .map(|(addr, _, data)| (*addr, data.clone()))
+            .collect::<Vec<_>>(),
+        [
+            (0x3c00_0020, vec![1, 2, 3, 4, 5, 6, 7, 8]),
+            (entry, vec![9, 10, 11, 12, 13, 14, 15, 0]),
+            (0x3fc8_0000, vec![16, 17, 18, 19]),
+        ]
+    );
+    for (addr, offset, _) in &segments[..2] {
+        assert_eq!(offset % PAGE, *addr as usize % PAGE);
+    }
+
+    let (image, digest) = image.split_at(image.len() - 32);
+    assert_eq!(digest, &Sha256::digest(image)[..]);
+    assert_eq!(image.len() % 16, 0);
+    assert_eq!(image[image.len() - 1], (1..20).fold(0xef, |c, b| c ^ b));
+}
+
+#[test]
+fn pack_and_verify() {
+    use shared::image::{ImageError, Verifier, HEADER_LEN};
+
+    let key = signing_key(&[7; 32]).unwrap();
+    assert!(signing_key(&[7; 31]).is_err());
+    let public_key = key.verifying_key().to_bytes();
+    let image = pack(b"firmware", 4, &key).unwrap();
+
+    let header = ImageHeader::from_bytes(&image).unwrap();
+    let mut verifier = Verifier::new(header);
+    verifier.update(&image[HEADER_LEN..]);
+    assert_eq!(verifier.clone().finish(&public_key, 4), Ok(()));
+    assert_eq!(verifier.finish(&public_key, 5), Err(ImageError::Rollback));
+}
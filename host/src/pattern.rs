@@ -0,0 +1,51 @@
+//! Status LED patterns on the target
+
+use crate::{unexpected, Target};
+use shared::led_pattern::{BuiltinPattern, LedPattern, Step};
+use shared::{Command, Response};
+use std::io::{Error, Result};
+
+fn pattern_request<T: Target>(target: &mut T, cmd: Command) -> Result<()> {
+    match target.request(&cmd)? {
+        Response::SetOk => Ok(()),
+        Response::PatternError(e) => Err(Error::other(format!("pattern error {:?}", e))),
+        r => Err(unexpected(r)),
+    }
+}
+
+pub fn select<T: Target>(target: &mut T, pattern: BuiltinPattern) -> Result<()> {
+    pattern_request(target, Command::SelectLedPattern(pattern))
+}
+
+pub fn upload<T: Target>(target: &mut T, pattern: LedPattern) -> Result<()> {
+    pattern_request(target, Command::UploadLedPattern(pattern))
+}
+
+/// Parse steps like "on:100,off:100,on:100,off:700" (durations in ms)
+pub fn parse_steps(s: &str) -> std::result::Result<Vec<Step>, String> {
+    s.split(',')
+        .map(|step| {
+            let (level, ms) = step
+                .trim()
+                .split_once(':')
+                .ok_or("expected on:<ms> or off:<ms>")?;
+            let ms = ms.trim().parse().map_err(|e| format!("{}", e))?;
+            match level.trim() {
+                "on" => Ok(Step::on(ms)),
+                "off" => Ok(Step::off(ms)),
+                _ => Err("expected on:<ms> or off:<ms>".into()),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn step_strings() {
+    assert_eq!(
+        parse_steps("on:100, off:700"),
+        Ok(vec![Step::on(100), Step::off(700)])
+    );
+    assert!(parse_steps("on").is_err());
+    assert!(parse_steps("blink:100").is_err());
+    assert!(parse_steps("on:70000").is_err());
+}
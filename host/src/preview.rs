@@ -0,0 +1,37 @@
+//! Terminal preview of smart LED effects
+//!
+//! Renders `shared::animation` frames with 24-bit ANSI colors, redrawing a single line.
+
+use shared::animation::{Animation, Effect, RGB8};
+use std::io::{Result, Write};
+use std::time::{Duration, Instant};
+
+pub const PIXELS: usize = 16;
+const FRAME_TIME: Duration = Duration::from_millis(20);
+
+/// One line of colored blocks
+pub fn ansi(frame: &[RGB8]) -> String {
+    let mut line = String::new();
+    for px in frame {
+        line += &format!("\x1b[38;2;{};{};{}m\u{2588}\u{2588}", px.r, px.g, px.b);
+    }
+    line + "\x1b[0m"
+}
+
+/// Animate `effect` in the terminal for `duration`
+pub fn run(effect: Effect, brightness: u8, duration: Duration) -> Result<()> {
+    // the terminal is not a LED, skip gamma correction
+    let mut animation = Animation::<PIXELS>::new(effect)
+        .with_brightness(brightness)
+        .with_gamma(false);
+    let start = Instant::now();
+    let mut stdout = std::io::stdout();
+
+    while start.elapsed() < duration {
+        let frame = animation.frame(start.elapsed().as_millis() as u64);
+        write!(stdout, "\r{}", ansi(&frame))?;
+        stdout.flush()?;
+        std::thread::sleep(FRAME_TIME);
+    }
+    writeln!(stdout)
+}
@@ -0,0 +1,326 @@
+//! Multiplexed link with a pseudo-terminal per channel
+//!
+//! `Mux` owns the serial port, sends commands on the control channel and
+//! relays the console and passthrough channels to Linux pseudo-terminals, so
+//! e.g. minicom can attach to them while the control client keeps running.
+
+use crate::{invalid, Target, IN_SIZE, OUT_SIZE, TIME_OUT};
+use serial2::SerialPort;
+use shared::mux::{self, Channel, Demux};
+use shared::{Command, Response};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Result, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Bytes read from a pseudo-terminal per frame
+const CHUNK: usize = 64;
+
+fn check(ret: libc::c_int) -> Result<libc::c_int> {
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// A pseudo-terminal in raw mode, programs open `path`
+pub struct Pty {
+    pub master: File,
+    pub path: PathBuf,
+    // keeps reads on the master from failing while no program has `path` open
+    _slave: File,
+}
+
+impl Pty {
+    pub fn open() -> Result<Self> {
+        // SAFETY: plain libc calls on a fresh descriptor, checked for errors
+        unsafe {
+            let fd = check(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY))?;
+            let master = File::from_raw_fd(fd);
+            check(libc::grantpt(fd))?;
+            check(libc::unlockpt(fd))?;
+            let mut name = [0 as libc::c_char; 128];
+            check(libc::ptsname_r(fd, name.as_mut_ptr(), name.len()))?;
+            let path = PathBuf::from(CStr::from_ptr(name.as_ptr()).to_str().map_err(invalid)?);
+
+            let slave = File::options().read(true).write(true).open(&path)?;
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            check(libc::tcgetattr(slave.as_raw_fd(), &mut termios))?;
+            libc::cfmakeraw(&mut termios);
+            check(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios))?;
+
+            Ok(Self {
+                master,
+                path,
+                _slave: slave,
+            })
+        }
+    }
+}
+
+/// Frame `data` for `channel` and write it to the port, one frame at a time
+fn send(port: &Mutex<SerialPort>, channel: Channel, data: &[u8]) -> Result<()> {
+    let mut out_buf = [0u8; mux::max_frame_len(CHUNK)];
+    for chunk in data.chunks(CHUNK) {
+        let frame = mux::encode(channel, chunk, &mut out_buf).map_err(invalid)?;
+        port.lock().unwrap().write_all(frame)?;
+    }
+    Ok(())
+}
+
+pub struct Mux {
+    port: Arc<Mutex<SerialPort>>,
+    responses: Receiver<Response>,
+    /// Unsolicited responses, e.g., `Response::PinEdge`, and the errors the
+    /// relay threads stopped on
+    pub events: Receiver<Result<Response>>,
+    /// The pseudo-terminals of the non-control channels
+    pub ptys: Vec<(Channel, PathBuf)>,
+}
+
+impl Mux {
+    /// Start relaying, the threads run until the port fails
+    pub fn start(port: SerialPort) -> Result<Self> {
+        let reader = port.try_clone()?;
+        let port = Arc::new(Mutex::new(port));
+        let (responses_tx, responses) = mpsc::channel();
+        let (events_tx, events) = mpsc::channel();
+
+        let mut ptys = vec![];
+        let mut masters = vec![];
+        for channel in [Channel::Console, Channel::Passthrough] {
+            let pty = Pty::open()?;
+            let mut master = pty.master.try_clone()?;
+            masters.push((channel, pty.master.try_clone()?));
+            ptys.push((channel, pty.path.clone()));
+
+            // pseudo-terminal to target
+            let port = port.clone();
+            let events_tx = events_tx.clone();
+            thread::spawn(move || {
+                let _pty = pty;
+                let mut buf = [0u8; CHUNK];
+                let e = loop {
+                    let n = match master.read(&mut buf) {
+                        Ok(n) => n,
+                        Err(e) => break e,
+                    };
+                    if let Err(e) = send(&port, channel, &buf[..n]) {
+                        break e;
+                    }
+                };
+                let _ = events_tx.send(Err(e));
+            });
+        }
+
+        // target to pseudo-terminals and the control client
+        thread::spawn(move || {
+            let e = relay(reader, masters, responses_tx, &events_tx);
+            let _ = events_tx.send(Err(e));
+        });
+
+        Ok(Self {
+            port,
+            responses,
+            events,
+            ptys,
+        })
+    }
+}
+
+fn relay(
+    reader: SerialPort,
+    mut masters: Vec<(Channel, File)>,
+    responses: Sender<Response>,
+    events: &Sender<Result<Response>>,
+) -> std::io::Error {
+    let mut demux = Demux::<IN_SIZE>::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) => return e,
+        };
+        for b in &buf[..n] {
+            match demux.push(*b) {
+                Some(Ok((Channel::Control, payload))) => {
+                    let Ok((response, _)) = ssmarshal::deserialize::<Response>(payload) else {
+                        continue;
+                    };
+                    // the receivers are gone once the `Mux` is dropped
+                    match response {
+                        Response::PinEdge(..) | Response::Samples(_) => {
+                            let _ = events.send(Ok(response));
+                        }
+                        _ => {
+                            let _ = responses.send(response);
+                        }
+                    }
+                }
+                Some(Ok((channel, payload))) => {
+                    if let Some((_, master)) = masters.iter_mut().find(|(c, _)| *c == channel) {
+                        if let Err(e) = master.write_all(payload) {
+                            return e;
+                        }
+                    }
+                }
+                // corrupted frames are dropped
+                Some(Err(_)) | None => {}
+            }
+        }
+    }
+}
+
+impl Target for Mux {
+    fn request(&mut self, cmd: &Command) -> Result<Response> {
+        let mut out_buf = [0u8; OUT_SIZE];
+        let frame = mux::encode_control(cmd, &mut out_buf).map_err(invalid)?;
+        // drop responses that timed out earlier
+        while self.responses.try_recv().is_ok() {}
+        self.port.lock().unwrap().write_all(frame)?;
+        self.responses.recv_timeout(TIME_OUT).map_err(|e| match e {
+            RecvTimeoutError::Timeout => ErrorKind::TimedOut.into(),
+            RecvTimeoutError::Disconnected => ErrorKind::BrokenPipe.into(),
+        })
+    }
+}
+
+#[test]
+fn pty_is_raw() {
+    let mut pty = Pty::open().unwrap();
+    let mut client = File::options()
+        .read(true)
+        .write(true)
+        .open(&pty.path)
+        .unwrap();
+    client.write_all(b"a\r\x03").unwrap();
+    let mut buf = [0u8; 3];
+    pty.master.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"a\r\x03");
+}
+
+#[test]
+fn relay_channels() {
+    // the target is on the master side of a pseudo-terminal
+    let mut target = Pty::open().unwrap();
+    let mut port = SerialPort::open(&target.path, 115200).unwrap();
+    port.set_read_timeout(TIME_OUT).unwrap();
+    let mut mux = Mux::start(port).unwrap();
+    let console = &mux.ptys[0];
+    assert_eq!(console.0, Channel::Console);
+    let mut console = File::options()
+        .read(true)
+        .write(true)
+        .open(&console.1)
+        .unwrap();
+
+    // console output from the target
+    let mut out_buf = [0u8; 64];
+    let frame = mux::encode(Channel::Console, b"boot ok\n", &mut out_buf).unwrap();
+    target.master.write_all(frame).unwrap();
+    let mut line = [0u8; 8];
+    console.read_exact(&mut line).unwrap();
+    assert_eq!(&line, b"boot ok\n");
+
+    // console input to the target
+    console.write_all(b"help\r").unwrap();
+    let mut demux = Demux::<64>::new();
+    let mut byte = [0u8];
+    let received = loop {
+        target.master.read_exact(&mut byte).unwrap();
+        if let Some(frame) = demux.push(byte[0]) {
+            let (channel, payload) = frame.unwrap();
+            break (channel, payload.to_vec());
+        }
+    };
+    assert_eq!(received, (Channel::Console, b"help\r".to_vec()));
+
+    // a control request answered by the target, with an event in between
+    let mut responder = target.master.try_clone().unwrap();
+    let answer = thread::spawn(move || {
+        let mut demux = Demux::<IN_SIZE>::new();
+        let mut byte = [0u8];
+        loop {
+            responder.read_exact(&mut byte).unwrap();
+            if let Some(frame) = demux.push(byte[0]) {
+                assert_eq!(frame.unwrap().0, Channel::Control);
+                break;
+            }
+        }
+        let mut out_buf = [0u8; IN_SIZE];
+        for r in [Response::PinEdge(9, false), Response::SetOk] {
+            let frame = mux::encode_control(&r, &mut out_buf).unwrap();
+            responder.write_all(frame).unwrap();
+        }
+    });
+    let response = mux.request(&Command::WritePin(7, true)).unwrap();
+    answer.join().unwrap();
+    assert_eq!(response, Response::SetOk);
+    assert_eq!(
+        mux.events.recv().unwrap().unwrap(),
+        Response::PinEdge(9, false)
+    );
+}
+
+#[test]
+fn receive_skips_corrupt_frames() {
+    let mut target = Pty::open().unwrap();
+    let mut port = SerialPort::open(&target.path, 115200).unwrap();
+    port.set_read_timeout(TIME_OUT).unwrap();
+
+    let mut out_buf = [0u8; IN_SIZE];
+    let frame = mux::encode_control(&Response::SetOk, &mut out_buf).unwrap();
+    let mut corrupt = frame.to_vec();
+    corrupt[1] ^= 0x55;
+    target.master.write_all(&corrupt).unwrap();
+    target.master.write_all(frame).unwrap();
+
+    let mut in_buf: crate::InBuf = crate::InBuf::new();
+    assert_eq!(
+        crate::receive(&mut port, &mut in_buf).unwrap(),
+        Response::SetOk
+    );
+    // nothing more until the timeout
+    assert_eq!(
+        crate::receive(&mut port, &mut in_buf).unwrap_err().kind(),
+        ErrorKind::TimedOut
+    );
+}
+
+#[test]
+fn request_with_slip() {
+    use shared::framing::Slip;
+
+    let target = Pty::open().unwrap();
+    let mut port = SerialPort::open(&target.path, 115200).unwrap();
+    port.set_read_timeout(TIME_OUT).unwrap();
+
+    let mut responder = target.master.try_clone().unwrap();
+    let answer = thread::spawn(move || {
+        let mut demux = Demux::<IN_SIZE, Slip>::new();
+        let mut byte = [0u8];
+        loop {
+            responder.read_exact(&mut byte).unwrap();
+            if let Some(frame) = demux.push(byte[0]) {
+                assert_eq!(frame.unwrap().0, Channel::Control);
+                break;
+            }
+        }
+        let mut out_buf = [0u8; IN_SIZE];
+        let frame =
+            mux::encode_control_with::<Slip, _, IN_SIZE>(&Response::SetOk, &mut out_buf).unwrap();
+        responder.write_all(frame).unwrap();
+    });
+    let mut out_buf = [0u8; OUT_SIZE];
+    let mut in_buf = crate::InBuf::<Slip>::new();
+    let cmd = Command::WritePin(7, true);
+    let response = crate::request(&cmd, &mut port, &mut out_buf, &mut in_buf).unwrap();
+    answer.join().unwrap();
+    assert_eq!(response, Response::SetOk);
+}
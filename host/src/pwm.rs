@@ -0,0 +1,53 @@
+//! Remote PWM on the target
+
+use crate::{unexpected, Target};
+use shared::pwm::{Channel, Duty, PwmConfig, PwmError};
+use shared::{Command, Response};
+use std::io::{Error, Result};
+
+fn pwm_error(e: PwmError) -> Error {
+    Error::other(format!("pwm error {:?}", e))
+}
+
+fn pwm_request<T: Target>(target: &mut T, cmd: Command) -> Result<()> {
+    match target.request(&cmd)? {
+        Response::SetOk => Ok(()),
+        Response::PwmError(e) => Err(pwm_error(e)),
+        r => Err(unexpected(r)),
+    }
+}
+
+pub fn configure<T: Target>(target: &mut T, channel: Channel, config: PwmConfig) -> Result<()> {
+    pwm_request(target, Command::ConfigurePwm(channel, config))
+}
+
+pub fn set_duty<T: Target>(target: &mut T, channel: Channel, duty: Duty) -> Result<()> {
+    pwm_request(target, Command::SetDuty(channel, duty))
+}
+
+/// Parse "50%", "12.5 %" or "1500us"
+pub fn parse_duty(s: &str) -> std::result::Result<Duty, String> {
+    let s = s.trim();
+    if let Some(p) = s.strip_suffix('%') {
+        p.trim()
+            .parse()
+            .map(Duty::Percent)
+            .map_err(|e| format!("{}", e))
+    } else if let Some(us) = s.strip_suffix("us") {
+        us.trim()
+            .parse()
+            .map(Duty::PulseUs)
+            .map_err(|e| format!("{}", e))
+    } else {
+        Err("expected a percentage (50%) or a pulse width (1500us)".into())
+    }
+}
+
+#[test]
+fn duty_strings() {
+    assert_eq!(parse_duty("50%"), Ok(Duty::Percent(50.0)));
+    assert_eq!(parse_duty("12.5 %"), Ok(Duty::Percent(12.5)));
+    assert_eq!(parse_duty("1500us"), Ok(Duty::PulseUs(1500)));
+    assert!(parse_duty("1500").is_err());
+    assert!(parse_duty("-5us").is_err());
+}
@@ -0,0 +1,98 @@
+//! Scheduled actions on the target
+//!
+//! The clock of the target is synced to the host's before scheduling, so
+//! `TimeSpec::At` and `TimeSpec::Cron` fire at the expected wall-clock time.
+
+use crate::{unexpected, Target};
+use chrono::{DateTime, Utc};
+use shared::date_time::UtcDateTime;
+use shared::schedule::{Cron, ScheduleError, Slot, TimeSpec, MONDAY_TO_FRIDAY};
+use shared::{Action, Command, Response};
+use std::io::{Error, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn schedule_error(e: ScheduleError) -> Error {
+    Error::other(format!("schedule error {:?}", e))
+}
+
+fn schedule_request<T: Target>(target: &mut T, cmd: Command) -> Result<Response> {
+    match target.request(&cmd)? {
+        Response::ScheduleError(e) => Err(schedule_error(e)),
+        r => Ok(r),
+    }
+}
+
+/// Set the clock of the target to the host's
+pub fn sync_time<T: Target>(target: &mut T) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(Error::other)?;
+    let now = UtcDateTime::from_timestamp_nanos(now.as_nanos() as i64);
+    match schedule_request(target, Command::SetTime(now))? {
+        Response::SetOk => Ok(()),
+        r => Err(unexpected(r)),
+    }
+}
+
+/// Sync the clock and schedule `action`, returns its slot
+pub fn add<T: Target>(target: &mut T, spec: TimeSpec, action: Action) -> Result<Slot> {
+    sync_time(target)?;
+    match schedule_request(target, Command::Schedule(spec, action))? {
+        Response::Scheduled(slot) => Ok(slot),
+        r => Err(unexpected(r)),
+    }
+}
+
+pub fn remove<T: Target>(target: &mut T, slot: Slot) -> Result<()> {
+    match schedule_request(target, Command::Unschedule(slot))? {
+        Response::SetOk => Ok(()),
+        r => Err(unexpected(r)),
+    }
+}
+
+fn hour_minute(s: &str) -> Option<Cron> {
+    let (h, m) = s.split_once(':')?;
+    Cron::daily(h.parse().ok()?, m.parse().ok()?)
+}
+
+/// Parse "every:300" (seconds), "daily:07:00", "weekdays:07:00" (UTC) or a
+/// time like "2023-10-16T07:00:00Z"
+pub fn parse_spec(s: &str) -> std::result::Result<TimeSpec, String> {
+    let s = s.trim();
+    let spec = if let Some(seconds) = s.strip_prefix("every:") {
+        seconds.parse().ok().map(TimeSpec::Every)
+    } else if let Some(time) = s.strip_prefix("daily:") {
+        hour_minute(time).map(TimeSpec::Cron)
+    } else if let Some(time) = s.strip_prefix("weekdays:") {
+        hour_minute(time).map(|c| TimeSpec::Cron(c.on_weekdays(MONDAY_TO_FRIDAY)))
+    } else {
+        s.parse::<DateTime<Utc>>()
+            .ok()
+            .map(|t| TimeSpec::At(t.into()))
+    };
+    spec.ok_or_else(|| {
+        "expected every:<seconds>, daily:<hh:mm>, weekdays:<hh:mm> or a time like \
+         2023-10-16T07:00:00Z"
+            .into()
+    })
+}
+
+#[test]
+fn spec_strings() {
+    assert_eq!(parse_spec("every:300"), Ok(TimeSpec::Every(300)));
+    assert_eq!(
+        parse_spec("weekdays:07:00"),
+        Ok(TimeSpec::Cron(
+            Cron::daily(7, 0).unwrap().on_weekdays(MONDAY_TO_FRIDAY)
+        ))
+    );
+    assert_eq!(
+        parse_spec("2023-10-16T07:30:15Z"),
+        Ok(TimeSpec::At(UtcDateTime::from_timestamp_nanos(
+            1_697_441_415_000_000_000
+        )))
+    );
+    assert!(parse_spec("daily:24:00").is_err());
+    assert!(parse_spec("every:-1").is_err());
+    assert!(parse_spec("tomorrow").is_err());
+}
@@ -0,0 +1,706 @@
+//! Target simulator
+//!
+//! Handles `Command`s like the firmware would, so host side tools can be tested
+//! without a device.
+
+use crate::Target;
+use shared::{
+    adc::Capture,
+    animation::RGB8,
+    bus::{Address, BusError, Bytes, I2c, Nack},
+    crash::CrashRing,
+    gpio::{Pin, Pins},
+    info::{Info, ResetReason},
+    kv::{KvError, KvStore},
+    led_pattern::{LedPattern, LedSequencer},
+    pixels::PixelBuffer,
+    pwm::PwmChannels,
+    schedule::{Schedule, ScheduleError},
+    update::{RamFlash, Updater},
+    wall_clock::WallClock,
+    Command, Id, Message, Response,
+};
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// Pixels on the simulated strip
+pub const PIXELS: usize = 60;
+
+/// The update slot, a 4 KiB header sector and 64 KiB for the image
+pub const FLASH_SIZE: usize = 68 * 1024;
+
+/// A locally administered address, as no chip has it
+pub const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+
+/// When the simulator started, for the uptime
+#[derive(Debug)]
+struct Boot(Instant);
+
+impl Default for Boot {
+    fn default() -> Self {
+        Self(Instant::now())
+    }
+}
+
+/// Wall clock on the uptime in ms, set with `Command::SetTime`
+#[derive(Debug)]
+struct Clock(WallClock);
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self(WallClock::new(1_000, 64))
+    }
+}
+
+/// Secret seed of the key the simulator accepts images signed with
+pub const SIGNING_KEY: [u8; 32] = [0x5a; 32];
+
+/// The firmware version the simulator runs, older images are rejected
+pub const VERSION: u32 = 1;
+
+/// The update slot, accepting images signed with `SIGNING_KEY`
+#[derive(Debug)]
+pub struct UpdateSlot(pub Updater<RamFlash<FLASH_SIZE>>);
+
+impl UpdateSlot {
+    /// Picks up the update in `flash`, as after a reset
+    pub fn new(flash: RamFlash<FLASH_SIZE>) -> Self {
+        let public_key = ed25519_dalek::SigningKey::from_bytes(&SIGNING_KEY)
+            .verifying_key()
+            .to_bytes();
+        Self(Updater::new(flash, public_key, VERSION))
+    }
+}
+
+impl Default for UpdateSlot {
+    fn default() -> Self {
+        Self::new(RamFlash::default())
+    }
+}
+
+/// Parameter storage, two 4 KiB sectors
+pub const CONFIG_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Default)]
+pub struct Simulator {
+    /// Parameter table, by `Id`
+    pub values: BTreeMap<Id, u32>,
+    pixels: PixelBuffer<PIXELS>,
+    pins: Pins,
+    pub pwm: PwmChannels,
+    capture: Option<(Capture, u32)>,
+    /// Fake devices on the I2C bus
+    pub i2c: BTreeMap<Address, Box<dyn I2cDevice>>,
+    /// Every committed strip, rendered to ASCII
+    pub shown: Vec<String>,
+    pub update: UpdateSlot,
+    /// Parameters kept with `Command::Save`
+    pub config: KvStore<RamFlash<CONFIG_SIZE>>,
+    /// Panic reports, as kept across a reset
+    pub crashes: CrashRing<4>,
+    boot: Boot,
+    /// The status LED, off until a pattern is selected
+    led: Option<LedSequencer>,
+    clock: Clock,
+    schedule: Schedule<8>,
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn handle(&mut self, cmd: Command) -> Response {
+        match cmd {
+            Command::Set(id, msg, _) => {
+                let value = match msg {
+                    Message::A => 0,
+                    Message::B(v) => v,
+                    Message::C(f) => f.to_bits(),
+                };
+                self.values.insert(id, value);
+                Response::SetOk
+            }
+            Command::Get(id, par, dev) => match self.values.get(&id) {
+                Some(value) => Response::Data(id, par, *value, dev),
+                None => Response::ParseError,
+            },
+            Command::Pixels(frame) => {
+                if let Some(pixels) = self.pixels.apply(&frame) {
+                    self.shown.push(ascii(pixels));
+                }
+                Response::SetOk
+            }
+            Command::ConfigurePin(pin, mode) => ok(self.pins.configure(pin, mode)),
+            Command::WritePin(pin, high) => ok(self.pins.write(pin, high)),
+            Command::ReadPin(pin) => match self.pins.read(pin) {
+                Ok(high) => Response::Level(pin, high),
+                Err(e) => Response::GpioError(e),
+            },
+            Command::SubscribePin(pin, trigger) => ok(self.pins.subscribe(pin, trigger)),
+            Command::ConfigurePwm(channel, config) => match self.pwm.configure(channel, config) {
+                Ok(_) => Response::SetOk,
+                Err(e) => Response::PwmError(e),
+            },
+            Command::SetDuty(channel, duty) => match self.pwm.set_duty(channel, duty) {
+                Ok(_) => Response::SetOk,
+                Err(e) => Response::PwmError(e),
+            },
+            Command::StartCapture {
+                channel,
+                rate_hz,
+                samples,
+            } => match Capture::new(channel, rate_hz, samples) {
+                Ok(capture) => {
+                    self.capture = Some((capture, 0));
+                    Response::SetOk
+                }
+                Err(e) => Response::CaptureError(e),
+            },
+            Command::StopCapture => {
+                self.capture = None;
+                Response::SetOk
+            }
+            Command::I2c(i2c) => match self.i2c(i2c) {
+                Ok(data) if i2c.read_len() > 0 => Response::BusData(data),
+                Ok(_) => Response::SetOk,
+                Err(e) => Response::BusError(e),
+            },
+            // MISO is tied to MOSI
+            Command::Spi(spi) => match spi.validate() {
+                Ok(()) => Response::BusData(spi.data),
+                Err(e) => Response::BusError(e),
+            },
+            Command::Update(update) => match self.update.0.handle(update) {
+                Ok(state) => Response::Update(state),
+                Err(e) => Response::UpdateError(e),
+            },
+            Command::Save => kv_ok(self.save()),
+            Command::Load => {
+                self.values.clear();
+                let values = &mut self.values;
+                kv_ok(self.config.entries(|id, value| {
+                    values.insert(id, value);
+                }))
+            }
+            Command::FactoryReset => {
+                self.values.clear();
+                kv_ok(self.config.clear())
+            }
+            Command::GetCrashReport => Response::CrashReport(self.crashes.take()),
+            Command::GetInfo => Response::Info(Info::new(
+                env!("CARGO_PKG_VERSION"),
+                MAC,
+                self.uptime_ms(),
+                ResetReason::PowerOn,
+            )),
+            Command::SelectLedPattern(builtin) => self.show(builtin.into()),
+            Command::UploadLedPattern(pattern) => self.show(pattern),
+            Command::SetTime(t) => match self.clock.0.sync(self.uptime_ms(), t) {
+                Ok(()) => Response::SetOk,
+                Err(_) => Response::ScheduleError(ScheduleError::InvalidTime),
+            },
+            Command::Schedule(spec, action) => {
+                let now = self.clock.0.now_nanos(self.uptime_ms());
+                match now
+                    .ok_or(ScheduleError::NoClock)
+                    .and_then(|now| self.schedule.insert(spec, action, now))
+                {
+                    Ok(slot) => Response::Scheduled(slot),
+                    Err(e) => Response::ScheduleError(e),
+                }
+            }
+            Command::Unschedule(slot) => match self.schedule.remove(slot) {
+                Ok(_) => Response::SetOk,
+                Err(e) => Response::ScheduleError(e),
+            },
+        }
+    }
+
+    fn show(&mut self, pattern: LedPattern) -> Response {
+        match pattern.validate() {
+            Ok(pattern) => {
+                self.led = Some(LedSequencer::new(pattern, self.uptime_ms()));
+                Response::SetOk
+            }
+            Err(e) => Response::PatternError(e),
+        }
+    }
+
+    /// The level of the status LED at `uptime_ms`
+    pub fn led(&mut self, uptime_ms: u64) -> bool {
+        self.led.as_mut().is_some_and(|led| led.tick(uptime_ms).0)
+    }
+
+    fn uptime_ms(&self) -> u64 {
+        self.boot.0.elapsed().as_millis() as u64
+    }
+
+    /// Run the scheduled actions due at `uptime_ms`, returns their responses
+    pub fn run_schedule(&mut self, uptime_ms: u64) -> Vec<Response> {
+        let mut responses = vec![];
+        let Some(now) = self.clock.0.now_nanos(uptime_ms) else {
+            return responses;
+        };
+        while let Some((_, action)) = self.schedule.poll(now) {
+            responses.push(self.handle(action.into()));
+        }
+        responses
+    }
+
+    fn save(&mut self) -> Result<(), KvError> {
+        for (id, value) in &self.values {
+            self.config.set(*id, value)?;
+        }
+        Ok(())
+    }
+
+    fn i2c(&mut self, i2c: I2c) -> Result<Bytes, BusError> {
+        i2c.validate()?;
+        let device = self
+            .i2c
+            .get_mut(&i2c.address())
+            .ok_or(BusError::Nack(Nack::Address))?;
+        let mut buf = [0; shared::bus::MAX_BYTES];
+        let buf = &mut buf[..i2c.read_len()];
+        match i2c {
+            I2c::Write(_, data) => device.write(data.as_slice()),
+            I2c::Read(..) => device.read(buf),
+            I2c::WriteRead(_, data, _) => device.write(data.as_slice()).and(device.read(buf)),
+        }
+        .map_err(BusError::Nack)?;
+        Ok(Bytes::new(buf).unwrap())
+    }
+
+    /// The next unsolicited response, i.e., the next block of a running capture
+    pub fn poll(&mut self) -> Option<Response> {
+        let (capture, n) = self.capture.as_mut()?;
+        while !capture.is_done() {
+            let t = *n as f32 / capture.rate_hz as f32;
+            *n += 1;
+            if let Some(block) = capture.push(waveform(capture.channel, t)) {
+                return Some(Response::Samples(block));
+            }
+        }
+        self.capture = None;
+        None
+    }
+
+    /// Drive an input pin from outside, returns the event the target would send
+    pub fn drive(&mut self, pin: Pin, high: bool) -> Option<Response> {
+        self.pins
+            .input(pin, high)
+            .map(|high| Response::PinEdge(pin, high))
+    }
+}
+
+impl Target for Simulator {
+    fn request(&mut self, cmd: &Command) -> std::io::Result<Response> {
+        Ok(self.handle(*cmd))
+    }
+}
+
+/// A fake I2C device
+pub trait I2cDevice: std::fmt::Debug {
+    fn write(&mut self, data: &[u8]) -> Result<(), Nack>;
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), Nack>;
+}
+
+/// A device with 8 bit registers, the first byte written selects the register,
+/// further bytes are written to it and reads continue from it, incrementing
+#[derive(Debug, Clone)]
+pub struct RegisterDevice {
+    pub registers: [u8; 256],
+    pointer: u8,
+}
+
+impl Default for RegisterDevice {
+    fn default() -> Self {
+        Self {
+            registers: [0; 256],
+            pointer: 0,
+        }
+    }
+}
+
+impl RegisterDevice {
+    pub fn with(mut self, register: u8, value: u8) -> Self {
+        self.registers[register as usize] = value;
+        self
+    }
+}
+
+impl I2cDevice for RegisterDevice {
+    fn write(&mut self, data: &[u8]) -> Result<(), Nack> {
+        if let Some((pointer, values)) = data.split_first() {
+            self.pointer = *pointer;
+            for v in values {
+                self.registers[self.pointer as usize] = *v;
+                self.pointer = self.pointer.wrapping_add(1);
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), Nack> {
+        for b in buf {
+            *b = self.registers[self.pointer as usize];
+            self.pointer = self.pointer.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+fn ok(r: Result<(), shared::gpio::GpioError>) -> Response {
+    match r {
+        Ok(()) => Response::SetOk,
+        Err(e) => Response::GpioError(e),
+    }
+}
+
+fn kv_ok(r: Result<(), KvError>) -> Response {
+    match r {
+        Ok(()) => Response::SetOk,
+        Err(e) => Response::KvError(e),
+    }
+}
+
+/// Synthetic 12 bit ADC signals, 50 Hz on channels 0 (sine), 1 (square),
+/// 2 (sawtooth) and 3 (triangle), mid scale on the others
+pub fn waveform(channel: u8, t: f32) -> u16 {
+    const FREQ: f32 = 50.0;
+    let phase = (t * FREQ).fract();
+    let v = match channel {
+        0 => (2.0 * std::f32::consts::PI * phase).sin(),
+        1 if phase < 0.5 => 1.0,
+        1 => -1.0,
+        2 => 2.0 * phase - 1.0,
+        3 => 1.0 - 4.0 * (phase - 0.5).abs(),
+        _ => 0.0,
+    };
+    (2048.0 + 2047.0 * v).round() as u16
+}
+
+/// One character per pixel, by brightness
+pub fn ascii(pixels: &[RGB8]) -> String {
+    const RAMP: &[u8] = b" .:-=+*#%@";
+    pixels
+        .iter()
+        .map(|p| {
+            // luma, 0..=255
+            let y = (p.r as u32 * 299 + p.g as u32 * 587 + p.b as u32 * 114) / 1000;
+            RAMP[y as usize * (RAMP.len() - 1) / 255] as char
+        })
+        .collect()
+}
+
+#[test]
+fn ascii_ramp() {
+    let pixels = [0, 60, 128, 255].map(|v| RGB8::new(v, v, v));
+    assert_eq!(ascii(&pixels), " :=@");
+}
+
+#[test]
+fn ddp_to_simulator() {
+    use crate::bridge::{ddp_packet, run, Protocol};
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    let mut sim = Simulator::new();
+    let ramp: Vec<RGB8> = (0..PIXELS as u8)
+        .map(|i| RGB8::new(i * 4, i * 4, i * 4))
+        .collect();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let to = socket.local_addr().unwrap();
+
+    // first half without push, nothing is shown
+    sender
+        .send_to(&ddp_packet(0, &ramp[..30], false), to)
+        .unwrap();
+    // runs until the read timeout
+    assert!(run(Protocol::Ddp, &socket, &mut sim).is_err());
+    assert!(sim.shown.is_empty());
+
+    sender
+        .send_to(&ddp_packet(30, &ramp[30..], true), to)
+        .unwrap();
+    assert!(run(Protocol::Ddp, &socket, &mut sim).is_err());
+    assert_eq!(
+        sim.shown,
+        ["        .......:::::::-------=======+++++++*******#######%%%"]
+    );
+}
+
+#[test]
+fn values() {
+    let mut sim = Simulator::new();
+    assert_eq!(sim.handle(Command::Get(0x12, 0, 0)), Response::ParseError);
+    assert_eq!(
+        sim.handle(Command::Set(0x12, Message::B(12), 0)),
+        Response::SetOk
+    );
+    assert_eq!(
+        sim.handle(Command::Get(0x12, 3, 1)),
+        Response::Data(0x12, 3, 12, 1)
+    );
+}
+
+#[test]
+fn save_load() {
+    let mut sim = Simulator::new();
+    sim.handle(Command::Set(1, Message::B(10), 0));
+    sim.handle(Command::Set(2, Message::B(20), 0));
+    assert_eq!(sim.handle(Command::Save), Response::SetOk);
+
+    // a reset, then load
+    let mut sim = Simulator {
+        config: KvStore::mount(sim.config.storage).unwrap(),
+        ..Simulator::new()
+    };
+    assert_eq!(sim.handle(Command::Get(2, 0, 0)), Response::ParseError);
+    assert_eq!(sim.handle(Command::Load), Response::SetOk);
+    assert_eq!(
+        sim.handle(Command::Get(2, 0, 0)),
+        Response::Data(2, 0, 20, 0)
+    );
+
+    assert_eq!(sim.handle(Command::FactoryReset), Response::SetOk);
+    assert_eq!(sim.handle(Command::Get(1, 0, 0)), Response::ParseError);
+    assert_eq!(sim.handle(Command::Load), Response::SetOk);
+    assert!(sim.values.is_empty());
+}
+
+#[test]
+fn schedule() {
+    use shared::{schedule::TimeSpec, Action};
+
+    let mut sim = Simulator::new();
+    let sample = Action::Get(0x12, 0, 0);
+    assert_eq!(
+        sim.handle(Command::Schedule(TimeSpec::Every(60), sample)),
+        Response::ScheduleError(ScheduleError::NoClock)
+    );
+
+    sim.handle(Command::Set(0x12, Message::B(12), 0));
+    let slot = crate::schedule::add(&mut sim, TimeSpec::Every(60), sample).unwrap();
+    assert_eq!(sim.run_schedule(30_000), []);
+    assert_eq!(sim.run_schedule(61_000), [Response::Data(0x12, 0, 12, 0)]);
+    crate::schedule::remove(&mut sim, slot).unwrap();
+    assert_eq!(sim.run_schedule(200_000), []);
+    assert!(crate::schedule::remove(&mut sim, slot).is_err());
+}
+
+#[test]
+fn led_pattern() {
+    use crate::pattern::{parse_steps, select, upload};
+    use shared::led_pattern::BuiltinPattern;
+
+    let mut sim = Simulator::new();
+    assert!(!sim.led(0));
+    select(&mut sim, BuiltinPattern::On).unwrap();
+    assert!(sim.led(1_000));
+
+    let steps = parse_steps("on:10000,off:10000").unwrap();
+    let pattern = LedPattern::new(&steps, Some(1)).unwrap().with_rest(true);
+    upload(&mut sim, pattern).unwrap();
+    assert!(sim.led(5_000));
+    assert!(!sim.led(15_000));
+    assert!(sim.led(25_000));
+}
+
+#[test]
+fn crash_reports() {
+    use shared::crash::CrashReport;
+
+    let mut sim = Simulator::new();
+    assert_eq!(crate::crash_reports(&mut sim).unwrap(), []);
+    let report = |line| CrashReport::new(&"explicit panic", "panic.rs", line, 10, 1);
+    sim.crashes.record(&report(1));
+    sim.crashes.record(&report(2));
+    // fetched once only
+    assert_eq!(
+        crate::crash_reports(&mut sim).unwrap(),
+        [report(1), report(2)]
+    );
+    assert_eq!(
+        sim.handle(Command::GetCrashReport),
+        Response::CrashReport(None)
+    );
+}
+
+#[test]
+fn info() {
+    use shared::info::PROTOCOL_VERSION;
+
+    let mut sim = Simulator::new();
+    let info = crate::info(&mut sim).unwrap();
+    assert_eq!(info.version.as_slice(), b"0.1.0");
+    assert_eq!(info.protocol, PROTOCOL_VERSION);
+    assert_eq!(info.mac, MAC);
+    assert!(info.uptime_ms < 1000);
+    assert!(info.to_string().contains("reset    PowerOn"));
+}
+
+#[test]
+fn gpio() {
+    use crate::gpio::{configure, read, subscribe, write};
+    use shared::gpio::{PinMode, Trigger, BUTTON, LED};
+
+    // the first write configures the pin as an output
+    let mut sim = Simulator::new();
+    write(&mut sim, LED, true).unwrap();
+    assert!(read(&mut sim, LED).unwrap());
+
+    // press and release the button
+    configure(&mut sim, BUTTON, PinMode::InputPullUp).unwrap();
+    assert!(write(&mut sim, BUTTON, false).is_err());
+    subscribe(&mut sim, BUTTON, Some(Trigger::Falling)).unwrap();
+    assert_eq!(
+        sim.drive(BUTTON, false),
+        Some(Response::PinEdge(BUTTON, false))
+    );
+    assert_eq!(sim.drive(BUTTON, true), None);
+    assert!(read(&mut sim, BUTTON).unwrap());
+}
+
+#[test]
+fn pwm() {
+    use crate::pwm::{configure, set_duty};
+    use shared::pwm::{Duty, PwmConfig};
+
+    let mut sim = Simulator::new();
+    let servo = PwmConfig {
+        pin: 4,
+        freq_hz: 50,
+        resolution_bits: 14,
+    };
+    assert!(set_duty(&mut sim, 1, Duty::Percent(50.0)).is_err());
+    configure(&mut sim, 1, servo).unwrap();
+    set_duty(&mut sim, 1, Duty::PulseUs(1_500)).unwrap();
+    assert_eq!(sim.pwm.get(1).unwrap().duty, 1229);
+    let e = set_duty(&mut sim, 1, Duty::PulseUs(25_000)).unwrap_err();
+    assert_eq!(e.to_string(), "pwm error DutyOutOfRange");
+    assert_eq!(sim.pwm.get(1).unwrap().duty, 1229);
+}
+
+#[test]
+fn capture_end_to_end() {
+    use crate::capture::{Gap, Reassembler};
+    use shared::adc::CaptureError;
+
+    let mut sim = Simulator::new();
+    assert_eq!(
+        sim.handle(Command::StartCapture {
+            channel: 7,
+            rate_hz: 8000,
+            samples: 100
+        }),
+        Response::CaptureError(CaptureError::InvalidChannel)
+    );
+    let cmd = Command::StartCapture {
+        channel: 0,
+        rate_hz: 8000,
+        samples: 100,
+    };
+    assert_eq!(sim.handle(cmd), Response::SetOk);
+
+    let mut reassembler = Reassembler::new(8000);
+    while let Some(Response::Samples(block)) = sim.poll() {
+        // lose a block on the way
+        if block.seq != 4 {
+            reassembler.push(&block);
+        }
+    }
+    let recording = reassembler.finish(100);
+    assert_eq!(recording.samples.len(), 100);
+    assert_eq!(
+        recording.gaps,
+        [Gap {
+            at: 64,
+            missing: 16
+        }]
+    );
+    // a quarter period (40 samples at 8 kHz) in is the peak of the sine
+    assert_eq!(recording.samples[0], 2048);
+    assert_eq!(recording.samples[40], 4095);
+    assert_eq!(sim.poll(), None);
+}
+
+#[test]
+fn i2c_devices() {
+    use crate::bus::RemoteI2c;
+    use embedded_hal::i2c::{Error, ErrorKind, I2c, NoAcknowledgeSource};
+
+    // a BME280 answers its chip id
+    let mut sim = Simulator::new();
+    sim.i2c
+        .insert(0x76, Box::new(RegisterDevice::default().with(0xd0, 0x60)));
+    let mut i2c = RemoteI2c::new(&mut sim);
+
+    let mut id = [0];
+    i2c.write_read(0x76, &[0xd0], &mut id).unwrap();
+    assert_eq!(id, [0x60]);
+
+    // registers auto increment
+    i2c.write(0x76, &[0xf4, 1, 2, 3]).unwrap();
+    let mut regs = [0; 3];
+    i2c.write_read(0x76, &[0xf4], &mut regs).unwrap();
+    assert_eq!(regs, [1, 2, 3]);
+
+    let e = i2c.read(0x50, &mut regs).unwrap_err();
+    assert_eq!(
+        e.kind(),
+        ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)
+    );
+    assert_eq!(i2c.scan().unwrap(), [0x76]);
+}
+
+#[test]
+fn i2c_transactions() {
+    use crate::bus::{Error, RemoteI2c};
+    use embedded_hal::i2c::{I2c, Operation::*};
+
+    let mut sim = Simulator::new();
+    sim.i2c.insert(0x76, Box::new(RegisterDevice::default()));
+    let mut i2c = RemoteI2c::new(&mut sim);
+
+    // the register pointer and its data are one write
+    i2c.transaction(0x76, &mut [Write(&[0x10]), Write(&[7, 8, 9])])
+        .unwrap();
+    let (mut a, mut b) = ([0; 1], [0; 2]);
+    i2c.transaction(0x76, &mut [Write(&[0x10]), Read(&mut a), Read(&mut b)])
+        .unwrap();
+    assert_eq!((a, b), ([7], [8, 9]));
+
+    // a repeated start after a read, a read longer than a single transfer
+    assert!(matches!(
+        i2c.transaction(0x76, &mut [Read(&mut a), Write(&[0x10])]),
+        Err(Error::Unsupported)
+    ));
+    assert!(matches!(
+        i2c.read(0x76, &mut [0; 33]),
+        Err(Error::Unsupported)
+    ));
+    assert!(matches!(i2c.write(0x76, &[0; 33]), Err(Error::Unsupported)));
+}
+
+#[test]
+fn spi_loopback() {
+    use crate::bus::RemoteSpi;
+    use embedded_hal::spi::SpiDevice;
+
+    let mut sim = Simulator::new();
+    let mut spi = RemoteSpi::new(&mut sim, Some(10));
+    // longer than a single transfer
+    let data: Vec<u8> = (0..40).collect();
+    let mut read = [0; 40];
+    spi.transfer(&mut read, &data).unwrap();
+    assert_eq!(read[..], data[..]);
+
+    let mut spi = RemoteSpi::new(&mut sim, Some(18));
+    assert!(spi.write(&[1]).is_err());
+}
@@ -0,0 +1,152 @@
+//! Firmware update through the target
+//!
+//! Sends a packed image (see `pack`) with the `shared::update` protocol. An
+//! interrupted update continues where the target left off when run again with
+//! the same image.
+
+use crate::{unexpected, Target};
+use shared::bus::{Bytes, MAX_BYTES};
+use shared::image::ImageHeader;
+use shared::update::{Image, Update, UpdateError, UpdateState};
+use shared::{Command, Response, CKSUM};
+use std::io::{Error, Result};
+
+fn update_request<T: Target>(target: &mut T, update: Update) -> Result<UpdateState> {
+    match target.request(&Command::Update(update))? {
+        Response::Update(state) => Ok(state),
+        Response::UpdateError(e) => Err(Error::other(format!("update failed {:?}", e))),
+        r => Err(unexpected(r)),
+    }
+}
+
+/// Send, verify and commit the packed `image`, `progress` gets the bytes sent
+/// and the total, returns the version committed
+pub fn update<T: Target>(
+    target: &mut T,
+    image: &[u8],
+    mut progress: impl FnMut(u32, u32),
+) -> Result<u32> {
+    let version = ImageHeader::from_bytes(image)
+        .map_err(|e| Error::other(format!("not a packed image {:?}", e)))?
+        .version;
+    let size = u32::try_from(image.len()).map_err(Error::other)?;
+    let begin = Image {
+        size,
+        crc: CKSUM.checksum(image),
+        version,
+    };
+    let mut state = update_request(target, Update::Begin(begin))?;
+    loop {
+        let offset = match state {
+            UpdateState::Receiving { offset } if offset < size => offset,
+            UpdateState::Receiving { .. } => break,
+            s => return Err(Error::other(format!("unexpected state {:?}", s))),
+        };
+        progress(offset, size);
+        let end = (offset as usize + MAX_BYTES).min(image.len());
+        let chunk = Update::Chunk {
+            offset,
+            data: Bytes::new(&image[offset as usize..end]).unwrap(),
+        };
+        state = match target.request(&Command::Update(chunk))? {
+            Response::Update(state) => state,
+            // e.g., a response lost earlier, continue where the target is
+            Response::UpdateError(UpdateError::UnexpectedOffset) => {
+                update_request(target, Update::Status)?
+            }
+            Response::UpdateError(e) => return Err(Error::other(format!("update failed {:?}", e))),
+            r => return Err(unexpected(r)),
+        };
+    }
+    progress(size, size);
+
+    update_request(target, Update::Verify)?;
+    match update_request(target, Update::Commit)? {
+        UpdateState::Committed { version: v } if v == version => Ok(version),
+        s => Err(Error::other(format!("unexpected state {:?}", s))),
+    }
+}
+
+/// Fails every request after `left`, like a cable pulled mid-update
+#[cfg(test)]
+struct Flaky<'a> {
+    target: &'a mut crate::sim::Simulator,
+    left: usize,
+}
+
+#[cfg(test)]
+impl Target for Flaky<'_> {
+    fn request(&mut self, cmd: &Command) -> Result<Response> {
+        if self.left == 0 {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.left -= 1;
+        self.target.request(cmd)
+    }
+}
+
+#[cfg(test)]
+fn packed(payload: &[u8], version: u32) -> Vec<u8> {
+    let key = crate::pack::signing_key(&crate::sim::SIGNING_KEY).unwrap();
+    crate::pack::pack(payload, version, &key).unwrap()
+}
+
+#[test]
+fn update_simulator() {
+    use crate::sim::{Simulator, UpdateSlot};
+
+    let payload: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+    let image = packed(&payload, 5);
+    let mut sim = Simulator::new();
+    let mut sent = vec![];
+    assert_eq!(
+        update(&mut sim, &image, |offset, _| sent.push(offset)).unwrap(),
+        5
+    );
+    assert_eq!(sent.len(), image.len().div_ceil(MAX_BYTES) + 1);
+    assert_eq!(sim.update.0.state(), UpdateState::Committed { version: 5 });
+
+    // interrupted after a few chunks, the target resets, then run again
+    let image = packed(&payload, 6);
+    let mut sim = Simulator::new();
+    let mut flaky = Flaky {
+        target: &mut sim,
+        left: 10,
+    };
+    assert!(update(&mut flaky, &image, |_, _| {}).is_err());
+    sim.update = UpdateSlot::new(sim.update.0.storage.clone());
+    assert_eq!(
+        sim.update.0.state(),
+        UpdateState::Receiving {
+            offset: 9 * MAX_BYTES as u32
+        }
+    );
+    let mut first = None;
+    update(&mut sim, &image, |offset, _| {
+        first.get_or_insert(offset);
+    })
+    .unwrap();
+    assert_eq!(first, Some(9 * MAX_BYTES as u32));
+    assert_eq!(sim.update.0.state(), UpdateState::Committed { version: 6 });
+}
+
+#[test]
+fn rejected_by_the_simulator() {
+    use crate::sim::{Simulator, VERSION};
+
+    let payload = [0x42u8; 100];
+    // older than the running version
+    let mut sim = Simulator::new();
+    let e = update(&mut sim, &packed(&payload, VERSION - 1), |_, _| {}).unwrap_err();
+    assert!(e.to_string().contains("Rollback"));
+
+    // signed with another key
+    let key = crate::pack::signing_key(&[1; 32]).unwrap();
+    let image = crate::pack::pack(&payload, VERSION, &key).unwrap();
+    let e = update(&mut sim, &image, |_, _| {}).unwrap_err();
+    assert!(e.to_string().contains("Signature"));
+    assert_eq!(sim.update.0.state(), UpdateState::Idle);
+
+    // not packed
+    assert!(update(&mut sim, &payload, |_, _| {}).is_err());
+}
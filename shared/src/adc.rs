@@ -0,0 +1,156 @@
+//! ADC capture streamed in blocks
+//!
+//! `Command::StartCapture` starts sampling a channel, the target then sends
+//! `Response::Samples` blocks until the requested number of samples is sent.
+//! Blocks carry a sequence number and the time of their first sample, so the
+//! host can detect lost blocks.
+
+use serde_derive::{Deserialize, Serialize};
+
+/// ADC1 channels 0..=4 (GPIO0..=GPIO4)
+pub const CHANNELS: u8 = 5;
+
+/// Continuous mode limit of the ESP32-C3 ADC
+pub const MAX_RATE_HZ: u32 = 100_000;
+
+/// 32 bytes of samples per block (serde arrays are limited to 32 elements)
+pub const SAMPLES_PER_BLOCK: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CaptureError {
+    InvalidChannel,
+    RateOutOfRange,
+    NoSamples,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SampleBlock {
+    /// Counts from 0 for each capture, wraps
+    pub seq: u16,
+    /// Time of the first sample since the capture started
+    pub timestamp_us: u64,
+    len: u8,
+    samples: [u16; SAMPLES_PER_BLOCK],
+}
+
+impl SampleBlock {
+    pub fn samples(&self) -> &[u16] {
+        &self.samples[..(self.len as usize).min(SAMPLES_PER_BLOCK)]
+    }
+}
+
+/// Validate a capture request
+pub fn validate(channel: u8, rate_hz: u32, samples: u32) -> Result<(), CaptureError> {
+    if channel >= CHANNELS {
+        Err(CaptureError::InvalidChannel)
+    } else if !(1..=MAX_RATE_HZ).contains(&rate_hz) {
+        Err(CaptureError::RateOutOfRange)
+    } else if samples == 0 {
+        Err(CaptureError::NoSamples)
+    } else {
+        Ok(())
+    }
+}
+
+/// Collects samples into blocks
+#[derive(Debug, Clone)]
+pub struct Capture {
+    pub channel: u8,
+    pub rate_hz: u32,
+    remaining: u32,
+    taken: u64,
+    block: SampleBlock,
+}
+
+impl Capture {
+    pub fn new(channel: u8, rate_hz: u32, samples: u32) -> Result<Self, CaptureError> {
+        validate(channel, rate_hz, samples)?;
+        Ok(Self {
+            channel,
+            rate_hz,
+            remaining: samples,
+            taken: 0,
+            block: SampleBlock {
+                seq: 0,
+                timestamp_us: 0,
+                len: 0,
+                samples: [0; SAMPLES_PER_BLOCK],
+            },
+        })
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Add the next sample, returns a block when full or on the last sample
+    pub fn push(&mut self, sample: u16) -> Option<SampleBlock> {
+        if self.is_done() {
+            return None;
+        }
+        if self.block.len == 0 {
+            self.block.timestamp_us = self.taken * 1_000_000 / self.rate_hz as u64;
+        }
+        self.block.samples[self.block.len as usize] = sample;
+        self.block.len += 1;
+        self.taken += 1;
+        self.remaining -= 1;
+
+        if self.block.len as usize == SAMPLES_PER_BLOCK || self.is_done() {
+            let block = self.block;
+            self.block.seq = self.block.seq.wrapping_add(1);
+            self.block.len = 0;
+            Some(block)
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn validation() {
+    assert_eq!(
+        Capture::new(5, 1000, 10).err(),
+        Some(CaptureError::InvalidChannel)
+    );
+    assert_eq!(
+        Capture::new(0, 0, 10).err(),
+        Some(CaptureError::RateOutOfRange)
+    );
+    assert_eq!(
+        Capture::new(0, 200_000, 10).err(),
+        Some(CaptureError::RateOutOfRange)
+    );
+    assert_eq!(
+        Capture::new(0, 1000, 0).err(),
+        Some(CaptureError::NoSamples)
+    );
+}
+
+#[test]
+fn blocks() {
+    let mut capture = Capture::new(0, 1000, 40).unwrap();
+    let blocks: Vec<_> = (0..50).filter_map(|i| capture.push(i)).collect();
+    assert!(capture.is_done());
+
+    assert_eq!(
+        blocks
+            .iter()
+            .map(|b| (b.seq, b.timestamp_us, b.samples().len()))
+            .collect::<Vec<_>>(),
+        [(0, 0, 16), (1, 16_000, 16), (2, 32_000, 8)]
+    );
+    let samples: Vec<u16> = blocks.iter().flat_map(|b| b.samples()).copied().collect();
+    assert_eq!(samples, (0..40).collect::<Vec<_>>());
+}
+
+#[test]
+fn serialize_block() {
+    let mut capture = Capture::new(1, 48_000, 2).unwrap();
+    capture.push(1);
+    let block = capture.push(0xfff).unwrap();
+    let mut buf = [0u8; 64];
+    let n = ssmarshal::serialize(&mut buf, &block).unwrap();
+    let (back, _) = ssmarshal::deserialize::<SampleBlock>(&buf[..n]).unwrap();
+    assert_eq!(back.samples(), [1, 0xfff]);
+}
@@ -0,0 +1,355 @@
+//! Smart LED animation engine
+//!
+//! Effects over N pixels, rendered to `[RGB8; N]` frames from a time input (in
+//! milliseconds), with gamma correction and brightness applied. The firmware
+//! pushes the frames to a `SmartLedsAdapter`, the host can preview them in a
+//! terminal.
+
+use core::f32::consts::PI;
+use libm::cosf;
+pub use rgb::RGB8;
+
+const BLACK: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    Solid(RGB8),
+    /// Hue cycle, spread over the pixels
+    Rainbow {
+        period_ms: u32,
+    },
+    /// Brightness swells and fades
+    Breathing {
+        color: RGB8,
+        period_ms: u32,
+    },
+    /// A lit segment running along the strip, one pixel per step
+    Chase {
+        color: RGB8,
+        width: u8,
+        step_ms: u32,
+    },
+    /// Flickering flames, rising from pixel 0
+    Fire,
+    /// Back and forth between two colors
+    Fade {
+        from: RGB8,
+        to: RGB8,
+        period_ms: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Animation<const N: usize> {
+    effect: Effect,
+    brightness: u8,
+    gamma: bool,
+    // Fire state
+    heat: [u8; N],
+    rng: u32,
+    last_step: Option<u64>,
+}
+
+const FIRE_STEP_MS: u64 = 30;
+
+/// Upper bound of the random cooling per step, more for shorter strips
+const fn max_cooling(n: usize) -> u8 {
+    let cooling = 55 * 10 / n as u32 + 2;
+    if cooling > 255 {
+        255
+    } else {
+        cooling as u8
+    }
+}
+
+impl<const N: usize> Animation<N> {
+    /// Full brightness, with gamma correction, `N` must not be 0
+    pub fn new(effect: Effect) -> Self {
+        // the effects divide by N
+        const { assert!(N > 0, "an animation needs at least one pixel") };
+        Self {
+            effect,
+            brightness: 255,
+            gamma: true,
+            heat: [0; N],
+            rng: 0x2545_f491,
+            last_step: None,
+        }
+    }
+
+    pub fn with_brightness(self, brightness: u8) -> Self {
+        Self { brightness, ..self }
+    }
+
+    pub fn with_gamma(self, gamma: bool) -> Self {
+        Self { gamma, ..self }
+    }
+
+    pub fn effect(&self) -> Effect {
+        self.effect
+    }
+
+    pub fn set_effect(&mut self, effect: Effect) {
+        *self = Self::new(effect)
+            .with_brightness(self.brightness)
+            .with_gamma(self.gamma);
+    }
+
+    /// Frame at time `t` (ms), gamma and brightness applied
+    pub fn frame(&mut self, t: u64) -> [RGB8; N] {
+        let mut frame = self.raw_frame(t);
+        for px in frame.iter_mut() {
+            let c = |c: u8| {
+                let c = if self.gamma { GAMMA8[c as usize] } else { c };
+                (c as u16 * (self.brightness as u16 + 1) / 256) as u8
+            };
+            *px = RGB8::new(c(px.r), c(px.g), c(px.b));
+        }
+        frame
+    }
+
+    fn raw_frame(&mut self, t: u64) -> [RGB8; N] {
+        match self.effect {
+            Effect::Solid(color) => [color; N],
+            Effect::Rainbow { period_ms } => {
+                let hue = phase(t, period_ms);
+                core::array::from_fn(|i| hsv2rgb(hue.wrapping_add((i * 256 / N) as u8), 255, 255))
+            }
+            Effect::Breathing { color, period_ms } => {
+                // raised cosine, starts dark
+                let x = phase(t, period_ms) as f32 / 256.0;
+                let level = ((1.0 - cosf(2.0 * PI * x)) / 2.0 * 255.0) as u8;
+                [scale(color, level); N]
+            }
+            Effect::Chase {
+                color,
+                width,
+                step_ms,
+            } => {
+                let head = (t / step_ms.max(1) as u64 % N as u64) as usize;
+                core::array::from_fn(|i| {
+                    // distance behind the head, wrapping around the strip
+                    let behind = (head + N - i) % N;
+                    if behind < width as usize {
+                        color
+                    } else {
+                        BLACK
+                    }
+                })
+            }
+            Effect::Fire => {
+                self.fire(t);
+                self.heat.map(heat_color)
+            }
+            Effect::Fade {
+                from,
+                to,
+                period_ms,
+            } => {
+                // triangle 0..=255..0 over the period
+                let p = phase(t, period_ms) as u16 * 2;
+                let x = if p < 256 { p } else { 511 - p } as u8;
+                [lerp(from, to, x); N]
+            }
+        }
+    }
+
+    // Advance the flame simulation to `t`, in fixed steps
+    fn fire(&mut self, t: u64) {
+        let steps = match self.last_step {
+            None => 1,
+            Some(last) => ((t.saturating_sub(last)) / FIRE_STEP_MS).min(N as u64 + 1),
+        };
+        if steps > 0 {
+            self.last_step = Some(t - t % FIRE_STEP_MS);
+        }
+        for _ in 0..steps {
+            // cool down
+            for i in 0..N {
+                let cooling = self.random(0, max_cooling(N));
+                self.heat[i] = self.heat[i].saturating_sub(cooling);
+            }
+            // heat drifts up
+            for i in (2..N).rev() {
+                self.heat[i] = ((self.heat[i - 1] as u16 + 2 * self.heat[i - 2] as u16) / 3) as u8;
+            }
+            // new sparks near the bottom
+            if N > 0 && self.random(0, 255) < 120 {
+                let i = self.random(0, (N.min(7)) as u8) as usize;
+                let spark = self.random(160, 255);
+                self.heat[i] = self.heat[i].saturating_add(spark);
+            }
+        }
+    }
+
+    // xorshift32, uniform in [lo, hi)
+    fn random(&mut self, lo: u8, hi: u8) -> u8 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        if hi <= lo {
+            lo
+        } else {
+            lo + (self.rng % (hi - lo) as u32) as u8
+        }
+    }
+}
+
+// Position within the period as 0..=255
+fn phase(t: u64, period_ms: u32) -> u8 {
+    let period = period_ms.max(1) as u64;
+    ((t % period) * 256 / period) as u8
+}
+
+fn scale(c: RGB8, level: u8) -> RGB8 {
+    let s = |c: u8| (c as u16 * level as u16 / 255) as u8;
+    RGB8::new(s(c.r), s(c.g), s(c.b))
+}
+
+fn lerp(from: RGB8, to: RGB8, x: u8) -> RGB8 {
+    let l = |a: u8, b: u8| (a as i32 + (b as i32 - a as i32) * x as i32 / 255) as u8;
+    RGB8::new(l(from.r, to.r), l(from.g, to.g), l(from.b, to.b))
+}
+
+// Black body-ish palette, black -> red -> yellow -> white
+fn heat_color(heat: u8) -> RGB8 {
+    let t = (heat as u16 * 191 / 255) as u8; // 0..=191
+    let ramp = (t & 0x3f) << 2;
+    match t {
+        0..=63 => RGB8::new(ramp, 0, 0),
+        64..=127 => RGB8::new(255, ramp, 0),
+        _ => RGB8::new(255, 255, ramp),
+    }
+}
+
+/// HSV (all 0..=255) to RGB, same as `smart_leds::hsv::hsv2rgb`
+pub fn hsv2rgb(hue: u8, sat: u8, val: u8) -> RGB8 {
+    let (v, s) = (val as u16, sat as u16);
+    let f = (hue as u16 * 2 % 85) * 3; // position within the sextant
+
+    let p = (v * (255 - s) / 255) as u8;
+    let q = (v * (255 - (s * f) / 255) / 255) as u8;
+    let t = (v * (255 - (s * (255 - f)) / 255) / 255) as u8;
+    let v = v as u8;
+    match hue {
+        0..=42 => RGB8::new(v, t, p),
+        43..=84 => RGB8::new(q, v, p),
+        85..=127 => RGB8::new(p, v, t),
+        128..=169 => RGB8::new(p, q, v),
+        170..=212 => RGB8::new(t, p, v),
+        213..=254 => RGB8::new(v, p, q),
+        255 => RGB8::new(v, t, p),
+    }
+}
+
+/// Gamma 2.8 correction, same table as `smart_leds::gamma`
+pub const GAMMA8: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 5, 5, 5,
+    5, 6, 6, 6, 6, 7, 7, 7, 7, 8, 8, 8, 9, 9, 9, 10, 10, 10, 11, 11, 11, 12, 12, 13, 13, 13, 14,
+    14, 15, 15, 16, 16, 17, 17, 18, 18, 19, 19, 20, 20, 21, 21, 22, 22, 23, 24, 24, 25, 25, 26, 27,
+    27, 28, 29, 29, 30, 31, 32, 32, 33, 34, 35, 35, 36, 37, 38, 39, 39, 40, 41, 42, 43, 44, 45, 46,
+    47, 48, 49, 50, 50, 51, 52, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 66, 67, 68, 69, 70, 72,
+    73, 74, 75, 77, 78, 79, 81, 82, 83, 85, 86, 87, 89, 90, 92, 93, 95, 96, 98, 99, 101, 102, 104,
+    105, 107, 109, 110, 112, 114, 115, 117, 119, 120, 122, 124, 126, 127, 129, 131, 133, 135, 137,
+    138, 140, 142, 144, 146, 148, 150, 152, 154, 156, 158, 160, 162, 164, 167, 169, 171, 173, 175,
+    177, 180, 182, 184, 186, 189, 191, 193, 196, 198, 200, 203, 205, 208, 210, 213, 215, 218, 220,
+    223, 225, 228, 231, 233, 236, 239, 241, 244, 247, 249, 252, 255,
+];
+
+#[cfg(test)]
+const RED: RGB8 = RGB8 { r: 255, g: 0, b: 0 };
+#[cfg(test)]
+const BLUE: RGB8 = RGB8 { r: 0, g: 0, b: 255 };
+
+#[test]
+fn solid_with_brightness_and_gamma() {
+    let mut a = Animation::<3>::new(Effect::Solid(RGB8::new(255, 128, 0)));
+    assert_eq!(a.frame(0), [RGB8::new(255, 37, 0); 3]);
+
+    let mut a = a.with_brightness(10);
+    assert_eq!(a.frame(0), [RGB8::new(10, 1, 0); 3]);
+
+    let mut a = a.with_gamma(false);
+    assert_eq!(a.frame(0), [RGB8::new(10, 5, 0); 3]);
+}
+
+#[test]
+fn rainbow_spreads_hue() {
+    let mut a = Animation::<3>::new(Effect::Rainbow { period_ms: 1000 }).with_gamma(false);
+    let frame = a.frame(0);
+    assert_eq!(frame[0], RED);
+    assert_eq!(frame[1], hsv2rgb(85, 255, 255));
+    assert_eq!(frame[2], hsv2rgb(170, 255, 255));
+    // one period later we are back at the start
+    assert_eq!(a.frame(1000), frame);
+    assert_ne!(a.frame(500), frame);
+}
+
+#[test]
+fn breathing() {
+    let mut a = Animation::<1>::new(Effect::Breathing {
+        color: BLUE,
+        period_ms: 2000,
+    })
+    .with_gamma(false);
+    assert_eq!(a.frame(0), [RGB8::new(0, 0, 0)]);
+    assert_eq!(a.frame(1000), [BLUE]);
+    assert_eq!(a.frame(2000), [RGB8::new(0, 0, 0)]);
+}
+
+#[test]
+fn chase_wraps_around() {
+    let mut a = Animation::<4>::new(Effect::Chase {
+        color: RED,
+        width: 2,
+        step_ms: 100,
+    });
+    assert_eq!(a.frame(0), [RED, BLACK, BLACK, RED]);
+    assert_eq!(a.frame(100), [RED, RED, BLACK, BLACK]);
+    assert_eq!(a.frame(350), [BLACK, BLACK, RED, RED]);
+}
+
+#[test]
+fn fade_between() {
+    let mut a = Animation::<2>::new(Effect::Fade {
+        from: RED,
+        to: BLUE,
+        period_ms: 1000,
+    })
+    .with_gamma(false);
+    assert_eq!(a.frame(0), [RED; 2]);
+    assert_eq!(a.frame(250), [RGB8::new(127, 0, 128); 2]);
+    assert_eq!(a.frame(500), [BLUE; 2]);
+    assert_eq!(a.frame(1000), [RED; 2]);
+}
+
+#[test]
+fn fire_is_deterministic_and_warm() {
+    let mut a = Animation::<8>::new(Effect::Fire);
+    let mut b = Animation::<8>::new(Effect::Fire);
+    let mut lit = 0;
+    for t in (0..3000).step_by(20) {
+        let frame = a.frame(t);
+        assert_eq!(frame, b.frame(t));
+        // no blue without full red and green
+        assert!(frame.iter().all(|c| c.b == 0 || (c.r == 255 && c.g == 255)));
+        lit += frame.iter().filter(|c| **c != BLACK).count();
+    }
+    assert!(lit > 0);
+}
+
+#[test]
+fn cooling_saturates() {
+    assert_eq!(max_cooling(1), 255);
+    assert_eq!(max_cooling(3), 185);
+    assert_eq!(max_cooling(60), 11);
+}
+
+#[test]
+fn hsv_primaries() {
+    assert_eq!(hsv2rgb(0, 255, 255), RED);
+    assert_eq!(hsv2rgb(85, 255, 255), RGB8::new(0, 255, 0));
+    assert_eq!(hsv2rgb(170, 255, 255), BLUE);
+    assert_eq!(hsv2rgb(42, 0, 100), RGB8::new(100, 100, 100));
+}
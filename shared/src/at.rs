@@ -0,0 +1,459 @@
+//! AT command line
+//!
+//! A human friendly alternative to the binary protocol, for driving a device
+//! from a terminal (e.g. minicom):
+//!
+//! ```text
+//! AT               OK
+//! ATE0 / ATE1      echo off / on
+//! AT+SET=0x12,12   Command::Set(0x12, Message::B(12), 0), 1.5 gives Message::C,
+//!                  no value Message::A, an optional third field is the DevId
+//! AT+GET=0x12      Command::Get(0x12, 0, 0), optional Parameter and DevId
+//! AT+TIME?         +TIME: 2023-10-16T07:30:15.000000000Z
+//! AT+VER?          +VER: 0.1.0
+//! AT+INFO?         Command::GetInfo, a +INFO: line per field
+//! ```
+//!
+//! Lines end with CR (LF is ignored), backspace and delete erase the last
+//! character. Every command is answered by `OK` or `ERROR`.
+
+use crate::date_time::UtcDateTime;
+use crate::{Command, DevId, Id, Message, Parameter, Response};
+use core::fmt::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AtCommand {
+    /// Plain `AT`
+    Attention,
+    Echo(bool),
+    Command(Command),
+    Time,
+    Version,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtError {
+    /// Not starting with `AT`
+    NotAt,
+    Unknown,
+    /// Missing or malformed argument
+    Argument,
+}
+
+fn number(s: &str) -> Result<u32, AtError> {
+    let s = s.trim();
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| AtError::Argument)
+}
+
+/// Field `i` of the arguments, 0 if absent
+fn field(args: &[&str], i: usize) -> Result<u32, AtError> {
+    args.get(i).map_or(Ok(0), |s| number(s))
+}
+
+/// Parse a command line (without the line ending), case insensitive
+pub fn parse(line: &str) -> Result<AtCommand, AtError> {
+    let line = line.trim();
+    if !line
+        .as_bytes()
+        .get(..2)
+        .is_some_and(|p| p.eq_ignore_ascii_case(b"AT"))
+    {
+        return Err(AtError::NotAt);
+    }
+    // "AT" is ASCII, so 2 is a char boundary
+    let rest = line.get(2..).ok_or(AtError::NotAt)?;
+    let (name, args) = match rest.split_once('=') {
+        Some((name, args)) => (name, Some(args)),
+        None => (rest, None),
+    };
+
+    let mut fields = [""; 3];
+    let mut n = 0;
+    if let Some(args) = args {
+        for (f, a) in fields.iter_mut().zip(args.split(',')) {
+            *f = a;
+            n += 1;
+        }
+        if args.split(',').count() > fields.len() {
+            return Err(AtError::Argument);
+        }
+    }
+    let fields = &fields[..n];
+
+    let is = |s: &str| name.eq_ignore_ascii_case(s);
+    match args {
+        None if name.is_empty() => Ok(AtCommand::Attention),
+        None if is("E0") => Ok(AtCommand::Echo(false)),
+        None if is("E1") => Ok(AtCommand::Echo(true)),
+        None if is("+TIME?") => Ok(AtCommand::Time),
+        None if is("+VER?") => Ok(AtCommand::Version),
+        None if is("+INFO?") => Ok(AtCommand::Command(Command::GetInfo)),
+        Some(_) if is("+SET") => {
+            let id: Id = number(fields.first().ok_or(AtError::Argument)?)?;
+            let msg = match fields.get(1).map(|s| s.trim()) {
+                None | Some("") => Message::A,
+                Some(v) if v.contains('.') => Message::C(v.parse().map_err(|_| AtError::Argument)?),
+                Some(v) => Message::B(number(v)?),
+            };
+            let dev: DevId = field(fields, 2)?;
+            Ok(AtCommand::Command(Command::Set(id, msg, dev)))
+        }
+        Some(_) if is("+GET") => {
+            let id: Id = number(fields.first().ok_or(AtError::Argument)?)?;
+            let par: Parameter = field(fields, 1)?;
+            let dev: DevId = field(fields, 2)?;
+            Ok(AtCommand::Command(Command::Get(id, par, dev)))
+        }
+        _ => Err(AtError::Unknown),
+    }
+}
+
+/// What an `AtShell` needs from the application
+pub trait Handler {
+    /// The same handler as for the binary protocol
+    fn command(&mut self, cmd: Command) -> Response;
+    /// None if the clock is not set
+    fn time(&mut self) -> Option<UtcDateTime>;
+    fn version(&self) -> &str;
+}
+
+/// Starts every line after the first with `+INFO: `
+struct InfoLines<'a, W>(&'a mut W);
+
+impl<W: Write> Write for InfoLines<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut lines = s.split('\n');
+        self.0.write_str(lines.next().unwrap_or(""))?;
+        for line in lines {
+            self.0.write_str("\r\n+INFO: ")?;
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Text for a response, without the final result code
+/// returns false for responses meaning `ERROR`
+pub fn write_response(w: &mut impl Write, response: &Response) -> Result<bool, fmt::Error> {
+    match response {
+        Response::Data(id, par, value, dev) => {
+            write!(w, "+DATA: 0x{:x},{},{},{}\r\n", id, par, value, dev)?;
+            Ok(true)
+        }
+        Response::Info(info) => {
+            w.write_str("+INFO: ")?;
+            write!(InfoLines(w), "{}", info)?;
+            w.write_str("\r\n")?;
+            Ok(true)
+        }
+        Response::SetOk => Ok(true),
+        Response::ParseError => Ok(false),
+        Response::Scheduled(_)
+        | Response::Level(..)
+        | Response::PinEdge(..)
+        | Response::Samples(_)
+        | Response::BusData(_)
+        | Response::Update(_)
+        | Response::CrashReport(_) => {
+            write!(w, "+RESPONSE: {:?}\r\n", response)?;
+            Ok(true)
+        }
+        Response::ScheduleError(_)
+        | Response::GpioError(_)
+        | Response::PwmError(_)
+        | Response::CaptureError(_)
+        | Response::BusError(_)
+        | Response::UpdateError(_)
+        | Response::KvError(_)
+        | Response::PatternError(_) => {
+            write!(w, "+RESPONSE: {:?}\r\n", response)?;
+            Ok(false)
+        }
+    }
+}
+
+/// Line editing and command dispatch on a byte stream
+#[derive(Debug, Clone)]
+pub struct AtShell<const N: usize> {
+    line: [u8; N],
+    len: usize,
+    overflow: bool,
+    echo: bool,
+}
+
+impl<const N: usize> Default for AtShell<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> AtShell<N> {
+    /// Echo is on, like most modems
+    pub const fn new() -> Self {
+        Self {
+            line: [0; N],
+            len: 0,
+            overflow: false,
+            echo: true,
+        }
+    }
+
+    pub fn echo(&self) -> bool {
+        self.echo
+    }
+
+    /// Feed a received byte, the echo and any answer are written to `out`
+    pub fn push(
+        &mut self,
+        byte: u8,
+        handler: &mut impl Handler,
+        out: &mut impl Write,
+    ) -> fmt::Result {
+        match byte {
+            b'\r' => {
+                if self.echo {
+                    out.write_str("\r\n")?;
+                }
+                let len = core::mem::take(&mut self.len);
+                if core::mem::take(&mut self.overflow) {
+                    return out.write_str("ERROR\r\n");
+                }
+                let line = self.line;
+                self.line(&line[..len], handler, out)
+            }
+            b'\n' => Ok(()),
+            0x08 | 0x7f => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    if self.echo {
+                        out.write_str("\x08 \x08")?;
+                    }
+                }
+                Ok(())
+            }
+            _ => {
+                match self.line.get_mut(self.len) {
+                    Some(b) => {
+                        *b = byte;
+                        self.len += 1;
+                    }
+                    None => self.overflow = true,
+                }
+                if self.echo && byte.is_ascii() && !byte.is_ascii_control() {
+                    out.write_char(byte as char)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Run a complete line, already edited and without the line ending
+    pub fn line(
+        &mut self,
+        line: &[u8],
+        handler: &mut impl Handler,
+        out: &mut impl Write,
+    ) -> fmt::Result {
+        let result = core::str::from_utf8(line)
+            .map_err(|_| AtError::Unknown)
+            .and_then(parse);
+        match result {
+            // empty lines are ignored
+            Err(AtError::NotAt) if line.is_empty() => Ok(()),
+            Ok(cmd) => self.execute(cmd, handler, out),
+            Err(_) => out.write_str("ERROR\r\n"),
+        }
+    }
+
+    fn execute(
+        &mut self,
+        cmd: AtCommand,
+        handler: &mut impl Handler,
+        out: &mut impl Write,
+    ) -> fmt::Result {
+        let ok = match cmd {
+            AtCommand::Attention => true,
+            AtCommand::Echo(on) => {
+                self.echo = on;
+                true
+            }
+            AtCommand::Command(cmd) => write_response(out, &handler.command(cmd))?,
+            AtCommand::Time => match handler.time() {
+                Some(t) => {
+                    write!(out, "+TIME: {}\r\n", t)?;
+                    true
+                }
+                None => false,
+            },
+            AtCommand::Version => {
+                write!(out, "+VER: {}\r\n", handler.version())?;
+                true
+            }
+        };
+        out.write_str(if ok { "OK\r\n" } else { "ERROR\r\n" })
+    }
+}
+
+#[test]
+fn parse_commands() {
+    assert_eq!(parse("AT"), Ok(AtCommand::Attention));
+    assert_eq!(parse("ate0"), Ok(AtCommand::Echo(false)));
+    assert_eq!(parse("AT+TIME?"), Ok(AtCommand::Time));
+    assert_eq!(parse("at+ver?"), Ok(AtCommand::Version));
+    assert_eq!(parse("AT+INFO?"), Ok(AtCommand::Command(Command::GetInfo)));
+    assert_eq!(
+        parse("AT+SET=0x12,12"),
+        Ok(AtCommand::Command(Command::Set(0x12, Message::B(12), 0)))
+    );
+    assert_eq!(parse("AT+SET=18, 1.5, 0b1"), Err(AtError::Argument));
+    assert_eq!(
+        parse("AT+SET=18, 1.5, 2"),
+        Ok(AtCommand::Command(Command::Set(18, Message::C(1.5), 2)))
+    );
+    assert_eq!(
+        parse("AT+SET=0x12"),
+        Ok(AtCommand::Command(Command::Set(0x12, Message::A, 0)))
+    );
+    assert_eq!(
+        parse("AT+GET=0x12"),
+        Ok(AtCommand::Command(Command::Get(0x12, 0, 0)))
+    );
+    assert_eq!(
+        parse("AT+GET=0x12,12,1"),
+        Ok(AtCommand::Command(Command::Get(0x12, 12, 1)))
+    );
+
+    assert_eq!(parse("hello"), Err(AtError::NotAt));
+    assert_eq!(parse("AT+FOO"), Err(AtError::Unknown));
+    assert_eq!(parse("AT+GET"), Err(AtError::Unknown));
+    assert_eq!(parse("AT+GET="), Err(AtError::Argument));
+    assert_eq!(parse("AT+GET=1,2,3,4"), Err(AtError::Argument));
+    assert_eq!(parse("AT+SET=0xzz,1"), Err(AtError::Argument));
+}
+
+#[cfg(test)]
+struct TestHandler {
+    value: u32,
+}
+
+#[cfg(test)]
+impl Handler for TestHandler {
+    fn command(&mut self, cmd: Command) -> Response {
+        match cmd {
+            Command::Set(_, Message::B(v), _) => {
+                self.value = v;
+                Response::SetOk
+            }
+            Command::Get(id, par, dev) => Response::Data(id, par, self.value, dev),
+            _ => Response::ParseError,
+        }
+    }
+
+    fn time(&mut self) -> Option<UtcDateTime> {
+        Some(UtcDateTime::from_timestamp_nanos(1_697_441_415_000_000_000))
+    }
+
+    fn version(&self) -> &str {
+        "0.1.0"
+    }
+}
+
+#[cfg(test)]
+fn session(shell: &mut AtShell<32>, handler: &mut TestHandler, input: &[u8]) -> String {
+    let mut out = String::new();
+    for b in input {
+        shell.push(*b, handler, &mut out).unwrap();
+    }
+    out
+}
+
+#[test]
+fn shell() {
+    let mut shell = AtShell::<32>::new();
+    let mut handler = TestHandler { value: 0 };
+
+    assert_eq!(
+        session(&mut shell, &mut handler, b"AT+SET=0x12,12\r\n"),
+        "AT+SET=0x12,12\r\nOK\r\n"
+    );
+    assert_eq!(handler.value, 12);
+
+    // echo off, typo fixed with backspace
+    assert_eq!(
+        session(&mut shell, &mut handler, b"ATE0\r"),
+        "ATE0\r\nOK\r\n"
+    );
+    assert_eq!(
+        session(&mut shell, &mut handler, b"AT+GEX\x7fT=0x12\r"),
+        "+DATA: 0x12,0,12,0\r\nOK\r\n"
+    );
+    assert_eq!(
+        session(&mut shell, &mut handler, b"AT+TIME?\r"),
+        "+TIME: 2023-10-16T07:30:15.000000000Z\r\nOK\r\n"
+    );
+    assert_eq!(
+        session(&mut shell, &mut handler, b"AT+VER?\r"),
+        "+VER: 0.1.0\r\nOK\r\n"
+    );
+    assert_eq!(session(&mut shell, &mut handler, b"\r\r"), "");
+    assert_eq!(
+        session(&mut shell, &mut handler, b"AT+SET=1,1.5\r"),
+        "ERROR\r\n"
+    );
+    assert_eq!(session(&mut shell, &mut handler, &[b'A'; 40]), "");
+    assert_eq!(
+        session(&mut shell, &mut handler, b"\rAT\r"),
+        "ERROR\r\nOK\r\n"
+    );
+}
+
+#[test]
+fn error_responses() {
+    use crate::update::UpdateError;
+
+    let mut out = String::new();
+    let update = Response::UpdateError(UpdateError::BadCrc);
+    assert_eq!(write_response(&mut out, &update), Ok(false));
+    let kv = Response::KvError(crate::kv::KvError::Full);
+    assert_eq!(write_response(&mut out, &kv), Ok(false));
+    let pattern = Response::PatternError(crate::led_pattern::InvalidPattern);
+    assert_eq!(write_response(&mut out, &pattern), Ok(false));
+    assert_eq!(write_response(&mut out, &Response::Scheduled(1)), Ok(true));
+}
+
+#[test]
+fn non_ascii() {
+    assert_eq!(parse("A€"), Err(AtError::NotAt));
+    assert_eq!(parse("€"), Err(AtError::NotAt));
+    assert_eq!(parse("AT€"), Err(AtError::Unknown));
+}
+
+#[test]
+fn echo_with_backspace() {
+    let mut shell = AtShell::<32>::new();
+    let mut handler = TestHandler { value: 0 };
+    assert_eq!(
+        session(&mut shell, &mut handler, b"AX\x08T\r"),
+        "AX\x08 \x08T\r\nOK\r\n"
+    );
+    // nothing to erase
+    assert_eq!(session(&mut shell, &mut handler, b"\x08"), "");
+}
+
+#[test]
+fn info_lines() {
+    use crate::info::{Info, ResetReason};
+
+    let info = Info::new("0.1.0", [2, 0, 0, 0, 0, 1], 1500, ResetReason::PowerOn);
+    let mut out = String::new();
+    assert_eq!(write_response(&mut out, &Response::Info(info)), Ok(true));
+    let lines: Vec<_> = out.split("\r\n").collect();
+    assert_eq!(lines.len(), 7);
+    assert!(lines[..6].iter().all(|l| l.starts_with("+INFO: ")));
+    assert_eq!(lines[4], "+INFO: uptime   0d 00:00:01.500");
+    assert_eq!(lines[6], "");
+}
@@ -0,0 +1,158 @@
+//! I2C and SPI bridge
+//!
+//! Bus transactions issued by the host and executed by the target, so sensor
+//! drivers can be prototyped without reflashing.
+
+use crate::gpio::{GpioError, Pin, Pins};
+use serde_derive::{Deserialize, Serialize};
+
+/// Bytes per transfer (serde arrays are limited to 32 elements)
+pub const MAX_BYTES: usize = 32;
+
+pub type Address = u8;
+
+/// A short byte string
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bytes {
+    len: u8,
+    data: [u8; MAX_BYTES],
+}
+
+impl Bytes {
+    pub const EMPTY: Self = Self {
+        len: 0,
+        data: [0; MAX_BYTES],
+    };
+
+    /// None if longer than `MAX_BYTES`
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        let mut data = [0; MAX_BYTES];
+        data.get_mut(..bytes.len())?.copy_from_slice(bytes);
+        Some(Self {
+            len: bytes.len() as u8,
+            data,
+        })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..(self.len as usize).min(MAX_BYTES)]
+    }
+}
+
+impl core::fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.as_slice().fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum I2c {
+    Write(Address, Bytes),
+    Read(Address, u8),
+    /// Write then read with a repeated start
+    WriteRead(Address, Bytes, u8),
+}
+
+impl I2c {
+    pub fn address(&self) -> Address {
+        match self {
+            I2c::Write(a, _) | I2c::Read(a, _) | I2c::WriteRead(a, _, _) => *a,
+        }
+    }
+
+    /// Bytes to read
+    pub fn read_len(&self) -> usize {
+        match self {
+            I2c::Write(..) => 0,
+            I2c::Read(_, n) | I2c::WriteRead(_, _, n) => *n as usize,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), BusError> {
+        // 0x00..=0x07 and 0x78..=0x7f are reserved
+        if !(0x08..=0x77).contains(&self.address()) {
+            Err(BusError::InvalidAddress)
+        } else if self.read_len() > MAX_BYTES {
+            Err(BusError::TooLong)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Full duplex transfer, as many bytes are read as written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Spi {
+    /// Chip select, driven low during the transfer
+    pub cs: Option<Pin>,
+    pub data: Bytes,
+    /// Keep chip select low after the transfer, for transactions longer than `MAX_BYTES`
+    pub keep_cs: bool,
+}
+
+impl Spi {
+    pub fn validate(&self) -> Result<(), BusError> {
+        match self.cs {
+            Some(pin) => Pins::validate(pin).map_err(BusError::Gpio),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Nack {
+    Address,
+    Data,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BusError {
+    Nack(Nack),
+    ArbitrationLoss,
+    Timeout,
+    /// Reserved I2C address
+    InvalidAddress,
+    /// More than `MAX_BYTES`
+    TooLong,
+    Gpio(GpioError),
+}
+
+#[test]
+fn bytes() {
+    let bytes = Bytes::new(&[1, 2, 3]).unwrap();
+    assert_eq!(bytes.as_slice(), [1, 2, 3]);
+    assert_eq!(format!("{:?}", bytes), "[1, 2, 3]");
+    assert!(Bytes::new(&[0; MAX_BYTES]).is_some());
+    assert!(Bytes::new(&[0; MAX_BYTES + 1]).is_none());
+
+    let mut buf = [0u8; 64];
+    let n = ssmarshal::serialize(&mut buf, &I2c::WriteRead(0x48, bytes, 2)).unwrap();
+    let (back, _) = ssmarshal::deserialize::<I2c>(&buf[..n]).unwrap();
+    assert_eq!(back, I2c::WriteRead(0x48, bytes, 2));
+}
+
+#[test]
+fn validation() {
+    assert_eq!(I2c::Read(0x48, 2).validate(), Ok(()));
+    assert_eq!(I2c::Read(0x03, 2).validate(), Err(BusError::InvalidAddress));
+    assert_eq!(
+        I2c::Write(0x78, Bytes::EMPTY).validate(),
+        Err(BusError::InvalidAddress)
+    );
+    assert_eq!(I2c::Read(0x48, 33).validate(), Err(BusError::TooLong));
+
+    let spi = Spi {
+        cs: Some(10),
+        data: Bytes::EMPTY,
+        keep_cs: false,
+    };
+    assert_eq!(spi.validate(), Ok(()));
+    assert_eq!(
+        Spi {
+            cs: Some(16),
+            ..spi
+        }
+        .validate(),
+        Err(BusError::Gpio(GpioError::Reserved))
+    );
+}
@@ -0,0 +1,348 @@
+//! Button debounce and gesture recognition
+//!
+//! Pure state machines driven by raw edges and timestamps (in milliseconds from
+//! any monotonic source). The firmware feeds edges from the GPIO interrupt and
+//! calls `poll` until it returns None, on every edge and by `next_deadline`.
+
+/// Debounced level changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    Press,
+    Release,
+}
+
+/// Accepts a level change once the raw input has been stable for `debounce_ms`
+#[derive(Debug, Clone, Copy)]
+pub struct Debouncer {
+    debounce_ms: u64,
+    pressed: bool,
+    candidate: Option<(bool, u64)>, // (level, time of last raw edge)
+}
+
+impl Debouncer {
+    pub const fn new(debounce_ms: u64) -> Self {
+        Self {
+            debounce_ms,
+            pressed: false,
+            candidate: None,
+        }
+    }
+
+    /// Raw edge, `pressed` is the level read after the edge
+    pub fn edge(&mut self, pressed: bool, now: u64) {
+        self.candidate = Some((pressed, now));
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.candidate.map(|(_, t)| t + self.debounce_ms)
+    }
+
+    /// Debounced level change, if any
+    pub fn poll(&mut self, now: u64) -> Option<Edge> {
+        let (pressed, t) = self.candidate?;
+        if now < t + self.debounce_ms {
+            return None;
+        }
+        self.candidate = None;
+        if pressed == self.pressed {
+            return None; // bounced back
+        }
+        self.pressed = pressed;
+        Some(if pressed { Edge::Press } else { Edge::Release })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    Press,
+    Release,
+    /// Single short press, reported once the double click window has passed
+    Click,
+    DoubleClick,
+    /// Held for `long_press_ms`, no click is reported on release
+    LongPress,
+    /// Every `repeat_ms` while held after a long press
+    Repeat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    pub debounce_ms: u64,
+    pub long_press_ms: u64,
+    pub double_click_ms: u64,
+    pub repeat_ms: u64,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Self {
+            debounce_ms: 20,
+            long_press_ms: 800,
+            double_click_ms: 300,
+            repeat_ms: 200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    /// Held, `second` if this is the second press of a double click,
+    /// `deadline` for the next long press or repeat
+    Down {
+        second: bool,
+        long: bool,
+        deadline: u64,
+    },
+    /// Released after a short press, waiting for a second press until deadline
+    Up {
+        deadline: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ButtonGestures {
+    timings: Timings,
+    debouncer: Debouncer,
+    state: State,
+    pending: Option<Gesture>,
+}
+
+impl Default for ButtonGestures {
+    fn default() -> Self {
+        Self::new(Timings::default())
+    }
+}
+
+impl ButtonGestures {
+    pub const fn new(timings: Timings) -> Self {
+        Self {
+            timings,
+            debouncer: Debouncer::new(timings.debounce_ms),
+            state: State::Idle,
+            pending: None,
+        }
+    }
+
+    /// Raw edge, `pressed` is the level read after the edge
+    pub fn edge(&mut self, pressed: bool, now: u64) {
+        self.debouncer.edge(pressed, now);
+    }
+
+    /// When `poll` needs to be called next (if no edge comes first)
+    pub fn next_deadline(&self) -> Option<u64> {
+        let state = match self.state {
+            State::Idle => None,
+            State::Down { deadline, .. } | State::Up { deadline } => Some(deadline),
+        };
+        match (self.debouncer.next_deadline(), state) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Next recognized gesture, call repeatedly until None
+    pub fn poll(&mut self, now: u64) -> Option<Gesture> {
+        if let Some(g) = self.pending.take() {
+            return Some(g);
+        }
+        // a deadline passed before the next debounced edge goes first
+        let edge_at = self.debouncer.next_deadline().filter(|t| *t <= now);
+        if let Some(g) = self.timeout(now, edge_at) {
+            return Some(g);
+        }
+        let t = &self.timings;
+        match (self.debouncer.poll(now)?, self.state) {
+            (Edge::Press, State::Idle) => {
+                self.state = State::Down {
+                    second: false,
+                    long: false,
+                    deadline: now + t.long_press_ms,
+                };
+                Some(Gesture::Press)
+            }
+            (Edge::Press, State::Up { .. }) => {
+                self.state = State::Down {
+                    second: true,
+                    long: false,
+                    deadline: now + t.long_press_ms,
+                };
+                Some(Gesture::Press)
+            }
+            (Edge::Release, State::Down { second, long, .. }) => {
+                self.state = State::Idle;
+                if second && !long {
+                    self.pending = Some(Gesture::DoubleClick);
+                } else if !long {
+                    self.state = State::Up {
+                        deadline: now + t.double_click_ms,
+                    };
+                }
+                Some(Gesture::Release)
+            }
+            _ => None, // can't happen, the debouncer alternates
+        }
+    }
+
+    /// Long press, repeat or click for a deadline before `edge_at` (or no edge)
+    fn timeout(&mut self, now: u64, edge_at: Option<u64>) -> Option<Gesture> {
+        let t = &self.timings;
+        let due = |deadline: u64| now >= deadline && edge_at.is_none_or(|e| e > deadline);
+        match self.state {
+            State::Down {
+                second,
+                long,
+                deadline,
+            } if due(deadline) => {
+                self.state = State::Down {
+                    second,
+                    long: true,
+                    deadline: deadline + t.repeat_ms,
+                };
+                Some(if long {
+                    Gesture::Repeat
+                } else {
+                    Gesture::LongPress
+                })
+            }
+            State::Up { deadline } if due(deadline) => {
+                self.state = State::Idle;
+                Some(Gesture::Click)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Run a scripted timeline of raw (time, pressed) edges, polling every ms until `end`
+#[cfg(test)]
+fn run(timings: Timings, edges: &[(u64, bool)], end: u64) -> Vec<(u64, Gesture)> {
+    let mut b = ButtonGestures::new(timings);
+    let mut out = Vec::new();
+    let mut edges = edges.iter().peekable();
+    for now in 0..=end {
+        while let Some((_, pressed)) = edges.next_if(|(t, _)| *t == now) {
+            b.edge(*pressed, now);
+        }
+        while let Some(g) = b.poll(now) {
+            out.push((now, g));
+        }
+    }
+    out
+}
+
+// An edge at `t`, with contact bounce
+#[cfg(test)]
+fn bouncy(t: u64, pressed: bool) -> [(u64, bool); 4] {
+    [
+        (t, pressed),
+        (t + 1, !pressed),
+        (t + 3, pressed),
+        (t + 4, pressed),
+    ]
+}
+
+#[test]
+fn debounce_bouncy_press() {
+    let mut edges = Vec::new();
+    edges.extend(bouncy(100, true));
+    edges.extend(bouncy(200, false));
+
+    use Gesture::*;
+    assert_eq!(
+        run(Timings::default(), &edges, 1000),
+        [(124, Press), (224, Release), (524, Click)]
+    );
+}
+
+#[test]
+fn debounce_ignores_glitch() {
+    // 5 ms glitch, shorter than the debounce time
+    let edges = [(100, true), (105, false)];
+    assert_eq!(run(Timings::default(), &edges, 1000), []);
+}
+
+#[test]
+fn double_click() {
+    let edges = [(0, true), (100, false), (200, true), (300, false)];
+
+    use Gesture::*;
+    assert_eq!(
+        run(Timings::default(), &edges, 1000),
+        [
+            (20, Press),
+            (120, Release),
+            (220, Press),
+            (320, Release),
+            (320, DoubleClick)
+        ]
+    );
+}
+
+#[test]
+fn slow_second_click_is_two_clicks() {
+    let edges = [(0, true), (100, false), (500, true), (600, false)];
+
+    use Gesture::*;
+    let gestures: Vec<_> = run(Timings::default(), &edges, 2000)
+        .into_iter()
+        .filter(|(_, g)| *g == Click)
+        .collect();
+    assert_eq!(gestures, [(420, Click), (920, Click)]);
+}
+
+#[test]
+fn long_press_and_repeat() {
+    let timings = Timings {
+        long_press_ms: 500,
+        repeat_ms: 100,
+        ..Timings::default()
+    };
+    let edges = [(0, true), (750, false)];
+
+    use Gesture::*;
+    assert_eq!(
+        run(timings, &edges, 2000),
+        [
+            (20, Press),
+            (520, LongPress),
+            (620, Repeat),
+            (720, Repeat),
+            (770, Release)
+        ]
+    );
+}
+
+#[test]
+fn next_deadline() {
+    let mut b = ButtonGestures::default();
+    assert_eq!(b.next_deadline(), None);
+    b.edge(true, 10);
+    assert_eq!(b.next_deadline(), Some(30));
+    assert_eq!(b.poll(30), Some(Gesture::Press));
+    assert_eq!(b.poll(30), None);
+    assert_eq!(b.next_deadline(), Some(830));
+}
+
+#[test]
+fn click_expires_before_late_press() {
+    use Gesture::*;
+
+    let mut b = ButtonGestures::default();
+    b.edge(true, 0);
+    assert_eq!(b.poll(20), Some(Press));
+    b.edge(false, 100);
+    assert_eq!(b.poll(120), Some(Release));
+    // debounced at 430, after the click window (420), but polled later
+    b.edge(true, 410);
+    assert_eq!(b.poll(440), Some(Click));
+    assert_eq!(b.poll(440), Some(Press));
+    b.edge(false, 500);
+    assert_eq!(b.poll(520), Some(Release));
+    assert_eq!(b.poll(520), None);
+    assert_eq!(b.poll(820), Some(Click));
+}
@@ -0,0 +1,274 @@
+//! Panic reports kept across a reset
+//!
+//! The panic handler stores a `CrashReport` in a `CrashRing`, placed in RAM
+//! that is not initialized at boot, and resets. After the restart the host
+//! fetches the reports, oldest first, with `Command::GetCrashReport`.
+//!
+//! The RAM holds garbage after a power cycle, so the ring has a magic number
+//! and every slot a crc, anything not matching is dropped at `boot`.
+
+use crate::bus::{Bytes, MAX_BYTES};
+use crate::CKSUM;
+use core::fmt::{self, Display, Write};
+use core::mem::size_of;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrashReport {
+    /// The panic message, cut to `MAX_BYTES`
+    pub message: Bytes,
+    /// The end of the source path
+    pub file: Bytes,
+    pub line: u32,
+    pub uptime_ms: u64,
+    /// Boots since the ring was last initialized
+    pub reset_count: u32,
+}
+
+/// Collects formatted text, dropping what does not fit
+struct Truncate {
+    buf: [u8; MAX_BYTES],
+    len: usize,
+}
+
+impl Write for Truncate {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let mut utf8 = [0; 4];
+            let c = c.encode_utf8(&mut utf8).as_bytes();
+            let Some(to) = self.buf.get_mut(self.len..self.len + c.len()) else {
+                break;
+            };
+            to.copy_from_slice(c);
+            self.len += c.len();
+        }
+        Ok(())
+    }
+}
+
+impl CrashReport {
+    /// E.g., from the `PanicInfo` message and location
+    pub fn new(
+        message: &dyn Display,
+        file: &str,
+        line: u32,
+        uptime_ms: u64,
+        reset_count: u32,
+    ) -> Self {
+        let mut text = Truncate {
+            buf: [0; MAX_BYTES],
+            len: 0,
+        };
+        let _ = write!(text, "{}", message);
+
+        let mut start = file.len().saturating_sub(MAX_BYTES);
+        while !file.is_char_boundary(start) {
+            start += 1;
+        }
+        Self {
+            message: Bytes::new(&text.buf[..text.len]).unwrap(),
+            file: Bytes::new(&file.as_bytes()[start..]).unwrap(),
+            line,
+            uptime_ms,
+            reset_count,
+        }
+    }
+}
+
+fn text(bytes: &Bytes) -> &str {
+    core::str::from_utf8(bytes.as_slice()).unwrap_or("?")
+}
+
+impl Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "panicked at {}:{}: {} (uptime {}.{:03} s, reset {})",
+            text(&self.file),
+            self.line,
+            text(&self.message),
+            self.uptime_ms / 1000,
+            self.uptime_ms % 1000,
+            self.reset_count
+        )
+    }
+}
+
+const MAGIC: u32 = 0x4352_5348; // "CRSH"
+const USED: u32 = 0x5553_4544; // "USED"
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Slot {
+    used: u32,
+    crc: u32,
+    data: [u8; size_of::<CrashReport>()],
+}
+
+impl Slot {
+    const EMPTY: Self = Self {
+        used: 0,
+        crc: 0,
+        data: [0; size_of::<CrashReport>()],
+    };
+
+    fn report(&self) -> Option<CrashReport> {
+        if self.used != USED || self.crc != CKSUM.checksum(&self.data) {
+            return None;
+        }
+        ssmarshal::deserialize(&self.data).ok().map(|(r, _)| r)
+    }
+}
+
+/// The last `N` reports and the reset count, plain integers only, so any
+/// content of uninitialized RAM is a valid (if meaningless) value
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct CrashRing<const N: usize> {
+    magic: u32,
+    resets: u32,
+    /// The slot written next
+    head: u32,
+    slots: [Slot; N],
+}
+
+impl<const N: usize> Default for CrashRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> CrashRing<N> {
+    pub const fn new() -> Self {
+        Self {
+            magic: MAGIC,
+            resets: 0,
+            head: 0,
+            slots: [Slot::EMPTY; N],
+        }
+    }
+
+    /// Call once at boot, starts over unless the ring survived a reset,
+    /// returns the reset count
+    pub fn boot(&mut self) -> u32 {
+        if self.magic != MAGIC || self.head as usize >= N {
+            *self = Self::new();
+        }
+        for slot in &mut self.slots {
+            if slot.report().is_none() {
+                *slot = Slot::EMPTY;
+            }
+        }
+        self.resets = self.resets.wrapping_add(1);
+        self.resets
+    }
+
+    pub fn resets(&self) -> u32 {
+        self.resets
+    }
+
+    /// Store a report, replacing the oldest when full
+    pub fn record(&mut self, report: &CrashReport) {
+        let Some(slot) = self.slots.get_mut(self.head as usize) else {
+            return;
+        };
+        let mut data = [0; size_of::<CrashReport>()];
+        if ssmarshal::serialize(&mut data, report).is_err() {
+            return;
+        }
+        *slot = Slot {
+            used: USED,
+            crc: CKSUM.checksum(&data),
+            data,
+        };
+        self.head = ((self.head as usize + 1) % N) as u32;
+    }
+
+    /// Remove and return the oldest report
+    pub fn take(&mut self) -> Option<CrashReport> {
+        (0..N).find_map(|i| {
+            let slot = &mut self.slots[(self.head as usize + i) % N];
+            let report = slot.report()?;
+            *slot = Slot::EMPTY;
+            Some(report)
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|s| s.report().is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test]
+fn report() {
+    let report = CrashReport::new(
+        &format_args!(
+            "index out of bounds: the len is {} but the index is {}",
+            3, 7
+        ),
+        "examples/very/long/path/to/the/source/panic.rs",
+        35,
+        12_345,
+        2,
+    );
+    assert_eq!(
+        report.message.as_slice(),
+        b"index out of bounds: the len is "
+    );
+    assert_eq!(report.file.as_slice(), b"long/path/to/the/source/panic.rs");
+    assert_eq!(
+        report.to_string(),
+        "panicked at long/path/to/the/source/panic.rs:35: index out of bounds: \
+         the len is  (uptime 12.345 s, reset 2)"
+    );
+
+    // multibyte chars are not split
+    let report = CrashReport::new(&"ü".repeat(20), &"ä".repeat(20), 1, 0, 0);
+    assert_eq!(report.message.as_slice(), "ü".repeat(16).as_bytes());
+    assert_eq!(report.file.as_slice(), "ä".repeat(16).as_bytes());
+
+    let mut buf = [0u8; size_of::<CrashReport>()];
+    let n = ssmarshal::serialize(&mut buf, &report).unwrap();
+    let (decoded, _) = ssmarshal::deserialize::<CrashReport>(&buf[..n]).unwrap();
+    assert_eq!(decoded, report);
+}
+
+#[test]
+fn ring() {
+    let report = |line| CrashReport::new(&"boom", "main.rs", line, 0, 1);
+
+    // garbage after a power cycle
+    let mut ring = CrashRing::<3>::new();
+    ring.magic = 0x1234;
+    ring.resets = 77;
+    assert_eq!(ring.boot(), 1);
+    assert!(ring.is_empty());
+
+    // more reports than slots, the oldest is replaced
+    for line in 1..=4 {
+        ring.record(&report(line));
+    }
+    assert_eq!(ring.len(), 3);
+    // a reset
+    assert_eq!(ring.boot(), 2);
+    assert_eq!(ring.take().map(|r| r.line), Some(2));
+    ring.record(&report(5));
+    let lines: Vec<_> = core::iter::from_fn(|| ring.take())
+        .map(|r| r.line)
+        .collect();
+    assert_eq!(lines, [3, 4, 5]);
+    assert_eq!(ring.take(), None);
+
+    // a corrupted slot is dropped, the others survive
+    ring.record(&report(6));
+    ring.record(&report(7));
+    let slot = (ring.head as usize + 2) % 3;
+    ring.slots[slot].data[0] ^= 1;
+    ring.boot();
+    assert_eq!(ring.take().map(|r| r.line), Some(6));
+    assert_eq!(ring.take(), None);
+}
@@ -1,7 +1,7 @@
-use chrono::{Datelike, TimeZone, Timelike, Utc};
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Timelike, Utc};
 use serde_derive::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UtcDateTime {
     year: i32,
     month: u32,
@@ -12,6 +12,33 @@ pub struct UtcDateTime {
     nanoseconds: u32,
 }
 
+impl UtcDateTime {
+    /// Nanoseconds since the unix epoch
+    /// None if the date is invalid or out of range (years 1677..=2262)
+    pub fn timestamp_nanos(&self) -> Option<i64> {
+        NaiveDate::from_ymd_opt(self.year, self.month, self.day)?
+            .and_hms_nano_opt(self.hour, self.minute, self.second, self.nanoseconds)?
+            .and_utc()
+            .timestamp_nanos_opt()
+    }
+
+    /// Inverse of `timestamp_nanos`
+    pub fn from_timestamp_nanos(ns: i64) -> Self {
+        DateTime::from_timestamp_nanos(ns).into()
+    }
+}
+
+/// ISO 8601, e.g. `2023-10-16T07:30:15.000000000Z`
+impl core::fmt::Display for UtcDateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.nanoseconds
+        )
+    }
+}
+
 impl From<chrono::DateTime<Utc>> for UtcDateTime {
     fn from(dt: chrono::DateTime<Utc>) -> Self {
         Self {
@@ -28,14 +55,55 @@ impl From<chrono::DateTime<Utc>> for UtcDateTime {
 
 impl From<UtcDateTime> for chrono::DateTime<Utc> {
     fn from(value: UtcDateTime) -> Self {
-        Utc.with_ymd_and_hms(
-            value.year,
-            value.month,
-            value.day,
-            value.hour,
-            value.minute,
-            value.second,
-        )
-        .unwrap()
+        let naive = NaiveDate::from_ymd_opt(value.year, value.month, value.day)
+            .and_then(|d| {
+                d.and_hms_nano_opt(value.hour, value.minute, value.second, value.nanoseconds)
+            })
+            .unwrap();
+        Utc.from_utc_datetime(&naive)
+    }
+}
+
+#[test]
+fn timestamp_round_trip() {
+    let dt = Utc.with_ymd_and_hms(2023, 10, 16, 7, 30, 15).unwrap()
+        + chrono::Duration::nanoseconds(123_456_789);
+    let udt = UtcDateTime::from(dt);
+
+    assert_eq!(udt.timestamp_nanos(), dt.timestamp_nanos_opt());
+    assert_eq!(
+        UtcDateTime::from_timestamp_nanos(udt.timestamp_nanos().unwrap()),
+        udt
+    );
+    assert_eq!(DateTime::<Utc>::from(udt), dt);
+}
+
+/// `t` with the month set to 13, as could come off the wire
+#[cfg(test)]
+pub(crate) fn invalid(t: UtcDateTime) -> UtcDateTime {
+    UtcDateTime { month: 13, ..t }
+}
+
+#[test]
+fn invalid_timestamp() {
+    let valid = UtcDateTime::from_timestamp_nanos(0);
+    let invalid = [
+        UtcDateTime { month: 13, ..valid },
+        UtcDateTime {
+            month: 2,
+            day: 31,
+            ..valid
+        },
+        UtcDateTime {
+            second: 60,
+            ..valid
+        },
+        UtcDateTime {
+            year: 2263,
+            ..valid
+        },
+    ];
+    for t in invalid {
+        assert_eq!(t.timestamp_nanos(), None, "{}", t);
     }
 }
@@ -0,0 +1,437 @@
+//! Digital filters for sensor data
+//!
+//! All filters run on `f32` samples at a fixed sample rate. IIR filter design
+//! follows the bilinear transform (RBJ "Audio EQ Cookbook"), with helpers for
+//! Butterworth cascades.
+
+use crate::shift_register::ShiftRegister;
+use core::f32::consts::PI;
+use libm::{cosf, expf, sinf, sqrtf};
+
+pub trait Filter {
+    /// Feed one sample, returns the filtered output
+    fn update(&mut self, x: f32) -> f32;
+    /// Forget all history
+    fn reset(&mut self);
+}
+
+/// Exponential moving average, y += alpha * (x - y)
+/// the first sample initializes the state
+#[derive(Debug, Clone, Copy)]
+pub struct Ema {
+    alpha: f32,
+    y: Option<f32>,
+}
+
+impl Ema {
+    /// alpha in (0, 1], larger is faster
+    pub fn new(alpha: f32) -> Self {
+        assert!(alpha > 0.0 && alpha <= 1.0);
+        Self { alpha, y: None }
+    }
+
+    /// Alpha giving the same lag as an N sample moving average
+    pub fn with_span(n: u32) -> Self {
+        Self::new(2.0 / (n as f32 + 1.0))
+    }
+}
+
+impl Filter for Ema {
+    fn update(&mut self, x: f32) -> f32 {
+        let y = match self.y {
+            Some(y) => y + self.alpha * (x - y),
+            None => x,
+        };
+        self.y = Some(y);
+        y
+    }
+
+    fn reset(&mut self) {
+        self.y = None;
+    }
+}
+
+/// Median over the last N samples, removes spikes while keeping edges
+#[derive(Debug, Clone)]
+pub struct Median<const N: usize> {
+    window: ShiftRegister<f32, N>,
+}
+
+impl<const N: usize> Default for Median<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Median<N> {
+    pub fn new() -> Self {
+        Self {
+            window: ShiftRegister::new(),
+        }
+    }
+}
+
+impl<const N: usize> Filter for Median<N> {
+    fn update(&mut self, x: f32) -> f32 {
+        self.window.insert(x);
+        self.window.median().unwrap()
+    }
+
+    fn reset(&mut self) {
+        self.window.clear();
+    }
+}
+
+/// First order (RC) low-pass
+#[derive(Debug, Clone, Copy)]
+pub struct LowPass {
+    a: f32,
+    y: Option<f32>,
+}
+
+impl LowPass {
+    pub fn new(cutoff_hz: f32, sample_rate_hz: f32) -> Self {
+        let a = 1.0 - expf(-2.0 * PI * cutoff_hz / sample_rate_hz);
+        Self { a, y: None }
+    }
+}
+
+impl Filter for LowPass {
+    fn update(&mut self, x: f32) -> f32 {
+        let y = match self.y {
+            Some(y) => y + self.a * (x - y),
+            None => x,
+        };
+        self.y = Some(y);
+        y
+    }
+
+    fn reset(&mut self) {
+        self.y = None;
+    }
+}
+
+/// First order (RC) high-pass
+#[derive(Debug, Clone, Copy)]
+pub struct HighPass {
+    a: f32,
+    state: Option<(f32, f32)>, // (x, y) of the previous sample
+}
+
+impl HighPass {
+    pub fn new(cutoff_hz: f32, sample_rate_hz: f32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff_hz);
+        let dt = 1.0 / sample_rate_hz;
+        Self {
+            a: rc / (rc + dt),
+            state: None,
+        }
+    }
+}
+
+impl Filter for HighPass {
+    fn update(&mut self, x: f32) -> f32 {
+        let y = match self.state {
+            Some((x1, y1)) => self.a * (y1 + x - x1),
+            None => 0.0,
+        };
+        self.state = Some((x, y));
+        y
+    }
+
+    fn reset(&mut self) {
+        self.state = None;
+    }
+}
+
+/// Normalized biquad coefficients (a0 = 1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coefficients {
+    pub b0: f32,
+    pub b1: f32,
+    pub b2: f32,
+    pub a1: f32,
+    pub a2: f32,
+}
+
+pub const BUTTERWORTH_Q: f32 = core::f32::consts::FRAC_1_SQRT_2;
+
+impl Coefficients {
+    /// Pass through
+    pub const IDENTITY: Self = Self {
+        b0: 1.0,
+        b1: 0.0,
+        b2: 0.0,
+        a1: 0.0,
+        a2: 0.0,
+    };
+
+    fn normalize(b: [f32; 3], a: [f32; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+        }
+    }
+
+    // (cos w0, alpha) for the cookbook formulas
+    fn prewarp(f0: f32, fs: f32, q: f32) -> (f32, f32) {
+        assert!(f0 > 0.0 && f0 < fs / 2.0 && q > 0.0);
+        let w0 = 2.0 * PI * f0 / fs;
+        (cosf(w0), sinf(w0) / (2.0 * q))
+    }
+
+    pub fn lowpass(f0: f32, fs: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(f0, fs, q);
+        let b1 = 1.0 - cos;
+        Self::normalize(
+            [b1 / 2.0, b1, b1 / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    pub fn highpass(f0: f32, fs: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(f0, fs, q);
+        let b1 = -(1.0 + cos);
+        Self::normalize(
+            [-b1 / 2.0, b1, -b1 / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Band-pass with 0 dB peak gain
+    pub fn bandpass(f0: f32, fs: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(f0, fs, q);
+        Self::normalize([alpha, 0.0, -alpha], [1.0 + alpha, -2.0 * cos, 1.0 - alpha])
+    }
+
+    /// Notch, e.g., for 50 Hz mains hum
+    pub fn notch(f0: f32, fs: f32, q: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(f0, fs, q);
+        Self::normalize(
+            [1.0, -2.0 * cos, 1.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Magnitude of the frequency response at f
+    pub fn magnitude(&self, f: f32, fs: f32) -> f32 {
+        let w = 2.0 * PI * f / fs;
+        let (c1, s1, c2, s2) = (cosf(w), sinf(w), cosf(2.0 * w), sinf(2.0 * w));
+        // H(e^jw) with z^-1 = cos w - j sin w
+        let num_re = self.b0 + self.b1 * c1 + self.b2 * c2;
+        let num_im = -(self.b1 * s1 + self.b2 * s2);
+        let den_re = 1.0 + self.a1 * c1 + self.a2 * c2;
+        let den_im = -(self.a1 * s1 + self.a2 * s2);
+        sqrtf((num_re * num_re + num_im * num_im) / (den_re * den_re + den_im * den_im))
+    }
+}
+
+/// Second order IIR section, transposed direct form II
+#[derive(Debug, Clone, Copy)]
+pub struct Biquad {
+    c: Coefficients,
+    s1: f32,
+    s2: f32,
+}
+
+impl Biquad {
+    pub const fn new(c: Coefficients) -> Self {
+        Self {
+            c,
+            s1: 0.0,
+            s2: 0.0,
+        }
+    }
+
+    pub fn coefficients(&self) -> &Coefficients {
+        &self.c
+    }
+}
+
+impl Filter for Biquad {
+    fn update(&mut self, x: f32) -> f32 {
+        let c = &self.c;
+        let y = c.b0 * x + self.s1;
+        self.s1 = c.b1 * x - c.a1 * y + self.s2;
+        self.s2 = c.b2 * x - c.a2 * y;
+        y
+    }
+
+    fn reset(&mut self) {
+        self.s1 = 0.0;
+        self.s2 = 0.0;
+    }
+}
+
+/// N biquads in series, i.e., a filter of order 2N
+#[derive(Debug, Clone, Copy)]
+pub struct Cascade<const N: usize> {
+    stages: [Biquad; N],
+}
+
+impl<const N: usize> Cascade<N> {
+    pub fn new(stages: [Coefficients; N]) -> Self {
+        Self {
+            stages: stages.map(Biquad::new),
+        }
+    }
+
+    /// Butterworth low-pass of order 2N
+    pub fn butterworth_lowpass(f0: f32, fs: f32) -> Self {
+        Self::new(core::array::from_fn(|k| {
+            Coefficients::lowpass(f0, fs, Self::butterworth_q(k))
+        }))
+    }
+
+    /// Butterworth high-pass of order 2N
+    pub fn butterworth_highpass(f0: f32, fs: f32) -> Self {
+        Self::new(core::array::from_fn(|k| {
+            Coefficients::highpass(f0, fs, Self::butterworth_q(k))
+        }))
+    }
+
+    // Q of the k:th pole pair of a Butterworth filter of order 2N
+    fn butterworth_q(k: usize) -> f32 {
+        1.0 / (2.0 * cosf(PI * (2 * k + 1) as f32 / (4 * N) as f32))
+    }
+
+    pub fn magnitude(&self, f: f32, fs: f32) -> f32 {
+        self.stages.iter().map(|s| s.c.magnitude(f, fs)).product()
+    }
+}
+
+impl<const N: usize> Filter for Cascade<N> {
+    fn update(&mut self, x: f32) -> f32 {
+        self.stages.iter_mut().fold(x, |x, s| s.update(x))
+    }
+
+    fn reset(&mut self) {
+        self.stages.iter_mut().for_each(Filter::reset);
+    }
+}
+
+#[cfg(test)]
+fn db(x: f32) -> f32 {
+    20.0 * libm::log10f(x)
+}
+
+// Output amplitude for a unit sine at f, once the filter has settled
+// (from the RMS over a whole number of periods, so sampling phase doesn't matter)
+#[cfg(test)]
+fn measured_gain(filter: &mut impl Filter, f: f32, fs: f32) -> f32 {
+    filter.reset();
+    let n = (fs * 2.0) as usize;
+    let sum_sq: f32 = (0..n)
+        .map(|i| filter.update(sinf(2.0 * PI * f * i as f32 / fs)))
+        .skip(n / 2)
+        .map(|y| y * y)
+        .sum();
+    sqrtf(2.0 * sum_sq / (n / 2) as f32)
+}
+
+#[cfg(test)]
+fn assert_near(a: f32, b: f32, tol: f32) {
+    assert!((a - b).abs() <= tol, "{} != {} (tolerance {})", a, b, tol);
+}
+
+// Reference: scipy.signal.butter(2, 100, fs=1000) and scipy.signal.freqz
+#[cfg(test)]
+const BUTTER2_100_1000: ([f32; 3], [f32; 3]) = (
+    [0.06745527, 0.13491055, 0.06745527],
+    [1.0, -1.1429805, 0.4128016],
+);
+
+#[cfg(test)]
+const BUTTER2_100_1000_DB: [(f32, f32); 5] = [
+    (50.0, -0.238533),
+    (100.0, -3.0103),
+    (200.0, -14.149733),
+    (300.0, -25.091983),
+    (450.0, -51.54049),
+];
+
+#[test]
+fn lowpass_matches_reference_coefficients() {
+    let c = Coefficients::lowpass(100.0, 1000.0, BUTTERWORTH_Q);
+    let (b, a) = BUTTER2_100_1000;
+    for (x, r) in [c.b0, c.b1, c.b2, c.a1, c.a2]
+        .iter()
+        .zip(b.iter().chain(&a[1..]))
+    {
+        assert_near(*x, *r, 1e-6);
+    }
+}
+
+#[test]
+fn lowpass_frequency_response() {
+    let c = Coefficients::lowpass(100.0, 1000.0, BUTTERWORTH_Q);
+    assert_near(c.magnitude(0.0, 1000.0), 1.0, 1e-5);
+    for (f, reference) in BUTTER2_100_1000_DB {
+        assert_near(db(c.magnitude(f, 1000.0)), reference, 0.01);
+    }
+    // and in the time domain
+    let mut biquad = Biquad::new(c);
+    for (f, reference) in &BUTTER2_100_1000_DB[..3] {
+        assert_near(db(measured_gain(&mut biquad, *f, 1000.0)), *reference, 0.1);
+    }
+}
+
+#[test]
+fn highpass_frequency_response() {
+    // Reference: scipy.signal.butter(2, 100, 'highpass', fs=1000)
+    let c = Coefficients::highpass(100.0, 1000.0, BUTTERWORTH_Q);
+    for (f, reference) in [(50.0, -12.721074), (100.0, -3.0103), (200.0, -0.170333)] {
+        assert_near(db(c.magnitude(f, 1000.0)), reference, 0.01);
+    }
+}
+
+#[test]
+fn butterworth_cascade() {
+    // 4th order, |H| = 1 / sqrt(1 + W^8) with W = tan(pi f / fs) / tan(pi f0 / fs)
+    let mut cascade = Cascade::<2>::butterworth_lowpass(100.0, 1000.0);
+    for (f, reference) in [(50.0, -0.013822), (100.0, -3.0103), (200.0, -27.965743)] {
+        assert_near(db(cascade.magnitude(f, 1000.0)), reference, 0.01);
+        assert_near(db(measured_gain(&mut cascade, f, 1000.0)), reference, 0.1);
+    }
+}
+
+#[test]
+fn notch_removes_mains_hum() {
+    let mut notch = Biquad::new(Coefficients::notch(50.0, 1000.0, 5.0));
+    assert!(db(measured_gain(&mut notch, 50.0, 1000.0)) < -40.0);
+    assert_near(db(measured_gain(&mut notch, 200.0, 1000.0)), 0.0, 0.1);
+}
+
+#[test]
+fn first_order_cutoff() {
+    // -3 dB at the cutoff, well below the Nyquist frequency
+    let mut lp = LowPass::new(10.0, 10_000.0);
+    assert_near(db(measured_gain(&mut lp, 10.0, 10_000.0)), -3.01, 0.1);
+    let mut hp = HighPass::new(10.0, 10_000.0);
+    assert_near(db(measured_gain(&mut hp, 10.0, 10_000.0)), -3.01, 0.1);
+    assert_near(db(measured_gain(&mut hp, 1000.0, 10_000.0)), 0.0, 0.1);
+}
+
+#[test]
+fn ema_converges() {
+    let mut ema = Ema::new(0.5);
+    assert_eq!(ema.update(10.0), 10.0);
+    assert_eq!(ema.update(20.0), 15.0);
+    assert_eq!(ema.update(20.0), 17.5);
+    ema.reset();
+    assert_eq!(ema.update(4.0), 4.0);
+    assert_near(Ema::with_span(9).alpha, 0.2, 1e-6);
+}
+
+#[test]
+fn median_rejects_spikes() {
+    let mut median = Median::<3>::new();
+    let out: Vec<f32> = [1.0, 1.0, 100.0, 1.0, 2.0, 2.0]
+        .iter()
+        .map(|x| median.update(*x))
+        .collect();
+    assert_eq!(out, [1.0, 1.0, 1.0, 1.0, 2.0, 2.0]);
+}
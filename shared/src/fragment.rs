@@ -0,0 +1,358 @@
+//! Fragmentation and reassembly of messages larger than one frame
+//!
+//! Every fragment starts with a `Header` (message id, fragment index and
+//! count, total length), followed by its part of the message. All fragments
+//! but the last carry `chunk()` bytes, so the position follows from the index
+//! and fragments may arrive in any order.
+//!
+//! The `Reassembler` collects one message at a time in a bounded buffer.
+//! Duplicates are ignored, a message is dropped if no fragment arrives for
+//! `timeout_ms` (timestamps in milliseconds from any monotonic source), and a
+//! fragment of a new message replaces an incomplete one.
+
+use core::ops::Range;
+
+pub type MessageId = u16;
+
+/// Encoded size of a `Header`
+pub const HEADER: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FragmentError {
+    /// Shorter than the header, or the data does not fit the header
+    Truncated,
+    /// Longer than the reassembly buffer, or too many fragments
+    TooLarge,
+    /// Index out of range, or count or length differing from earlier fragments
+    Inconsistent,
+    BufferTooSmall,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub id: MessageId,
+    pub index: u16,
+    pub count: u16,
+    /// Length of the whole message
+    pub total: u32,
+}
+
+impl Header {
+    /// Bytes per fragment, except for the last
+    pub fn chunk(&self) -> usize {
+        (self.total as usize).div_ceil(self.count.max(1) as usize)
+    }
+
+    /// Position of the fragment data in the message
+    pub fn range(&self) -> Range<usize> {
+        let start = (self.index as usize * self.chunk()).min(self.total as usize);
+        start..(start + self.chunk()).min(self.total as usize)
+    }
+
+    /// Little endian fields, in declaration order
+    pub fn write(&self, out: &mut [u8]) -> Result<(), FragmentError> {
+        let out = out.get_mut(..HEADER).ok_or(FragmentError::BufferTooSmall)?;
+        out[0..2].copy_from_slice(&self.id.to_le_bytes());
+        out[2..4].copy_from_slice(&self.index.to_le_bytes());
+        out[4..6].copy_from_slice(&self.count.to_le_bytes());
+        out[6..10].copy_from_slice(&self.total.to_le_bytes());
+        Ok(())
+    }
+
+    /// Split a fragment into header and data, checking the data length
+    pub fn read(fragment: &[u8]) -> Result<(Self, &[u8]), FragmentError> {
+        if fragment.len() < HEADER {
+            return Err(FragmentError::Truncated);
+        }
+        let (h, data) = fragment.split_at(HEADER);
+        let u16_at = |i: usize| u16::from_le_bytes([h[i], h[i + 1]]);
+        let header = Self {
+            id: u16_at(0),
+            index: u16_at(2),
+            count: u16_at(4),
+            total: u32::from_le_bytes([h[6], h[7], h[8], h[9]]),
+        };
+        if header.count == 0 || header.index >= header.count {
+            return Err(FragmentError::Inconsistent);
+        }
+        if data.len() != header.range().len() {
+            return Err(FragmentError::Truncated);
+        }
+        Ok((header, data))
+    }
+}
+
+/// The fragments of a message, see `fragments`
+#[derive(Debug, Clone)]
+pub struct Fragments<'a> {
+    id: MessageId,
+    message: &'a [u8],
+    count: u16,
+    index: u16,
+}
+
+/// Split `message` into fragments of at most `max_data` bytes after the header
+pub fn fragments(
+    id: MessageId,
+    message: &[u8],
+    max_data: usize,
+) -> Result<Fragments<'_>, FragmentError> {
+    let count = message.len().div_ceil(max_data.max(1)).max(1);
+    let count = u16::try_from(count).map_err(|_| FragmentError::TooLarge)?;
+    u32::try_from(message.len()).map_err(|_| FragmentError::TooLarge)?;
+    Ok(Fragments {
+        id,
+        message,
+        count,
+        index: 0,
+    })
+}
+
+impl<'a> Iterator for Fragments<'a> {
+    type Item = (Header, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.count {
+            return None;
+        }
+        let header = Header {
+            id: self.id,
+            index: self.index,
+            count: self.count,
+            total: self.message.len() as u32,
+        };
+        self.index += 1;
+        Some((header, &self.message[header.range()]))
+    }
+}
+
+/// Header and data into `out`, returns the fragment
+pub fn encode<'a>(
+    header: &Header,
+    data: &[u8],
+    out: &'a mut [u8],
+) -> Result<&'a [u8], FragmentError> {
+    let n = HEADER + data.len();
+    let out = out.get_mut(..n).ok_or(FragmentError::BufferTooSmall)?;
+    header.write(out)?;
+    out[HEADER..].copy_from_slice(data);
+    Ok(out)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    header: Header,
+    received: u16,
+    last: u64,
+}
+
+/// Messages up to `N` bytes in up to `F` fragments
+#[derive(Debug, Clone)]
+pub struct Reassembler<const N: usize, const F: usize> {
+    buf: [u8; N],
+    received: [bool; F],
+    pending: Option<Pending>,
+    /// Late duplicates of the last completed message are ignored until the
+    /// timeout, with the time it completed
+    done: Option<(MessageId, u64)>,
+    timeout_ms: u64,
+}
+
+impl<const N: usize, const F: usize> Reassembler<N, F> {
+    pub const fn new(timeout_ms: u64) -> Self {
+        Self {
+            buf: [0; N],
+            received: [false; F],
+            pending: None,
+            done: None,
+            timeout_ms,
+        }
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.pending.map(|p| p.last + self.timeout_ms)
+    }
+
+    /// Drops an incomplete message after the timeout, returns its id
+    pub fn poll(&mut self, now: u64) -> Option<MessageId> {
+        if self.done.is_some_and(|(_, at)| now >= at + self.timeout_ms) {
+            self.done = None;
+        }
+        let pending = self.pending?;
+        if now < pending.last + self.timeout_ms {
+            return None;
+        }
+        self.pending = None;
+        Some(pending.header.id)
+    }
+
+    /// Feed a fragment, returns the message once complete
+    pub fn push(
+        &mut self,
+        fragment: &[u8],
+        now: u64,
+    ) -> Result<Option<(MessageId, &[u8])>, FragmentError> {
+        let (header, data) = Header::read(fragment)?;
+        if header.total as usize > N || header.count as usize > F {
+            return Err(FragmentError::TooLarge);
+        }
+        self.poll(now);
+        if self.done.is_some_and(|(id, _)| id == header.id) {
+            return Ok(None);
+        }
+
+        let pending = match &mut self.pending {
+            Some(p) if p.header.id == header.id => {
+                if (p.header.count, p.header.total) != (header.count, header.total) {
+                    return Err(FragmentError::Inconsistent);
+                }
+                p
+            }
+            // a new message, any incomplete one is dropped
+            _ => {
+                self.received = [false; F];
+                self.pending.insert(Pending {
+                    header,
+                    received: 0,
+                    last: now,
+                })
+            }
+        };
+        pending.last = now;
+
+        let seen = &mut self.received[header.index as usize];
+        if !*seen {
+            *seen = true;
+            pending.received += 1;
+            self.buf[header.range()].copy_from_slice(data);
+        }
+        if pending.received < header.count {
+            return Ok(None);
+        }
+        self.pending = None;
+        self.done = Some((header.id, now));
+        Ok(Some((header.id, &self.buf[..header.total as usize])))
+    }
+}
+
+#[cfg(test)]
+fn encoded(id: MessageId, message: &[u8], max_data: usize) -> Vec<Vec<u8>> {
+    let mut out = [0u8; 64];
+    fragments(id, message, max_data)
+        .unwrap()
+        .map(|(h, data)| encode(&h, data, &mut out).unwrap().to_vec())
+        .collect()
+}
+
+#[test]
+fn split() {
+    let message: Vec<u8> = (0..100).collect();
+    let parts: Vec<_> = fragments(7, &message, 32).unwrap().collect();
+    assert_eq!(parts.len(), 4);
+    // 100 bytes in 4 fragments of 25
+    assert_eq!(parts[0].0.chunk(), 25);
+    assert_eq!(parts[3].1, &message[75..]);
+    assert_eq!(parts.iter().map(|(_, d)| d.len()).sum::<usize>(), 100);
+
+    let empty: Vec<_> = fragments(1, &[], 32).unwrap().collect();
+    assert_eq!(empty.len(), 1);
+    assert!(empty[0].1.is_empty());
+
+    let fragment = &encoded(7, &message, 32)[1];
+    let (header, data) = Header::read(fragment).unwrap();
+    assert_eq!(header, parts[1].0);
+    assert_eq!(data, parts[1].1);
+    assert_eq!(
+        Header::read(&fragment[..fragment.len() - 1]),
+        Err(FragmentError::Truncated)
+    );
+}
+
+#[test]
+fn reorder_and_duplicates() {
+    let message: Vec<u8> = (0..=255).cycle().take(300).collect();
+    let parts = encoded(1, &message, 40);
+    assert_eq!(parts.len(), 8);
+
+    let mut reassembler = Reassembler::<512, 16>::new(100);
+    let mut complete = vec![];
+    // reversed, with every fragment twice
+    for part in parts.iter().rev().flat_map(|p| [p, p]) {
+        if let Some((id, m)) = reassembler.push(part, 0).unwrap() {
+            complete.push((id, m.to_vec()));
+        }
+    }
+    assert_eq!(complete, [(1, message.clone())]);
+
+    // late duplicates of the completed message
+    assert_eq!(reassembler.push(&parts[3], 1), Ok(None));
+    assert_eq!(reassembler.next_deadline(), None);
+
+    // a new message with the same id after the timeout
+    let message = [9u8; 20];
+    let mut last = None;
+    for part in &encoded(1, &message, 40) {
+        last = reassembler
+            .push(part, 100)
+            .unwrap()
+            .map(|(id, m)| (id, m.to_vec()));
+    }
+    assert_eq!(last, Some((1, message.to_vec())));
+}
+
+#[test]
+fn loss_and_timeout() {
+    let message = [5u8; 100];
+    let parts = encoded(2, &message, 30);
+    let mut reassembler = Reassembler::<128, 8>::new(100);
+
+    // fragment 1 is lost
+    for (i, part) in parts.iter().enumerate() {
+        if i != 1 {
+            assert_eq!(reassembler.push(part, 10 * i as u64), Ok(None));
+        }
+    }
+    assert_eq!(reassembler.next_deadline(), Some(130));
+    assert_eq!(reassembler.poll(129), None);
+    assert_eq!(reassembler.poll(130), Some(2));
+
+    // the retransmission starts over
+    assert_eq!(reassembler.push(&parts[1], 200), Ok(None));
+    let mut last = None;
+    for part in &parts {
+        last = reassembler
+            .push(part, 210)
+            .unwrap()
+            .map(|(id, m)| (id, m.to_vec()));
+    }
+    assert_eq!(last, Some((2, message.to_vec())));
+
+    // an incomplete message is replaced by the next
+    let next = encoded(3, &[1, 2, 3], 2);
+    assert_eq!(
+        reassembler.push(&encoded(4, &message, 30)[0], 300),
+        Ok(None)
+    );
+    assert_eq!(reassembler.push(&next[1], 301), Ok(None));
+    assert_eq!(
+        reassembler.push(&next[0], 302),
+        Ok(Some((3, &[1u8, 2, 3][..])))
+    );
+
+    // limits and consistency
+    assert_eq!(
+        reassembler.push(&encoded(5, &[0; 200], 30)[0], 400),
+        Err(FragmentError::TooLarge)
+    );
+    assert_eq!(
+        reassembler.push(&encoded(6, &[0; 100], 10)[0], 400),
+        Err(FragmentError::TooLarge)
+    );
+    reassembler
+        .push(&encoded(7, &[0; 100], 30)[0], 400)
+        .unwrap();
+    assert_eq!(
+        reassembler.push(&encoded(7, &[0; 90], 30)[1], 400),
+        Err(FragmentError::Inconsistent)
+    );
+}
@@ -0,0 +1,491 @@
+//! Framing of serialized messages
+//!
+//! A `Framer` delimits payloads on the byte stream:
+//! - `Cobs`, the default, zero terminated (see `mux` for the channel framing)
+//! - `Slip`, RFC 1055, END delimited with escaping
+//! - `LengthSync`, a sync byte and a little endian u16 length, unescaped
+//!
+//! `serialize_crc` and `deserialize_crc` add and check a crc32 (`CKSUM`)
+//! around the ssmarshal encoding, for any framer.
+
+use crate::CKSUM;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    BufferTooSmall,
+    /// Invalid encoding, e.g., a bad SLIP escape
+    Corrupt,
+    /// Shorter than the crc
+    Truncated,
+    Crc,
+    Serialize,
+    Deserialize,
+}
+
+/// Incremental decoding of frames from a byte stream
+pub trait Decoder {
+    /// Feed a byte, returns the decoded payload at the end of a frame
+    fn push(&mut self, byte: u8) -> Option<Result<&[u8], FrameError>>;
+}
+
+pub trait Framer {
+    /// Decoder buffering up to `N` bytes
+    type Decoder<const N: usize>: Decoder + Default;
+
+    /// Worst case encoded size of `payload` bytes, including delimiters
+    fn max_encoded_len(payload: usize) -> usize;
+
+    /// Encode `payload` into `out`, returns the encoded length
+    fn encode(payload: &[u8], out: &mut [u8]) -> Result<usize, FrameError>;
+
+    /// Decode a complete frame in place, returns the payload length
+    fn decode_in_place(frame: &mut [u8]) -> Result<usize, FrameError>;
+}
+
+/// Bytes collected up to a delimiter, for decoders of a whole frame
+#[derive(Debug, Clone)]
+struct Collector<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    overflow: bool,
+}
+
+impl<const N: usize> Collector<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    fn store(&mut self, byte: u8) {
+        match self.buf.get_mut(self.len) {
+            Some(b) => {
+                *b = byte;
+                self.len += 1;
+            }
+            None => self.overflow = true,
+        }
+    }
+
+    /// The collected bytes, None for back to back delimiters
+    fn take(&mut self) -> Option<Result<&mut [u8], FrameError>> {
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.overflow) {
+            return Some(Err(FrameError::BufferTooSmall));
+        }
+        if len == 0 {
+            return None;
+        }
+        Some(Ok(&mut self.buf[..len]))
+    }
+}
+
+/// Consistent Overhead Byte Stuffing, zero terminated
+#[derive(Debug, Clone, Copy)]
+pub struct Cobs;
+
+#[derive(Debug, Clone)]
+pub struct CobsDecoder<const N: usize>(Collector<N>);
+
+impl<const N: usize> Default for CobsDecoder<N> {
+    fn default() -> Self {
+        Self(Collector::new())
+    }
+}
+
+impl<const N: usize> Decoder for CobsDecoder<N> {
+    fn push(&mut self, byte: u8) -> Option<Result<&[u8], FrameError>> {
+        if byte != 0 {
+            self.0.store(byte);
+            return None;
+        }
+        Some(self.0.take()?.and_then(|frame| {
+            let n = Cobs::decode_in_place(frame)?;
+            Ok(&frame[..n])
+        }))
+    }
+}
+
+impl Framer for Cobs {
+    // collects the encoded frame, `N` must fit `max_encoded_len`
+    type Decoder<const N: usize> = CobsDecoder<N>;
+
+    fn max_encoded_len(payload: usize) -> usize {
+        corncobs::max_encoded_len(payload)
+    }
+
+    fn encode(payload: &[u8], out: &mut [u8]) -> Result<usize, FrameError> {
+        if Self::max_encoded_len(payload.len()) > out.len() {
+            return Err(FrameError::BufferTooSmall);
+        }
+        Ok(corncobs::encode_buf(payload, out))
+    }
+
+    fn decode_in_place(frame: &mut [u8]) -> Result<usize, FrameError> {
+        corncobs::decode_in_place(frame).map_err(|_| FrameError::Corrupt)
+    }
+}
+
+/// Serial Line IP framing, RFC 1055
+#[derive(Debug, Clone, Copy)]
+pub struct Slip;
+
+impl Slip {
+    pub const END: u8 = 0xc0;
+    pub const ESC: u8 = 0xdb;
+    pub const ESC_END: u8 = 0xdc;
+    pub const ESC_ESC: u8 = 0xdd;
+
+    fn unescape(byte: u8) -> Result<u8, FrameError> {
+        match byte {
+            Self::ESC_END => Ok(Self::END),
+            Self::ESC_ESC => Ok(Self::ESC),
+            _ => Err(FrameError::Corrupt),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SlipDecoder<const N: usize> {
+    frame: Collector<N>,
+    escape: bool,
+    corrupt: bool,
+}
+
+impl<const N: usize> Default for SlipDecoder<N> {
+    fn default() -> Self {
+        Self {
+            frame: Collector::new(),
+            escape: false,
+            corrupt: false,
+        }
+    }
+}
+
+impl<const N: usize> Decoder for SlipDecoder<N> {
+    fn push(&mut self, byte: u8) -> Option<Result<&[u8], FrameError>> {
+        match byte {
+            Slip::END => {
+                let corrupt =
+                    core::mem::take(&mut self.corrupt) | core::mem::take(&mut self.escape);
+                let frame = self.frame.take()?;
+                Some(match corrupt {
+                    true => Err(FrameError::Corrupt),
+                    false => frame.map(|f| &*f),
+                })
+            }
+            Slip::ESC => {
+                self.escape = true;
+                None
+            }
+            _ if core::mem::take(&mut self.escape) => {
+                match Slip::unescape(byte) {
+                    Ok(b) => self.frame.store(b),
+                    Err(_) => self.corrupt = true,
+                }
+                None
+            }
+            _ => {
+                self.frame.store(byte);
+                None
+            }
+        }
+    }
+}
+
+impl Framer for Slip {
+    type Decoder<const N: usize> = SlipDecoder<N>;
+
+    /// Every byte escaped, and END on both sides
+    fn max_encoded_len(payload: usize) -> usize {
+        2 * payload + 2
+    }
+
+    /// A leading END flushes line noise, as suggested by the RFC
+    fn encode(payload: &[u8], out: &mut [u8]) -> Result<usize, FrameError> {
+        let mut n = 0;
+        let mut put = |b: u8| -> Result<(), FrameError> {
+            *out.get_mut(n).ok_or(FrameError::BufferTooSmall)? = b;
+            n += 1;
+            Ok(())
+        };
+        put(Self::END)?;
+        for b in payload {
+            match *b {
+                Self::END => [Self::ESC, Self::ESC_END]
+                    .into_iter()
+                    .try_for_each(&mut put)?,
+                Self::ESC => [Self::ESC, Self::ESC_ESC]
+                    .into_iter()
+                    .try_for_each(&mut put)?,
+                b => put(b)?,
+            }
+        }
+        put(Self::END)?;
+        Ok(n)
+    }
+
+    fn decode_in_place(frame: &mut [u8]) -> Result<usize, FrameError> {
+        let mut n = 0;
+        let mut i = 0;
+        while i < frame.len() {
+            let b = match frame[i] {
+                Self::END => {
+                    i += 1;
+                    continue;
+                }
+                Self::ESC => {
+                    i += 1;
+                    Self::unescape(*frame.get(i).ok_or(FrameError::Corrupt)?)?
+                }
+                b => b,
+            };
+            frame[n] = b;
+            n += 1;
+            i += 1;
+        }
+        Ok(n)
+    }
+}
+
+/// A sync byte, the payload length as little endian u16 and the payload
+#[derive(Debug, Clone, Copy)]
+pub struct LengthSync;
+
+impl LengthSync {
+    pub const SYNC: u8 = 0xa5;
+    pub const HEADER: usize = 3;
+}
+
+#[derive(Debug, Clone)]
+pub struct LengthSyncDecoder<const N: usize> {
+    buf: [u8; N],
+    /// Header bytes seen, 0 while waiting for sync
+    header: usize,
+    expected: usize,
+    len: usize,
+}
+
+impl<const N: usize> Default for LengthSyncDecoder<N> {
+    fn default() -> Self {
+        Self {
+            buf: [0; N],
+            header: 0,
+            expected: 0,
+            len: 0,
+        }
+    }
+}
+
+impl<const N: usize> Decoder for LengthSyncDecoder<N> {
+    fn push(&mut self, byte: u8) -> Option<Result<&[u8], FrameError>> {
+        match self.header {
+            // bytes outside frames are skipped
+            0 => {
+                if byte == LengthSync::SYNC {
+                    self.header = 1;
+                }
+                None
+            }
+            1 => {
+                self.expected = byte as usize;
+                self.header = 2;
+                None
+            }
+            2 => {
+                self.expected |= (byte as usize) << 8;
+                self.len = 0;
+                if self.expected > N {
+                    // wait for the next sync
+                    self.header = 0;
+                    return Some(Err(FrameError::BufferTooSmall));
+                }
+                self.header = LengthSync::HEADER;
+                (self.expected == 0).then(|| {
+                    self.header = 0;
+                    Ok(&self.buf[..0])
+                })
+            }
+            _ => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                if self.len < self.expected {
+                    return None;
+                }
+                self.header = 0;
+                Some(Ok(&self.buf[..self.len]))
+            }
+        }
+    }
+}
+
+impl Framer for LengthSync {
+    type Decoder<const N: usize> = LengthSyncDecoder<N>;
+
+    fn max_encoded_len(payload: usize) -> usize {
+        payload + Self::HEADER
+    }
+
+    fn encode(payload: &[u8], out: &mut [u8]) -> Result<usize, FrameError> {
+        let len = u16::try_from(payload.len()).map_err(|_| FrameError::BufferTooSmall)?;
+        let n = payload.len() + Self::HEADER;
+        let out = out.get_mut(..n).ok_or(FrameError::BufferTooSmall)?;
+        out[0] = Self::SYNC;
+        out[1..Self::HEADER].copy_from_slice(&len.to_le_bytes());
+        out[Self::HEADER..].copy_from_slice(payload);
+        Ok(n)
+    }
+
+    fn decode_in_place(frame: &mut [u8]) -> Result<usize, FrameError> {
+        if frame.len() < Self::HEADER || frame[0] != Self::SYNC {
+            return Err(FrameError::Corrupt);
+        }
+        let len = u16::from_le_bytes([frame[1], frame[2]]) as usize;
+        if frame.len() < Self::HEADER + len {
+            return Err(FrameError::Truncated);
+        }
+        frame.copy_within(Self::HEADER..Self::HEADER + len, 0);
+        Ok(len)
+    }
+}
+
+/// Serialize `t` with a crc and frame it into `out_buf`
+pub fn serialize_crc<'a, F: Framer, T: Serialize, const N: usize>(
+    t: &T,
+    out_buf: &'a mut [u8; N],
+) -> Result<&'a [u8], FrameError> {
+    let mut raw = [0u8; N];
+    let n = ssmarshal::serialize(&mut raw, t).map_err(|_| FrameError::Serialize)?;
+    let crc = CKSUM.checksum(&raw[..n]);
+    raw.get_mut(n..n + 4)
+        .ok_or(FrameError::BufferTooSmall)?
+        .copy_from_slice(&crc.to_le_bytes());
+    let len = F::encode(&raw[..n + 4], out_buf)?;
+    Ok(&out_buf[..len])
+}
+
+/// Check the crc of a decoded payload and deserialize it
+pub fn deserialize_payload<T>(payload: &[u8]) -> Result<T, FrameError>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let n = payload.len().checked_sub(4).ok_or(FrameError::Truncated)?;
+    let (data, crc) = payload.split_at(n);
+    if CKSUM.checksum(data).to_le_bytes() != crc {
+        return Err(FrameError::Crc);
+    }
+    let (t, _) = ssmarshal::deserialize(data).map_err(|_| FrameError::Deserialize)?;
+    Ok(t)
+}
+
+/// Decode a complete frame in place, check the crc and deserialize it
+pub fn deserialize_crc<F: Framer, T>(in_buf: &mut [u8]) -> Result<T, FrameError>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let n = F::decode_in_place(in_buf)?;
+    deserialize_payload(&in_buf[..n])
+}
+
+#[cfg(test)]
+fn round_trip<F: Framer>() {
+    use crate::{Command, Message};
+
+    let cmd = Command::Set(0xc0db, Message::B(0xdb00c0), 0);
+    let mut out_buf = [0u8; 64];
+    let mut frame = serialize_crc::<F, _, 64>(&cmd, &mut out_buf)
+        .unwrap()
+        .to_vec();
+    assert!(frame.len() <= F::max_encoded_len(core::mem::size_of::<Command>() + 4));
+
+    // streaming, after noise and twice in a row
+    let mut stream = vec![0x55, 0x00];
+    stream.extend(&frame);
+    stream.extend(&frame);
+    let mut decoder = F::Decoder::<64>::default();
+    let mut decoded = vec![];
+    for b in stream {
+        // the noise may decode as a frame, with a bad crc
+        if let Some(Ok(payload)) = decoder.push(b) {
+            decoded.extend(deserialize_payload::<Command>(payload).ok());
+        }
+    }
+    assert_eq!(decoded, [cmd, cmd]);
+
+    assert_eq!(deserialize_crc::<F, Command>(&mut frame.clone()), Ok(cmd));
+    let last = frame.len() - 2;
+    frame[last] ^= 0x01;
+    assert!(deserialize_crc::<F, Command>(&mut frame).is_err());
+}
+
+#[test]
+fn framers() {
+    round_trip::<Cobs>();
+    round_trip::<Slip>();
+    round_trip::<LengthSync>();
+}
+
+#[test]
+fn slip() {
+    let mut out = [0u8; 16];
+    let n = Slip::encode(&[1, 0xc0, 2, 0xdb], &mut out).unwrap();
+    assert_eq!(out[..n], [0xc0, 1, 0xdb, 0xdc, 2, 0xdb, 0xdd, 0xc0]);
+    assert_eq!(Slip::decode_in_place(&mut out[..n]), Ok(4));
+    assert_eq!(out[..4], [1, 0xc0, 2, 0xdb]);
+    assert_eq!(
+        Slip::encode(&[0xc0; 8], &mut out),
+        Err(FrameError::BufferTooSmall)
+    );
+
+    // a bad escape spoils the frame, not the next one
+    let mut decoder = SlipDecoder::<8>::default();
+    let mut results = vec![];
+    for b in [0xc0, 1, 0xdb, 0x02, 0xc0, 3, 0xc0] {
+        if let Some(r) = decoder.push(b) {
+            results.push(r.map(|p| p.to_vec()));
+        }
+    }
+    assert_eq!(results, [Err(FrameError::Corrupt), Ok(vec![3])]);
+}
+
+#[test]
+fn length_sync() {
+    let mut out = [0u8; 8];
+    let n = LengthSync::encode(&[1, 2], &mut out).unwrap();
+    assert_eq!(out[..n], [0xa5, 2, 0, 1, 2]);
+    assert_eq!(
+        LengthSync::decode_in_place(&mut out[..4]),
+        Err(FrameError::Truncated)
+    );
+
+    // too long for the decoder, then back in sync
+    let mut decoder = LengthSyncDecoder::<4>::default();
+    let mut results = vec![];
+    for b in [0xa5, 9, 0, 0xa5, 0, 0, 0xa5, 1, 0, 7] {
+        if let Some(r) = decoder.push(b) {
+            results.push(r.map(|p| p.to_vec()));
+        }
+    }
+    assert_eq!(
+        results,
+        [Err(FrameError::BufferTooSmall), Ok(vec![]), Ok(vec![7])]
+    );
+}
+
+#[test]
+fn cobs_wrappers() {
+    use crate::Response;
+
+    let mut out_buf = [0u8; 32];
+    let frame = crate::serialize_crc_cobs(&Response::SetOk, &mut out_buf).unwrap();
+    assert_eq!(frame.last(), Some(&0));
+    let mut frame = frame.to_vec();
+    assert_eq!(crate::deserialize_crc_cobs(&mut frame), Ok(Response::SetOk));
+    assert_eq!(
+        crate::deserialize_crc_cobs::<Response>(&mut [1, 0]),
+        Err(FrameError::Truncated)
+    );
+}
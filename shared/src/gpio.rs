@@ -0,0 +1,225 @@
+//! Remote GPIO, Firmata style
+//!
+//! The host configures, writes and reads pins and may subscribe to edges on
+//! inputs. `Pins` keeps track of the pin modes and levels and validates requests,
+//! the firmware applies them to the hardware and the simulator just uses the
+//! bookkeeping.
+
+use serde_derive::{Deserialize, Serialize};
+
+pub type Pin = u8;
+
+/// GPIO0..=GPIO21
+pub const PIN_COUNT: usize = 22;
+
+/// On board LED of the examples
+pub const LED: Pin = 7;
+/// Button of the examples
+pub const BUTTON: Pin = 9;
+
+/// SPI flash (GPIO12..=GPIO17) and USB (GPIO18, GPIO19), i.e., the link to the host
+pub const RESERVED: core::ops::RangeInclusive<Pin> = 12..=19;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PinMode {
+    Input,
+    InputPullUp,
+    Output,
+    OpenDrain,
+}
+
+impl PinMode {
+    pub fn is_output(self) -> bool {
+        matches!(self, PinMode::Output | PinMode::OpenDrain)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Trigger {
+    Rising,
+    Falling,
+    AnyEdge,
+}
+
+impl Trigger {
+    pub fn matches(self, high: bool) -> bool {
+        match self {
+            Trigger::Rising => high,
+            Trigger::Falling => !high,
+            Trigger::AnyEdge => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GpioError {
+    /// Not a GPIO of the ESP32-C3
+    InvalidPin,
+    /// Used by flash or USB
+    Reserved,
+    /// The pin has not been configured
+    NotConfigured,
+    /// Writing an input or subscribing to an output
+    WrongMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PinState {
+    mode: PinMode,
+    high: bool,
+    trigger: Option<Trigger>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pins {
+    pins: [Option<PinState>; PIN_COUNT],
+}
+
+impl Default for Pins {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pins {
+    pub const fn new() -> Self {
+        Self {
+            pins: [None; PIN_COUNT],
+        }
+    }
+
+    /// Check that `pin` may be used
+    pub fn validate(pin: Pin) -> Result<(), GpioError> {
+        if pin as usize >= PIN_COUNT {
+            Err(GpioError::InvalidPin)
+        } else if RESERVED.contains(&pin) {
+            Err(GpioError::Reserved)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn state_mut(&mut self, pin: Pin) -> Result<&mut PinState, GpioError> {
+        Self::validate(pin)?;
+        self.pins[pin as usize]
+            .as_mut()
+            .ok_or(GpioError::NotConfigured)
+    }
+
+    /// (Re)configure a pin, outputs start low and subscriptions are dropped
+    pub fn configure(&mut self, pin: Pin, mode: PinMode) -> Result<(), GpioError> {
+        Self::validate(pin)?;
+        self.pins[pin as usize] = Some(PinState {
+            mode,
+            // a pulled up input reads high until driven
+            high: mode == PinMode::InputPullUp,
+            trigger: None,
+        });
+        Ok(())
+    }
+
+    pub fn mode(&self, pin: Pin) -> Result<PinMode, GpioError> {
+        Self::validate(pin)?;
+        self.pins[pin as usize]
+            .map(|s| s.mode)
+            .ok_or(GpioError::NotConfigured)
+    }
+
+    pub fn write(&mut self, pin: Pin, high: bool) -> Result<(), GpioError> {
+        let state = self.state_mut(pin)?;
+        if !state.mode.is_output() {
+            return Err(GpioError::WrongMode);
+        }
+        state.high = high;
+        Ok(())
+    }
+
+    /// Last known level, outputs read back what was written
+    pub fn read(&self, pin: Pin) -> Result<bool, GpioError> {
+        Self::validate(pin)?;
+        self.pins[pin as usize]
+            .map(|s| s.high)
+            .ok_or(GpioError::NotConfigured)
+    }
+
+    /// Report edges on an input, `None` unsubscribes
+    pub fn subscribe(&mut self, pin: Pin, trigger: Option<Trigger>) -> Result<(), GpioError> {
+        let state = self.state_mut(pin)?;
+        if state.mode.is_output() {
+            return Err(GpioError::WrongMode);
+        }
+        state.trigger = trigger;
+        Ok(())
+    }
+
+    /// Record the level of an input (from the pin interrupt or the simulation),
+    /// returns the level if it changed and the pin is subscribed to that edge
+    pub fn input(&mut self, pin: Pin, high: bool) -> Option<bool> {
+        let state = self.state_mut(pin).ok()?;
+        if state.mode.is_output() || state.high == high {
+            return None;
+        }
+        state.high = high;
+        state.trigger.filter(|t| t.matches(high)).map(|_| high)
+    }
+}
+
+#[test]
+fn validation() {
+    let mut pins = Pins::new();
+    assert_eq!(
+        pins.configure(22, PinMode::Output),
+        Err(GpioError::InvalidPin)
+    );
+    assert_eq!(pins.configure(18, PinMode::Input), Err(GpioError::Reserved));
+    assert_eq!(pins.write(LED, true), Err(GpioError::NotConfigured));
+    assert_eq!(pins.mode(BUTTON), Err(GpioError::NotConfigured));
+
+    pins.configure(LED, PinMode::Output).unwrap();
+    pins.configure(BUTTON, PinMode::InputPullUp).unwrap();
+    assert_eq!(pins.write(BUTTON, false), Err(GpioError::WrongMode));
+    assert_eq!(
+        pins.subscribe(LED, Some(Trigger::AnyEdge)),
+        Err(GpioError::WrongMode)
+    );
+}
+
+#[test]
+fn write_and_read() {
+    let mut pins = Pins::new();
+    pins.configure(LED, PinMode::OpenDrain).unwrap();
+    assert_eq!(pins.read(LED), Ok(false));
+    pins.write(LED, true).unwrap();
+    assert_eq!(pins.read(LED), Ok(true));
+
+    // outputs ignore external levels
+    assert_eq!(pins.input(LED, false), None);
+    assert_eq!(pins.read(LED), Ok(true));
+
+    // reconfiguring resets the level
+    pins.configure(LED, PinMode::Output).unwrap();
+    assert_eq!(pins.read(LED), Ok(false));
+}
+
+#[test]
+fn edges() {
+    let mut pins = Pins::new();
+    pins.configure(BUTTON, PinMode::InputPullUp).unwrap();
+    assert_eq!(pins.read(BUTTON), Ok(true));
+
+    // not subscribed, the level is still tracked
+    assert_eq!(pins.input(BUTTON, false), None);
+    assert_eq!(pins.read(BUTTON), Ok(false));
+
+    pins.subscribe(BUTTON, Some(Trigger::Falling)).unwrap();
+    assert_eq!(pins.input(BUTTON, true), None);
+    assert_eq!(pins.input(BUTTON, false), Some(false));
+    // no change, no edge
+    assert_eq!(pins.input(BUTTON, false), None);
+
+    pins.subscribe(BUTTON, Some(Trigger::AnyEdge)).unwrap();
+    assert_eq!(pins.input(BUTTON, true), Some(true));
+
+    pins.subscribe(BUTTON, None).unwrap();
+    assert_eq!(pins.input(BUTTON, false), None);
+}
@@ -0,0 +1,259 @@
+//! Signed firmware images
+//!
+//! A packed image is a header followed by the payload (the firmware binary):
+//!
+//! | offset | field                                            |
+//! |--------|--------------------------------------------------|
+//! | 0      | magic `b"SIMG"`                                  |
+//! | 4      | version, little endian u32                       |
+//! | 8      | payload length, little endian u32                |
+//! | 12     | SHA-256 of the payload                           |
+//! | 44     | Ed25519 signature of bytes 0..44                 |
+//!
+//! The target accepts an image if it is signed by the key it was built with
+//! and its version is not below the running one (no rollback).
+
+use crate::update::FlashStorage;
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+pub const MAGIC: [u8; 4] = *b"SIMG";
+/// The signed part of the header
+pub const SIGNED_LEN: usize = 44;
+pub const HEADER_LEN: usize = SIGNED_LEN + 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    BadMagic,
+    Truncated,
+    /// Older than the minimum version
+    Rollback,
+    /// The payload does not match the hash
+    Hash,
+    Signature,
+    BadKey,
+    Flash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub version: u32,
+    pub length: u32,
+    pub sha256: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+impl ImageHeader {
+    /// The header of `payload`, not yet signed
+    pub fn new(version: u32, payload: &[u8]) -> Self {
+        Self {
+            version,
+            length: payload.len() as u32,
+            sha256: Sha256::digest(payload).into(),
+            signature: [0; 64],
+        }
+    }
+
+    /// The bytes covered by the signature
+    pub fn signed_bytes(&self) -> [u8; SIGNED_LEN] {
+        let mut out = [0; SIGNED_LEN];
+        out[0..4].copy_from_slice(&MAGIC);
+        out[4..8].copy_from_slice(&self.version.to_le_bytes());
+        out[8..12].copy_from_slice(&self.length.to_le_bytes());
+        out[12..44].copy_from_slice(&self.sha256);
+        out
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut out = [0; HEADER_LEN];
+        out[..SIGNED_LEN].copy_from_slice(&self.signed_bytes());
+        out[SIGNED_LEN..].copy_from_slice(&self.signature);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        let bytes = bytes.get(..HEADER_LEN).ok_or(ImageError::Truncated)?;
+        if bytes[0..4] != MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Ok(Self {
+            version: u32_at(4),
+            length: u32_at(8),
+            sha256: bytes[12..44].try_into().unwrap(),
+            signature: bytes[44..].try_into().unwrap(),
+        })
+    }
+
+    /// Check the signature and the version, not the payload
+    pub fn verify(&self, public_key: &[u8; 32], min_version: u32) -> Result<(), ImageError> {
+        let key = VerifyingKey::from_bytes(public_key).map_err(|_| ImageError::BadKey)?;
+        key.verify_strict(
+            &self.signed_bytes(),
+            &Signature::from_bytes(&self.signature),
+        )
+        .map_err(|_| ImageError::Signature)?;
+        if self.version < min_version {
+            return Err(ImageError::Rollback);
+        }
+        Ok(())
+    }
+}
+
+/// Hashes the payload as it arrives, e.g., chunk by chunk
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    header: ImageHeader,
+    hasher: Sha256,
+    len: u32,
+}
+
+impl Verifier {
+    pub fn new(header: ImageHeader) -> Self {
+        Self {
+            header,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.len += data.len() as u32;
+    }
+
+    /// Check header and payload
+    pub fn finish(self, public_key: &[u8; 32], min_version: u32) -> Result<(), ImageError> {
+        self.header.verify(public_key, min_version)?;
+        if self.len != self.header.length {
+            return Err(ImageError::Truncated);
+        }
+        if self.hasher.finalize()[..] != self.header.sha256 {
+            return Err(ImageError::Hash);
+        }
+        Ok(())
+    }
+}
+
+/// Verify a packed image stored at `offset`, returns its header
+pub fn verify_flash<S: FlashStorage>(
+    storage: &mut S,
+    offset: u32,
+    public_key: &[u8; 32],
+    min_version: u32,
+) -> Result<ImageHeader, ImageError> {
+    let mut buf = [0u8; HEADER_LEN];
+    storage
+        .read(offset, &mut buf)
+        .map_err(|_| ImageError::Flash)?;
+    let header = ImageHeader::from_bytes(&buf)?;
+    // fail early on a bad signature, before hashing
+    header.verify(public_key, min_version)?;
+
+    let mut verifier = Verifier::new(header);
+    let start = offset + HEADER_LEN as u32;
+    let mut at = 0;
+    while at < header.length {
+        let buf = &mut buf[..(header.length - at).min(HEADER_LEN as u32) as usize];
+        storage
+            .read(start + at, buf)
+            .map_err(|_| ImageError::Flash)?;
+        verifier.update(buf);
+        at += buf.len() as u32;
+    }
+    verifier.finish(public_key, min_version)?;
+    Ok(header)
+}
+
+/// RFC 8032 section 7.1, test 1
+#[cfg(test)]
+const SECRET: [u8; 32] = [
+    0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92, 0xec, 0x2c, 0xc4,
+    0x44, 0x49, 0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b, 0xac, 0x03, 0x1c, 0xae, 0x7f, 0x60,
+];
+
+#[cfg(test)]
+pub(crate) fn signed(version: u32, payload: &[u8]) -> (ImageHeader, [u8; 32]) {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    let key = SigningKey::from_bytes(&SECRET);
+    let mut header = ImageHeader::new(version, payload);
+    header.signature = key.sign(&header.signed_bytes()).to_bytes();
+    (header, key.verifying_key().to_bytes())
+}
+
+#[test]
+fn known_vectors() {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    // RFC 8032 test 1, the empty message
+    let key = SigningKey::from_bytes(&SECRET);
+    assert_eq!(
+        key.verifying_key().to_bytes()[..4],
+        [0xd7, 0x5a, 0x98, 0x01]
+    );
+    let signature = key.sign(&[]).to_bytes();
+    assert_eq!(signature[..4], [0xe5, 0x56, 0x43, 0x00]);
+    assert_eq!(signature[60..], [0x8e, 0x7a, 0x10, 0x0b]);
+
+    // FIPS 180-2, "abc"
+    let header = ImageHeader::new(1, b"abc");
+    assert_eq!(header.sha256[..4], [0xba, 0x78, 0x16, 0xbf]);
+    assert_eq!(header.sha256[28..], [0xf2, 0x00, 0x15, 0xad]);
+}
+
+#[test]
+fn tampering_and_rollback() {
+    let payload = [0x42u8; 100];
+    let (header, public_key) = signed(3, &payload);
+    let bytes = header.to_bytes();
+    assert_eq!(ImageHeader::from_bytes(&bytes), Ok(header));
+
+    let mut verifier = Verifier::new(header);
+    verifier.update(&payload[..50]);
+    verifier.update(&payload[50..]);
+    assert_eq!(verifier.clone().finish(&public_key, 3), Ok(()));
+    assert_eq!(verifier.finish(&public_key, 4), Err(ImageError::Rollback));
+
+    // a changed payload byte
+    let mut verifier = Verifier::new(header);
+    verifier.update(&[0x42; 99]);
+    verifier.update(&[0x43]);
+    assert_eq!(verifier.finish(&public_key, 0), Err(ImageError::Hash));
+
+    // a changed version, e.g., to get around the rollback check
+    let mut bytes = header.to_bytes();
+    bytes[4] = 9;
+    let tampered = ImageHeader::from_bytes(&bytes).unwrap();
+    assert_eq!(tampered.verify(&public_key, 0), Err(ImageError::Signature));
+
+    // signed with another key
+    let mut other = public_key;
+    other[0] ^= 1;
+    assert!(header.verify(&other, 0).is_err());
+
+    bytes[0] = b'X';
+    assert_eq!(ImageHeader::from_bytes(&bytes), Err(ImageError::BadMagic));
+    assert_eq!(
+        ImageHeader::from_bytes(&bytes[..10]),
+        Err(ImageError::Truncated)
+    );
+}
+
+#[test]
+fn in_flash() {
+    use crate::update::RamFlash;
+
+    let payload: Vec<u8> = (0..300u32).map(|i| i as u8).collect();
+    let (header, public_key) = signed(2, &payload);
+    let mut flash = RamFlash::<1024>::new(256);
+    flash.data[256..256 + HEADER_LEN].copy_from_slice(&header.to_bytes());
+    flash.data[256 + HEADER_LEN..256 + HEADER_LEN + 300].copy_from_slice(&payload);
+    assert_eq!(verify_flash(&mut flash, 256, &public_key, 1), Ok(header));
+
+    flash.data[256 + HEADER_LEN + 299] ^= 1;
+    assert_eq!(
+        verify_flash(&mut flash, 256, &public_key, 1),
+        Err(ImageError::Hash)
+    );
+}
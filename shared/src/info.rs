@@ -0,0 +1,152 @@
+//! Device information, answered to `Command::GetInfo`
+//!
+//! The git hash and build time are embedded by the build script of this
+//! crate, the rest is filled in by the firmware (or the simulator).
+
+use crate::bus::Bytes;
+use crate::date_time::UtcDateTime;
+use crate::log::text;
+use core::fmt::{self, Display};
+use serde_derive::{Deserialize, Serialize};
+
+/// Bumped on incompatible changes to `Command` or `Response`
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Short git hash of the build, "unknown" outside a git checkout
+pub const GIT_HASH: &str = env!("GIT_HASH");
+
+/// Build time, seconds since the Unix epoch
+///
+/// Set when the build script last ran, which is on the first build after a
+/// commit or checkout, not on every rebuild of changed sources.
+pub const BUILD_TIME: u64 = parse(env!("BUILD_TIME"));
+
+const fn parse(digits: &str) -> u64 {
+    let digits = digits.as_bytes();
+    let mut value = 0;
+    let mut i = 0;
+    while i < digits.len() {
+        value = value * 10 + (digits[i] - b'0') as u64;
+        i += 1;
+    }
+    value
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResetReason {
+    PowerOn,
+    /// The reset pin
+    External,
+    /// E.g., after a panic or an update
+    Software,
+    Watchdog,
+    Brownout,
+    DeepSleep,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Info {
+    /// Firmware crate version
+    pub version: Bytes,
+    pub git_hash: Bytes,
+    /// Seconds since the Unix epoch
+    pub build_time: u64,
+    pub protocol: u16,
+    /// The factory MAC address, unique per chip
+    pub mac: [u8; 6],
+    pub uptime_ms: u64,
+    pub reset_reason: ResetReason,
+}
+
+impl Info {
+    /// `version` is the `CARGO_PKG_VERSION` of the firmware
+    pub fn new(version: &str, mac: [u8; 6], uptime_ms: u64, reset_reason: ResetReason) -> Self {
+        Self {
+            version: text(version),
+            git_hash: text(GIT_HASH),
+            build_time: BUILD_TIME,
+            protocol: PROTOCOL_VERSION,
+            mac,
+            uptime_ms,
+            reset_reason,
+        }
+    }
+}
+
+fn as_str(bytes: &Bytes) -> &str {
+    core::str::from_utf8(bytes.as_slice()).unwrap_or("?")
+}
+
+impl Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let built = i64::try_from(self.build_time)
+            .ok()
+            .and_then(|s| s.checked_mul(1_000_000_000))
+            .map(UtcDateTime::from_timestamp_nanos);
+        let m = self.mac;
+        let s = self.uptime_ms / 1000;
+        writeln!(
+            f,
+            "version  {} ({})",
+            as_str(&self.version),
+            as_str(&self.git_hash)
+        )?;
+        match built {
+            Some(built) => writeln!(f, "built    {}", built)?,
+            // past 2262
+            None => writeln!(f, "built    {} s after the epoch", self.build_time)?,
+        }
+        writeln!(f, "protocol {}", self.protocol)?;
+        writeln!(
+            f,
+            "mac      {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        )?;
+        writeln!(
+            f,
+            "uptime   {}d {:02}:{:02}:{:02}.{:03}",
+            s / 86_400,
+            s / 3600 % 24,
+            s / 60 % 60,
+            s % 60,
+            self.uptime_ms % 1000
+        )?;
+        write!(f, "reset    {:?}", self.reset_reason)
+    }
+}
+
+#[test]
+fn info() {
+    let mut info = Info::new(
+        "1.2.3",
+        [0x02, 0, 0, 0xab, 0xcd, 0xef],
+        90_061_001,
+        ResetReason::Watchdog,
+    );
+    // from the build script
+    assert!(info.build_time > 1_700_000_000);
+    assert!(!info.git_hash.as_slice().is_empty());
+    info.git_hash = text("0123456789ab");
+    info.build_time = 1_700_000_000;
+    assert_eq!(
+        info.to_string(),
+        "version  1.2.3 (0123456789ab)\n\
+         built    2023-11-14T22:13:20.000000000Z\n\
+         protocol 1\n\
+         mac      02:00:00:ab:cd:ef\n\
+         uptime   1d 01:01:01.001\n\
+         reset    Watchdog"
+    );
+
+    let mut buf = [0u8; core::mem::size_of::<Info>()];
+    let n = ssmarshal::serialize(&mut buf, &info).unwrap();
+    let (decoded, _) = ssmarshal::deserialize::<Info>(&buf[..n]).unwrap();
+    assert_eq!(decoded, info);
+
+    // not representable as a date
+    info.build_time = u64::MAX;
+    assert!(info
+        .to_string()
+        .contains("built    18446744073709551615 s after the epoch"));
+}
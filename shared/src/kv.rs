@@ -0,0 +1,484 @@
+//! Persistent key-value store on flash
+//!
+//! Values are kept by parameter `Id`, serialized with ssmarshal, in a log
+//! structured store on a `FlashStorage` of at least two erase sectors. One
+//! sector is active, it starts with a header (sequence number, then magic)
+//! and records are appended to it:
+//!
+//! | size | field                                     |
+//! |------|-------------------------------------------|
+//! | 1    | value length, 0xff where nothing written  |
+//! | 4    | key                                       |
+//! | len  | value                                     |
+//! | 4    | `CKSUM` of the above                      |
+//!
+//! The last valid record of a key wins, a record torn by a power cut fails
+//! the crc and is skipped. When the active sector is full, the latest record
+//! of every key is copied to the next sector (round robin, for wear
+//! leveling). Its header is written last, so a power cut during compaction
+//! leaves the old sector active.
+
+use crate::update::FlashStorage;
+use crate::{Id, CKSUM};
+use serde_derive::{Deserialize, Serialize};
+
+/// Largest serialized value
+pub const MAX_VALUE: usize = 64;
+
+const MAGIC: u32 = 0x4b56_5354; // "KVST"
+const SECTOR_HEADER: u32 = 8;
+const RECORD_HEADER: usize = 5;
+const MAX_RECORD: usize = RECORD_HEADER + MAX_VALUE + 4;
+const ERASED: u8 = 0xff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KvError {
+    /// Fewer than two sectors, or sectors too small for a record
+    TooSmall,
+    /// A value serializing to more than `MAX_VALUE` bytes
+    TooLarge,
+    /// The latest values fill a sector
+    Full,
+    Serialize,
+    Deserialize,
+    Flash,
+}
+
+fn flash<E>(_: E) -> KvError {
+    KvError::Flash
+}
+
+enum Entry {
+    /// Nothing written from here
+    End,
+    /// An unreadable length, nothing after it is used
+    Corrupt,
+    /// Failed the crc, the next record follows
+    Torn {
+        next: u32,
+    },
+    Valid {
+        key: Id,
+        len: usize,
+        next: u32,
+    },
+}
+
+/// The store, writing through `S`
+#[derive(Debug)]
+pub struct KvStore<S> {
+    pub storage: S,
+    sectors: u32,
+    sector_size: u32,
+    active: u32,
+    seq: u32,
+    /// Where the next record goes, relative to the active sector
+    end: u32,
+}
+
+/// Panics if the storage is too small
+impl<S: FlashStorage + Default> Default for KvStore<S> {
+    fn default() -> Self {
+        Self::mount(S::default()).expect("storage too small")
+    }
+}
+
+impl<S: FlashStorage> KvStore<S> {
+    /// Picks the newest sector, or starts an empty store
+    pub fn mount(mut storage: S) -> Result<Self, KvError> {
+        let sector_size = storage.erase_size();
+        let sectors = storage.capacity() / sector_size.max(1);
+        if sectors < 2 || sector_size < SECTOR_HEADER + MAX_RECORD as u32 {
+            return Err(KvError::TooSmall);
+        }
+        let mut newest: Option<(u32, u32)> = None;
+        for sector in 0..sectors {
+            let mut header = [0u8; SECTOR_HEADER as usize];
+            storage
+                .read(sector * sector_size, &mut header)
+                .map_err(flash)?;
+            let seq = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+            let magic = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            if magic == MAGIC && newest.is_none_or(|(_, s)| seq > s) {
+                newest = Some((sector, seq));
+            }
+        }
+
+        let mut store = Self {
+            storage,
+            sectors,
+            sector_size,
+            active: 0,
+            seq: 0,
+            end: SECTOR_HEADER,
+        };
+        match newest {
+            Some((active, seq)) => {
+                store.active = active;
+                store.seq = seq;
+                store.end = store.scan_end()?;
+            }
+            None => {
+                store.erase(0)?;
+                store.activate(0, 0)?;
+            }
+        }
+        Ok(store)
+    }
+
+    /// The latest value of `key`
+    pub fn get<V>(&mut self, key: Id) -> Result<Option<V>, KvError>
+    where
+        V: for<'de> serde::Deserialize<'de>,
+    {
+        let Some(at) = self.last(key)? else {
+            return Ok(None);
+        };
+        let mut buf = [0; MAX_RECORD];
+        match self.entry(at, &mut buf)? {
+            Entry::Valid { len, .. } => value(&buf[RECORD_HEADER..RECORD_HEADER + len]).map(Some),
+            _ => Err(KvError::Flash),
+        }
+    }
+
+    /// Store `value` for `key`, unless it is unchanged
+    pub fn set<V: serde::Serialize>(&mut self, key: Id, value: &V) -> Result<(), KvError> {
+        let mut record = [0; MAX_RECORD];
+        let len =
+            ssmarshal::serialize(&mut record[RECORD_HEADER..RECORD_HEADER + MAX_VALUE], value)
+                .map_err(|e| match e {
+                    ssmarshal::Error::EndOfStream => KvError::TooLarge,
+                    _ => KvError::Serialize,
+                })?;
+        record[0] = len as u8;
+        record[1..RECORD_HEADER].copy_from_slice(&key.to_le_bytes());
+        let crc = CKSUM.checksum(&record[..RECORD_HEADER + len]);
+        record[RECORD_HEADER + len..][..4].copy_from_slice(&crc.to_le_bytes());
+        let record = &record[..RECORD_HEADER + len + 4];
+
+        if let Some(at) = self.last(key)? {
+            let mut buf = [0; MAX_RECORD];
+            self.entry(at, &mut buf)?;
+            if buf[..record.len()] == *record {
+                return Ok(());
+            }
+        }
+        if self.end + record.len() as u32 > self.sector_size {
+            self.compact(|_| true)?;
+            if self.end + record.len() as u32 > self.sector_size {
+                return Err(KvError::Full);
+            }
+        }
+        let at = self.active * self.sector_size + self.end;
+        // the end moves on even if the write fails half way, the rest is torn
+        self.end += record.len() as u32;
+        self.storage.write(at, record).map_err(flash)
+    }
+
+    /// Calls `f` with the latest value of every key
+    pub fn entries<V>(&mut self, mut f: impl FnMut(Id, V)) -> Result<(), KvError>
+    where
+        V: for<'de> serde::Deserialize<'de>,
+    {
+        let mut buf = [0; MAX_RECORD];
+        let mut at = SECTOR_HEADER;
+        loop {
+            match self.entry(at, &mut buf)? {
+                Entry::End | Entry::Corrupt => return Ok(()),
+                Entry::Torn { next } => at = next,
+                Entry::Valid { key, len, next } => {
+                    if self.last(key)? == Some(at) {
+                        f(key, value(&buf[RECORD_HEADER..RECORD_HEADER + len])?);
+                    }
+                    at = next;
+                }
+            }
+        }
+    }
+
+    /// Remove all values, like a compaction keeping nothing
+    pub fn clear(&mut self) -> Result<(), KvError> {
+        self.compact(|_| false)
+    }
+
+    /// Reads the record at `at` of the active sector into `buf`
+    fn entry(&mut self, at: u32, buf: &mut [u8; MAX_RECORD]) -> Result<Entry, KvError> {
+        let base = self.active * self.sector_size;
+        let left = (self.sector_size - at) as usize;
+        if left < RECORD_HEADER + 4 {
+            return Ok(Entry::End);
+        }
+        self.storage
+            .read(base + at, &mut buf[..RECORD_HEADER])
+            .map_err(flash)?;
+        if buf[..RECORD_HEADER].iter().all(|b| *b == ERASED) {
+            return Ok(Entry::End);
+        }
+        let len = buf[0] as usize;
+        let n = RECORD_HEADER + len + 4;
+        if len > MAX_VALUE || n > left {
+            return Ok(Entry::Corrupt);
+        }
+        self.storage
+            .read(base + at + RECORD_HEADER as u32, &mut buf[RECORD_HEADER..n])
+            .map_err(flash)?;
+        let next = at + n as u32;
+        let crc = u32::from_le_bytes(buf[n - 4..n].try_into().unwrap());
+        if crc != CKSUM.checksum(&buf[..n - 4]) {
+            return Ok(Entry::Torn { next });
+        }
+        let key = Id::from_le_bytes(buf[1..RECORD_HEADER].try_into().unwrap());
+        Ok(Entry::Valid { key, len, next })
+    }
+
+    /// Position of the latest valid record of `key`
+    fn last(&mut self, key: Id) -> Result<Option<u32>, KvError> {
+        let mut buf = [0; MAX_RECORD];
+        let mut at = SECTOR_HEADER;
+        let mut last = None;
+        loop {
+            match self.entry(at, &mut buf)? {
+                Entry::End | Entry::Corrupt => return Ok(last),
+                Entry::Torn { next } => at = next,
+                Entry::Valid { key: k, next, .. } => {
+                    if k == key {
+                        last = Some(at);
+                    }
+                    at = next;
+                }
+            }
+        }
+    }
+
+    /// Where appends go, a corrupt record fills the sector
+    fn scan_end(&mut self) -> Result<u32, KvError> {
+        let mut buf = [0; MAX_RECORD];
+        let mut at = SECTOR_HEADER;
+        loop {
+            match self.entry(at, &mut buf)? {
+                Entry::End => return Ok(at),
+                Entry::Corrupt => return Ok(self.sector_size),
+                Entry::Torn { next } | Entry::Valid { next, .. } => at = next,
+            }
+        }
+    }
+
+    /// Erase a sector, invalidating its header first in case the erase is cut
+    fn erase(&mut self, sector: u32) -> Result<(), KvError> {
+        let base = sector * self.sector_size;
+        self.storage.write(base + 4, &[0; 4]).map_err(flash)?;
+        self.storage
+            .erase(base, base + self.sector_size)
+            .map_err(flash)
+    }
+
+    /// Write the header of an erased sector, sequence number before magic
+    fn activate(&mut self, sector: u32, seq: u32) -> Result<(), KvError> {
+        let base = sector * self.sector_size;
+        self.storage
+            .write(base, &seq.to_le_bytes())
+            .map_err(flash)?;
+        self.storage
+            .write(base + 4, &MAGIC.to_le_bytes())
+            .map_err(flash)?;
+        self.active = sector;
+        self.seq = seq;
+        Ok(())
+    }
+
+    /// Copy the latest records of the keys to `keep` to the next sector
+    fn compact(&mut self, keep: impl Fn(Id) -> bool) -> Result<(), KvError> {
+        let next = (self.active + 1) % self.sectors;
+        let base = next * self.sector_size;
+        self.erase(next)?;
+
+        let mut buf = [0; MAX_RECORD];
+        let mut at = SECTOR_HEADER;
+        let mut end = SECTOR_HEADER;
+        loop {
+            match self.entry(at, &mut buf)? {
+                Entry::End | Entry::Corrupt => break,
+                Entry::Torn { next } => at = next,
+                Entry::Valid { key, next, .. } => {
+                    if keep(key) && self.last(key)? == Some(at) {
+                        let n = next - at;
+                        self.storage
+                            .write(base + end, &buf[..n as usize])
+                            .map_err(flash)?;
+                        end += n;
+                    }
+                    at = next;
+                }
+            }
+        }
+        self.activate(next, self.seq.wrapping_add(1))?;
+        self.end = end;
+        Ok(())
+    }
+}
+
+fn value<V>(bytes: &[u8]) -> Result<V, KvError>
+where
+    V: for<'de> serde::Deserialize<'de>,
+{
+    ssmarshal::deserialize(bytes)
+        .map(|(v, _)| v)
+        .map_err(|_| KvError::Deserialize)
+}
+
+/// Fails every write and erase from the `cut`th on, a failing write half done
+#[cfg(test)]
+#[derive(Debug)]
+struct PowerCut<'a, S> {
+    storage: &'a mut S,
+    cut: usize,
+}
+
+#[cfg(test)]
+impl<S: FlashStorage> FlashStorage for PowerCut<'_, S> {
+    type Error = ();
+
+    fn capacity(&self) -> u32 {
+        self.storage.capacity()
+    }
+
+    fn erase_size(&self) -> u32 {
+        self.storage.erase_size()
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), ()> {
+        if self.cut == 0 {
+            return Err(());
+        }
+        self.cut -= 1;
+        self.storage.erase(from, to).map_err(|_| ())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), ()> {
+        if self.cut == 0 {
+            let _ = self.storage.write(offset, &data[..data.len() / 2]);
+            return Err(());
+        }
+        self.cut -= 1;
+        self.storage.write(offset, data).map_err(|_| ())
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), ()> {
+        self.storage.read(offset, buf).map_err(|_| ())
+    }
+}
+
+#[test]
+fn set_get_compact() {
+    use crate::update::RamFlash;
+    use crate::Message;
+
+    let mut store = KvStore::mount(RamFlash::<1024>::new(256)).unwrap();
+    assert_eq!(store.get::<u32>(1), Ok(None));
+    store.set(1, &Message::C(1.5)).unwrap();
+    store.set(2, &Message::B(7)).unwrap();
+    assert_eq!(store.get(1), Ok(Some(Message::C(1.5))));
+
+    // unchanged values are not written again
+    let end = store.end;
+    store.set(2, &Message::B(7)).unwrap();
+    assert_eq!(store.end, end);
+
+    // many updates wrap through all sectors
+    for i in 0..200u32 {
+        store.set(3 + i % 4, &i).unwrap();
+    }
+    assert_eq!(store.seq, 16);
+    let mut store = KvStore::mount(store.storage).unwrap();
+    assert_eq!(store.get(1), Ok(Some(Message::C(1.5))));
+    let mut entries = vec![];
+    store.entries(|k, v: u32| entries.push((k, v))).unwrap();
+    assert_eq!(entries.len(), 6);
+    assert!(entries.contains(&(6, 199)));
+
+    store.clear().unwrap();
+    assert_eq!(store.get::<u32>(6), Ok(None));
+    let mut store = KvStore::mount(store.storage).unwrap();
+    assert_eq!(store.get::<u32>(6), Ok(None));
+
+    assert_eq!(
+        KvStore::mount(RamFlash::<256>::new(256)).err(),
+        Some(KvError::TooSmall)
+    );
+}
+
+#[test]
+fn power_cuts() {
+    use crate::update::RamFlash;
+    use std::collections::BTreeMap;
+
+    #[derive(Clone, Copy)]
+    enum Op {
+        Set(Id, u32),
+        Clear,
+    }
+    // enough updates for a few compactions, with a clear in between
+    let ops: Vec<Op> = (0..60u32)
+        .map(|i| {
+            if i == 40 {
+                Op::Clear
+            } else {
+                Op::Set(i % 3, i)
+            }
+        })
+        .collect();
+    let apply = |values: &mut BTreeMap<Id, u32>, op: Op| match op {
+        Op::Set(k, v) => {
+            values.insert(k, v);
+        }
+        Op::Clear => values.clear(),
+    };
+
+    for cut in 0.. {
+        let mut flash = RamFlash::<768>::new(256);
+        // the values after the completed operations
+        let mut done = BTreeMap::new();
+        let mut pending = None;
+        let mut complete = false;
+        if let Ok(mut store) = KvStore::mount(PowerCut {
+            storage: &mut flash,
+            cut,
+        }) {
+            pending = ops.iter().copied().find(|&op| {
+                let result = match op {
+                    Op::Set(k, v) => store.set(k, &v),
+                    Op::Clear => store.clear(),
+                };
+                if result.is_ok() {
+                    apply(&mut done, op);
+                }
+                result.is_err()
+            });
+            complete = pending.is_none();
+        }
+
+        // after the reset, every key holds its value before or after the cut
+        let mut store = KvStore::mount(flash).unwrap();
+        let mut after = done.clone();
+        if let Some(op) = pending {
+            apply(&mut after, op);
+        }
+        for k in 0..3 {
+            let v = store.get::<u32>(k).unwrap();
+            assert!(
+                v == done.get(&k).copied() || v == after.get(&k).copied(),
+                "cut {} key {}",
+                cut,
+                k
+            );
+        }
+        // and the store keeps working
+        store.set(0, &1234u32).unwrap();
+        let mut store = KvStore::mount(store.storage).unwrap();
+        assert_eq!(store.get(0), Ok(Some(1234u32)));
+
+        if complete {
+            break;
+        }
+    }
+}
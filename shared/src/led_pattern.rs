@@ -0,0 +1,274 @@
+//! LED pattern sequencer for status indication
+//!
+//! A `LedPattern` is a declarative list of on/off steps, repeated a number of
+//! times or forever. `LedSequencer::tick` returns the pin level for a given time
+//! (milliseconds from any monotonic source) and the deadline of the next change.
+
+use serde_derive::{Deserialize, Serialize};
+
+pub const MAX_STEPS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Step {
+    pub on: bool,
+    pub ms: u16,
+}
+
+impl Step {
+    pub const fn on(ms: u16) -> Self {
+        Self { on: true, ms }
+    }
+
+    pub const fn off(ms: u16) -> Self {
+        Self { on: false, ms }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedPattern {
+    steps: [Step; MAX_STEPS],
+    len: u8,
+    /// Number of runs through the steps, None for forever
+    repeat: Option<u16>,
+    /// Level once done
+    rest: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InvalidPattern;
+
+impl LedPattern {
+    /// Fails if there are no steps, too many steps or a zero total duration
+    pub fn new(steps: &[Step], repeat: Option<u16>) -> Result<Self, InvalidPattern> {
+        if steps.len() > MAX_STEPS {
+            return Err(InvalidPattern);
+        }
+        let mut pattern = Self {
+            steps: [Step::default(); MAX_STEPS],
+            len: steps.len() as u8,
+            repeat,
+            rest: false,
+        };
+        pattern.steps[..steps.len()].copy_from_slice(steps);
+        pattern.validate()
+    }
+
+    /// Constant level
+    pub fn solid(on: bool) -> Self {
+        Self {
+            rest: on,
+            ..Self::new(&[Step { on, ms: 1 }], Some(0)).unwrap()
+        }
+    }
+
+    /// Level once all repeats are done (off by default)
+    pub fn with_rest(self, rest: bool) -> Self {
+        Self { rest, ..self }
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps[..self.len as usize]
+    }
+
+    /// Check a pattern, e.g., after deserializing it
+    pub fn validate(self) -> Result<Self, InvalidPattern> {
+        let len = self.len as usize;
+        if len == 0 || len > MAX_STEPS || self.steps().iter().all(|s| s.ms == 0) {
+            Err(InvalidPattern)
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+/// Predefined patterns, selectable from the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BuiltinPattern {
+    Off,
+    On,
+    /// 1 Hz, like `blinky`
+    Blink,
+    /// 5 Hz, e.g., for errors
+    FastBlink,
+    /// Double pulse every second
+    Heartbeat,
+    /// n (1..=7) short blinks and a pause, forever
+    BlinkCode(u8),
+}
+
+impl From<BuiltinPattern> for LedPattern {
+    fn from(builtin: BuiltinPattern) -> Self {
+        use BuiltinPattern::*;
+        match builtin {
+            Off => LedPattern::solid(false),
+            On => LedPattern::solid(true),
+            Blink => LedPattern::new(&[Step::on(500), Step::off(500)], None).unwrap(),
+            FastBlink => LedPattern::new(&[Step::on(100), Step::off(100)], None).unwrap(),
+            Heartbeat => LedPattern::new(
+                &[Step::on(100), Step::off(100), Step::on(100), Step::off(700)],
+                None,
+            )
+            .unwrap(),
+            BlinkCode(n) => {
+                let n = n.clamp(1, (MAX_STEPS / 2) as u8 - 1) as usize;
+                let mut steps = [Step::default(); MAX_STEPS];
+                for pair in steps[..2 * n].chunks_mut(2) {
+                    pair[0] = Step::on(200);
+                    pair[1] = Step::off(200);
+                }
+                steps[2 * n] = Step::off(1000);
+                LedPattern::new(&steps[..2 * n + 1], None).unwrap()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LedSequencer {
+    pattern: LedPattern,
+    index: usize,
+    run: u16,
+    deadline: Option<u64>, // end of the current step, None when done
+}
+
+impl LedSequencer {
+    pub fn new(pattern: LedPattern, now: u64) -> Self {
+        let mut seq = Self {
+            pattern: LedPattern::solid(false),
+            index: 0,
+            run: 0,
+            deadline: None,
+        };
+        // an invalid pattern leaves the LED off
+        let _ = seq.start(pattern, now);
+        seq
+    }
+
+    /// Restart with a new pattern
+    pub fn start(&mut self, pattern: LedPattern, now: u64) -> Result<(), InvalidPattern> {
+        self.pattern = pattern.validate()?;
+        self.index = 0;
+        self.run = 0;
+        self.deadline =
+            (self.pattern.repeat != Some(0)).then(|| now + self.pattern.steps[0].ms as u64);
+        Ok(())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.deadline.is_none()
+    }
+
+    /// Pin level at `now` and the time of the next change (None when done)
+    pub fn tick(&mut self, now: u64) -> (bool, Option<u64>) {
+        while let Some(deadline) = self.deadline {
+            if now < deadline {
+                break;
+            }
+            self.index += 1;
+            if self.index == self.pattern.len as usize {
+                self.index = 0;
+                self.run = self.run.saturating_add(1);
+                if Some(self.run) == self.pattern.repeat {
+                    self.deadline = None;
+                    break;
+                }
+            }
+            self.deadline = Some(deadline + self.pattern.steps[self.index].ms as u64);
+        }
+        match self.deadline {
+            Some(_) => (self.pattern.steps[self.index].on, self.deadline),
+            None => (self.pattern.rest, None),
+        }
+    }
+}
+
+// Levels sampled every ms in [0, end)
+#[cfg(test)]
+fn levels(seq: &mut LedSequencer, end: u64) -> Vec<(u64, bool)> {
+    // run length encoded as (start, level)
+    let mut out: Vec<(u64, bool)> = Vec::new();
+    for now in 0..end {
+        let (on, _) = seq.tick(now);
+        if out.last().map(|(_, l)| *l) != Some(on) {
+            out.push((now, on));
+        }
+    }
+    out
+}
+
+#[test]
+fn blink_timing() {
+    let mut seq = LedSequencer::new(BuiltinPattern::Blink.into(), 0);
+    assert_eq!(
+        levels(&mut seq, 2000),
+        [(0, true), (500, false), (1000, true), (1500, false)]
+    );
+}
+
+#[test]
+fn next_deadline() {
+    let mut seq = LedSequencer::new(BuiltinPattern::Heartbeat.into(), 1000);
+    assert_eq!(seq.tick(1000), (true, Some(1100)));
+    assert_eq!(seq.tick(1100), (false, Some(1200)));
+    // late tick skips the missed steps
+    assert_eq!(seq.tick(1350), (false, Some(2000)));
+    assert_eq!(seq.tick(2000), (true, Some(2100)));
+}
+
+#[test]
+fn repeats_then_rests() {
+    let pattern = LedPattern::new(&[Step::on(10), Step::off(10)], Some(2))
+        .unwrap()
+        .with_rest(true);
+    let mut seq = LedSequencer::new(pattern, 0);
+    assert_eq!(
+        levels(&mut seq, 100),
+        [(0, true), (10, false), (20, true), (30, false), (40, true)]
+    );
+    assert!(seq.is_done());
+    assert_eq!(seq.tick(1000), (true, None));
+}
+
+#[test]
+fn blink_code() {
+    let mut seq = LedSequencer::new(BuiltinPattern::BlinkCode(3).into(), 0);
+    assert_eq!(
+        levels(&mut seq, 2400),
+        [
+            (0, true),
+            (200, false),
+            (400, true),
+            (600, false),
+            (800, true),
+            (1000, false),
+            (2200, true)
+        ]
+    );
+}
+
+#[test]
+fn solid() {
+    let mut seq = LedSequencer::new(BuiltinPattern::On.into(), 0);
+    assert_eq!(seq.tick(0), (true, None));
+    assert_eq!(seq.tick(10_000), (true, None));
+}
+
+#[test]
+fn invalid_patterns() {
+    assert_eq!(LedPattern::new(&[], None), Err(InvalidPattern));
+    assert_eq!(
+        LedPattern::new(&[Step::on(0), Step::off(0)], None),
+        Err(InvalidPattern)
+    );
+    assert_eq!(
+        LedPattern::new(&[Step::on(1); MAX_STEPS + 1], None),
+        Err(InvalidPattern)
+    );
+
+    let mut seq = LedSequencer::new(BuiltinPattern::Blink.into(), 0);
+    let zero = LedPattern {
+        steps: [Step::on(0); MAX_STEPS],
+        ..LedPattern::solid(true)
+    };
+    assert_eq!(seq.start(zero, 0), Err(InvalidPattern));
+}
@@ -1,23 +1,118 @@
 #![cfg_attr(not(test), no_std)]
 
+pub mod adc;
+pub mod animation;
+pub mod at;
+pub mod bus;
+pub mod button;
+pub mod crash;
 pub mod date_time;
+pub mod filters;
+pub mod fragment;
+pub mod framing;
+pub mod gpio;
+pub mod image;
+pub mod info;
+pub mod kv;
+pub mod led_pattern;
+pub mod log;
+pub mod modbus;
+pub mod mux;
+pub mod pixels;
+pub mod pwm;
+pub mod schedule;
 pub mod shift_register;
+pub mod sniffer;
+pub mod update;
+pub mod wall_clock;
+pub mod ws2812;
 
+use adc::{CaptureError, SampleBlock};
+use bus::{BusError, Bytes, I2c, Spi};
+use crash::CrashReport;
+use date_time::UtcDateTime;
+use framing::{Cobs, FrameError};
+use gpio::{GpioError, Pin, PinMode, Trigger};
+use info::Info;
+use kv::KvError;
+use led_pattern::{BuiltinPattern, InvalidPattern, LedPattern};
+use pixels::PixelFrame;
+use pwm::{Channel, Duty, PwmConfig, PwmError};
+use schedule::{ScheduleError, Slot, TimeSpec};
 use serde_derive::{Deserialize, Serialize};
+use update::{Update, UpdateError, UpdateState};
 
 // we could use new-type pattern here but let's keep it simple
 pub type Id = u32;
 pub type DevId = u32;
 pub type Parameter = u32;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[repr(C)]
 pub enum Command {
     Set(Id, Message, DevId),
     Get(Id, Parameter, DevId),
+    Schedule(TimeSpec, Action),
+    Unschedule(Slot),
+    /// Show a predefined pattern on the status LED
+    SelectLedPattern(BuiltinPattern),
+    /// Show a custom pattern, `Response::PatternError` if it is invalid
+    UploadLedPattern(LedPattern),
+    Pixels(PixelFrame),
+    ConfigurePin(Pin, PinMode),
+    /// Set an output high (true) or low
+    WritePin(Pin, bool),
+    ReadPin(Pin),
+    /// Report edges with `Response::PinEdge`, `None` unsubscribes
+    SubscribePin(Pin, Option<Trigger>),
+    ConfigurePwm(Channel, PwmConfig),
+    SetDuty(Channel, Duty),
+    /// Sample an ADC channel, answered by `Response::Samples` blocks
+    StartCapture {
+        channel: u8,
+        rate_hz: u32,
+        samples: u32,
+    },
+    StopCapture,
+    /// Answered by `Response::BusData` if anything is read
+    I2c(I2c),
+    /// Answered by `Response::BusData`
+    Spi(Spi),
+    /// Firmware update, answered by `Response::Update`
+    Update(Update),
+    /// Store the parameters in flash
+    Save,
+    /// Restore the parameters stored with `Save`
+    Load,
+    /// Erase the stored parameters and restore the defaults
+    FactoryReset,
+    /// Fetch and clear the oldest panic report, answered by `Response::CrashReport`
+    GetCrashReport,
+    /// Answered by `Response::Info`
+    GetInfo,
+    /// Sync the wall clock, needed before `Schedule`
+    SetTime(UtcDateTime),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The subset of `Command`s that can be scheduled
+/// (a `Command` can't embed itself without an allocator)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[repr(C)]
+pub enum Action {
+    Set(Id, Message, DevId),
+    Get(Id, Parameter, DevId),
+}
+
+impl From<Action> for Command {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::Set(id, msg, dev_id) => Command::Set(id, msg, dev_id),
+            Action::Get(id, par, dev_id) => Command::Get(id, par, dev_id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[repr(C)]
 pub enum Message {
     A,
@@ -25,43 +120,47 @@ pub enum Message {
     C(f32), // we might consider "f16" but not sure it plays well with `ssmarshal`
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 #[repr(C)]
 pub enum Response {
     Data(Id, Parameter, u32, DevId),
     SetOk,
     ParseError,
+    Scheduled(Slot),
+    ScheduleError(ScheduleError),
+    Level(Pin, bool),
+    /// Sent unsolicited for subscribed pins, with the new level
+    PinEdge(Pin, bool),
+    GpioError(GpioError),
+    PwmError(PwmError),
+    /// Sent unsolicited during a capture
+    Samples(SampleBlock),
+    CaptureError(CaptureError),
+    BusData(Bytes),
+    BusError(BusError),
+    Update(UpdateState),
+    UpdateError(UpdateError),
+    KvError(KvError),
+    /// `None` once all reports are fetched
+    CrashReport(Option<CrashReport>),
+    Info(Info),
+    PatternError(InvalidPattern),
 }
 
 pub const CKSUM: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
 
 /// Serialize T into cobs encoded out_buf with crc
-/// panics on all errors
-/// TODO: reasonable error handling
 pub fn serialize_crc_cobs<'a, T: serde::Serialize, const N: usize>(
     t: &T,
     out_buf: &'a mut [u8; N],
-) -> &'a [u8] {
-    let n_ser = ssmarshal::serialize(out_buf, t).unwrap();
-    let crc = CKSUM.checksum(&out_buf[0..n_ser]);
-    let n_crc = ssmarshal::serialize(&mut out_buf[n_ser..], &crc).unwrap();
-    let buf_copy = *out_buf; // implies memcpy, could we do better?
-    let n = corncobs::encode_buf(&buf_copy[0..n_ser + n_crc], out_buf);
-    &out_buf[0..n]
+) -> Result<&'a [u8], FrameError> {
+    framing::serialize_crc::<Cobs, T, N>(t, out_buf)
 }
 
 /// deserialize T from cobs in_buf with crc check
-/// panics on all errors
-/// TODO: reasonable error handling
-pub fn deserialize_crc_cobs<T>(in_buf: &mut [u8]) -> Result<T, ()>
+pub fn deserialize_crc_cobs<T>(in_buf: &mut [u8]) -> Result<T, FrameError>
 where
     T: for<'de> serde::Deserialize<'de>,
 {
-    let n = corncobs::decode_in_place(in_buf).unwrap();
-    let (t, resp_used) = ssmarshal::deserialize::<T>(&in_buf[0..n]).unwrap();
-    let crc_buf = &in_buf[resp_used..];
-    let (crc, _crc_used) = ssmarshal::deserialize::<u32>(crc_buf).unwrap();
-    let pkg_crc = CKSUM.checksum(&in_buf[0..resp_used]);
-    assert_eq! {crc, pkg_crc};
-    Ok(t)
+    framing::deserialize_crc::<Cobs, T>(in_buf)
 }
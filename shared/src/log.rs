@@ -0,0 +1,284 @@
+//! Log records over the serial link
+//!
+//! The firmware logs through a `Logger`, which sends ssmarshal encoded
+//! `Record`s on `Channel::Log`, so logs are visible without a probe. A record
+//! carries short text, or the id of a format string in `FORMATS` with its
+//! arguments, which is cheaper to send than the formatted text.
+
+use crate::bus::{Bytes, MAX_BYTES};
+use crate::mux::{self, max_frame_len, Channel};
+use core::fmt;
+use core::mem::size_of;
+use serde_derive::{Deserialize, Serialize};
+
+/// Most severe first, a filter passes the levels up to its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+pub const MAX_ARGS: usize = 4;
+
+/// Format strings known to both sides, by id, `{}` takes the next argument
+pub const FORMATS: &[&str] = &[
+    "boot, version {}.{}.{}",
+    "frame error on channel {}",
+    "command answered in {} us",
+    "{} records dropped",
+];
+pub const BOOT: u16 = 0;
+pub const FRAME_ERROR: u16 = 1;
+pub const LATENCY: u16 = 2;
+pub const DROPPED: u16 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Body {
+    Text(Bytes),
+    Format {
+        id: u16,
+        len: u8,
+        args: [u32; MAX_ARGS],
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub level: Level,
+    /// The subsystem, e.g., "uart"
+    pub target: Bytes,
+    /// Milliseconds since boot
+    pub timestamp: u64,
+    pub body: Body,
+}
+
+/// Encoded size of a record frame, at most
+pub const MAX_FRAME: usize = max_frame_len(size_of::<Record>());
+
+/// `s` cut to `MAX_BYTES` at a char boundary
+pub fn text(s: &str) -> Bytes {
+    let mut end = s.len().min(MAX_BYTES);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    Bytes::new(&s.as_bytes()[..end]).unwrap()
+}
+
+fn as_str(bytes: &Bytes) -> &str {
+    core::str::from_utf8(bytes.as_slice()).unwrap_or("?")
+}
+
+impl Record {
+    pub fn text(level: Level, target: &str, timestamp: u64, message: &str) -> Self {
+        Self {
+            level,
+            target: text(target),
+            timestamp,
+            body: Body::Text(text(message)),
+        }
+    }
+
+    /// Arguments past `MAX_ARGS` are dropped
+    pub fn format(level: Level, target: &str, timestamp: u64, id: u16, args: &[u32]) -> Self {
+        let len = args.len().min(MAX_ARGS);
+        let mut a = [0; MAX_ARGS];
+        a[..len].copy_from_slice(&args[..len]);
+        Self {
+            level,
+            target: text(target),
+            timestamp,
+            body: Body::Format {
+                id,
+                len: len as u8,
+                args: a,
+            },
+        }
+    }
+
+    pub fn target(&self) -> &str {
+        as_str(&self.target)
+    }
+
+    /// The text, or the format string with the arguments filled in
+    pub fn write_message(&self, w: &mut impl fmt::Write) -> fmt::Result {
+        let (id, args) = match &self.body {
+            Body::Text(t) => return w.write_str(as_str(t)),
+            Body::Format { id, len, args } => (*id, &args[..(*len as usize).min(MAX_ARGS)]),
+        };
+        let Some(format) = FORMATS.get(id as usize) else {
+            return write!(w, "format {} {:?}", id, args);
+        };
+        let mut args = args.iter();
+        let mut parts = format.split("{}");
+        w.write_str(parts.next().unwrap_or(""))?;
+        for part in parts {
+            match args.next() {
+                Some(a) => write!(w, "{}", a)?,
+                None => w.write_str("?")?,
+            }
+            w.write_str(part)?;
+        }
+        Ok(())
+    }
+}
+
+/// Sends records up to `level` through `sink`, which returns false if a frame
+/// could not be sent, e.g., a full queue
+#[derive(Debug)]
+pub struct Logger<F> {
+    pub level: Level,
+    sink: F,
+    /// Records not sent, reported with the next one sent
+    dropped: u32,
+}
+
+impl<F: FnMut(&[u8]) -> bool> Logger<F> {
+    pub const fn new(level: Level, sink: F) -> Self {
+        Self {
+            level,
+            sink,
+            dropped: 0,
+        }
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    pub fn log(&mut self, record: &Record) {
+        if !self.enabled(record.level) {
+            return;
+        }
+        if self.dropped > 0 {
+            let dropped = Record::format(
+                Level::Warn,
+                "log",
+                record.timestamp,
+                DROPPED,
+                &[self.dropped],
+            );
+            if !self.send(&dropped) {
+                self.dropped += 1;
+                return;
+            }
+            self.dropped = 0;
+        }
+        if !self.send(record) {
+            self.dropped += 1;
+        }
+    }
+
+    pub fn text(&mut self, level: Level, target: &str, now: u64, message: &str) {
+        if self.enabled(level) {
+            self.log(&Record::text(level, target, now, message));
+        }
+    }
+
+    pub fn format(&mut self, level: Level, target: &str, now: u64, id: u16, args: &[u32]) {
+        if self.enabled(level) {
+            self.log(&Record::format(level, target, now, id, args));
+        }
+    }
+
+    fn send(&mut self, record: &Record) -> bool {
+        let mut payload = [0u8; size_of::<Record>()];
+        let mut out_buf = [0u8; MAX_FRAME];
+        let Ok(n) = ssmarshal::serialize(&mut payload, record) else {
+            return false;
+        };
+        match mux::encode(Channel::Log, &payload[..n], &mut out_buf) {
+            Ok(frame) => (self.sink)(frame),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+fn message(record: &Record) -> String {
+    let mut s = String::new();
+    record.write_message(&mut s).unwrap();
+    s
+}
+
+#[test]
+fn messages() {
+    let record = Record::format(Level::Info, "main", 5, BOOT, &[1, 2, 3]);
+    assert_eq!(message(&record), "boot, version 1.2.3");
+    let record = Record::format(Level::Info, "main", 5, BOOT, &[1]);
+    assert_eq!(message(&record), "boot, version 1.?.?");
+    let record = Record::format(Level::Info, "main", 5, 999, &[1, 2]);
+    assert_eq!(message(&record), "format 999 [1, 2]");
+
+    // cut at a char boundary
+    let long = "äöü".repeat(10);
+    let record = Record::text(Level::Error, "uart", 0, &long);
+    assert_eq!(message(&record), "äöü".repeat(5) + "ä");
+    assert_eq!(record.target(), "uart");
+    assert!(Level::Error < Level::Warn);
+}
+
+#[test]
+fn logger() {
+    use crate::mux::Demux;
+
+    let mut frames = vec![];
+    let mut logger = Logger::new(Level::Info, |frame: &[u8]| {
+        frames.push(frame.to_vec());
+        true
+    });
+    logger.text(Level::Warn, "uart", 1, "overrun");
+    logger.text(Level::Debug, "uart", 2, "filtered");
+    logger.format(Level::Info, "cmd", 3, LATENCY, &[120]);
+
+    let mut demux = Demux::<MAX_FRAME>::new();
+    let mut records = vec![];
+    for b in frames.concat() {
+        if let Some(frame) = demux.push(b) {
+            let (channel, payload) = frame.unwrap();
+            assert_eq!(channel, Channel::Log);
+            records.push(ssmarshal::deserialize::<Record>(payload).unwrap().0);
+        }
+    }
+    assert_eq!(
+        records,
+        [
+            Record::text(Level::Warn, "uart", 1, "overrun"),
+            Record::format(Level::Info, "cmd", 3, LATENCY, &[120]),
+        ]
+    );
+
+    // records dropped while the sink is full are counted
+    let full = std::cell::Cell::new(true);
+    let mut sent = vec![];
+    let mut logger = Logger::new(Level::Trace, |frame: &[u8]| {
+        if full.get() {
+            return false;
+        }
+        let mut frame = frame.to_vec();
+        let (_, payload) = mux::decode(&mut frame).unwrap();
+        sent.push(ssmarshal::deserialize::<Record>(payload).unwrap().0);
+        true
+    });
+    logger.text(Level::Info, "a", 1, "lost");
+    logger.text(Level::Info, "a", 2, "lost");
+    full.set(false);
+    logger.text(Level::Info, "a", 3, "sent");
+    let messages: Vec<_> = sent.iter().map(message).collect();
+    assert_eq!(messages, ["2 records dropped", "sent"]);
+}
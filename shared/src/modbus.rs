@@ -0,0 +1,458 @@
+//! Modbus RTU server
+//!
+//! Exposes the parameter table to Modbus masters (e.g., plant tooling), by
+//! mapping registers onto `Command::Get` and `Command::Set`:
+//!
+//! - holding registers 2n and 2n+1 (function codes 3, 6 and 16) are the value
+//!   of `Id` n, `Get(n, 0, 0)` and `Set(n, Message::B(value), 0)`
+//! - input registers 0xii00 + 2p and 2p+1 (function code 4) are `Parameter` p
+//!   of `Id` 0xii, `Get(0xii, p, 0)`
+//!
+//! Values are 32 bits, high word first. Writing one word of a value reads the
+//! other word first.
+//!
+//! Frames are delimited by 3.5 character times of silence, a gap of more than
+//! 1.5 character times within a frame discards it.
+
+use crate::{Command, Id, Message, Parameter, Response};
+use crc::{Crc, CRC_16_MODBUS};
+
+pub const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_MODBUS);
+
+/// Largest RTU frame
+pub const MAX_FRAME: usize = 256;
+
+pub const READ_HOLDING: u8 = 3;
+pub const READ_INPUT: u8 = 4;
+pub const WRITE_SINGLE: u8 = 6;
+pub const WRITE_MULTIPLE: u8 = 16;
+
+/// Registers per read, limited by the frame size
+pub const MAX_READ: u16 = 125;
+pub const MAX_WRITE: u16 = 123;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+    ServerDeviceFailure = 4,
+}
+
+impl Exception {
+    pub fn from_u8(code: u8) -> Option<Self> {
+        use Exception::*;
+        [
+            IllegalFunction,
+            IllegalDataAddress,
+            IllegalDataValue,
+            ServerDeviceFailure,
+        ]
+        .into_iter()
+        .find(|e| *e as u8 == code)
+    }
+}
+
+/// Silence between characters that ends a frame (t3.5) and that
+/// invalidates a frame (t1.5), in µs, fixed above 19200 baud
+pub const fn silence_us(baud: u32) -> (u64, u64) {
+    if baud > 19_200 {
+        (1_750, 750)
+    } else {
+        // 11 bits per character
+        let char_us = 11_000_000 / baud as u64;
+        (char_us * 7 / 2, char_us * 3 / 2)
+    }
+}
+
+/// Append the crc, low byte first, returns the frame length
+pub fn append_crc(frame: &mut [u8], len: usize) -> usize {
+    let crc = CRC.checksum(&frame[..len]);
+    frame[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+    len + 2
+}
+
+/// True if the last two bytes are the crc of the rest
+pub fn check_crc(frame: &[u8]) -> bool {
+    match frame.len().checked_sub(2) {
+        Some(n) => CRC.checksum(&frame[..n]).to_le_bytes() == frame[n..],
+        None => false,
+    }
+}
+
+fn value(response: Response) -> Result<u32, Exception> {
+    match response {
+        Response::Data(_, _, value, _) => Ok(value),
+        Response::ParseError => Err(Exception::IllegalDataAddress),
+        _ => Err(Exception::ServerDeviceFailure),
+    }
+}
+
+fn word(value: u32, address: u16) -> u16 {
+    if address.is_multiple_of(2) {
+        (value >> 16) as u16
+    } else {
+        value as u16
+    }
+}
+
+/// Replace the word of `value` at `address`
+fn with_word(value: u32, address: u16, word: u16) -> u32 {
+    if address.is_multiple_of(2) {
+        (value & 0xffff) | (word as u32) << 16
+    } else {
+        (value & 0xffff_0000) | word as u32
+    }
+}
+
+fn holding_id(address: u16) -> Id {
+    (address / 2) as Id
+}
+
+fn input_id(address: u16) -> (Id, Parameter) {
+    ((address >> 8) as Id, ((address & 0xff) / 2) as Parameter)
+}
+
+fn u16_at(data: &[u8], i: usize) -> u16 {
+    u16::from_be_bytes([data[i], data[i + 1]])
+}
+
+/// Modbus RTU server for one unit address
+#[derive(Debug, Clone)]
+pub struct Server {
+    unit: u8,
+    t35: u64,
+    t15: u64,
+    buf: [u8; MAX_FRAME],
+    len: usize,
+    last_us: Option<u64>,
+    /// Too long, or a gap within the frame
+    corrupt: bool,
+}
+
+impl Server {
+    pub fn new(unit: u8, baud: u32) -> Self {
+        let (t35, t15) = silence_us(baud);
+        Self {
+            unit,
+            t35,
+            t15,
+            buf: [0; MAX_FRAME],
+            len: 0,
+            last_us: None,
+            corrupt: false,
+        }
+    }
+
+    /// A received byte, `now_us` from a monotonic clock
+    /// `poll` must run between frames, an unpolled frame is dropped
+    pub fn push(&mut self, byte: u8, now_us: u64) {
+        let gap = self
+            .last_us
+            .map_or(u64::MAX, |last| now_us.saturating_sub(last));
+        self.last_us = Some(now_us);
+        if gap >= self.t35 {
+            self.len = 0;
+            self.corrupt = false;
+        } else if gap > self.t15 {
+            self.corrupt = true;
+        }
+        match self.buf.get_mut(self.len) {
+            Some(b) => {
+                *b = byte;
+                self.len += 1;
+            }
+            None => self.corrupt = true,
+        }
+    }
+
+    /// Handles a complete frame once the line is silent, returns the
+    /// response to send, if any
+    pub fn poll<'a>(
+        &mut self,
+        now_us: u64,
+        handler: &mut impl FnMut(Command) -> Response,
+        out_buf: &'a mut [u8; MAX_FRAME],
+    ) -> Option<&'a [u8]> {
+        let last = self.last_us?;
+        if self.len == 0 || now_us.saturating_sub(last) < self.t35 {
+            return None;
+        }
+        let len = core::mem::take(&mut self.len);
+        if core::mem::take(&mut self.corrupt) || len < 4 || !check_crc(&self.buf[..len]) {
+            return None;
+        }
+        let frame = &self.buf[..len - 2];
+        let broadcast = frame[0] == 0;
+        if frame[0] != self.unit && !broadcast {
+            return None;
+        }
+
+        let function = frame[1];
+        out_buf[0] = self.unit;
+        out_buf[1] = function;
+        let n = match Self::handle(&frame[1..], handler, out_buf) {
+            Ok(n) => n,
+            Err(e) => {
+                out_buf[1] = function | 0x80;
+                out_buf[2] = e as u8;
+                3
+            }
+        };
+        // no response to broadcasts
+        if broadcast {
+            return None;
+        }
+        let n = append_crc(out_buf, n);
+        Some(&out_buf[..n])
+    }
+
+    /// Handle the PDU, writes the response data after the function code
+    /// returns the length of the response up to the crc
+    fn handle(
+        pdu: &[u8],
+        handler: &mut impl FnMut(Command) -> Response,
+        out_buf: &mut [u8; MAX_FRAME],
+    ) -> Result<usize, Exception> {
+        let function = pdu[0];
+        if !matches!(
+            function,
+            READ_HOLDING | READ_INPUT | WRITE_SINGLE | WRITE_MULTIPLE
+        ) {
+            return Err(Exception::IllegalFunction);
+        }
+        if pdu.len() < 5 {
+            return Err(Exception::IllegalDataValue);
+        }
+        let address = u16_at(pdu, 1);
+        let count = u16_at(pdu, 3);
+
+        match function {
+            READ_HOLDING | READ_INPUT => {
+                if !(1..=MAX_READ).contains(&count) {
+                    return Err(Exception::IllegalDataValue);
+                }
+                address
+                    .checked_add(count - 1)
+                    .ok_or(Exception::IllegalDataAddress)?;
+                out_buf[2] = (count * 2) as u8;
+                // one request per value, not per register
+                let mut cached: Option<(u16, u32)> = None;
+                for i in 0..count {
+                    let a = address + i;
+                    let v = match cached {
+                        Some((pair, v)) if pair == a / 2 => v,
+                        _ => {
+                            let cmd = match function {
+                                READ_HOLDING => Command::Get(holding_id(a), 0, 0),
+                                _ => {
+                                    let (id, par) = input_id(a);
+                                    Command::Get(id, par, 0)
+                                }
+                            };
+                            let v = value(handler(cmd))?;
+                            cached = Some((a / 2, v));
+                            v
+                        }
+                    };
+                    let at = 3 + 2 * i as usize;
+                    out_buf[at..at + 2].copy_from_slice(&word(v, a).to_be_bytes());
+                }
+                Ok(3 + 2 * count as usize)
+            }
+            WRITE_SINGLE => {
+                // address and value are echoed
+                write(handler, address, &pdu[3..5])?;
+                out_buf[2..6].copy_from_slice(&pdu[1..5]);
+                Ok(6)
+            }
+            _ => {
+                let bytes = pdu.get(5).copied().unwrap_or(0) as usize;
+                if !(1..=MAX_WRITE).contains(&count)
+                    || bytes != 2 * count as usize
+                    || pdu.len() != 6 + bytes
+                {
+                    return Err(Exception::IllegalDataValue);
+                }
+                address
+                    .checked_add(count - 1)
+                    .ok_or(Exception::IllegalDataAddress)?;
+                write(handler, address, &pdu[6..])?;
+                out_buf[2..6].copy_from_slice(&pdu[1..5]);
+                Ok(6)
+            }
+        }
+    }
+}
+
+/// Write big endian words to consecutive holding registers, whole values
+/// are set at once
+fn write(
+    handler: &mut impl FnMut(Command) -> Response,
+    address: u16,
+    words: &[u8],
+) -> Result<(), Exception> {
+    let count = (words.len() / 2) as u16;
+    let mut i = 0;
+    while i < count {
+        let a = address + i;
+        let id = holding_id(a);
+        // both words given, no need to read
+        let mut v = if a.is_multiple_of(2) && i + 1 < count {
+            0
+        } else {
+            value(handler(Command::Get(id, 0, 0)))?
+        };
+        while i < count && holding_id(address + i) == id {
+            v = with_word(v, address + i, u16_at(words, 2 * i as usize));
+            i += 1;
+        }
+        match handler(Command::Set(id, Message::B(v), 0)) {
+            Response::SetOk => {}
+            r => return Err(value(r).err().unwrap_or(Exception::ServerDeviceFailure)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn table(values: &mut [u32; 4]) -> impl FnMut(Command) -> Response + '_ {
+    move |cmd| match cmd {
+        Command::Get(id, par, dev) if (id as usize) < values.len() => {
+            Response::Data(id, par, values[id as usize] + par, dev)
+        }
+        Command::Set(id, Message::B(v), _) if (id as usize) < values.len() => {
+            values[id as usize] = v;
+            Response::SetOk
+        }
+        _ => Response::ParseError,
+    }
+}
+
+#[cfg(test)]
+fn request(
+    server: &mut Server,
+    handler: &mut impl FnMut(Command) -> Response,
+    pdu: &[u8],
+) -> Option<Vec<u8>> {
+    let mut frame = vec![1];
+    frame.extend(pdu);
+    frame.extend([0, 0]);
+    let n = frame.len() - 2;
+    append_crc(&mut frame, n);
+    // 9600 baud, a character every ~1.15 ms
+    for (i, b) in frame.iter().enumerate() {
+        server.push(*b, 1_000_000 + i as u64 * 1_150);
+    }
+    let mut out_buf = [0; MAX_FRAME];
+    let end = 1_000_000 + frame.len() as u64 * 1_150 + 4_000;
+    let response = server.poll(end, handler, &mut out_buf)?;
+    assert!(check_crc(response));
+    Some(response[1..response.len() - 2].to_vec())
+}
+
+#[test]
+fn crc() {
+    // read holding registers 0x006b..0x006d of unit 0x11, from the spec
+    let mut frame = [0x11, 0x03, 0x00, 0x6b, 0x00, 0x03, 0, 0];
+    assert_eq!(append_crc(&mut frame, 6), 8);
+    assert_eq!(frame[6..], [0x76, 0x87]);
+    assert!(check_crc(&frame));
+    assert_eq!(silence_us(9_600), (4_007, 1_717));
+    assert_eq!(silence_us(115_200), (1_750, 750));
+}
+
+#[test]
+fn functions() {
+    let mut values = [0x0001_0002, 7, 0, 0];
+    let mut server = Server::new(1, 9_600);
+    let mut handler = table(&mut values);
+    let s = &mut server;
+
+    assert_eq!(
+        request(s, &mut handler, &[3, 0, 0, 0, 4]),
+        Some(vec![3, 8, 0, 1, 0, 2, 0, 0, 0, 7])
+    );
+    // odd start, one word
+    assert_eq!(
+        request(s, &mut handler, &[3, 0, 3, 0, 1]),
+        Some(vec![3, 2, 0, 7])
+    );
+    // parameter 2 of id 1
+    assert_eq!(
+        request(s, &mut handler, &[4, 0x01, 0x05, 0, 1]),
+        Some(vec![4, 2, 0, 9])
+    );
+    // high word of id 1
+    assert_eq!(
+        request(s, &mut handler, &[6, 0, 2, 0x12, 0x34]),
+        Some(vec![6, 0, 2, 0x12, 0x34])
+    );
+    // low word of id 2 and both words of id 3
+    assert_eq!(
+        request(s, &mut handler, &[16, 0, 5, 0, 3, 6, 0, 5, 0, 6, 0, 7]),
+        Some(vec![16, 0, 5, 0, 3])
+    );
+    drop(handler);
+    assert_eq!(values, [0x0001_0002, 0x1234_0007, 5, 0x0006_0007]);
+}
+
+#[test]
+fn exceptions_and_framing() {
+    let mut values = [0; 4];
+    let mut server = Server::new(1, 9_600);
+    let mut handler = table(&mut values);
+
+    assert_eq!(
+        request(&mut server, &mut handler, &[5, 0, 0, 0xff, 0]),
+        Some(vec![0x85, 1])
+    );
+    assert_eq!(
+        request(&mut server, &mut handler, &[3, 0, 8, 0, 1]),
+        Some(vec![0x83, 2])
+    );
+    assert_eq!(
+        request(&mut server, &mut handler, &[3, 0, 0, 0, 126]),
+        Some(vec![0x83, 3])
+    );
+    assert_eq!(
+        request(&mut server, &mut handler, &[16, 0, 0, 0, 2, 3, 0, 0, 0]),
+        Some(vec![0x90, 3])
+    );
+
+    // corrupted crc, no response
+    let mut out_buf = [0; MAX_FRAME];
+    for (i, b) in [1, 3, 0, 0, 0, 1, 0, 0].iter().enumerate() {
+        server.push(*b, 2_000_000 + i as u64 * 1_000);
+    }
+    assert_eq!(server.poll(2_020_000, &mut handler, &mut out_buf), None);
+
+    let mut frame = [1, 3, 0, 0, 0, 1, 0, 0];
+    append_crc(&mut frame, 6);
+    let send = |server: &mut Server, start: u64, gap_at: usize| {
+        for (i, b) in frame.iter().enumerate() {
+            let gap = if i == gap_at { 2_500 } else { 0 };
+            server.push(*b, start + i as u64 * 1_000 + gap);
+        }
+    };
+    send(&mut server, 3_000_000, 0);
+    // not silent long enough yet
+    assert_eq!(server.poll(3_009_000, &mut handler, &mut out_buf), None);
+    assert!(server.poll(3_020_000, &mut handler, &mut out_buf).is_some());
+
+    // a gap of 2 characters within the frame
+    send(&mut server, 4_000_000, 3);
+    assert_eq!(server.poll(4_020_000, &mut handler, &mut out_buf), None);
+
+    // other units and broadcasts are not answered
+    for unit in [2, 0] {
+        frame[0] = unit;
+        append_crc(&mut frame, 6);
+        for b in frame {
+            server.push(b, 5_000_000 + unit as u64 * 100_000);
+        }
+        let end = 5_050_000 + unit as u64 * 100_000;
+        assert_eq!(server.poll(end, &mut handler, &mut out_buf), None);
+    }
+}
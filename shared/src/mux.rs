@@ -0,0 +1,313 @@
+//! Channel multiplexing on the serial link
+//!
+//! Every frame starts with a channel id, so logical streams share one UART:
+//!
+//! COBS(channel, payload, crc32(channel, payload)) 0
+//!
+//! COBS is the default, the `_with` functions and `Demux` take any `Framer`.
+//! The control channel carries ssmarshal encoded `Command`s and `Response`s,
+//! the other channels raw bytes (e.g., a console).
+
+use crate::framing::{Cobs, Decoder, FrameError, Framer};
+use crate::CKSUM;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Channel {
+    Control = 0,
+    Console = 1,
+    /// Bytes relayed to and from a second UART of the target
+    Passthrough = 2,
+    /// `log::Record`s from the target
+    Log = 3,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 4] = [
+        Channel::Control,
+        Channel::Console,
+        Channel::Passthrough,
+        Channel::Log,
+    ];
+
+    pub fn from_u8(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| *c as u8 == id)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxError {
+    BufferTooSmall,
+    /// Invalid framing, e.g., bad COBS
+    Frame(FrameError),
+    Crc,
+    UnknownChannel(u8),
+    /// Shorter than the channel id and crc
+    Truncated,
+}
+
+impl From<FrameError> for MuxError {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::BufferTooSmall => MuxError::BufferTooSmall,
+            e => MuxError::Frame(e),
+        }
+    }
+}
+
+/// Channel id and crc
+pub const OVERHEAD: usize = 1 + 4;
+
+/// COBS encoded size of a frame with `payload` bytes, including the
+/// terminating zero
+pub const fn max_frame_len(payload: usize) -> usize {
+    corncobs::max_encoded_len(payload + OVERHEAD)
+}
+
+/// Frame `payload` for `channel` into `out_buf`, returns the frame including
+/// the terminating zero
+pub fn encode<'a, const N: usize>(
+    channel: Channel,
+    payload: &[u8],
+    out_buf: &'a mut [u8; N],
+) -> Result<&'a [u8], MuxError> {
+    encode_with::<Cobs, N>(channel, payload, out_buf)
+}
+
+/// `encode` with the framer `F`
+pub fn encode_with<'a, F: Framer, const N: usize>(
+    channel: Channel,
+    payload: &[u8],
+    out_buf: &'a mut [u8; N],
+) -> Result<&'a [u8], MuxError> {
+    let mut raw = [0u8; N];
+    raw.get_mut(1..payload.len() + 1)
+        .ok_or(MuxError::BufferTooSmall)?
+        .copy_from_slice(payload);
+    finish::<F, N>(channel, payload.len(), raw, out_buf)
+}
+
+/// Frame a `Command` or `Response` for the control channel
+pub fn encode_control<'a, T: serde::Serialize, const N: usize>(
+    t: &T,
+    out_buf: &'a mut [u8; N],
+) -> Result<&'a [u8], MuxError> {
+    encode_control_with::<Cobs, T, N>(t, out_buf)
+}
+
+/// `encode_control` with the framer `F`
+pub fn encode_control_with<'a, F: Framer, T: serde::Serialize, const N: usize>(
+    t: &T,
+    out_buf: &'a mut [u8; N],
+) -> Result<&'a [u8], MuxError> {
+    let mut raw = [0u8; N];
+    let n = ssmarshal::serialize(&mut raw[1..], t).map_err(|_| MuxError::BufferTooSmall)?;
+    finish::<F, N>(Channel::Control, n, raw, out_buf)
+}
+
+/// Add channel id and crc around the `n` byte payload at `raw[1..]` and frame
+/// it into `out_buf`
+fn finish<F: Framer, const N: usize>(
+    channel: Channel,
+    n: usize,
+    mut raw: [u8; N],
+    out_buf: &mut [u8; N],
+) -> Result<&[u8], MuxError> {
+    raw[0] = channel as u8;
+    let crc = CKSUM.checksum(&raw[..n + 1]);
+    raw.get_mut(n + 1..n + OVERHEAD)
+        .ok_or(MuxError::BufferTooSmall)?
+        .copy_from_slice(&crc.to_le_bytes());
+    let len = F::encode(&raw[..n + OVERHEAD], out_buf)?;
+    Ok(&out_buf[..len])
+}
+
+/// Decode a COBS frame in place, with or without the terminating zero
+pub fn decode(frame: &mut [u8]) -> Result<(Channel, &[u8]), MuxError> {
+    decode_with::<Cobs>(frame)
+}
+
+/// `decode` with the framer `F`
+pub fn decode_with<F: Framer>(frame: &mut [u8]) -> Result<(Channel, &[u8]), MuxError> {
+    let n = F::decode_in_place(frame)?;
+    split(&frame[..n])
+}
+
+/// Check the crc of a decoded frame and split off the channel id
+fn split(data: &[u8]) -> Result<(Channel, &[u8]), MuxError> {
+    let n = data.len();
+    if n < OVERHEAD {
+        return Err(MuxError::Truncated);
+    }
+    let (data, crc) = data.split_at(n - 4);
+    if CKSUM.checksum(data).to_le_bytes() != crc {
+        return Err(MuxError::Crc);
+    }
+    let channel = Channel::from_u8(data[0]).ok_or(MuxError::UnknownChannel(data[0]))?;
+    Ok((channel, &data[1..]))
+}
+
+/// Collects bytes from the link into frames, framed by `F`
+#[derive(Debug, Clone)]
+pub struct Demux<const N: usize, F: Framer = Cobs> {
+    decoder: F::Decoder<N>,
+}
+
+impl<const N: usize, F: Framer> Default for Demux<N, F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, F: Framer> Demux<N, F> {
+    pub fn new() -> Self {
+        Self {
+            decoder: Default::default(),
+        }
+    }
+
+    /// Feed a byte, returns the decoded frame at its end
+    pub fn push(&mut self, byte: u8) -> Option<Result<(Channel, &[u8]), MuxError>> {
+        Some(
+            self.decoder
+                .push(byte)?
+                .map_err(MuxError::from)
+                .and_then(split),
+        )
+    }
+}
+
+#[test]
+fn round_trip() {
+    let mut out_buf = [0u8; max_frame_len(5)];
+    let frame = encode(Channel::Console, b"hello", &mut out_buf).unwrap();
+    assert_eq!(frame.last(), Some(&0));
+    assert!(!frame[..frame.len() - 1].contains(&0));
+
+    let mut frame = frame.to_vec();
+    assert_eq!(decode(&mut frame), Ok((Channel::Console, &b"hello"[..])));
+
+    let mut small = [0u8; 8];
+    assert_eq!(
+        encode(Channel::Console, b"hello", &mut small),
+        Err(MuxError::BufferTooSmall)
+    );
+}
+
+#[test]
+fn errors() {
+    let mut out_buf = [0u8; 32];
+    let mut frame = encode(Channel::Control, &[1, 0, 2], &mut out_buf)
+        .unwrap()
+        .to_vec();
+    frame[2] ^= 0x40;
+    assert_eq!(decode(&mut frame), Err(MuxError::Crc));
+
+    // a valid frame for an unknown channel
+    let mut raw = [7u8, 1, 0, 0, 0, 0];
+    let crc = CKSUM.checksum(&raw[..2]);
+    raw[2..].copy_from_slice(&crc.to_le_bytes());
+    let mut frame = [0u8; 16];
+    let n = corncobs::encode_buf(&raw, &mut frame);
+    assert_eq!(decode(&mut frame[..n]), Err(MuxError::UnknownChannel(7)));
+
+    assert_eq!(decode(&mut [2, 1, 0]), Err(MuxError::Truncated));
+}
+
+#[test]
+fn demux_interleaved() {
+    let mut stream = vec![0u8];
+    let mut out_buf = [0u8; 64];
+    stream.extend(encode(Channel::Console, b"log line\n", &mut out_buf).unwrap());
+    stream.extend(encode(Channel::Control, &[0, 0, 3], &mut out_buf).unwrap());
+    stream.extend(encode(Channel::Passthrough, b"AT\r", &mut out_buf).unwrap());
+
+    let mut demux = Demux::<32>::new();
+    let mut frames = vec![];
+    for b in stream {
+        if let Some(frame) = demux.push(b) {
+            let (channel, payload) = frame.unwrap();
+            frames.push((channel, payload.to_vec()));
+        }
+    }
+    assert_eq!(
+        frames,
+        [
+            (Channel::Console, b"log line\n".to_vec()),
+            (Channel::Control, vec![0, 0, 3]),
+            (Channel::Passthrough, b"AT\r".to_vec()),
+        ]
+    );
+
+    // too long for the buffer, then back in sync
+    let mut demux = Demux::<8>::new();
+    let long = encode(Channel::Console, b"too long", &mut out_buf).unwrap();
+    let mut results = vec![];
+    for b in long
+        .iter()
+        .chain(encode(Channel::Console, b"ok", &mut [0; 16]).unwrap())
+    {
+        if let Some(frame) = demux.push(*b) {
+            results.push(frame.map(|(c, p)| (c, p.to_vec())));
+        }
+    }
+    assert_eq!(
+        results,
+        [
+            Err(MuxError::BufferTooSmall),
+            Ok((Channel::Console, b"ok".to_vec()))
+        ]
+    );
+}
+
+#[test]
+fn control() {
+    use crate::{Command, Message};
+
+    let cmd = Command::Set(0x12, Message::B(12), 0b001);
+    let mut out_buf = [0u8; max_frame_len(core::mem::size_of::<Command>())];
+    let mut frame = encode_control(&cmd, &mut out_buf).unwrap().to_vec();
+    let (channel, payload) = decode(&mut frame).unwrap();
+    assert_eq!(channel, Channel::Control);
+    assert_eq!(ssmarshal::deserialize::<Command>(payload).unwrap().0, cmd);
+}
+
+#[test]
+fn any_framer() {
+    use crate::framing::{LengthSync, Slip};
+
+    fn frames<F: Framer>() -> Vec<(Channel, Vec<u8>)> {
+        let mut out_buf = [0u8; 64];
+        let mut stream = encode_with::<F, 64>(Channel::Console, b"\xc0\x00\xdb", &mut out_buf)
+            .unwrap()
+            .to_vec();
+        let mut frame = stream.clone();
+        assert_eq!(
+            decode_with::<F>(&mut frame).map(|(c, p)| (c, p.to_vec())),
+            Ok((Channel::Console, b"\xc0\x00\xdb".to_vec()))
+        );
+        stream.extend(
+            encode_control_with::<F, _, 64>(&crate::Response::SetOk, &mut out_buf).unwrap(),
+        );
+
+        let mut demux = Demux::<64, F>::new();
+        stream
+            .into_iter()
+            .filter_map(|b| {
+                demux
+                    .push(b)
+                    .map(|f| f.map(|(c, p)| (c, p.to_vec())).unwrap())
+            })
+            .collect()
+    }
+
+    let mut set_ok = [0u8; 8];
+    let n = ssmarshal::serialize(&mut set_ok, &crate::Response::SetOk).unwrap();
+    let expected = [
+        (Channel::Console, b"\xc0\x00\xdb".to_vec()),
+        (Channel::Control, set_ok[..n].to_vec()),
+    ];
+    assert_eq!(frames::<Cobs>(), expected);
+    assert_eq!(frames::<Slip>(), expected);
+    assert_eq!(frames::<LengthSync>(), expected);
+}
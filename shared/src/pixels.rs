@@ -0,0 +1,158 @@
+//! Pixel frames streamed from the host
+//!
+//! A strip update is sent as a number of `PixelFrame`s, each carrying a few
+//! pixels at an offset. Frames are collected in a back buffer and shown once a
+//! frame with the `commit` flag arrives, so partial updates are never displayed.
+
+use rgb::RGB8;
+use serde_derive::{Deserialize, Serialize};
+
+/// Pixels per frame, 30 bytes of payload (serde arrays are limited to 32 elements)
+pub const PIXELS_PER_FRAME: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PixelFrame {
+    /// Index of the first pixel
+    pub offset: u16,
+    len: u8,
+    data: [[u8; 3]; PIXELS_PER_FRAME],
+    /// Show the collected pixels after this frame
+    pub commit: bool,
+}
+
+impl PixelFrame {
+    /// At most `PIXELS_PER_FRAME` pixels are taken
+    pub fn new(offset: u16, pixels: &[RGB8], commit: bool) -> Self {
+        let mut data = [[0; 3]; PIXELS_PER_FRAME];
+        let len = pixels.len().min(PIXELS_PER_FRAME);
+        for (d, p) in data.iter_mut().zip(pixels) {
+            *d = [p.r, p.g, p.b];
+        }
+        Self {
+            offset,
+            len: len as u8,
+            data,
+            commit,
+        }
+    }
+
+    /// Split an update into frames, the last one commits if `commit` is set
+    pub fn split(
+        offset: u16,
+        pixels: &[RGB8],
+        commit: bool,
+    ) -> impl Iterator<Item = PixelFrame> + '_ {
+        let n = pixels.len().div_ceil(PIXELS_PER_FRAME).max(1);
+        (0..n).map(move |i| {
+            let start = i * PIXELS_PER_FRAME;
+            let end = pixels.len().min(start + PIXELS_PER_FRAME);
+            PixelFrame::new(
+                offset.saturating_add(start as u16),
+                &pixels[start..end],
+                commit && i == n - 1,
+            )
+        })
+    }
+
+    pub fn pixels(&self) -> impl Iterator<Item = RGB8> + '_ {
+        self.data[..(self.len as usize).min(PIXELS_PER_FRAME)]
+            .iter()
+            .map(|[r, g, b]| RGB8::new(*r, *g, *b))
+    }
+}
+
+/// Double buffered receiver for a strip of N pixels
+#[derive(Debug, Clone)]
+pub struct PixelBuffer<const N: usize> {
+    back: [RGB8; N],
+    front: [RGB8; N],
+}
+
+impl<const N: usize> Default for PixelBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> PixelBuffer<N> {
+    pub const fn new() -> Self {
+        let black = RGB8 { r: 0, g: 0, b: 0 };
+        Self {
+            back: [black; N],
+            front: [black; N],
+        }
+    }
+
+    /// Collect a frame, pixels beyond the strip are dropped
+    /// returns the pixels to show if the frame commits
+    pub fn apply(&mut self, frame: &PixelFrame) -> Option<&[RGB8; N]> {
+        let offset = frame.offset as usize;
+        if offset < N {
+            for (b, p) in self.back[offset..].iter_mut().zip(frame.pixels()) {
+                *b = p;
+            }
+        }
+        if frame.commit {
+            self.front = self.back;
+            Some(&self.front)
+        } else {
+            None
+        }
+    }
+
+    /// The pixels currently shown
+    pub fn front(&self) -> &[RGB8; N] {
+        &self.front
+    }
+}
+
+#[cfg(test)]
+fn gray(n: usize) -> Vec<RGB8> {
+    (0..n)
+        .map(|i| RGB8::new(i as u8, i as u8, i as u8))
+        .collect()
+}
+
+#[test]
+fn split_into_frames() {
+    let pixels = gray(25);
+    let frames: Vec<_> = PixelFrame::split(5, &pixels, true).collect();
+    assert_eq!(frames.len(), 3);
+    assert_eq!(
+        frames
+            .iter()
+            .map(|f| (f.offset, f.commit))
+            .collect::<Vec<_>>(),
+        [(5, false), (15, false), (25, true)]
+    );
+    let back: Vec<RGB8> = frames.iter().flat_map(|f| f.pixels()).collect();
+    assert_eq!(back, pixels);
+
+    // an empty commit is a single frame, e.g., for a separate sync
+    let frames: Vec<_> = PixelFrame::split(0, &[], true).collect();
+    assert_eq!(frames, [PixelFrame::new(0, &[], true)]);
+}
+
+#[test]
+fn serialize_frame() {
+    let frame = PixelFrame::new(3, &gray(4), true);
+    let mut buf = [0u8; 64];
+    let n = ssmarshal::serialize(&mut buf, &frame).unwrap();
+    let (back, used) = ssmarshal::deserialize::<PixelFrame>(&buf[..n]).unwrap();
+    assert_eq!((back, used), (frame, n));
+}
+
+#[test]
+fn buffer_commits() {
+    let mut buffer = PixelBuffer::<12>::new();
+    let pixels = gray(14);
+    let mut frames = PixelFrame::split(0, &pixels, true);
+
+    // nothing shown until the commit
+    assert_eq!(buffer.apply(&frames.next().unwrap()), None);
+    assert_eq!(buffer.front(), &[RGB8::default(); 12]);
+
+    // pixels beyond the strip are dropped
+    let shown = buffer.apply(&frames.next().unwrap()).unwrap();
+    assert_eq!(shown[..], pixels[..12]);
+}
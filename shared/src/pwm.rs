@@ -0,0 +1,312 @@
+//! Remote PWM on the LEDC peripheral
+//!
+//! The LEDC timers divide the 80 MHz APB clock by a 10.8 fixed point divider
+//! (1.0 to 1023.996) and count to 2^resolution, so
+//!
+//! f = 80 MHz * 256 / (divider * 2^resolution)
+//!
+//! Duty is given as a percentage (dimming) or a pulse width (servos) and
+//! converted to timer ticks here, the firmware just writes the registers.
+//!
+//! The 6 channels share 4 timers, channels with the same frequency and
+//! resolution use the same timer.
+
+use crate::gpio::{GpioError, Pin, Pins};
+use serde_derive::{Deserialize, Serialize};
+
+pub type Channel = u8;
+
+/// LEDC channels on the ESP32-C3
+pub const CHANNELS: usize = 6;
+
+/// LEDC timers on the ESP32-C3
+pub const TIMERS: usize = 4;
+
+pub const APB_HZ: u64 = 80_000_000;
+
+/// Fractional bits of the divider
+const DIV_FRAC_BITS: u32 = 8;
+const DIV_MIN: u64 = 1 << DIV_FRAC_BITS;
+const DIV_MAX: u64 = (1 << 18) - 1;
+
+pub const MAX_RESOLUTION: u8 = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PwmConfig {
+    pub pin: Pin,
+    pub freq_hz: u32,
+    pub resolution_bits: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Duty {
+    /// 0.0 to 100.0
+    Percent(f32),
+    /// High time per period
+    PulseUs(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PwmError {
+    InvalidChannel,
+    Gpio(GpioError),
+    /// 1 to 14 bits
+    InvalidResolution,
+    /// Not reachable with the divider at this resolution
+    FrequencyOutOfRange,
+    /// Below 0 %, above 100 % or longer than the period
+    DutyOutOfRange,
+    NotConfigured,
+    /// All timers are in use with other frequencies or resolutions
+    NoTimer,
+}
+
+/// Timer settings for a frequency and resolution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timer {
+    /// 10.8 fixed point
+    pub divider: u32,
+    pub resolution_bits: u8,
+}
+
+impl Timer {
+    pub fn new(freq_hz: u32, resolution_bits: u8) -> Result<Self, PwmError> {
+        if !(1..=MAX_RESOLUTION).contains(&resolution_bits) {
+            return Err(PwmError::InvalidResolution);
+        }
+        let counts = (freq_hz as u64) << resolution_bits;
+        if counts == 0 {
+            return Err(PwmError::FrequencyOutOfRange);
+        }
+        // rounded to the nearest 1/256
+        let divider = ((APB_HZ << DIV_FRAC_BITS) + counts / 2) / counts;
+        if !(DIV_MIN..=DIV_MAX).contains(&divider) {
+            return Err(PwmError::FrequencyOutOfRange);
+        }
+        Ok(Self {
+            divider: divider as u32,
+            resolution_bits,
+        })
+    }
+
+    /// The frequency after rounding the divider
+    pub fn freq_hz(&self) -> f32 {
+        (APB_HZ << DIV_FRAC_BITS) as f32 / ((self.divider as u64) << self.resolution_bits) as f32
+    }
+
+    /// Ticks per period, a duty of `period()` ticks is fully on
+    pub fn period(&self) -> u32 {
+        1 << self.resolution_bits
+    }
+
+    pub fn duty_ticks(&self, duty: Duty) -> Result<u32, PwmError> {
+        let ticks = match duty {
+            Duty::Percent(p) if (0.0..=100.0).contains(&p) => {
+                libm::roundf(p / 100.0 * self.period() as f32) as u128
+            }
+            Duty::Percent(_) => return Err(PwmError::DutyOutOfRange),
+            // a tick is divider / 256 APB cycles
+            Duty::PulseUs(us) => {
+                let num = (us as u128 * APB_HZ as u128) << DIV_FRAC_BITS;
+                let den = self.divider as u128 * 1_000_000;
+                (num + den / 2) / den
+            }
+        };
+        if ticks > self.period() as u128 {
+            return Err(PwmError::DutyOutOfRange);
+        }
+        Ok(ticks as u32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelState {
+    pub pin: Pin,
+    /// 0 to 3
+    pub timer_id: u8,
+    pub timer: Timer,
+    pub duty: u32,
+}
+
+/// Channel bookkeeping, new channels start at 0 % duty
+#[derive(Debug, Clone, Default)]
+pub struct PwmChannels {
+    channels: [Option<ChannelState>; CHANNELS],
+}
+
+impl PwmChannels {
+    pub const fn new() -> Self {
+        Self {
+            channels: [None; CHANNELS],
+        }
+    }
+
+    /// Picks a timer already running at this frequency and resolution, or a
+    /// free one
+    pub fn configure(
+        &mut self,
+        channel: Channel,
+        config: PwmConfig,
+    ) -> Result<ChannelState, PwmError> {
+        if channel as usize >= CHANNELS {
+            return Err(PwmError::InvalidChannel);
+        }
+        Pins::validate(config.pin).map_err(PwmError::Gpio)?;
+        let timer = Timer::new(config.freq_hz, config.resolution_bits)?;
+
+        // timers of the other channels, this one's is released
+        let mut in_use = [None; TIMERS];
+        for (i, state) in self.channels.iter().enumerate() {
+            match state {
+                Some(state) if i != channel as usize => {
+                    in_use[state.timer_id as usize] = Some(state.timer)
+                }
+                _ => {}
+            }
+        }
+        let timer_id = in_use
+            .iter()
+            .position(|t| *t == Some(timer))
+            .or_else(|| in_use.iter().position(Option::is_none))
+            .ok_or(PwmError::NoTimer)?;
+
+        let state = ChannelState {
+            pin: config.pin,
+            timer_id: timer_id as u8,
+            timer,
+            duty: 0,
+        };
+        self.channels[channel as usize] = Some(state);
+        Ok(state)
+    }
+
+    /// Returns the duty in ticks
+    pub fn set_duty(&mut self, channel: Channel, duty: Duty) -> Result<u32, PwmError> {
+        let state = self
+            .channels
+            .get_mut(channel as usize)
+            .ok_or(PwmError::InvalidChannel)?
+            .as_mut()
+            .ok_or(PwmError::NotConfigured)?;
+        state.duty = state.timer.duty_ticks(duty)?;
+        Ok(state.duty)
+    }
+
+    pub fn get(&self, channel: Channel) -> Option<&ChannelState> {
+        self.channels.get(channel as usize)?.as_ref()
+    }
+}
+
+#[test]
+fn timer_divider() {
+    // 5 kHz, 13 bits: 80e6 / (5000 * 8192) = 1.953125
+    let timer = Timer::new(5_000, 13).unwrap();
+    assert_eq!(timer.divider, 500);
+    assert_eq!(timer.freq_hz(), 5_000.0);
+
+    // servo, 50 Hz, 14 bits: 97.65625
+    let timer = Timer::new(50, 14).unwrap();
+    assert_eq!(timer.divider, 25_000);
+
+    // 3 kHz, 10 bits: 26.041666.. rounds to 26 + 11/256
+    let timer = Timer::new(3_000, 10).unwrap();
+    assert_eq!(timer.divider, 26 * 256 + 11);
+    assert!((timer.freq_hz() - 3_000.0).abs() < 1.0);
+}
+
+#[test]
+fn timer_out_of_range() {
+    // divider below 1
+    assert_eq!(Timer::new(40_000, 12), Err(PwmError::FrequencyOutOfRange));
+    assert!(Timer::new(19_531, 12).is_ok());
+    // divider above 1023.996
+    assert_eq!(Timer::new(1, 6), Err(PwmError::FrequencyOutOfRange));
+    assert_eq!(Timer::new(0, 10), Err(PwmError::FrequencyOutOfRange));
+    assert_eq!(Timer::new(1_000, 0), Err(PwmError::InvalidResolution));
+    assert_eq!(Timer::new(1_000, 15), Err(PwmError::InvalidResolution));
+}
+
+#[test]
+fn duty() {
+    let timer = Timer::new(5_000, 13).unwrap();
+    assert_eq!(timer.duty_ticks(Duty::Percent(0.0)), Ok(0));
+    assert_eq!(timer.duty_ticks(Duty::Percent(50.0)), Ok(4096));
+    assert_eq!(timer.duty_ticks(Duty::Percent(100.0)), Ok(8192));
+    // 0.01 % of 8192 is 0.82 ticks
+    assert_eq!(timer.duty_ticks(Duty::Percent(0.01)), Ok(1));
+    assert_eq!(
+        timer.duty_ticks(Duty::Percent(100.5)),
+        Err(PwmError::DutyOutOfRange)
+    );
+    assert_eq!(
+        timer.duty_ticks(Duty::Percent(-1.0)),
+        Err(PwmError::DutyOutOfRange)
+    );
+    // 200 us period
+    assert_eq!(timer.duty_ticks(Duty::PulseUs(100)), Ok(4096));
+    assert_eq!(timer.duty_ticks(Duty::PulseUs(200)), Ok(8192));
+    assert_eq!(
+        timer.duty_ticks(Duty::PulseUs(201)),
+        Err(PwmError::DutyOutOfRange)
+    );
+}
+
+#[test]
+fn servo_pulses() {
+    // 20 ms period, 16384 ticks, 1.2207 us per tick
+    let timer = Timer::new(50, 14).unwrap();
+    assert_eq!(timer.duty_ticks(Duty::PulseUs(1_000)), Ok(819));
+    assert_eq!(timer.duty_ticks(Duty::PulseUs(1_500)), Ok(1229));
+    assert_eq!(timer.duty_ticks(Duty::PulseUs(2_000)), Ok(1638));
+    assert_eq!(
+        timer.duty_ticks(Duty::PulseUs(u32::MAX)),
+        Err(PwmError::DutyOutOfRange)
+    );
+}
+
+#[test]
+fn channels() {
+    let mut pwm = PwmChannels::new();
+    let config = PwmConfig {
+        pin: crate::gpio::LED,
+        freq_hz: 5_000,
+        resolution_bits: 13,
+    };
+    assert_eq!(pwm.configure(6, config), Err(PwmError::InvalidChannel));
+    assert_eq!(
+        pwm.configure(0, PwmConfig { pin: 18, ..config }),
+        Err(PwmError::Gpio(GpioError::Reserved))
+    );
+    assert_eq!(
+        pwm.set_duty(0, Duty::Percent(10.0)),
+        Err(PwmError::NotConfigured)
+    );
+
+    pwm.configure(0, config).unwrap();
+    assert_eq!(pwm.set_duty(0, Duty::Percent(25.0)), Ok(2048));
+    assert_eq!(pwm.get(0).unwrap().duty, 2048);
+    // a rejected duty leaves the channel as is
+    assert!(pwm.set_duty(0, Duty::PulseUs(1_000)).is_err());
+    assert_eq!(pwm.get(0).unwrap().duty, 2048);
+}
+
+#[test]
+fn shared_timers() {
+    let mut pwm = PwmChannels::new();
+    let config = |freq_hz| PwmConfig {
+        pin: 4,
+        freq_hz,
+        resolution_bits: 10,
+    };
+    assert_eq!(pwm.configure(0, config(1_000)).unwrap().timer_id, 0);
+    assert_eq!(pwm.configure(1, config(2_000)).unwrap().timer_id, 1);
+    // same frequency and resolution as channel 0
+    assert_eq!(pwm.configure(2, config(1_000)).unwrap().timer_id, 0);
+    assert_eq!(pwm.configure(3, config(3_000)).unwrap().timer_id, 2);
+    assert_eq!(pwm.configure(4, config(4_000)).unwrap().timer_id, 3);
+    assert_eq!(pwm.configure(5, config(5_000)), Err(PwmError::NoTimer));
+    assert_eq!(pwm.get(5), None);
+    // reconfiguring the only channel on a timer reuses it
+    assert_eq!(pwm.configure(4, config(5_000)).unwrap().timer_id, 3);
+    assert_eq!(pwm.configure(5, config(2_000)).unwrap().timer_id, 1);
+}
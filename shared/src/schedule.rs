@@ -0,0 +1,407 @@
+//! Scheduled actions
+//!
+//! A bounded table of `Action`s to run at a wall-clock time, at a fixed interval
+//! or on a cron-like schedule. The device evaluates it against its `WallClock`,
+//! all times are nanoseconds since the unix epoch (see `UtcDateTime::timestamp_nanos`).
+
+use crate::{date_time::UtcDateTime, Action};
+use chrono::{DateTime, Datelike, NaiveDate, Timelike};
+use serde_derive::{Deserialize, Serialize};
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+const NANOS_PER_MIN: i64 = 60 * NANOS_PER_SEC;
+
+pub type Slot = u8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TimeSpec {
+    /// Once, at the given time
+    At(UtcDateTime),
+    /// Every n seconds, starting n seconds from now
+    Every(u32),
+    /// At the start of every minute matching the cron fields
+    Cron(Cron),
+}
+
+/// Cron-like time pattern, one bit per allowed value in each field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cron {
+    pub minutes: u64, // bit 0..=59
+    pub hours: u32,   // bit 0..=23
+    pub days: u32,    // bit 1..=31
+    pub months: u16,  // bit 1..=12
+    pub weekdays: u8, // bit 0 = Monday ..= bit 6 = Sunday
+}
+
+pub const MONDAY_TO_FRIDAY: u8 = 0b001_1111;
+pub const WEEKEND: u8 = 0b110_0000;
+
+const ALL_MINUTES: u64 = (1 << 60) - 1;
+const ALL_HOURS: u32 = (1 << 24) - 1;
+const ALL_DAYS: u32 = ((1 << 31) - 1) << 1;
+const ALL_MONTHS: u16 = ((1 << 12) - 1) << 1;
+const ALL_WEEKDAYS: u8 = (1 << 7) - 1;
+
+impl Cron {
+    /// Every minute of every day
+    pub const fn every_minute() -> Self {
+        Self {
+            minutes: ALL_MINUTES,
+            hours: ALL_HOURS,
+            days: ALL_DAYS,
+            months: ALL_MONTHS,
+            weekdays: ALL_WEEKDAYS,
+        }
+    }
+
+    /// Every n minutes (on the hour, n, 2n, ...), i.e., `*/n` in cron
+    pub const fn every_n_minutes(n: u32) -> Self {
+        let mut minutes = 0;
+        let mut m = 0;
+        while n > 0 && m < 60 {
+            minutes |= 1 << m;
+            m += n;
+        }
+        Self {
+            minutes,
+            ..Self::every_minute()
+        }
+    }
+
+    /// Daily at hour:minute, None unless `hour < 24` and `minute < 60`
+    pub const fn daily(hour: u32, minute: u32) -> Option<Self> {
+        if hour >= 24 || minute >= 60 {
+            return None;
+        }
+        Some(Self {
+            minutes: 1 << minute,
+            hours: 1 << hour,
+            ..Self::every_minute()
+        })
+    }
+
+    /// Restrict to the given weekdays, e.g., `MONDAY_TO_FRIDAY`
+    pub const fn on_weekdays(self, weekdays: u8) -> Self {
+        Self { weekdays, ..self }
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        self.days & (1 << date.day()) != 0
+            && self.months & (1 << date.month()) != 0
+            && self.weekdays & (1 << date.weekday().num_days_from_monday()) != 0
+    }
+
+    /// Next matching time strictly after `after`
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        let t = DateTime::from_timestamp_nanos(after).naive_utc();
+        let mut date = t.date();
+        let mut first_minute = t.hour() * 60 + t.minute() + 1;
+
+        // a valid pattern matches at least once in 4 years (Feb 29)
+        for _ in 0..=4 * 366 {
+            if self.matches_date(date) {
+                for minute_of_day in first_minute..24 * 60 {
+                    let (hour, minute) = (minute_of_day / 60, minute_of_day % 60);
+                    if self.hours & (1 << hour) != 0 && self.minutes & (1 << minute) != 0 {
+                        let midnight = date.and_hms_opt(0, 0, 0)?.and_utc();
+                        return Some(
+                            midnight.timestamp_nanos_opt()? + minute_of_day as i64 * NANOS_PER_MIN,
+                        );
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+            first_minute = 0;
+        }
+        None
+    }
+}
+
+impl TimeSpec {
+    /// First fire time for a spec scheduled at `now`
+    pub fn first_after(&self, now: i64) -> Option<i64> {
+        match *self {
+            TimeSpec::At(t) => t.timestamp_nanos().filter(|t| *t > now),
+            TimeSpec::Every(0) => None,
+            TimeSpec::Every(s) => Some(now + s as i64 * NANOS_PER_SEC),
+            TimeSpec::Cron(c) => c.next_after(now),
+        }
+    }
+
+    /// Next fire time after firing at `fired` (the deadline), observed at `now`
+    /// missed periods are skipped, not caught up
+    fn next_after(&self, fired: i64, now: i64) -> Option<i64> {
+        match *self {
+            TimeSpec::At(_) => None,
+            TimeSpec::Every(s) => {
+                let period = s as i64 * NANOS_PER_SEC;
+                Some(fired + ((now - fired) / period + 1) * period)
+            }
+            TimeSpec::Cron(c) => c.next_after(now),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScheduleError {
+    /// No free slot
+    Full,
+    /// The time spec never fires (in the past, zero interval or empty pattern)
+    NeverFires,
+    /// No entry in the given slot
+    NoSuchSlot,
+    /// An invalid or out of range `UtcDateTime` (years 1677..=2262)
+    InvalidTime,
+    /// The wall clock is not set, see `Command::SetTime`
+    NoClock,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    spec: TimeSpec,
+    action: Action,
+    next: i64,
+}
+
+/// Schedule table with N slots
+#[derive(Debug)]
+pub struct Schedule<const N: usize> {
+    entries: [Option<Entry>; N],
+}
+
+impl<const N: usize> Default for Schedule<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Schedule<N> {
+    pub const fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// Add an action, returns the slot it was put in
+    pub fn insert(
+        &mut self,
+        spec: TimeSpec,
+        action: Action,
+        now: i64,
+    ) -> Result<Slot, ScheduleError> {
+        if let TimeSpec::At(t) = spec {
+            t.timestamp_nanos().ok_or(ScheduleError::InvalidTime)?;
+        }
+        let next = spec.first_after(now).ok_or(ScheduleError::NeverFires)?;
+        let (slot, entry) = self
+            .entries
+            .iter_mut()
+            .enumerate()
+            .find(|(_, e)| e.is_none())
+            .ok_or(ScheduleError::Full)?;
+        *entry = Some(Entry { spec, action, next });
+        Ok(slot as Slot)
+    }
+
+    pub fn remove(&mut self, slot: Slot) -> Result<(TimeSpec, Action), ScheduleError> {
+        self.entries
+            .get_mut(slot as usize)
+            .and_then(Option::take)
+            .map(|e| (e.spec, e.action))
+            .ok_or(ScheduleError::NoSuchSlot)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.iter().filter(|e| e.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Earliest pending fire time, e.g., to set up a timer
+    pub fn next_deadline(&self) -> Option<i64> {
+        self.entries.iter().flatten().map(|e| e.next).min()
+    }
+
+    /// Take one due action (if any) and reschedule or retire its entry
+    /// call repeatedly until None to drain all due actions
+    pub fn poll(&mut self, now: i64) -> Option<(Slot, Action)> {
+        let (slot, entry) = self
+            .entries
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, e)| matches!(e, Some(e) if e.next <= now))
+            .min_by_key(|(_, e)| e.map(|e| e.next))?;
+
+        let e = entry.as_mut()?;
+        let action = e.action;
+        match e.spec.next_after(e.next, now) {
+            Some(next) => e.next = next,
+            None => *entry = None,
+        }
+        Some((slot as Slot, action))
+    }
+}
+
+#[cfg(test)]
+use crate::{wall_clock::WallClock, Message};
+
+#[cfg(test)]
+fn ts(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> i64 {
+    use chrono::{TimeZone, Utc};
+    Utc.with_ymd_and_hms(y, mo, d, h, mi, s)
+        .unwrap()
+        .timestamp_nanos_opt()
+        .unwrap()
+}
+
+#[test]
+fn cron_next_weekday_morning() {
+    // 07:00 every weekday, 2023-10-13 is a Friday
+    let cron = Cron::daily(7, 0).unwrap().on_weekdays(MONDAY_TO_FRIDAY);
+
+    let friday = ts(2023, 10, 13, 6, 59, 59);
+    assert_eq!(cron.next_after(friday), Some(ts(2023, 10, 13, 7, 0, 0)));
+
+    // strictly after, so at 07:00 sharp we get Monday
+    let next = cron.next_after(ts(2023, 10, 13, 7, 0, 0));
+    assert_eq!(next, Some(ts(2023, 10, 16, 7, 0, 0)));
+}
+
+#[test]
+fn cron_daily_out_of_range() {
+    assert!(Cron::daily(23, 59).is_some());
+    assert_eq!(Cron::daily(24, 0), None);
+    assert_eq!(Cron::daily(0, 60), None);
+    assert_eq!(Cron::daily(0, 64), None);
+}
+
+#[test]
+fn cron_every_5_minutes() {
+    let cron = Cron::every_n_minutes(5);
+    assert_eq!(
+        cron.next_after(ts(2023, 12, 31, 23, 57, 30)),
+        Some(ts(2024, 1, 1, 0, 0, 0))
+    );
+    assert_eq!(
+        cron.next_after(ts(2023, 12, 31, 23, 50, 0)),
+        Some(ts(2023, 12, 31, 23, 55, 0))
+    );
+}
+
+#[test]
+fn cron_leap_day() {
+    let cron = Cron {
+        days: 1 << 29,
+        months: 1 << 2,
+        ..Cron::daily(12, 0).unwrap()
+    };
+    assert_eq!(
+        cron.next_after(ts(2024, 3, 1, 0, 0, 0)),
+        Some(ts(2028, 2, 29, 12, 0, 0))
+    );
+}
+
+#[test]
+fn cron_never_fires() {
+    let cron = Cron {
+        days: 1 << 31,
+        months: 1 << 2,
+        ..Cron::every_minute()
+    };
+    assert_eq!(cron.next_after(ts(2024, 1, 1, 0, 0, 0)), None);
+}
+
+#[test]
+fn schedule_one_shot() {
+    let mut schedule: Schedule<2> = Schedule::new();
+    let now = ts(2023, 10, 16, 12, 0, 0);
+    let action = Action::Set(0x12, Message::B(12), 0b001);
+
+    let past = TimeSpec::At(UtcDateTime::from_timestamp_nanos(now - 1));
+    assert_eq!(
+        schedule.insert(past, action, now),
+        Err(ScheduleError::NeverFires)
+    );
+
+    let at = TimeSpec::At(UtcDateTime::from_timestamp_nanos(now + NANOS_PER_MIN));
+    let slot = schedule.insert(at, action, now).unwrap();
+    assert_eq!(schedule.next_deadline(), Some(now + NANOS_PER_MIN));
+    assert_eq!(schedule.poll(now), None);
+    assert_eq!(schedule.poll(now + NANOS_PER_MIN), Some((slot, action)));
+    assert!(schedule.is_empty());
+}
+
+#[test]
+fn schedule_invalid_time() {
+    let mut schedule: Schedule<1> = Schedule::new();
+    let action = Action::Get(0x12, 12, 0b001);
+    let t = crate::date_time::invalid(UtcDateTime::from_timestamp_nanos(0));
+    assert_eq!(
+        schedule.insert(TimeSpec::At(t), action, 0),
+        Err(ScheduleError::InvalidTime)
+    );
+    assert!(schedule.is_empty());
+}
+
+#[test]
+fn schedule_full_and_remove() {
+    let mut schedule: Schedule<1> = Schedule::new();
+    let action = Action::Get(0x12, 12, 0b001);
+
+    let slot = schedule.insert(TimeSpec::Every(1), action, 0).unwrap();
+    assert_eq!(
+        schedule.insert(TimeSpec::Every(1), action, 0),
+        Err(ScheduleError::Full)
+    );
+    assert_eq!(schedule.remove(slot), Ok((TimeSpec::Every(1), action)));
+    assert_eq!(schedule.remove(slot), Err(ScheduleError::NoSuchSlot));
+}
+
+#[test]
+fn schedule_with_fake_clock() {
+    // sample every 5 minutes, and set a parameter every weekday at 07:00
+    let mut clock = WallClock::new(1_000, 32);
+    let mut schedule: Schedule<4> = Schedule::new();
+    let sample = Action::Get(0x12, 12, 0b001);
+    let wake = Action::Set(0x10, Message::B(1), 0b001);
+
+    // Friday 06:50
+    clock
+        .sync(
+            0,
+            UtcDateTime::from_timestamp_nanos(ts(2023, 10, 13, 6, 50, 0)),
+        )
+        .unwrap();
+    let now = clock.now_nanos(0).unwrap();
+    schedule.insert(TimeSpec::Every(300), sample, now).unwrap();
+    let spec = TimeSpec::Cron(Cron::daily(7, 0).unwrap().on_weekdays(MONDAY_TO_FRIDAY));
+    schedule.insert(spec, wake, now).unwrap();
+
+    // run for three days, polling once a second
+    let mut fired = [0; 2];
+    let mut woken_at = None;
+    for ms in (0..=3 * 24 * 3600 * 1000).step_by(1000) {
+        let now = clock.now_nanos(ms).unwrap();
+        while let Some((slot, action)) = schedule.poll(now) {
+            if action == wake {
+                woken_at = Some(now);
+            }
+            fired[slot as usize] += 1;
+        }
+    }
+    // Saturday and Sunday are skipped
+    assert_eq!(fired, [3 * 24 * 12, 1]);
+    assert_eq!(woken_at, Some(ts(2023, 10, 13, 7, 0, 0)));
+}
+
+#[test]
+fn every_skips_missed_periods() {
+    let mut schedule: Schedule<1> = Schedule::new();
+    let action = Action::Get(0x12, 12, 0b001);
+    schedule.insert(TimeSpec::Every(10), action, 0).unwrap();
+
+    // polled late, 35 s in, fires once and realigns to the original phase
+    assert!(schedule.poll(35 * NANOS_PER_SEC).is_some());
+    assert!(schedule.poll(35 * NANOS_PER_SEC).is_none());
+    assert_eq!(schedule.next_deadline(), Some(40 * NANOS_PER_SEC));
+}
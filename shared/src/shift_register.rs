@@ -1,47 +1,370 @@
-#[derive(PartialEq)]
-pub struct ShiftRegister;
+//! Windowed statistics over the last N samples
+//!
+//! `ShiftRegister<T, N>` is a ring buffer keeping a running sum (in a wider
+//! accumulator type, so a full window of `u64::MAX` does not overflow) and a
+//! running sum of squared deviations from the mean (Welford), making `avg` and
+//! `variance` O(1). `min` and `max` are O(1) too, from monotonic queues
+//! updated in amortised O(1) per insert. `median` sorts a copy of the window.
+//!
+//! A NaN or infinite float sample makes the running sums non-finite, they are
+//! then recomputed on every insert until the sample has left the window.
 
-impl ShiftRegister {
-    pub fn insert(&self, val: u64) {
-        todo!()
+use core::cmp::Ordering;
+use core::ops::{Add, Sub};
+
+/// Sample types supported by `ShiftRegister`
+pub trait Sample: Copy + PartialOrd {
+    /// Accumulator wide enough to hold the sum of a window of samples
+    type Acc: Copy + Default + Add<Output = Self::Acc> + Sub<Output = Self::Acc>;
+
+    fn widen(self) -> Self::Acc;
+    /// acc / n, back in the sample type
+    fn mean(acc: Self::Acc, n: usize) -> Self;
+    /// False for a NaN or infinite (float) sum
+    fn is_finite(acc: Self::Acc) -> bool;
+    fn to_f64(self) -> f64;
+}
+
+macro_rules! impl_sample {
+    ($($t:ty => $acc:ty),*) => {
+        $(
+            impl Sample for $t {
+                type Acc = $acc;
+
+                fn widen(self) -> $acc {
+                    self as $acc
+                }
+
+                fn mean(acc: $acc, n: usize) -> Self {
+                    (acc / n as $acc) as $t
+                }
+
+                fn is_finite(acc: $acc) -> bool {
+                    (acc as f64).is_finite()
+                }
+
+                fn to_f64(self) -> f64 {
+                    self as f64
+                }
+            }
+        )*
+    };
+}
+
+impl_sample!(
+    u8 => u128, u16 => u128, u32 => u128, u64 => u128,
+    i8 => i128, i16 => i128, i32 => i128, i64 => i128,
+    f32 => f64, f64 => f64
+);
+
+/// Sequence numbers of samples in the window, front to back oldest first,
+/// each sample ordered before all samples behind it
+#[derive(Debug, Clone, PartialEq)]
+struct MonotonicQueue<const N: usize> {
+    seqs: [u64; N],
+    front: usize,
+    len: usize,
+}
+
+impl<const N: usize> MonotonicQueue<N> {
+    const fn new() -> Self {
+        Self {
+            seqs: [0; N],
+            front: 0,
+            len: 0,
+        }
+    }
+
+    fn front(&self) -> Option<u64> {
+        (self.len > 0).then(|| self.seqs[self.front])
+    }
+
+    /// Drop the samples older than `oldest` from the front, and those `seq`
+    /// replaces as the extreme from the back
+    fn push(&mut self, seq: u64, oldest: u64, replaced: impl Fn(u64) -> bool) {
+        while self.front().is_some_and(|s| s < oldest) {
+            self.front = (self.front + 1) % N;
+            self.len -= 1;
+        }
+        while self.len > 0 && replaced(self.seqs[(self.front + self.len - 1) % N]) {
+            self.len -= 1;
+        }
+        // at most N - 1 samples are left
+        self.seqs[(self.front + self.len) % N] = seq;
+        self.len += 1;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShiftRegister<T: Sample, const N: usize> {
+    data: [Option<T>; N],
+    head: usize, // index of the next write
+    len: usize,
+    sum: T::Acc,
+    /// Mean and sum of squared deviations, for the variance
+    mean: f64,
+    m2: f64,
+    /// Sequence number of the next sample, its index is `seq % N`
+    seq: u64,
+    /// Front is the minimum
+    mins: MonotonicQueue<N>,
+    /// Front is the maximum
+    maxs: MonotonicQueue<N>,
+}
+
+impl<T: Sample, const N: usize> Default for ShiftRegister<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Sample, const N: usize> ShiftRegister<T, N> {
+    pub fn new() -> Self {
+        assert!(N > 0);
+        Self {
+            data: [None; N],
+            head: 0,
+            len: 0,
+            sum: T::Acc::default(),
+            mean: 0.0,
+            m2: 0.0,
+            seq: 0,
+            mins: MonotonicQueue::new(),
+            maxs: MonotonicQueue::new(),
+        }
+    }
+
+    fn at(&self, seq: u64) -> T {
+        self.data[(seq % N as u64) as usize].unwrap()
+    }
+
+    /// Insert a sample, evicting the oldest one when full
+    pub fn insert(&mut self, val: T) {
+        let x = val.to_f64();
+        if let Some(old) = self.data[self.head].replace(val) {
+            self.sum = self.sum - old.widen();
+            let (o, mean) = (old.to_f64(), self.mean);
+            self.mean += (x - o) / N as f64;
+            self.m2 += (x - o) * (x - self.mean + o - mean);
+        } else {
+            self.len += 1;
+            let d = x - self.mean;
+            self.mean += d / self.len as f64;
+            self.m2 += d * (x - self.mean);
+        }
+        self.sum = self.sum + val.widen();
+        self.head = (self.head + 1) % N;
+        if !T::is_finite(self.sum) || !self.m2.is_finite() {
+            self.sum = self
+                .iter()
+                .fold(T::Acc::default(), |sum, v| sum + v.widen());
+            let n = self.len as f64;
+            self.mean = self.iter().map(T::to_f64).sum::<f64>() / n;
+            self.m2 = self
+                .iter()
+                .map(|v| (v.to_f64() - self.mean) * (v.to_f64() - self.mean))
+                .sum();
+        }
+
+        let (seq, oldest) = (self.seq, (self.seq + 1).saturating_sub(N as u64));
+        let data = &self.data;
+        let value = |seq: u64| data[(seq % N as u64) as usize].unwrap();
+        // older equal samples are dropped, ties go to the newest
+        self.mins.push(seq, oldest, |s| {
+            matches!(
+                value(s).partial_cmp(&val),
+                Some(Ordering::Greater | Ordering::Equal)
+            )
+        });
+        self.maxs.push(seq, oldest, |s| {
+            matches!(
+                value(s).partial_cmp(&val),
+                Some(Ordering::Less | Ordering::Equal)
+            )
+        });
+        self.seq += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Samples, newest first
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        (1..=self.len).map(move |i| self.data[(self.head + N - i) % N].unwrap())
+    }
+
+    /// The window, newest first, padded with None
+    pub fn as_array(&self) -> [Option<T>; N] {
+        let mut out = [None; N];
+        for (o, v) in out.iter_mut().zip(self.iter()) {
+            *o = Some(v);
+        }
+        out
+    }
+
+    /// Mean (truncated for integers), None if empty
+    pub fn avg(&self) -> Option<T> {
+        (!self.is_empty()).then(|| T::mean(self.sum, self.len))
     }
-    pub fn avg(&self) -> u64 {
-        todo!()
+
+    pub fn min(&self) -> Option<T> {
+        Some(self.at(self.mins.front()?))
+    }
+
+    pub fn max(&self) -> Option<T> {
+        Some(self.at(self.maxs.front()?))
+    }
+
+    /// Population variance, None if empty
+    pub fn variance(&self) -> Option<f64> {
+        if self.is_empty() {
+            return None;
+        }
+        // rounding can take it just below 0
+        let m2 = if self.m2 < 0.0 { 0.0 } else { self.m2 };
+        Some(m2 / self.len as f64)
     }
 
-    /// Don't use this. It's for testing :-)
-    fn as_array(&self) -> &[Option<u64>] {
-        todo!()
+    /// Median, the mean of the two middle samples for an even count, None if empty
+    pub fn median(&self) -> Option<T> {
+        let mut sorted = self.as_array();
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        let mid = self.len / 2;
+        match self.len {
+            0 => None,
+            n if n % 2 == 1 => sorted[mid],
+            _ => {
+                let (a, b) = (sorted[mid - 1]?, sorted[mid]?);
+                Some(T::mean(a.widen() + b.widen(), 2))
+            }
+        }
     }
 }
 
 #[test]
 fn insert_works() {
-    let mut sr = ShiftRegister;
+    let mut sr = ShiftRegister::<u64, 3>::new();
 
     sr.insert(1);
-    assert_eq!(sr.as_array(), &[Some(1), None, None]);
+    assert_eq!(sr.as_array(), [Some(1), None, None]);
 
     sr.insert(2);
-    assert_eq!(sr.as_array(), &[Some(2), Some(1), None]);
+    assert_eq!(sr.as_array(), [Some(2), Some(1), None]);
 
     sr.insert(3);
-    assert_eq!(sr.as_array(), &[Some(3), Some(2), Some(1)]);
+    assert_eq!(sr.as_array(), [Some(3), Some(2), Some(1)]);
 
     sr.insert(4);
-    assert_eq!(sr.as_array(), &[Some(4), Some(3), Some(2)]);
+    assert_eq!(sr.as_array(), [Some(4), Some(3), Some(2)]);
 }
 
+#[test]
 fn avg_works() {
-    let sr = ShiftRegister;
+    let mut sr = ShiftRegister::<u64, 3>::new();
+    assert_eq!(sr.avg(), None);
 
     sr.insert(1);
     sr.insert(2);
     sr.insert(3);
 
-    assert_eq!(sr.avg(), 1);
+    assert_eq!(sr.avg(), Some(2));
 
     sr.insert(4);
 
-    assert_eq!(sr.avg(), 1);
+    assert_eq!(sr.avg(), Some(3));
+}
+
+#[test]
+fn avg_does_not_overflow() {
+    let mut sr = ShiftRegister::<u64, 4>::new();
+    for _ in 0..10 {
+        sr.insert(u64::MAX);
+    }
+    assert_eq!(sr.avg(), Some(u64::MAX));
+    assert_eq!(sr.variance(), Some(0.0));
+
+    sr.insert(u64::MAX - 4);
+    assert_eq!(sr.avg(), Some(u64::MAX - 1));
+}
+
+#[test]
+fn statistics_work() {
+    let mut sr = ShiftRegister::<i32, 5>::new();
+    for v in [7, -3, 5, 1] {
+        sr.insert(v);
+    }
+    assert_eq!(sr.iter().collect::<Vec<_>>(), [1, 5, -3, 7]);
+    assert_eq!(sr.min(), Some(-3));
+    assert_eq!(sr.max(), Some(7));
+    assert_eq!(sr.median(), Some(3));
+    assert_eq!(sr.variance(), Some(14.75));
+
+    sr.insert(9);
+    sr.insert(2); // evicts 7
+    assert_eq!(sr.max(), Some(9));
+    assert_eq!(sr.median(), Some(2));
+}
+
+#[test]
+fn sliding_window_matches_a_rescan() {
+    let mut sr = ShiftRegister::<i32, 7>::new();
+    let mut all = vec![];
+    // a pseudo random walk, with repeated values
+    let mut v: i32 = 0;
+    for i in 0..500u32 {
+        v += (i.wrapping_mul(2_654_435_761) >> 28) as i32 - 7;
+        sr.insert(v);
+        all.push(v);
+        let window = &all[all.len().saturating_sub(7)..];
+        assert_eq!(sr.min(), window.iter().copied().min());
+        assert_eq!(sr.max(), window.iter().copied().max());
+        let n = window.len() as f64;
+        let mean = window.iter().map(|v| *v as f64).sum::<f64>() / n;
+        let variance = window
+            .iter()
+            .map(|v| (*v as f64 - mean) * (*v as f64 - mean))
+            .sum::<f64>()
+            / n;
+        assert!((sr.variance().unwrap() - variance).abs() < 1e-6);
+    }
+    sr.clear();
+    assert_eq!((sr.min(), sr.max(), sr.variance()), (None, None, None));
+}
+
+#[test]
+fn float_samples() {
+    let mut sr = ShiftRegister::<f32, 2>::new();
+    sr.insert(1.5);
+    sr.insert(2.5);
+    assert_eq!(sr.avg(), Some(2.0));
+    assert_eq!(sr.median(), Some(2.0));
+    assert_eq!(sr.variance(), Some(0.25));
+}
+
+#[test]
+fn nan_leaves_the_window() {
+    let mut sr = ShiftRegister::<f32, 2>::new();
+    sr.insert(1.0);
+    sr.insert(f32::NAN);
+    assert!(sr.avg().unwrap().is_nan());
+    sr.insert(f32::INFINITY);
+    assert!(sr.avg().unwrap().is_nan());
+    sr.insert(3.0);
+    assert_eq!(sr.avg(), Some(f32::INFINITY));
+    sr.insert(5.0);
+    assert_eq!(sr.avg(), Some(4.0));
 }
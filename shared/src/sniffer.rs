@@ -0,0 +1,411 @@
+//! Binary or text protocol on the same UART
+//!
+//! `Sniffer` splits the incoming bytes into units and classifies them:
+//! - zero terminated frames with a valid crc (see `mux`) are binary,
+//! - printable lines ending in CR or LF are text (see `at`),
+//! - anything else is garbage and dropped.
+//!
+//! COBS frames may contain CR and LF, so a line is only taken as text if
+//! everything since the last zero or the previous line is printable. Every
+//! frame has a control byte in its first two, the COBS code 0x01 before the
+//! control channel id or the id of another channel, so a frame is never
+//! mistaken for text. Garbage in text mode is dropped at the next zero or on
+//! overflow.
+//!
+//! `Responder` answers in the format of the last valid input.
+
+use crate::at::{AtShell, Handler};
+use crate::mux::{self, Channel};
+use crate::{Command, Response};
+use core::fmt::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Binary,
+    Text,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input<'a> {
+    Frame(Channel, &'a [u8]),
+    /// Edited line, without the line ending
+    Line(&'a [u8]),
+    Garbage,
+}
+
+/// Text, including backspace and delete for line editing
+fn is_text(b: u8) -> bool {
+    matches!(b, b'\t' | 0x08 | b' '..=0x7f)
+}
+
+/// Apply backspace and delete in place, returns the new length
+fn edit(line: &mut [u8]) -> usize {
+    let mut len = 0usize;
+    for i in 0..line.len() {
+        match line[i] {
+            0x08 | 0x7f => len = len.saturating_sub(1),
+            b => {
+                line[len] = b;
+                len += 1;
+            }
+        }
+    }
+    len
+}
+
+#[derive(Debug, Clone)]
+pub struct Sniffer<const N: usize> {
+    buf: [u8; N],
+    /// Bytes since the last zero or the previous line
+    len: usize,
+    overflow: bool,
+    mode: Mode,
+}
+
+impl<const N: usize> Default for Sniffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Sniffer<N> {
+    /// Starts in binary mode
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            overflow: false,
+            mode: Mode::Binary,
+        }
+    }
+
+    /// The mode of the last valid input
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Feed a byte, returns the classified unit when complete
+    pub fn push(&mut self, byte: u8) -> Option<Input<'_>> {
+        match byte {
+            0 => {
+                let len = core::mem::take(&mut self.len);
+                if core::mem::take(&mut self.overflow) {
+                    return Some(Input::Garbage);
+                }
+                if len == 0 {
+                    return None;
+                }
+                match mux::decode(&mut self.buf[..len]) {
+                    Ok((channel, payload)) => {
+                        self.mode = Mode::Binary;
+                        Some(Input::Frame(channel, payload))
+                    }
+                    Err(_) => Some(Input::Garbage),
+                }
+            }
+            b'\r' | b'\n' => {
+                if core::mem::take(&mut self.overflow) {
+                    self.len = 0;
+                    return Some(Input::Garbage);
+                }
+                if self.len == 0 {
+                    // the LF of CR LF, or an empty line
+                    return None;
+                }
+                if self.buf[..self.len].iter().all(|b| is_text(*b)) {
+                    let n = edit(&mut self.buf[..core::mem::take(&mut self.len)]);
+                    self.mode = Mode::Text;
+                    return Some(Input::Line(&self.buf[..n]));
+                }
+                // part of a frame, or garbage
+                self.store(byte);
+                None
+            }
+            _ => {
+                self.store(byte);
+                None
+            }
+        }
+    }
+
+    fn store(&mut self, byte: u8) {
+        match self.buf.get_mut(self.len) {
+            Some(b) => {
+                *b = byte;
+                self.len += 1;
+            }
+            None => self.overflow = true,
+        }
+    }
+}
+
+/// `fmt::Write` into a byte buffer, fails if the output does not fit
+struct Cursor<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Cursor<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let to = self
+            .buf
+            .get_mut(self.len..self.len + s.len())
+            .ok_or(fmt::Error)?;
+        to.copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+/// Answers commands in binary or text, whichever was used last
+#[derive(Debug, Clone)]
+pub struct Responder<const N: usize> {
+    sniffer: Sniffer<N>,
+    // lines are collected by the sniffer
+    shell: AtShell<0>,
+}
+
+impl<const N: usize> Default for Responder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Responder<N> {
+    pub const fn new() -> Self {
+        Self {
+            sniffer: Sniffer::new(),
+            shell: AtShell::new(),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.sniffer.mode()
+    }
+
+    /// Feed a received byte, returns the answer to send, if any
+    pub fn push<'a, const M: usize>(
+        &mut self,
+        byte: u8,
+        handler: &mut impl Handler,
+        out_buf: &'a mut [u8; M],
+    ) -> Option<&'a [u8]> {
+        match self.sniffer.push(byte)? {
+            Input::Frame(Channel::Control, payload) => {
+                let response = match ssmarshal::deserialize::<Command>(payload) {
+                    Ok((cmd, _)) => handler.command(cmd),
+                    Err(_) => Response::ParseError,
+                };
+                mux::encode_control(&response, out_buf).ok()
+            }
+            // other channels are not for the responder
+            Input::Frame(..) | Input::Garbage => None,
+            Input::Line(line) => {
+                let mut out = Cursor {
+                    buf: out_buf,
+                    len: 0,
+                };
+                // no echo while typing, the mode is not known before the line ends
+                if self.shell.echo() {
+                    for b in line {
+                        out.write_char(*b as char).ok()?;
+                    }
+                    out.write_str("\r\n").ok()?;
+                }
+                self.shell.line(line, handler, &mut out).ok()?;
+                let len = out.len;
+                Some(&out_buf[..len])
+            }
+        }
+    }
+
+    /// An unsolicited response (e.g., `Response::PinEdge`) in the current format
+    pub fn event<'a, const M: usize>(
+        &self,
+        response: &Response,
+        out_buf: &'a mut [u8; M],
+    ) -> Option<&'a [u8]> {
+        match self.mode() {
+            Mode::Binary => mux::encode_control(response, out_buf).ok(),
+            Mode::Text => {
+                let mut out = Cursor {
+                    buf: out_buf,
+                    len: 0,
+                };
+                crate::at::write_response(&mut out, response).ok()?;
+                let len = out.len;
+                Some(&out_buf[..len])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+struct Values(u32);
+
+#[cfg(test)]
+impl Handler for Values {
+    fn command(&mut self, cmd: Command) -> Response {
+        match cmd {
+            Command::Set(_, crate::Message::B(v), _) => {
+                self.0 = v;
+                Response::SetOk
+            }
+            Command::Get(id, par, dev) => Response::Data(id, par, self.0, dev),
+            _ => Response::ParseError,
+        }
+    }
+
+    fn time(&mut self) -> Option<crate::date_time::UtcDateTime> {
+        None
+    }
+
+    fn version(&self) -> &str {
+        "0.1.0"
+    }
+}
+
+#[cfg(test)]
+fn control_frame(cmd: &Command) -> Vec<u8> {
+    let mut out_buf = [0u8; 64];
+    mux::encode_control(cmd, &mut out_buf).unwrap().to_vec()
+}
+
+#[test]
+fn classify() {
+    use crate::Message;
+
+    let set = control_frame(&Command::Set(0x12, Message::B(12), 0));
+    // COBS bytes with CRs, and "AAA" between them
+    let cr = control_frame(&Command::Set(
+        0x0d0d0d0d,
+        Message::B(0x0d414141),
+        0x0d414141,
+    ));
+    assert!(cr.contains(&b'\r'));
+    let mut stream = set.clone();
+    stream.extend(b"AT+GEX\x7fT=0x12\r\n");
+    stream.extend(&cr);
+    stream.extend(b"AT\r");
+    stream.extend(b"\x01\x02\x03\x00"); // bad crc
+    stream.extend(&set);
+
+    let mut sniffer = Sniffer::<64>::new();
+    let mut units = vec![];
+    for b in stream {
+        if let Some(input) = sniffer.push(b) {
+            units.push((
+                match input {
+                    Input::Frame(c, _) => format!("{:?}", c),
+                    Input::Line(l) => String::from_utf8(l.to_vec()).unwrap(),
+                    Input::Garbage => "garbage".into(),
+                },
+                sniffer.mode(),
+            ));
+        }
+    }
+    assert_eq!(
+        units,
+        [
+            ("Control".into(), Mode::Binary),
+            ("AT+GET=0x12".into(), Mode::Text),
+            ("Control".into(), Mode::Binary),
+            ("AT".into(), Mode::Text),
+            ("garbage".into(), Mode::Text),
+            ("Control".into(), Mode::Binary),
+        ]
+    );
+}
+
+#[cfg(test)]
+fn feed(responder: &mut Responder<64>, values: &mut Values, bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut out_buf = [0u8; 64];
+    let mut answers = vec![];
+    for b in bytes {
+        if let Some(out) = responder.push(*b, values, &mut out_buf) {
+            answers.push(out.to_vec());
+        }
+    }
+    answers
+}
+
+#[cfg(test)]
+fn response(mut frame: Vec<u8>) -> Response {
+    let (_, payload) = mux::decode(&mut frame).unwrap();
+    ssmarshal::deserialize::<Response>(payload).unwrap().0
+}
+
+#[test]
+fn responder_switches_format() {
+    use crate::Message;
+
+    let mut responder = Responder::<64>::new();
+    let mut values = Values(0);
+
+    let set = control_frame(&Command::Set(1, Message::B(7), 0));
+    let answers = feed(&mut responder, &mut values, &set);
+    assert_eq!(response(answers[0].clone()), Response::SetOk);
+
+    let answers = feed(&mut responder, &mut values, b"AT+GET=1\r\n");
+    assert_eq!(answers, [b"AT+GET=1\r\n+DATA: 0x1,0,7,0\r\nOK\r\n"]);
+    let answers = feed(&mut responder, &mut values, b"ATE0\rAT+GET=1\r");
+    assert_eq!(
+        answers,
+        [&b"ATE0\r\nOK\r\n"[..], b"+DATA: 0x1,0,7,0\r\nOK\r\n"]
+    );
+    assert_eq!(responder.mode(), Mode::Text);
+
+    let answers = feed(
+        &mut responder,
+        &mut values,
+        &control_frame(&Command::Get(1, 0, 0)),
+    );
+    assert_eq!(responder.mode(), Mode::Binary);
+    assert_eq!(response(answers[0].clone()), Response::Data(1, 0, 7, 0));
+
+    // events follow the mode
+    let edge = Response::PinEdge(9, true);
+    let mut out_buf = [0u8; 64];
+    let event = responder.event(&edge, &mut out_buf).unwrap().to_vec();
+    assert_eq!(response(event), edge);
+    feed(&mut responder, &mut values, b"AT\r");
+    assert_eq!(
+        responder.event(&edge, &mut out_buf).unwrap(),
+        b"+RESPONSE: PinEdge(9, true)\r\n"
+    );
+}
+
+#[test]
+fn garbage() {
+    // deterministic pseudo random bytes, xorshift
+    let mut x = 0x1234_5678u32;
+    let mut sniffer = Sniffer::<32>::new();
+    for _ in 0..10_000 {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        // lines of noise are fine, frames are not expected by chance
+        if let Some(Input::Frame(..)) = sniffer.push(x as u8) {
+            panic!("frame from noise");
+        }
+    }
+
+    // back in sync after a zero, and after overflow
+    sniffer.push(0);
+    let set = control_frame(&Command::Get(1, 0, 0));
+    for b in &set {
+        if let Some(input) = sniffer.push(*b) {
+            assert!(matches!(input, Input::Frame(Channel::Control, _)));
+        }
+    }
+    assert_eq!(sniffer.mode(), Mode::Binary);
+    for b in [0xffu8; 40] {
+        assert_eq!(sniffer.push(b), None);
+    }
+    assert_eq!(sniffer.push(b'\r'), Some(Input::Garbage));
+    let mut last = None;
+    for b in b"AT\r" {
+        last = sniffer.push(*b).map(|i| i == Input::Line(b"AT"));
+    }
+    assert_eq!(last, Some(true));
+}
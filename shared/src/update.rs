@@ -0,0 +1,519 @@
+//! Firmware update over the serial protocol
+//!
+//! The host sends `Update::Begin` with the image size, crc (`CKSUM`) and
+//! version, then the image in sequential `Update::Chunk`s, then
+//! `Update::Verify` and `Update::Commit`. Every step is answered with
+//! `Response::Update(state)`, `Receiving` carries the offset expected next.
+//!
+//! The image is a packed image (see `image`), `Verify` checks the crc, then
+//! the signature against the key of the `Updater` and that the version is the
+//! one of `Begin` and not below the running one.
+//!
+//! The image goes to an update slot behind `FlashStorage`. The first erase
+//! page of the slot holds a header, the image follows:
+//!
+//! | offset | field                                       |
+//! |--------|---------------------------------------------|
+//! | 0      | magic, cleared when the image is invalid    |
+//! | 4      | size                                        |
+//! | 8      | crc                                         |
+//! | 12     | version                                     |
+//! | 16     | commit word, cleared on commit              |
+//!
+//! Flash is NOR like, erased to 0xff and written by clearing bits. After a
+//! reset `Updater::new` resumes from the last byte that is not 0xff, an image
+//! ending in 0xff bytes just has them written again. A `Begin` for the same
+//! image resumes instead of starting over, so the host can continue an
+//! interrupted transfer. The bootloader (not part of this) boots a committed
+//! image.
+
+use crate::bus::Bytes;
+use crate::image::{self, ImageError};
+use crate::CKSUM;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Image {
+    pub size: u32,
+    pub crc: u32,
+    pub version: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Update {
+    Begin(Image),
+    /// Image bytes at `offset`, in order
+    Chunk {
+        offset: u32,
+        data: Bytes,
+    },
+    Verify,
+    Commit,
+    Status,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UpdateState {
+    Idle,
+    Receiving { offset: u32 },
+    Verified,
+    Committed { version: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UpdateError {
+    /// Larger than the update slot
+    TooLarge,
+    /// Not in a state for the request, e.g., a chunk before `Begin`
+    WrongState,
+    /// A chunk at another offset than expected, see `UpdateState::Receiving`
+    UnexpectedOffset,
+    /// A chunk past the end of the image
+    TooLong,
+    /// Verify before all bytes were received
+    Incomplete,
+    /// The image does not match the crc of `Begin` and was discarded
+    BadCrc,
+    /// Not a packed image signed with the key of the target, or signed for
+    /// another version than the one of `Begin`, discarded
+    Signature,
+    /// Older than the running version, discarded
+    Rollback,
+    Flash,
+}
+
+/// The update slot, offsets relative to its start
+pub trait FlashStorage {
+    type Error: core::fmt::Debug;
+
+    fn capacity(&self) -> u32;
+    fn erase_size(&self) -> u32;
+    /// Erase `from..to`, aligned to `erase_size`
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error>;
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// NOR flash in RAM, for tests and the simulator
+#[derive(Debug, Clone)]
+pub struct RamFlash<const N: usize> {
+    pub data: [u8; N],
+    erase_size: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfRange;
+
+/// 4 KiB sectors, like the ESP32-C3
+impl<const N: usize> Default for RamFlash<N> {
+    fn default() -> Self {
+        Self::new(4096)
+    }
+}
+
+impl<const N: usize> RamFlash<N> {
+    /// Erased
+    pub const fn new(erase_size: u32) -> Self {
+        Self {
+            data: [0xff; N],
+            erase_size,
+        }
+    }
+
+    fn range(&self, offset: u32, len: usize) -> Result<core::ops::Range<usize>, OutOfRange> {
+        let start = offset as usize;
+        let end = start.checked_add(len).ok_or(OutOfRange)?;
+        if end > N {
+            return Err(OutOfRange);
+        }
+        Ok(start..end)
+    }
+}
+
+impl<const N: usize> FlashStorage for RamFlash<N> {
+    type Error = OutOfRange;
+
+    fn capacity(&self) -> u32 {
+        N as u32
+    }
+
+    fn erase_size(&self) -> u32 {
+        self.erase_size
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), OutOfRange> {
+        let aligned = |a: u32| a.is_multiple_of(self.erase_size);
+        if !aligned(from) || !aligned(to) || from > to {
+            return Err(OutOfRange);
+        }
+        let range = self.range(from, (to - from) as usize)?;
+        self.data[range].fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), OutOfRange> {
+        let range = self.range(offset, data.len())?;
+        self.data[range]
+            .iter_mut()
+            .zip(data)
+            .for_each(|(d, s)| *d &= *s);
+        Ok(())
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), OutOfRange> {
+        let range = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+}
+
+const MAGIC: u32 = 0x5550_4454; // "UPDT"
+const COMMIT: u32 = 16;
+const HEADER_LEN: usize = 20;
+
+/// The update state machine, writing through `S`
+#[derive(Debug)]
+pub struct Updater<S> {
+    pub storage: S,
+    state: UpdateState,
+    image: Option<Image>,
+    /// Ed25519 key images must be signed with
+    public_key: [u8; 32],
+    /// The running version, older images are rejected
+    version: u32,
+}
+
+impl<S: FlashStorage> Updater<S> {
+    /// Picks up an update in progress or committed before a reset
+    pub fn new(mut storage: S, public_key: [u8; 32], version: u32) -> Self {
+        let mut header = [0u8; HEADER_LEN];
+        let (state, image) = match storage.read(0, &mut header) {
+            Ok(()) => {
+                let word = |i: usize| {
+                    u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]])
+                };
+                let image = Image {
+                    size: word(4),
+                    crc: word(8),
+                    version: word(12),
+                };
+                if word(0) != MAGIC {
+                    (UpdateState::Idle, None)
+                } else if word(COMMIT as usize) == 0 {
+                    (
+                        UpdateState::Committed {
+                            version: image.version,
+                        },
+                        None,
+                    )
+                } else {
+                    let offset = Self::written(&mut storage, &image).unwrap_or(0);
+                    (UpdateState::Receiving { offset }, Some(image))
+                }
+            }
+            Err(_) => (UpdateState::Idle, None),
+        };
+        Self {
+            storage,
+            state,
+            image,
+            public_key,
+            version,
+        }
+    }
+
+    pub fn state(&self) -> UpdateState {
+        self.state
+    }
+
+    /// Length of the image received so far, from the last written byte
+    fn written(storage: &mut S, image: &Image) -> Result<u32, S::Error> {
+        let base = storage.erase_size();
+        let mut buf = [0u8; 32];
+        let mut end = image.size.min(storage.capacity().saturating_sub(base));
+        while end > 0 {
+            let start = end.saturating_sub(buf.len() as u32);
+            let buf = &mut buf[..(end - start) as usize];
+            storage.read(base + start, buf)?;
+            if let Some(i) = buf.iter().rposition(|b| *b != 0xff) {
+                return Ok(start + i as u32 + 1);
+            }
+            end = start;
+        }
+        Ok(0)
+    }
+
+    pub fn handle(&mut self, update: Update) -> Result<UpdateState, UpdateError> {
+        match update {
+            Update::Begin(image) => self.begin(image)?,
+            Update::Chunk { offset, data } => self.chunk(offset, data.as_slice())?,
+            Update::Verify => self.verify()?,
+            Update::Commit => self.commit()?,
+            Update::Status => {}
+        }
+        Ok(self.state)
+    }
+
+    fn begin(&mut self, image: Image) -> Result<(), UpdateError> {
+        if let (Some(current), UpdateState::Receiving { .. }) = (self.image, self.state) {
+            if current == image {
+                // resume
+                return Ok(());
+            }
+        }
+        let base = self.storage.erase_size();
+        if image.size > self.storage.capacity().saturating_sub(base) {
+            return Err(UpdateError::TooLarge);
+        }
+        let end = (base + image.size).div_ceil(base) * base;
+        self.storage.erase(0, end).map_err(|_| UpdateError::Flash)?;
+
+        let mut header = [0xffu8; HEADER_LEN];
+        for (i, word) in [MAGIC, image.size, image.crc, image.version]
+            .into_iter()
+            .enumerate()
+        {
+            header[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        self.storage
+            .write(0, &header)
+            .map_err(|_| UpdateError::Flash)?;
+        self.image = Some(image);
+        self.state = UpdateState::Receiving { offset: 0 };
+        Ok(())
+    }
+
+    fn chunk(&mut self, at: u32, data: &[u8]) -> Result<(), UpdateError> {
+        let (UpdateState::Receiving { offset }, Some(image)) = (self.state, self.image) else {
+            return Err(UpdateError::WrongState);
+        };
+        if at != offset {
+            return Err(UpdateError::UnexpectedOffset);
+        }
+        let end = offset + data.len() as u32;
+        if end > image.size {
+            return Err(UpdateError::TooLong);
+        }
+        let base = self.storage.erase_size();
+        self.storage
+            .write(base + offset, data)
+            .map_err(|_| UpdateError::Flash)?;
+        self.state = UpdateState::Receiving { offset: end };
+        Ok(())
+    }
+
+    fn verify(&mut self) -> Result<(), UpdateError> {
+        let (UpdateState::Receiving { offset }, Some(image)) = (self.state, self.image) else {
+            return Err(UpdateError::WrongState);
+        };
+        if offset != image.size {
+            return Err(UpdateError::Incomplete);
+        }
+        let base = self.storage.erase_size();
+        let mut digest = CKSUM.digest();
+        let mut buf = [0u8; 32];
+        let mut at = 0;
+        while at < image.size {
+            let buf = &mut buf[..(image.size - at).min(32) as usize];
+            self.storage
+                .read(base + at, buf)
+                .map_err(|_| UpdateError::Flash)?;
+            digest.update(buf);
+            at += buf.len() as u32;
+        }
+        if digest.finalize() != image.crc {
+            return self.discard(UpdateError::BadCrc);
+        }
+        match image::verify_flash(&mut self.storage, base, &self.public_key, self.version) {
+            Ok(header) if header.version == image.version => {}
+            Ok(_) => return self.discard(UpdateError::Signature),
+            Err(ImageError::Flash) => return Err(UpdateError::Flash),
+            Err(ImageError::Rollback) => return self.discard(UpdateError::Rollback),
+            Err(_) => return self.discard(UpdateError::Signature),
+        }
+        self.state = UpdateState::Verified;
+        Ok(())
+    }
+
+    /// Clear the magic, the image must not be resumed
+    fn discard(&mut self, e: UpdateError) -> Result<(), UpdateError> {
+        self.storage
+            .write(0, &[0; 4])
+            .map_err(|_| UpdateError::Flash)?;
+        self.image = None;
+        self.state = UpdateState::Idle;
+        Err(e)
+    }
+
+    fn commit(&mut self) -> Result<(), UpdateError> {
+        let (UpdateState::Verified, Some(image)) = (self.state, self.image) else {
+            return Err(UpdateError::WrongState);
+        };
+        self.storage
+            .write(COMMIT, &[0; 4])
+            .map_err(|_| UpdateError::Flash)?;
+        self.image = None;
+        self.state = UpdateState::Committed {
+            version: image.version,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn send(
+    updater: &mut Updater<RamFlash<1024>>,
+    image: &[u8],
+    from: u32,
+) -> Result<UpdateState, UpdateError> {
+    let mut state = updater.state();
+    for (i, chunk) in image[from as usize..].chunks(32).enumerate() {
+        state = updater.handle(Update::Chunk {
+            offset: from + 32 * i as u32,
+            data: Bytes::new(chunk).unwrap(),
+        })?;
+    }
+    Ok(state)
+}
+
+#[cfg(test)]
+fn image(data: &[u8], version: u32) -> Image {
+    Image {
+        size: data.len() as u32,
+        crc: CKSUM.checksum(data),
+        version,
+    }
+}
+
+/// `payload` packed and signed, with its `Begin`
+#[cfg(test)]
+fn packed(payload: &[u8], version: u32) -> (Vec<u8>, Image) {
+    let (header, _) = image::signed(version, payload);
+    let mut data = header.to_bytes().to_vec();
+    data.extend_from_slice(payload);
+    let begin = image(&data, version);
+    (data, begin)
+}
+
+/// Running version 2, accepting images signed by `image::signed`
+#[cfg(test)]
+fn updater() -> Updater<RamFlash<1024>> {
+    let (_, public_key) = image::signed(0, &[]);
+    Updater::new(RamFlash::new(256), public_key, 2)
+}
+
+#[test]
+fn update() {
+    let payload: Vec<u8> = (0..200u32).map(|i| (i * 7) as u8).collect();
+    let (data, begin) = packed(&payload, 2);
+    let mut updater = updater();
+    assert_eq!(updater.state(), UpdateState::Idle);
+    assert_eq!(updater.handle(Update::Commit), Err(UpdateError::WrongState));
+
+    assert_eq!(
+        updater.handle(Update::Begin(begin)),
+        Ok(UpdateState::Receiving { offset: 0 })
+    );
+    assert_eq!(updater.handle(Update::Verify), Err(UpdateError::Incomplete));
+    assert_eq!(
+        updater.handle(Update::Chunk {
+            offset: 32,
+            data: Bytes::EMPTY
+        }),
+        Err(UpdateError::UnexpectedOffset)
+    );
+    assert_eq!(
+        send(&mut updater, &data, 0),
+        Ok(UpdateState::Receiving { offset: 308 })
+    );
+    assert_eq!(updater.handle(Update::Verify), Ok(UpdateState::Verified));
+    assert_eq!(
+        updater.handle(Update::Commit),
+        Ok(UpdateState::Committed { version: 2 })
+    );
+    assert_eq!(&updater.storage.data[256..564], &data[..]);
+
+    // after a reset
+    let updater = Updater::new(updater.storage, updater.public_key, 2);
+    assert_eq!(updater.state(), UpdateState::Committed { version: 2 });
+}
+
+#[test]
+fn resume_after_reset() {
+    // ends in 0xff, written again on resume
+    let mut payload: Vec<u8> = (0..100u8).collect();
+    payload.extend([0xff; 10]);
+    let (data, begin) = packed(&payload, 3);
+    let mut updater = updater();
+    updater.handle(Update::Begin(begin)).unwrap();
+    send(&mut updater, &data[..64], 0).unwrap();
+
+    let key = updater.public_key;
+    let mut updater = Updater::new(updater.storage, key, 2);
+    assert_eq!(updater.state(), UpdateState::Receiving { offset: 64 });
+    // the same image resumes, another one starts over
+    assert_eq!(
+        updater.handle(Update::Begin(begin)),
+        Ok(UpdateState::Receiving { offset: 64 })
+    );
+    send(&mut updater, &data, 64).unwrap();
+
+    let mut updater = Updater::new(updater.storage, key, 2);
+    assert_eq!(updater.state(), UpdateState::Receiving { offset: 208 });
+    send(&mut updater, &data, 208).unwrap();
+    assert_eq!(updater.handle(Update::Verify), Ok(UpdateState::Verified));
+
+    assert_eq!(
+        updater.handle(Update::Begin(image(&data, 4))),
+        Ok(UpdateState::Receiving { offset: 0 })
+    );
+    assert_eq!(
+        updater.handle(Update::Begin(image(&[0; 800], 4))),
+        Err(UpdateError::TooLarge)
+    );
+}
+
+#[test]
+fn bad_crc() {
+    let (data, begin) = packed(&[1u8; 40], 2);
+    let mut updater = updater();
+    updater
+        .handle(Update::Begin(Image { crc: 0, ..begin }))
+        .unwrap();
+    send(&mut updater, &data, 0).unwrap();
+    assert_eq!(updater.handle(Update::Verify), Err(UpdateError::BadCrc));
+    assert_eq!(updater.state(), UpdateState::Idle);
+    let updater = Updater::new(updater.storage, updater.public_key, 2);
+    assert_eq!(updater.state(), UpdateState::Idle);
+}
+
+#[test]
+fn rejected_images() {
+    let verify = |data: &[u8], begin: Image| {
+        let mut updater = updater();
+        updater.handle(Update::Begin(begin)).unwrap();
+        send(&mut updater, data, 0).unwrap();
+        let result = updater.handle(Update::Verify);
+        // discarded
+        let updater = Updater::new(updater.storage, updater.public_key, 2);
+        assert_eq!(updater.state(), UpdateState::Idle);
+        result
+    };
+
+    // a payload byte changed after signing, with a matching crc
+    let (mut data, _) = packed(&[1u8; 40], 2);
+    data[image::HEADER_LEN] ^= 1;
+    assert_eq!(verify(&data, image(&data, 2)), Err(UpdateError::Signature));
+
+    // older than the running version
+    let (data, begin) = packed(&[1u8; 40], 1);
+    assert_eq!(verify(&data, begin), Err(UpdateError::Rollback));
+
+    // another version claimed in `Begin`
+    let (data, _) = packed(&[1u8; 40], 2);
+    assert_eq!(verify(&data, image(&data, 3)), Err(UpdateError::Signature));
+
+    // not a packed image
+    let data = [1u8; 40];
+    assert_eq!(verify(&data, image(&data, 2)), Err(UpdateError::Signature));
+}
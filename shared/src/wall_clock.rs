@@ -0,0 +1,205 @@
+//! Software wall clock
+//!
+//! Anchors a `UtcDateTime` to a free running tick counter (e.g., `Rtc::get_time_ms()`
+//! or the `Systimer` monotonic) and extrapolates from there. Successive syncs
+//! (from the host) are used to estimate the drift of the local crystal, which is
+//! then compensated for when extrapolating.
+//!
+//! The tick counter may be narrower than 64 bits, wraparound is handled as long
+//! as `now`/`sync` are called at least once per wrap period.
+
+use crate::date_time::UtcDateTime;
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+/// Drift estimates beyond this are assumed to be clock steps (not drift) and discarded
+pub const MAX_DRIFT_PPB: i64 = 1_000_000; // 1000 ppm
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTime;
+
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    ticks: u64,
+    unix_ns: i64,
+}
+
+#[derive(Debug)]
+pub struct WallClock {
+    tick_hz: u64,
+    mask: u64,
+    last_raw: Option<u64>,
+    ticks: u64, // extended (unwrapped) tick count
+    anchor: Option<Anchor>,
+    drift_ppb: Option<i64>,
+}
+
+impl WallClock {
+    /// `tick_hz` is the nominal frequency of the counter and `counter_bits` its width
+    /// e.g., `WallClock::new(1_000, 64)` for `Rtc::get_time_ms()`
+    pub const fn new(tick_hz: u64, counter_bits: u32) -> Self {
+        assert!(tick_hz > 0 && counter_bits > 0 && counter_bits <= 64);
+        let mask = if counter_bits == 64 {
+            u64::MAX
+        } else {
+            (1 << counter_bits) - 1
+        };
+        Self {
+            tick_hz,
+            mask,
+            last_raw: None,
+            ticks: 0,
+            anchor: None,
+            drift_ppb: None,
+        }
+    }
+
+    /// Extend a raw counter value to a monotonic 64 bit tick count
+    pub fn update(&mut self, raw: u64) -> u64 {
+        let raw = raw & self.mask;
+        if let Some(last) = self.last_raw {
+            self.ticks = self.ticks.wrapping_add(raw.wrapping_sub(last) & self.mask);
+        }
+        self.last_raw = Some(raw);
+        self.ticks
+    }
+
+    /// Current time, or None if never synced
+    pub fn now(&mut self, raw: u64) -> Option<UtcDateTime> {
+        self.now_nanos(raw).map(UtcDateTime::from_timestamp_nanos)
+    }
+
+    /// Current time in nanoseconds since the unix epoch, or None if never synced
+    pub fn now_nanos(&mut self, raw: u64) -> Option<i64> {
+        let ticks = self.update(raw);
+        self.anchor
+            .map(|a| a.unix_ns + self.corrected_nanos(ticks - a.ticks))
+    }
+
+    /// Sync to a known time at counter value `raw`, an invalid or out of
+    /// range time is rejected and leaves the clock as it was
+    pub fn sync(&mut self, raw: u64, time: UtcDateTime) -> Result<(), InvalidTime> {
+        let unix_ns = time.timestamp_nanos().ok_or(InvalidTime)?;
+        let ticks = self.update(raw);
+
+        if let Some(a) = self.anchor {
+            let local = self.nominal_nanos(ticks - a.ticks);
+            let actual = unix_ns as i128 - a.unix_ns as i128;
+            if local > 0 {
+                let estimate = (actual - local) * NANOS_PER_SEC / local;
+                if estimate.abs() <= MAX_DRIFT_PPB as i128 {
+                    let estimate = estimate as i64;
+                    // average with the previous estimate to smooth out sync jitter
+                    self.drift_ppb = Some(match self.drift_ppb {
+                        Some(d) => (d + estimate) / 2,
+                        None => estimate,
+                    });
+                }
+            }
+        }
+        self.anchor = Some(Anchor { ticks, unix_ns });
+        Ok(())
+    }
+
+    /// Estimated drift in parts per billion, positive if the counter runs slow
+    pub fn drift_ppb(&self) -> Option<i64> {
+        self.drift_ppb
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.anchor.is_some()
+    }
+
+    fn nominal_nanos(&self, ticks: u64) -> i128 {
+        ticks as i128 * NANOS_PER_SEC / self.tick_hz as i128
+    }
+
+    fn corrected_nanos(&self, ticks: u64) -> i64 {
+        let nominal = self.nominal_nanos(ticks);
+        let drift = self.drift_ppb.unwrap_or(0) as i128;
+        (nominal + nominal * drift / NANOS_PER_SEC) as i64
+    }
+}
+
+#[cfg(test)]
+fn date(h: u32, m: u32, s: u32) -> UtcDateTime {
+    use chrono::{TimeZone, Utc};
+    Utc.with_ymd_and_hms(2023, 10, 16, h, m, s).unwrap().into()
+}
+
+#[test]
+fn not_synced() {
+    let mut clock = WallClock::new(1_000, 64);
+    assert!(!clock.is_synced());
+    assert_eq!(clock.now(1234), None);
+}
+
+#[test]
+fn extrapolates_from_anchor() {
+    let mut clock = WallClock::new(1_000, 64);
+    clock.sync(5_000, date(7, 0, 0)).unwrap();
+    assert_eq!(clock.now(5_000), Some(date(7, 0, 0)));
+    assert_eq!(clock.now(65_000), Some(date(7, 1, 0)));
+    assert_eq!(clock.now(3_605_000), Some(date(8, 0, 0)));
+}
+
+#[test]
+fn handles_wraparound() {
+    // 16 bit millisecond counter wraps every ~65 s
+    let mut clock = WallClock::new(1_000, 16);
+    clock.sync(60_000, date(7, 0, 0)).unwrap();
+
+    let mut raw: u64 = 60_000;
+    for _ in 0..10 {
+        raw = (raw + 30_000) & 0xffff;
+        clock.update(raw);
+    }
+    assert_eq!(clock.now(raw), Some(date(7, 5, 0)));
+}
+
+#[test]
+fn handles_full_width_wraparound() {
+    let mut clock = WallClock::new(1_000, 64);
+    clock.sync(u64::MAX - 499, date(7, 0, 0)).unwrap();
+    assert_eq!(clock.now(500), Some(date(7, 0, 1)));
+}
+
+#[test]
+fn compensates_drift() {
+    // the crystal runs 100 ppm fast, i.e., 1_000_100 ticks per true 1000 s
+    let mut clock = WallClock::new(1_000, 32);
+    let ticks_at = |s: u64| s * 1_000_100 / 1_000;
+
+    clock.sync(ticks_at(0), date(7, 0, 0)).unwrap();
+    clock.sync(ticks_at(1000), date(7, 16, 40)).unwrap();
+    let drift = clock.drift_ppb().unwrap();
+    assert!((drift + 99_990).abs() < 10, "drift {}", drift);
+
+    // an hour later we should be within a millisecond
+    let now = clock.now_nanos(ticks_at(4600)).unwrap();
+    let expected = date(8, 16, 40).timestamp_nanos().unwrap();
+    assert!(
+        (now - expected).abs() < 1_000_000,
+        "error {}",
+        now - expected
+    );
+}
+
+#[test]
+fn clock_step_is_not_drift() {
+    let mut clock = WallClock::new(1_000, 64);
+    clock.sync(0, date(7, 0, 0)).unwrap();
+    // host clock was adjusted by an hour
+    clock.sync(10_000, date(8, 0, 10)).unwrap();
+    assert_eq!(clock.drift_ppb(), None);
+    assert_eq!(clock.now(20_000), Some(date(8, 0, 20)));
+}
+
+#[test]
+fn rejects_invalid_time() {
+    let mut clock = WallClock::new(1_000, 64);
+    clock.sync(0, date(7, 0, 0)).unwrap();
+    let bad = crate::date_time::invalid(date(7, 0, 10));
+    assert_eq!(clock.sync(10_000, bad), Err(InvalidTime));
+    assert_eq!(clock.now(10_000), Some(date(7, 0, 10)));
+}
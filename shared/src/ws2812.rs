@@ -0,0 +1,356 @@
+//! WS2812/SK6812 encoder for the RMT peripheral
+//!
+//! Turns pixel buffers into RMT pulse codes, one code per data bit (MSB first)
+//! and a final reset/end code. A pulse code is a `u32` holding two pulses:
+//!
+//! - bits 0..=14 duration of the first pulse (in RMT ticks), bit 15 its level
+//! - bits 16..=30 duration of the second pulse, bit 31 its level
+//!
+//! A duration of 0 ends the transmission.
+
+use rgb::{RGB8, RGBA8};
+
+/// RGBW pixel, the alpha channel is the white LED
+pub type RGBW8 = RGBA8;
+
+const MAX_TICKS: u32 = 0x7fff;
+
+/// Pulse code with two pulses
+pub const fn pulse_code(level0: bool, ticks0: u16, level1: bool, ticks1: u16) -> u32 {
+    (level0 as u32) << 15
+        | (ticks0 as u32 & MAX_TICKS)
+        | (level1 as u32) << 31
+        | (ticks1 as u32 & MAX_TICKS) << 16
+}
+
+/// Bit timing in ns, from the LED datasheet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub t0h: u32,
+    pub t0l: u32,
+    pub t1h: u32,
+    pub t1l: u32,
+    pub reset: u32,
+}
+
+impl Timing {
+    pub const WS2812: Self = Self {
+        t0h: 350,
+        t0l: 800,
+        t1h: 700,
+        t1l: 600,
+        reset: 50_000,
+    };
+
+    pub const WS2812B: Self = Self {
+        t0h: 400,
+        t0l: 850,
+        t1h: 800,
+        t1l: 450,
+        reset: 50_000,
+    };
+
+    pub const SK6812: Self = Self {
+        t0h: 300,
+        t0l: 900,
+        t1h: 600,
+        t1l: 600,
+        reset: 80_000,
+    };
+}
+
+/// Order of the color channels on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorOrder {
+    /// WS2812, SK6812
+    Grb,
+    Rgb,
+}
+
+pub trait Pixel: Copy {
+    const CHANNELS: usize;
+    /// Channel bytes in wire order, the first `CHANNELS` are used
+    fn wire_bytes(&self, order: ColorOrder) -> [u8; 4];
+}
+
+impl Pixel for RGB8 {
+    const CHANNELS: usize = 3;
+
+    fn wire_bytes(&self, order: ColorOrder) -> [u8; 4] {
+        match order {
+            ColorOrder::Grb => [self.g, self.r, self.b, 0],
+            ColorOrder::Rgb => [self.r, self.g, self.b, 0],
+        }
+    }
+}
+
+impl Pixel for RGBW8 {
+    const CHANNELS: usize = 4;
+
+    fn wire_bytes(&self, order: ColorOrder) -> [u8; 4] {
+        match order {
+            ColorOrder::Grb => [self.g, self.r, self.b, self.a],
+            ColorOrder::Rgb => [self.r, self.g, self.b, self.a],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferTooSmall;
+
+/// Pulse codes needed for `pixels` pixels of type P
+pub const fn buffer_len<P: Pixel>(pixels: usize) -> usize {
+    pixels * P::CHANNELS * 8 + 1
+}
+
+/// Rounded to the nearest tick, saturating at the 15 bit duration
+const fn ticks(ns: u32, tick_hz: u64) -> u16 {
+    let t = (ns as u64 * tick_hz + 500_000_000) / 1_000_000_000;
+    if t > MAX_TICKS as u64 {
+        MAX_TICKS as u16
+    } else {
+        t as u16
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoder {
+    order: ColorOrder,
+    zero: u32,
+    one: u32,
+    end: u32,
+}
+
+impl Encoder {
+    /// `clock_hz` is the RMT source clock (80 MHz APB) and `divider` the channel clock divider
+    /// (1 to 255)
+    pub const fn new(timing: Timing, order: ColorOrder, clock_hz: u32, divider: u8) -> Self {
+        assert!(divider > 0);
+        let hz = clock_hz as u64 / divider as u64;
+        Self {
+            order,
+            zero: pulse_code(true, ticks(timing.t0h, hz), false, ticks(timing.t0l, hz)),
+            one: pulse_code(true, ticks(timing.t1h, hz), false, ticks(timing.t1l, hz)),
+            // hold low for the reset time, then end the transmission
+            end: pulse_code(false, ticks(timing.reset, hz), false, 0),
+        }
+    }
+
+    /// Pulse codes for a 0 bit, 1 bit and the end
+    pub fn codes(&self) -> (u32, u32, u32) {
+        (self.zero, self.one, self.end)
+    }
+
+    /// Encode pixels into `out`, returns the number of pulse codes written
+    pub fn encode<P: Pixel>(
+        &self,
+        pixels: impl IntoIterator<Item = P>,
+        out: &mut [u32],
+    ) -> Result<usize, BufferTooSmall> {
+        let mut n = 0;
+        for p in pixels {
+            let bytes = p.wire_bytes(self.order);
+            for byte in &bytes[..P::CHANNELS] {
+                let codes = out.get_mut(n..n + 8).ok_or(BufferTooSmall)?;
+                for (i, code) in codes.iter_mut().enumerate() {
+                    *code = if byte & (0x80 >> i) != 0 {
+                        self.one
+                    } else {
+                        self.zero
+                    };
+                }
+                n += 8;
+            }
+        }
+        *out.get_mut(n).ok_or(BufferTooSmall)? = self.end;
+        Ok(n + 1)
+    }
+}
+
+/// Two pulse code buffers, encode the next frame while the current one is sent
+#[derive(Debug, Clone)]
+pub struct DoubleBuffer<const N: usize> {
+    bufs: [[u32; N]; 2],
+    lens: [usize; 2],
+    front: usize,
+}
+
+impl<const N: usize> Default for DoubleBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> DoubleBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            bufs: [[0; N]; 2],
+            lens: [0; 2],
+            front: 0,
+        }
+    }
+
+    /// Encode into the back buffer
+    pub fn encode<P: Pixel>(
+        &mut self,
+        encoder: &Encoder,
+        pixels: impl IntoIterator<Item = P>,
+    ) -> Result<(), BufferTooSmall> {
+        self.split().1.encode(encoder, pixels)
+    }
+
+    /// The front buffer, to send, and the back buffer to encode the next frame
+    /// into meanwhile, `swap` once both are done
+    pub fn split(&mut self) -> (&[u32], Back<'_>) {
+        let [a, b] = &mut self.bufs;
+        let [len_a, len_b] = &mut self.lens;
+        let ((front, front_len), (back, back_len)) = if self.front == 0 {
+            ((a, len_a), (b, len_b))
+        } else {
+            ((b, len_b), (a, len_a))
+        };
+        (
+            &front[..*front_len],
+            Back {
+                buf: back,
+                len: back_len,
+            },
+        )
+    }
+
+    /// Make the back buffer the front one, returns the pulse codes to send
+    pub fn swap(&mut self) -> &[u32] {
+        self.front = 1 - self.front;
+        self.front()
+    }
+
+    pub fn front(&self) -> &[u32] {
+        &self.bufs[self.front][..self.lens[self.front]]
+    }
+}
+
+/// The back buffer of a `DoubleBuffer`, see `DoubleBuffer::split`
+#[derive(Debug)]
+pub struct Back<'a> {
+    buf: &'a mut [u32],
+    len: &'a mut usize,
+}
+
+impl Back<'_> {
+    pub fn encode<P: Pixel>(
+        self,
+        encoder: &Encoder,
+        pixels: impl IntoIterator<Item = P>,
+    ) -> Result<(), BufferTooSmall> {
+        *self.len = encoder.encode(pixels, self.buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn decode(code: u32) -> (bool, u32, bool, u32) {
+    (
+        code & 1 << 15 != 0,
+        code & MAX_TICKS,
+        code & 1 << 31 != 0,
+        code >> 16 & MAX_TICKS,
+    )
+}
+
+#[test]
+fn pulse_code_layout() {
+    assert_eq!(pulse_code(true, 32, false, 68), 0x0044_8020);
+    assert_eq!(decode(0x0044_8020), (true, 32, false, 68));
+    assert_eq!(pulse_code(false, 1, true, 2), 0x8002_0001);
+}
+
+#[test]
+fn ws2812b_ticks_at_80mhz() {
+    // 12.5 ns ticks
+    let (zero, one, end) = Encoder::new(Timing::WS2812B, ColorOrder::Grb, 80_000_000, 1).codes();
+    assert_eq!(decode(zero), (true, 32, false, 68)); // 400 ns, 850 ns
+    assert_eq!(decode(one), (true, 64, false, 36)); // 800 ns, 450 ns
+    assert_eq!(decode(end), (false, 4000, false, 0)); // 50 us
+}
+
+#[test]
+fn ticks_are_rounded_within_tolerance() {
+    // 37.5 ns ticks, the datasheet allows +-150 ns
+    let (zero, one, _) = Encoder::new(Timing::WS2812B, ColorOrder::Grb, 80_000_000, 3).codes();
+    assert_eq!(decode(zero), (true, 11, false, 23)); // 412.5 ns, 862.5 ns
+    assert_eq!(decode(one), (true, 21, false, 12)); // 787.5 ns, 450 ns
+
+    // reset saturates instead of wrapping
+    let (_, _, end) = Encoder::new(Timing::SK6812, ColorOrder::Grb, 80_000_000, 1).codes();
+    assert_eq!(decode(end), (false, 6400, false, 0));
+    let slow = Timing {
+        reset: 1_000_000,
+        ..Timing::WS2812
+    };
+    let (_, _, end) = Encoder::new(slow, ColorOrder::Grb, 80_000_000, 1).codes();
+    assert_eq!(decode(end), (false, MAX_TICKS, false, 0));
+}
+
+#[test]
+fn encode_grb_msb_first() {
+    let encoder = Encoder::new(Timing::WS2812B, ColorOrder::Grb, 80_000_000, 1);
+    let (zero, one, end) = encoder.codes();
+    let mut out = [0; buffer_len::<RGB8>(1)];
+
+    let n = encoder
+        .encode([RGB8::new(0x01, 0x80, 0xff)], &mut out)
+        .unwrap();
+    assert_eq!(n, 25);
+    // green 0x80
+    assert_eq!(out[0], one);
+    assert!(out[1..8].iter().all(|c| *c == zero));
+    // red 0x01
+    assert!(out[8..15].iter().all(|c| *c == zero));
+    assert_eq!(out[15], one);
+    // blue 0xff
+    assert!(out[16..24].iter().all(|c| *c == one));
+    assert_eq!(out[24], end);
+}
+
+#[test]
+fn encode_rgbw_and_runtime_length() {
+    let encoder = Encoder::new(Timing::SK6812, ColorOrder::Rgb, 80_000_000, 1);
+    let (zero, one, end) = encoder.codes();
+    let pixels = [RGBW8::new(0xff, 0, 0, 0x01); 3];
+    let mut out = [0; 80];
+
+    let n = encoder
+        .encode(pixels.iter().copied().take(2), &mut out)
+        .unwrap();
+    assert_eq!(n, buffer_len::<RGBW8>(2));
+    assert!(out[0..8].iter().all(|c| *c == one));
+    assert!(out[8..31].iter().all(|c| *c == zero));
+    assert_eq!(out[31], one);
+    assert_eq!(out[64], end);
+
+    // 3 RGBW pixels don't fit
+    assert_eq!(encoder.encode(pixels, &mut out), Err(BufferTooSmall));
+}
+
+#[test]
+fn double_buffer() {
+    let encoder = Encoder::new(Timing::WS2812B, ColorOrder::Grb, 80_000_000, 1);
+    let mut buffers = DoubleBuffer::<{ buffer_len::<RGB8>(2) }>::new();
+    assert!(buffers.front().is_empty());
+
+    buffers.encode(&encoder, [RGB8::new(1, 1, 1)]).unwrap();
+    let first = buffers.swap().to_vec();
+    assert_eq!(first.len(), 25);
+
+    // encoding the next frame leaves the front alone
+    buffers.encode(&encoder, [RGB8::new(2, 2, 2); 2]).unwrap();
+    assert_eq!(buffers.front(), &first[..]);
+    assert_eq!(buffers.swap().len(), 49);
+
+    // the front is still readable (sent) while the back is encoded
+    let (front, back) = buffers.split();
+    assert_eq!(front.len(), 49);
+    back.encode(&encoder, [RGB8::new(3, 3, 3)]).unwrap();
+    assert_eq!(buffers.front().len(), 49);
+    assert_eq!(buffers.swap().len(), 25);
+}